@@ -13,15 +13,12 @@ Retrieves a database connection pool for a PostgreSQL database.
 
 # Example
 
-```rust
-# use anyhow::Result;
-# use sqlx::{Pool, Postgres};
+```no_run
+# use rusty_mvt::db::get_db_connector;
 #
-# pub async fn var(_: &str) -> Result<String> { Ok("".to_string()) }
-#
-# pub async fn get_db_connector() -> Result<Pool<Postgres>> {
+# async fn example() -> anyhow::Result<()> {
 let pool = get_db_connector().await?;
-#     Ok(pool)
+#     Ok(())
 # }
 ```
 
@@ -51,9 +48,9 @@ pub async fn get_db_connector() -> Result<Pool<Postgres>> {
         .connect(&db_url)
         .await
     {
-        Ok(pool) => return Ok(pool),
+        Ok(pool) => Ok(pool),
 
-        Err(e) => return Err(anyhow!("Failed to connect using provided string: {}", e)),
+        Err(e) => Err(anyhow!("Failed to connect using provided string: {}", e)),
     }
 }
 
@@ -71,72 +68,44 @@ A `Result` containing the populated `TableRegistry` object. If the function is s
 
 # Example
 
-```rust
-# use anyhow::Result;
-# use sqlx::{Pool, Postgres};
-#
-# pub async fn var(_: &str) -> Result<String> { Ok("".to_string()) }
-#
-# pub async fn get_db_connector() -> Result<Pool<Postgres>> {
-#     let pool = get_db_connector().await?;
-#     Ok(pool)
-# }
-#
-# pub async fn get_srid_unit(_: i32) -> Option<String> { Some("".to_string()) }
-#
-# mod structs {
-#     pub struct Schema {
-#         pub tables: std::collections::HashMap<String, Table>,
-#     }
-#
-#     pub struct Table {
-#         pub srid: Option<i32>,
-#         pub dist_unit: Option<String>,
-#         pub use_geog: bool,
-#     }
-#
-#     pub struct TableRegistry {
-#         pub schemas: std::collections::HashMap<String, Schema>,
-#     }
-# }
+```no_run
+# use rusty_mvt::db::{get_db_connector, load_table_registry};
 #
-# async fn query(_: &str) -> Result<Vec<sqlx::postgres::PgRow>> { Ok(Vec::new()) }
-#
-# async fn fetch_all(_: &sqlx::postgres::PgPool) -> Result<Vec<sqlx::postgres::PgRow>> { Ok(Vec::new()) }
-#
-# async fn get_srid_unit(_: i32) -> Option<String> { Some("".to_string()) }
-#
-# async fn get_db_connector() -> Result<sqlx::postgres::PgPool> { Ok(sqlx::postgres::PgPool::new("")) }
-#
-# async fn load_table_registry(p: &sqlx::postgres::PgPool, db: String) -> Result<structs::TableRegistry> {
+# async fn example() -> anyhow::Result<()> {
 let pool = get_db_connector().await?;
 let registry = load_table_registry(&pool, "my_database".to_string()).await?;
-#     Ok(registry)
+#     Ok(())
 # }
 ```
 */
 pub async fn load_table_registry(p: &PgPool, db: String) -> Result<TableRegistry> {
     let schema_and_table_info = query("select
     tabs.*,
-    gc.f_geometry_column as geom_column, gc.srid as srid, gc.type as geom_type, gc.coord_dimension as geom_coord_dimension
+    gc.f_geometry_column as geom_column, gc.srid as srid, gc.type as geom_type, gc.coord_dimension as geom_coord_dimension,
+    dt.column_name::TEXT as datetime_column
     from
     (
         select
         pks.schema_name as schema,
         pks.table_name as table,
-        array_agg(pks.pk)::TEXT[] as primary_key_columns
+        array_agg(pks.pk)::TEXT[] as primary_key_columns,
+        array_agg(pks.pk_type)::TEXT[] as primary_key_types
         from
         (
             select
             tab.table_schema as schema_name,
             tab.table_name as table_name,
-            tco.column_name as pk
+            tco.column_name as pk,
+            format_type(att.atttypid, att.atttypmod) as pk_type
             from
             information_schema.table_constraints tab
             left join information_schema.key_column_usage tco on
             tab.table_schema = tco.table_schema
             and tab.table_name = tco.table_name
             and tab.constraint_name = tco.constraint_name
+            left join pg_attribute att on
+            att.attrelid = format('%I.%I', tab.table_schema, tab.table_name)::regclass
+            and att.attname = tco.column_name
             where
             tab.constraint_type = 'PRIMARY KEY'
             and tab.table_schema <> 'pg_catalog'
@@ -153,7 +122,20 @@ pub async fn load_table_registry(p: &PgPool, db: String) -> Result<TableRegistry
         left join geometry_columns gc
         on
         tabs.schema = gc.f_table_schema
-        and tabs.table = gc.f_table_name")
+        and tabs.table = gc.f_table_name
+        left join lateral (
+            select
+            cols.column_name
+            from
+            information_schema.columns cols
+            where
+            cols.table_schema = tabs.schema
+            and cols.table_name = tabs.table
+            and cols.data_type in ('timestamp with time zone', 'timestamp without time zone', 'date')
+            order by
+            cols.ordinal_position
+            limit 1
+        ) dt on true")
         .fetch_all(p)
         .await
         .context("Encountered error while querying database for schemata")?
@@ -180,7 +162,7 @@ pub async fn load_table_registry(p: &PgPool, db: String) -> Result<TableRegistry
         if let Some(srid) = this_table.srid {
             this_table.dist_unit = get_srid_unit(srid).map(|unit| unit.to_owned());
             if let Some(unit) = &this_table.dist_unit {
-                this_table.use_geog = *unit == "deg".to_string();
+                this_table.use_geog = *unit == "deg";
             } else {
                 this_table.use_geog = false;
             }
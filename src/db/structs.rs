@@ -47,6 +47,9 @@ pub struct Table {
     #[sqlx(rename = "schema")]
     pub schema_name: String,
     pub primary_key_columns: Vec<String>,
+    //SQL types of the primary key columns, as `format_type` names them
+    #[sqlx(default)]
+    pub primary_key_types: Vec<String>,
     #[sqlx(default)]
     pub geom_column: Option<String>,
     #[sqlx(default)]
@@ -54,7 +57,11 @@ pub struct Table {
     #[sqlx(default)]
     pub srid: Option<i32>,
     #[sqlx(default)]
+    pub geom_coord_dimension: Option<i32>,
+    #[sqlx(default)]
     pub attr_columns: Option<Vec<String>>,
+    #[sqlx(default)]
+    pub datetime_column: Option<String>,
     #[sqlx(skip)]
     pub dist_unit: Option<String>,
     #[sqlx(skip)]
@@ -62,6 +69,7 @@ pub struct Table {
 }
 
 impl Table {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         name: String,
         schema_name: String,
@@ -78,10 +86,13 @@ impl Table {
                 name,
                 schema_name,
                 primary_key_columns,
+                primary_key_types: Vec::new(),
                 geom_column: Some(geom_column),
                 geom_type: Some(geom_type),
                 srid: Some(srid),
+                geom_coord_dimension: None,
                 attr_columns: Some(attr_columns),
+                datetime_column: None,
                 dist_unit: Some(dist_unit),
                 use_geog,
             }
@@ -90,10 +101,13 @@ impl Table {
                 name,
                 schema_name,
                 primary_key_columns,
+                primary_key_types: Vec::new(),
                 geom_column: Some(geom_column),
                 geom_type: Some(geom_type),
                 srid: Some(srid),
+                geom_coord_dimension: None,
                 attr_columns: Some(Vec::new()),
+                datetime_column: None,
                 dist_unit: Some(dist_unit),
                 use_geog,
             }
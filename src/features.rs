@@ -0,0 +1,449 @@
+mod editing;
+mod utils;
+use crate::{db::Table, AppError, AppState};
+use anyhow::anyhow;
+use axum::{
+    extract::{Host, Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use axum_macros::debug_handler;
+pub use editing::{create_item, delete_item, replace_item, update_item};
+use reqwest::header::CONTENT_TYPE;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use utils::{
+    find_collection, get_collection_id, get_estimated_extent, get_feature_item, get_feature_items,
+    get_publishable_tables, parse_bbox, parse_datetime,
+};
+
+const DEFAULT_ITEM_LIMIT: usize = 10;
+const MAX_ITEM_LIMIT: usize = 10000;
+const CRS84: &str = "http://www.opengis.net/def/crs/OGC/1.3/CRS84";
+const CONFORMANCE_CLASSES: [&str; 2] = [
+    "http://www.opengis.net/spec/ogcapi-features-1/1.0/conf/core",
+    "http://www.opengis.net/spec/ogcapi-features-1/1.0/conf/geojson",
+];
+
+/**
+An error from the features API, answered with the status the standard expects: `404 Not Found` for unknown collections and features, `400 Bad Request` for invalid parameters, and `500 Internal Server Error` otherwise.
+*/
+#[derive(Debug)]
+pub enum FeaturesError {
+    NotFound(String),
+    BadRequest(String),
+    Internal(AppError),
+}
+
+impl IntoResponse for FeaturesError {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            FeaturesError::NotFound(message) => (StatusCode::NOT_FOUND, message).into_response(),
+            FeaturesError::BadRequest(message) => {
+                (StatusCode::BAD_REQUEST, message).into_response()
+            }
+            FeaturesError::Internal(e) => e.into_response(),
+        }
+    }
+}
+
+impl From<AppError> for FeaturesError {
+    fn from(err: AppError) -> Self {
+        FeaturesError::Internal(err)
+    }
+}
+
+//Editing endpoints report every failure through `AppError`
+impl From<FeaturesError> for AppError {
+    fn from(err: FeaturesError) -> Self {
+        match err {
+            FeaturesError::NotFound(message) | FeaturesError::BadRequest(message) => {
+                AppError(anyhow!(message))
+            }
+            FeaturesError::Internal(e) => e,
+        }
+    }
+}
+
+pub struct GeoJSONResponse(Value);
+
+impl IntoResponse for GeoJSONResponse {
+    fn into_response(self) -> axum::response::Response {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, "application/geo+json".parse().unwrap());
+        (headers, Json(self.0)).into_response()
+    }
+}
+
+#[derive(Serialize)]
+pub struct Link {
+    href: String,
+    rel: String,
+    #[serde(rename = "type")]
+    media_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+}
+
+impl Link {
+    fn new(href: String, rel: &str, media_type: &str, title: Option<&str>) -> Link {
+        Link {
+            href,
+            rel: rel.to_string(),
+            media_type: media_type.to_string(),
+            title: title.map(|t| t.to_string()),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ItemsOptions {
+    bbox: Option<String>,
+    datetime: Option<String>,
+    limit: Option<usize>,
+    offset: Option<usize>,
+}
+
+/**
+Builds the root URL of the features API from the request's host, honouring `X-Forwarded-Proto` when the server sits behind a proxy.
+*/
+fn get_base_url(host: &str, headers: &HeaderMap) -> String {
+    let scheme = headers
+        .get("x-forwarded-proto")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("http");
+    format!("{}://{}/ogc", scheme, host)
+}
+
+fn make_collection(base_url: &str, table: &Table, extent: Option<[f64; 4]>) -> Value {
+    let collection_id = get_collection_id(table);
+    let collection_url = format!("{}/collections/{}", base_url, collection_id);
+
+    let mut collection = json!({
+        "id": collection_id,
+        "title": table.name,
+        "itemType": "feature",
+        "crs": [CRS84],
+        "links": [
+            Link::new(collection_url.clone(), "self", "application/json", None),
+            Link::new(
+                format!("{}/items", collection_url),
+                "items",
+                "application/geo+json",
+                Some("Features"),
+            ),
+        ],
+    });
+
+    if let Some(bbox) = extent {
+        collection["extent"] = json!({
+            "spatial": { "bbox": [bbox], "crs": CRS84 }
+        });
+    }
+
+    collection
+}
+
+#[debug_handler]
+/**
+Handles a GET request for the OGC API – Features landing page.
+*/
+pub async fn get_landing_page(Host(host): Host, headers: HeaderMap) -> Json<Value> {
+    let base_url = get_base_url(&host, &headers);
+    Json(json!({
+        "title": "RustyMVT",
+        "description": "OGC API – Features access to the tables published by this server.",
+        "links": [
+            Link::new(base_url.clone(), "self", "application/json", Some("This document")),
+            Link::new(
+                format!("{}/conformance", base_url),
+                "conformance",
+                "application/json",
+                Some("Conformance classes"),
+            ),
+            Link::new(
+                format!("{}/collections", base_url),
+                "data",
+                "application/json",
+                Some("Feature collections"),
+            ),
+        ],
+    }))
+}
+
+#[debug_handler]
+/**
+Handles a GET request for the list of OGC API – Features conformance classes implemented by the server.
+*/
+pub async fn get_conformance() -> Json<Value> {
+    Json(json!({ "conformsTo": CONFORMANCE_CLASSES }))
+}
+
+#[debug_handler]
+/**
+Handles a GET request describing every table in the registry that can be published as a feature collection.
+*/
+pub async fn get_collections(
+    State(state): State<AppState>,
+    Host(host): Host,
+    headers: HeaderMap,
+) -> Json<Value> {
+    let base_url = get_base_url(&host, &headers);
+    let collections = get_publishable_tables(&state.table_registry)
+        .into_iter()
+        .map(|table| make_collection(&base_url, table, None))
+        .collect::<Vec<Value>>();
+
+    Json(json!({
+        "links": [
+            Link::new(format!("{}/collections", base_url), "self", "application/json", None),
+        ],
+        "collections": collections,
+    }))
+}
+
+#[debug_handler]
+/**
+Handles a GET request describing a single feature collection, including its estimated spatial extent where available.
+*/
+pub async fn get_collection(
+    State(state): State<AppState>,
+    Path(collectionid): Path<String>,
+    Host(host): Host,
+    headers: HeaderMap,
+) -> Result<Json<Value>, FeaturesError> {
+    let base_url = get_base_url(&host, &headers);
+    let table_spec = find_collection(&state.table_registry, &collectionid)?;
+    let extent = get_estimated_extent(&state.db_pool, table_spec).await;
+
+    Ok(Json(make_collection(&base_url, table_spec, extent)))
+}
+
+/**
+Builds the `self`, `next` and `prev` links of a page of items, repeating the request's filters. The `next` link is given while features remain beyond the page, and `prev` whenever the page does not start at the first feature.
+*/
+fn make_paging_links(
+    items_url: &str,
+    options: &ItemsOptions,
+    limit: usize,
+    offset: usize,
+    number_returned: usize,
+    number_matched: i64,
+) -> Vec<Link> {
+    let make_page_url = |page_offset: usize| {
+        let mut params = vec![
+            format!("limit={}", limit),
+            format!("offset={}", page_offset),
+        ];
+        if let Some(bbox) = &options.bbox {
+            params.push(format!("bbox={}", urlencoding::encode(bbox)));
+        }
+        if let Some(datetime) = &options.datetime {
+            params.push(format!("datetime={}", urlencoding::encode(datetime)));
+        }
+        format!("{}?{}", items_url, params.join("&"))
+    };
+
+    let mut links = vec![Link::new(
+        make_page_url(offset),
+        "self",
+        "application/geo+json",
+        None,
+    )];
+    if ((offset + number_returned) as i64) < number_matched {
+        links.push(Link::new(
+            make_page_url(offset + number_returned),
+            "next",
+            "application/geo+json",
+            Some("Next page"),
+        ));
+    }
+    if offset > 0 {
+        links.push(Link::new(
+            make_page_url(offset.saturating_sub(limit)),
+            "prev",
+            "application/geo+json",
+            Some("Previous page"),
+        ));
+    }
+    links
+}
+
+#[debug_handler]
+/**
+Handles a GET request for the features of a collection.
+
+# Arguments
+
+* `State(state)`: The state of the application, which contains the table registry and database pool.
+* `Path(collectionid)`: The collection identifier, in the form `schema.table`.
+* `Query(options)`: The `bbox` (in WGS84), `datetime`, `limit` and `offset` parameters defined by the standard.
+
+# Returns
+
+Returns a result that either contains a GeoJSON `FeatureCollection` with paging links and counts, or a `FeaturesError` if the collection is unknown, a parameter is invalid or there was an error during the process. A `limit` above the maximum is reduced to it.
+*/
+pub async fn get_items(
+    State(state): State<AppState>,
+    Path(collectionid): Path<String>,
+    Query(options): Query<ItemsOptions>,
+    Host(host): Host,
+    headers: HeaderMap,
+) -> Result<GeoJSONResponse, FeaturesError> {
+    let base_url = get_base_url(&host, &headers);
+    let table_spec = find_collection(&state.table_registry, &collectionid)?;
+
+    let limit = options
+        .limit
+        .unwrap_or(DEFAULT_ITEM_LIMIT)
+        .clamp(1, MAX_ITEM_LIMIT);
+    let offset = options.offset.unwrap_or(0);
+
+    let bbox = options.bbox.as_deref().map(parse_bbox).transpose()?;
+    let datetime = options
+        .datetime
+        .as_deref()
+        .map(parse_datetime)
+        .transpose()?;
+
+    let (features, number_matched) = get_feature_items(
+        &state.db_pool,
+        table_spec,
+        bbox,
+        datetime.as_ref(),
+        limit,
+        offset,
+    )
+    .await?;
+    let number_returned = features.len();
+
+    let items_url = format!(
+        "{}/collections/{}/items",
+        base_url,
+        get_collection_id(table_spec)
+    );
+    let links = make_paging_links(
+        &items_url,
+        &options,
+        limit,
+        offset,
+        number_returned,
+        number_matched,
+    );
+
+    Ok(GeoJSONResponse(json!({
+        "type": "FeatureCollection",
+        "features": features
+            .into_iter()
+            .map(|f| f.into_geojson())
+            .collect::<Vec<Value>>(),
+        "numberMatched": number_matched,
+        "numberReturned": number_returned,
+        "links": links,
+    })))
+}
+
+#[debug_handler]
+/**
+Handles a GET request for a single feature of a collection, identified by its primary key. Unknown collections and features are answered with `404 Not Found`.
+*/
+pub async fn get_item(
+    State(state): State<AppState>,
+    Path((collectionid, featureid)): Path<(String, String)>,
+    Host(host): Host,
+    headers: HeaderMap,
+) -> Result<GeoJSONResponse, FeaturesError> {
+    let base_url = get_base_url(&host, &headers);
+    let table_spec = find_collection(&state.table_registry, &collectionid)?;
+
+    let feature = get_feature_item(&state.db_pool, table_spec, &featureid).await?;
+    let collection_url = format!("{}/collections/{}", base_url, get_collection_id(table_spec));
+
+    let mut geojson = feature.into_geojson();
+    geojson["links"] = json!([
+        Link::new(
+            format!(
+                "{}/items/{}",
+                collection_url,
+                urlencoding::encode(&featureid)
+            ),
+            "self",
+            "application/geo+json",
+            None,
+        ),
+        Link::new(collection_url, "collection", "application/json", None),
+    ]);
+
+    Ok(GeoJSONResponse(geojson))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ITEMS_URL: &str = "http://localhost/ogc/collections/public.roads/items";
+
+    fn options(bbox: Option<&str>, datetime: Option<&str>) -> ItemsOptions {
+        ItemsOptions {
+            bbox: bbox.map(str::to_string),
+            datetime: datetime.map(str::to_string),
+            limit: None,
+            offset: None,
+        }
+    }
+
+    fn rels_and_hrefs(links: &[Link]) -> Vec<(&str, &str)> {
+        links
+            .iter()
+            .map(|l| (l.rel.as_str(), l.href.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn first_page_links_only_to_the_next() {
+        let links = make_paging_links(ITEMS_URL, &options(None, None), 10, 0, 10, 25);
+        assert_eq!(
+            rels_and_hrefs(&links),
+            vec![
+                ("self", format!("{}?limit=10&offset=0", ITEMS_URL).as_str()),
+                ("next", format!("{}?limit=10&offset=10", ITEMS_URL).as_str()),
+            ]
+        );
+    }
+
+    #[test]
+    fn last_page_links_only_to_the_previous() {
+        let links = make_paging_links(ITEMS_URL, &options(None, None), 10, 20, 5, 25);
+        assert_eq!(
+            rels_and_hrefs(&links),
+            vec![
+                ("self", format!("{}?limit=10&offset=20", ITEMS_URL).as_str()),
+                ("prev", format!("{}?limit=10&offset=10", ITEMS_URL).as_str()),
+            ]
+        );
+
+        //A page starting part-way into the first page links back to the very start
+        let links = make_paging_links(ITEMS_URL, &options(None, None), 10, 5, 10, 15);
+        assert_eq!(links[1].href, format!("{}?limit=10&offset=0", ITEMS_URL));
+    }
+
+    #[test]
+    fn paging_links_repeat_the_filters() {
+        let links = make_paging_links(
+            ITEMS_URL,
+            &options(Some("-1,50,0,51"), Some("2024-01-01T00:00:00Z/..")),
+            10,
+            0,
+            10,
+            20,
+        );
+        assert_eq!(
+            links[1].href,
+            format!(
+                "{}?limit=10&offset=10&bbox=-1%2C50%2C0%2C51&datetime=2024-01-01T00%3A00%3A00Z%2F..",
+                ITEMS_URL
+            )
+        );
+    }
+}
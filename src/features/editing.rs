@@ -0,0 +1,665 @@
+use super::utils::{find_collection, get_collection_id, get_feature_item, make_pk_condition};
+use super::{get_base_url, GeoJSONResponse};
+use crate::{db::Table, AppError, AppState};
+use anyhow::anyhow;
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Host, Path, State},
+    http::{header::AUTHORIZATION, request::Parts, HeaderMap, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use axum_macros::debug_handler;
+use geojson::Feature;
+use reqwest::header::LOCATION;
+use serde_json::Value;
+use sqlx::{query_as, query_scalar, PgPool, Postgres, Transaction};
+use std::collections::HashSet;
+use subtle::ConstantTimeEq;
+
+const DEFAULT_INPUT_SRID: i32 = 4326;
+const CACHE_SRID: i32 = 3857;
+
+/**
+Extractor guarding the editing endpoints. Requests must carry an `Authorization: Bearer <token>` header matching the configured `EDIT_API_TOKEN`; editing is disabled entirely when no token is configured.
+*/
+pub struct EditAuthorization;
+
+#[async_trait]
+impl FromRequestParts<AppState> for EditAuthorization {
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let expected = match &state.edit_token {
+            Some(token) => token,
+            None => {
+                return Err((
+                    StatusCode::FORBIDDEN,
+                    "Feature editing is not enabled".to_string(),
+                ))
+            }
+        };
+
+        let provided = parts
+            .headers
+            .get(AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "));
+
+        //Compared in constant time, so response timing does not reveal how much of a guess matched
+        match provided {
+            Some(token) if bool::from(token.as_bytes().ct_eq(expected.as_bytes())) => {
+                Ok(EditAuthorization)
+            }
+            _ => Err((
+                StatusCode::UNAUTHORIZED,
+                "Missing or invalid bearer token".to_string(),
+            )),
+        }
+    }
+}
+
+enum EditMode {
+    Create,
+    Replace,
+    Update,
+}
+
+/**
+Reads the SRID of incoming geometries from the `Content-Crs` header, accepting OGC CRS URIs (optionally wrapped in angle brackets) and `EPSG:<code>` strings. Geometries without the header are taken to be WGS84, per RFC 7946.
+*/
+fn get_input_srid(headers: &HeaderMap) -> Result<i32, AppError> {
+    let crs = match headers.get("content-crs").and_then(|v| v.to_str().ok()) {
+        Some(crs) => crs.trim().trim_start_matches('<').trim_end_matches('>'),
+        None => return Ok(DEFAULT_INPUT_SRID),
+    };
+
+    if crs.ends_with("/OGC/1.3/CRS84") {
+        return Ok(DEFAULT_INPUT_SRID);
+    }
+
+    crs.rsplit(['/', ':'])
+        .next()
+        .and_then(|code| code.parse::<i32>().ok())
+        .filter(|_| crs.contains("EPSG"))
+        .ok_or_else(|| AppError(anyhow!("Unsupported Content-Crs: {}", crs)))
+}
+
+/**
+Checks a GeoJSON geometry against the geometry type declared for a table.
+
+# Returns
+
+A `Result` containing `true` if the geometry is a single-part geometry destined for the multi-part column of the same kind, and so must be promoted with `ST_Multi`, or an `AppError` if the types are incompatible.
+*/
+fn check_geometry_type(table: &Table, geometry: &geojson::Geometry) -> Result<bool, AppError> {
+    let incoming = geometry.value.type_name().to_uppercase();
+    let expected = table
+        .geom_type
+        .as_deref()
+        .map(|t| t.to_uppercase())
+        .unwrap_or_else(|| "GEOMETRY".to_string());
+
+    if expected == "GEOMETRY" || expected == incoming {
+        Ok(false)
+    } else if expected == format!("MULTI{}", incoming) {
+        Ok(true)
+    } else {
+        Err(AppError(anyhow!(
+            "Geometry type {} does not match table geometry type {}",
+            geometry.value.type_name(),
+            expected
+        )))
+    }
+}
+
+/**
+Builds the SQL expression reading a GeoJSON geometry from the given query parameter and transforming it into the table SRID.
+*/
+fn make_geom_expression(
+    param_index: usize,
+    input_srid: i32,
+    table_srid: i32,
+    promote_to_multi: bool,
+) -> String {
+    let transformed = format!(
+        "ST_Transform(ST_SetSRID(ST_GeomFromGeoJSON(${}), {}), {})",
+        param_index, input_srid, table_srid
+    );
+    if promote_to_multi {
+        format!("ST_Multi({})", transformed)
+    } else {
+        transformed
+    }
+}
+
+fn get_table_srid(table: &Table) -> Result<i32, AppError> {
+    match table.srid {
+        Some(srid) if srid > 0 => Ok(srid),
+        _ => Err(AppError(anyhow!(
+            "Table does not declare an SRID. Unable to edit features."
+        ))),
+    }
+}
+
+fn get_edit_columns(table: &Table) -> Result<(&String, &String), AppError> {
+    match (
+        table.geom_column.as_ref(),
+        table.primary_key_columns.first(),
+    ) {
+        (Some(geom_col), Some(pk_col)) => Ok((geom_col, pk_col)),
+        _ => Err(AppError(anyhow!(
+            "Table does not contain valid geometry and primary key data"
+        ))),
+    }
+}
+
+/**
+Lists the columns of a table, other than its geometry column, that feature properties may be written to.
+*/
+async fn get_writable_columns(
+    pool: &PgPool,
+    table: &Table,
+    geom_col: &str,
+) -> Result<Vec<String>, AppError> {
+    query_scalar::<Postgres, String>(
+        "select column_name::text
+        from information_schema.columns
+        where table_schema = $1 and table_name = $2 and column_name <> $3
+        order by ordinal_position",
+    )
+    .bind(&table.schema_name)
+    .bind(&table.name)
+    .bind(geom_col)
+    .fetch_all(pool)
+    .await
+    .map_err(|e| AppError(anyhow!(e)))
+}
+
+/**
+Validates a geometry with PostGIS after transforming it into the table SRID, returning the reason it is invalid, if any.
+*/
+async fn check_geometry_validity(
+    tx: &mut Transaction<'_, Postgres>,
+    geom_expr: &str,
+    geometry_json: &str,
+) -> Result<(), AppError> {
+    let validity_query = format!("select ST_IsValidReason({})", geom_expr);
+    let reason = query_scalar::<Postgres, String>(&validity_query)
+        .bind(geometry_json)
+        .fetch_one(&mut **tx)
+        .await
+        .map_err(|e| AppError(anyhow!("Failed to read geometry: {}", e)))?;
+
+    if reason == "Valid Geometry" {
+        Ok(())
+    } else {
+        Err(AppError(anyhow!("Invalid geometry: {}", reason)))
+    }
+}
+
+fn make_bounds_expression(geom_col: &str) -> String {
+    format!(
+        "(select array[ST_XMin(b), ST_YMin(b), ST_XMax(b), ST_YMax(b)]
+            from (select ST_Transform(t.{}, {})::box2d as b) bounds)",
+        geom_col, CACHE_SRID
+    )
+}
+
+async fn get_stored_bounds(
+    tx: &mut Transaction<'_, Postgres>,
+    table: &Table,
+    geom_col: &str,
+    pk_col: &str,
+    feature_id: &str,
+) -> Result<Option<Option<Vec<f64>>>, AppError> {
+    let bounds_query = format!(
+        "select {} from {}.{} t where {} for update",
+        make_bounds_expression(geom_col),
+        table.schema_name,
+        table.name,
+        make_pk_condition(table, pk_col, 1)
+    );
+
+    query_scalar::<Postgres, Option<Vec<f64>>>(&bounds_query)
+        .bind(feature_id)
+        .fetch_optional(&mut **tx)
+        .await
+        .map_err(|e| AppError(anyhow!(e)))
+}
+
+fn invalidate_tiles(state: &AppState, table: &Table, bounds: Option<Vec<f64>>) {
+    state
+        .network_analyses
+        .invalidate(&table.schema_name, &table.name);
+    if let Some(Ok(bounds)) = bounds.map(<[f64; 4]>::try_from) {
+        state
+            .tile_cache
+            .invalidate(&table.schema_name, &table.name, bounds);
+    }
+}
+
+/**
+Writes a GeoJSON feature to a table, creating a new row or replacing or updating an existing one, and evicts any cached tiles covering either the previous or the new geometry, along with any cached analysis of the table as a routing network.
+
+# Arguments
+
+* `state` - The application state, providing the database pool and tile cache.
+* `table` - The table being edited.
+* `feature` - The incoming GeoJSON feature.
+* `input_srid` - The SRID of the incoming geometry.
+* `feature_id` - The primary key of the row to replace or update. Ignored when creating.
+* `mode` - Whether to create a row, replace every writable column of a row (absent properties become null), or update only the supplied properties.
+
+# Returns
+
+A `Result` containing the primary key of the written row, or an `AppError` if validation or the write fails.
+*/
+async fn write_feature(
+    state: &AppState,
+    table: &Table,
+    feature: Feature,
+    input_srid: i32,
+    feature_id: Option<&str>,
+    mode: EditMode,
+) -> Result<String, AppError> {
+    let (geom_col, pk_col) = get_edit_columns(table)?;
+    let table_srid = get_table_srid(table)?;
+
+    let geometry = match (&feature.geometry, &mode) {
+        (Some(geometry), _) => Some(geometry),
+        (None, EditMode::Update) => None,
+        (None, _) => return Err(AppError(anyhow!("Feature must contain a geometry"))),
+    };
+
+    let promote_to_multi = geometry
+        .map(|g| check_geometry_type(table, g))
+        .transpose()?;
+
+    let properties = feature.properties.unwrap_or_default();
+    let writable_columns = get_writable_columns(&state.db_pool, table, geom_col).await?;
+    let unknown_columns = properties
+        .keys()
+        .filter(|k| !writable_columns.contains(k))
+        .cloned()
+        .collect::<Vec<String>>();
+    if !unknown_columns.is_empty() {
+        return Err(AppError(anyhow!(
+            "Unknown properties for table: {}",
+            unknown_columns.join(", ")
+        )));
+    }
+
+    let primary_keys = table
+        .primary_key_columns
+        .iter()
+        .collect::<HashSet<&String>>();
+    let columns = match mode {
+        EditMode::Create => properties.keys().cloned().collect::<Vec<String>>(),
+        EditMode::Replace => writable_columns
+            .into_iter()
+            .filter(|c| !primary_keys.contains(c))
+            .collect(),
+        EditMode::Update => properties
+            .keys()
+            .filter(|c| !primary_keys.contains(c))
+            .cloned()
+            .collect(),
+    };
+
+    let geometry_json = match geometry {
+        Some(geometry) => serde_json::to_string(geometry)?,
+        None => "".to_string(),
+    };
+    let properties_json = Value::Object(properties);
+
+    let mut tx = state.db_pool.begin().await?;
+
+    if let Some(promote) = promote_to_multi {
+        let validity_expr = make_geom_expression(1, input_srid, table_srid, promote);
+        check_geometry_validity(&mut tx, &validity_expr, &geometry_json).await?;
+    }
+    let geom_expr =
+        promote_to_multi.map(|promote| make_geom_expression(2, input_srid, table_srid, promote));
+
+    let bounds_expr = make_bounds_expression(geom_col);
+    let (written, previous_bounds) = match mode {
+        EditMode::Create => {
+            let mut insert_columns = columns
+                .iter()
+                .map(|c| format!("\"{}\"", c))
+                .collect::<Vec<String>>();
+            let mut insert_values = columns
+                .iter()
+                .map(|c| format!("r.\"{}\"", c))
+                .collect::<Vec<String>>();
+            insert_columns.push(geom_col.to_string());
+            insert_values.push(geom_expr.unwrap_or_default());
+
+            let insert_query = format!(
+                "insert into {}.{} as t ({})
+                select {}
+                from jsonb_populate_record(null::{}.{}, $1::jsonb) r
+                returning t.{}::text, {}",
+                table.schema_name,
+                table.name,
+                insert_columns.join(", "),
+                insert_values.join(", "),
+                table.schema_name,
+                table.name,
+                pk_col,
+                bounds_expr
+            );
+
+            let written = query_as::<Postgres, (String, Option<Vec<f64>>)>(&insert_query)
+                .bind(&properties_json)
+                .bind(&geometry_json)
+                .fetch_one(&mut *tx)
+                .await
+                .map_err(|e| AppError(anyhow!(e)))?;
+            (written, None)
+        }
+        EditMode::Replace | EditMode::Update => {
+            let feature_id = feature_id.ok_or_else(|| AppError(anyhow!("Missing feature id")))?;
+            let previous_bounds =
+                match get_stored_bounds(&mut tx, table, geom_col, pk_col, feature_id).await? {
+                    Some(bounds) => bounds,
+                    None => return Err(AppError(anyhow!("Failed to locate specified feature"))),
+                };
+
+            let mut assignments = columns
+                .iter()
+                .map(|c| format!("\"{}\" = r.\"{}\"", c, c))
+                .collect::<Vec<String>>();
+            if let Some(geom_expr) = geom_expr {
+                assignments.push(format!("{} = {}", geom_col, geom_expr));
+            }
+            if assignments.is_empty() {
+                return Err(AppError(anyhow!("Feature contains no changes to apply")));
+            }
+
+            let update_query = format!(
+                "update {}.{} as t
+                set {}
+                from jsonb_populate_record(null::{}.{}, $1::jsonb) r
+                where {}
+                returning t.{}::text, {}",
+                table.schema_name,
+                table.name,
+                assignments.join(", "),
+                table.schema_name,
+                table.name,
+                make_pk_condition(table, pk_col, 3),
+                pk_col,
+                bounds_expr
+            );
+
+            let written = query_as::<Postgres, (String, Option<Vec<f64>>)>(&update_query)
+                .bind(&properties_json)
+                .bind(&geometry_json)
+                .bind(feature_id)
+                .fetch_one(&mut *tx)
+                .await
+                .map_err(|e| AppError(anyhow!(e)))?;
+            (written, previous_bounds)
+        }
+    };
+
+    tx.commit().await?;
+
+    let (written_id, new_bounds) = written;
+    invalidate_tiles(state, table, previous_bounds);
+    invalidate_tiles(state, table, new_bounds);
+
+    Ok(written_id)
+}
+
+#[debug_handler(state = AppState)]
+/**
+Handles a POST request creating a feature in a collection from a GeoJSON feature.
+
+The geometry must match the table's geometry type, and is transformed from the CRS named in the `Content-Crs` header (WGS84 by default) into the table SRID. Properties must name existing columns.
+
+# Returns
+
+Returns a result that either contains the stored feature, with a `201 Created` status and `Location` header, or an `AppError` if there was an error during the process.
+*/
+pub async fn create_item(
+    _: EditAuthorization,
+    State(state): State<AppState>,
+    Path(collectionid): Path<String>,
+    Host(host): Host,
+    headers: HeaderMap,
+    Json(feature): Json<Feature>,
+) -> Result<impl IntoResponse, AppError> {
+    let table_spec = find_collection(&state.table_registry, &collectionid)?;
+    let input_srid = get_input_srid(&headers)?;
+
+    let feature_id = write_feature(
+        &state,
+        table_spec,
+        feature,
+        input_srid,
+        None,
+        EditMode::Create,
+    )
+    .await?;
+    let stored = get_feature_item(&state.db_pool, table_spec, &feature_id).await?;
+
+    let location = format!(
+        "{}/collections/{}/items/{}",
+        get_base_url(&host, &headers),
+        get_collection_id(table_spec),
+        urlencoding::encode(&feature_id)
+    );
+
+    Ok((
+        StatusCode::CREATED,
+        [(LOCATION, location)],
+        GeoJSONResponse(stored.into_geojson()),
+    ))
+}
+
+#[debug_handler(state = AppState)]
+/**
+Handles a PUT request replacing a feature. Writable columns absent from the feature's properties are set to null; primary key columns are never changed.
+
+# Returns
+
+Returns a result that either contains the stored feature or an `AppError` if there was an error during the process.
+*/
+pub async fn replace_item(
+    _: EditAuthorization,
+    State(state): State<AppState>,
+    Path((collectionid, featureid)): Path<(String, String)>,
+    headers: HeaderMap,
+    Json(feature): Json<Feature>,
+) -> Result<GeoJSONResponse, AppError> {
+    let table_spec = find_collection(&state.table_registry, &collectionid)?;
+    let input_srid = get_input_srid(&headers)?;
+
+    let feature_id = write_feature(
+        &state,
+        table_spec,
+        feature,
+        input_srid,
+        Some(&featureid),
+        EditMode::Replace,
+    )
+    .await?;
+    let stored = get_feature_item(&state.db_pool, table_spec, &feature_id).await?;
+
+    Ok(GeoJSONResponse(stored.into_geojson()))
+}
+
+#[debug_handler(state = AppState)]
+/**
+Handles a PATCH request updating a feature. Only the supplied properties, and the geometry if one is given, are changed.
+
+# Returns
+
+Returns a result that either contains the stored feature or an `AppError` if there was an error during the process.
+*/
+pub async fn update_item(
+    _: EditAuthorization,
+    State(state): State<AppState>,
+    Path((collectionid, featureid)): Path<(String, String)>,
+    headers: HeaderMap,
+    Json(feature): Json<Feature>,
+) -> Result<GeoJSONResponse, AppError> {
+    let table_spec = find_collection(&state.table_registry, &collectionid)?;
+    let input_srid = get_input_srid(&headers)?;
+
+    let feature_id = write_feature(
+        &state,
+        table_spec,
+        feature,
+        input_srid,
+        Some(&featureid),
+        EditMode::Update,
+    )
+    .await?;
+    let stored = get_feature_item(&state.db_pool, table_spec, &feature_id).await?;
+
+    Ok(GeoJSONResponse(stored.into_geojson()))
+}
+
+#[debug_handler(state = AppState)]
+/**
+Handles a DELETE request removing a feature from a collection.
+
+# Returns
+
+Returns a result that either contains a `204 No Content` status or an `AppError` if there was an error during the process.
+*/
+pub async fn delete_item(
+    _: EditAuthorization,
+    State(state): State<AppState>,
+    Path((collectionid, featureid)): Path<(String, String)>,
+) -> Result<StatusCode, AppError> {
+    let table_spec = find_collection(&state.table_registry, &collectionid)?;
+    let (geom_col, pk_col) = get_edit_columns(table_spec)?;
+
+    let delete_query = format!(
+        "delete from {}.{} as t
+        where {}
+        returning {}",
+        table_spec.schema_name,
+        table_spec.name,
+        make_pk_condition(table_spec, pk_col, 1),
+        make_bounds_expression(geom_col)
+    );
+
+    let deleted = query_scalar::<Postgres, Option<Vec<f64>>>(&delete_query)
+        .bind(&featureid)
+        .fetch_optional(&state.db_pool)
+        .await
+        .map_err(|e| AppError(anyhow!(e)))?;
+
+    match deleted {
+        Some(bounds) => {
+            invalidate_tiles(&state, table_spec, bounds);
+            Ok(StatusCode::NO_CONTENT)
+        }
+        None => Err(AppError(anyhow!("Failed to locate specified feature"))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use geojson::Geometry;
+
+    fn table(geom_type: &str) -> Table {
+        Table::new(
+            "roads".to_string(),
+            "public".to_string(),
+            vec!["id".to_string()],
+            "geom".to_string(),
+            geom_type.to_string(),
+            27700,
+            None,
+            "m".to_string(),
+            false,
+        )
+    }
+
+    fn content_crs(crs: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("content-crs", crs.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn input_srid_defaults_to_wgs84() {
+        assert_eq!(get_input_srid(&HeaderMap::new()).unwrap(), 4326);
+        assert_eq!(
+            get_input_srid(&content_crs(
+                "<http://www.opengis.net/def/crs/OGC/1.3/CRS84>"
+            ))
+            .unwrap(),
+            4326
+        );
+    }
+
+    #[test]
+    fn input_srid_reads_epsg_codes() {
+        assert_eq!(
+            get_input_srid(&content_crs(
+                "<http://www.opengis.net/def/crs/EPSG/0/27700>"
+            ))
+            .unwrap(),
+            27700
+        );
+        assert_eq!(
+            get_input_srid(&content_crs(" http://www.opengis.net/def/crs/EPSG/0/3857 ")).unwrap(),
+            3857
+        );
+        assert_eq!(get_input_srid(&content_crs("EPSG:2056")).unwrap(), 2056);
+    }
+
+    #[test]
+    fn input_srid_rejects_other_crs() {
+        for crs in [
+            "http://www.opengis.net/def/crs/OGC/1.3/CRS83",
+            "http://example.com/crs/4326",
+            "EPSG:abc",
+            "",
+        ] {
+            assert!(get_input_srid(&content_crs(crs)).is_err(), "{}", crs);
+        }
+    }
+
+    #[test]
+    fn geometry_type_must_match_the_table() {
+        let line = Geometry::new(geojson::Value::LineString(vec![
+            vec![0.0, 0.0],
+            vec![1.0, 1.0],
+        ]));
+        let point = Geometry::new(geojson::Value::Point(vec![0.0, 0.0]));
+
+        assert!(!check_geometry_type(&table("LINESTRING"), &line).unwrap());
+        assert!(!check_geometry_type(&table("linestring"), &line).unwrap());
+        assert!(!check_geometry_type(&table("GEOMETRY"), &point).unwrap());
+        assert!(check_geometry_type(&table("LINESTRING"), &point).is_err());
+    }
+
+    #[test]
+    fn single_part_geometries_are_promoted_for_multi_part_tables() {
+        let line = Geometry::new(geojson::Value::LineString(vec![
+            vec![0.0, 0.0],
+            vec![1.0, 1.0],
+        ]));
+        let multi_line = Geometry::new(geojson::Value::MultiLineString(vec![vec![
+            vec![0.0, 0.0],
+            vec![1.0, 1.0],
+        ]]));
+
+        assert!(check_geometry_type(&table("MULTILINESTRING"), &line).unwrap());
+        assert!(!check_geometry_type(&table("MULTILINESTRING"), &multi_line).unwrap());
+        assert!(check_geometry_type(&table("LINESTRING"), &multi_line).is_err());
+    }
+}
@@ -0,0 +1,444 @@
+use super::FeaturesError;
+use crate::db::{Table, TableRegistry};
+use crate::AppError;
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sqlx::{query_as, query_scalar, PgPool, Postgres};
+
+pub const OUTGOING_SRID: i32 = 4326;
+//SQLSTATE raised when text cannot be cast to a column's type
+const INVALID_TEXT_REPRESENTATION: &str = "22P02";
+
+#[derive(sqlx::FromRow, Debug, Deserialize, Serialize)]
+pub struct FeatureRow {
+    pub id: String,
+    #[sqlx(default)]
+    pub geometry: Option<Value>,
+    pub properties: Value,
+}
+
+impl FeatureRow {
+    pub fn into_geojson(self) -> Value {
+        json!({
+            "type": "Feature",
+            "id": self.id,
+            "geometry": self.geometry,
+            "properties": self.properties,
+        })
+    }
+}
+
+pub enum DatetimeFilter {
+    Instant(String),
+    Interval(Option<String>, Option<String>),
+}
+
+enum BindValue {
+    Float(f64),
+    Text(String),
+}
+
+/**
+Builds the OGC API collection identifier for a table, in the form `schema.table`.
+*/
+pub fn get_collection_id(table: &Table) -> String {
+    format!("{}.{}", table.schema_name, table.name)
+}
+
+/**
+Lists the tables in a registry that can be published as feature collections, namely those with both a primary key and a geometry column, ordered by collection identifier.
+*/
+pub fn get_publishable_tables(registry: &TableRegistry) -> Vec<&Table> {
+    let mut tables = registry
+        .schemas
+        .values()
+        .flat_map(|schema| schema.tables.values())
+        .filter(|table| table.geom_column.is_some() && !table.primary_key_columns.is_empty())
+        .collect::<Vec<&Table>>();
+    tables.sort_by_key(|table| get_collection_id(table));
+    tables
+}
+
+/**
+Locates the table corresponding to an OGC API collection identifier.
+
+# Arguments
+
+* `registry` - The `TableRegistry` to search.
+* `collection_id` - A collection identifier, in the form `schema.table`.
+
+# Returns
+
+A `Result` containing a reference to the matching `Table`, or `FeaturesError::NotFound` if the identifier is malformed or does not match a publishable table.
+*/
+pub fn find_collection<'a>(
+    registry: &'a TableRegistry,
+    collection_id: &str,
+) -> Result<&'a Table, FeaturesError> {
+    collection_id
+        .split_once('.')
+        .and_then(|(schemaid, tableid)| registry.schemas.get(schemaid)?.tables.get(tableid))
+        .filter(|table| table.geom_column.is_some() && !table.primary_key_columns.is_empty())
+        .ok_or_else(|| FeaturesError::NotFound(format!("Unknown collection: {}", collection_id)))
+}
+
+/**
+Parses an OGC API `bbox` parameter into `[min_lon, min_lat, max_lon, max_lat]`. Six-value bounding boxes have their vertical extent discarded.
+*/
+pub fn parse_bbox(bbox: &str) -> Result<[f64; 4], FeaturesError> {
+    let invalid = || FeaturesError::BadRequest(format!("Invalid bbox: {}", bbox));
+    let values = bbox
+        .split(',')
+        .map(|v| v.trim().parse::<f64>())
+        .collect::<Result<Vec<f64>, _>>()
+        .map_err(|_| invalid())?;
+
+    match values.as_slice() {
+        [min_x, min_y, max_x, max_y] | [min_x, min_y, _, max_x, max_y, _] => {
+            Ok([*min_x, *min_y, *max_x, *max_y])
+        }
+        _ => Err(invalid()),
+    }
+}
+
+/**
+Parses an OGC API `datetime` parameter, which may be a single RFC 3339 instant or an interval of two instants separated by `/`, either of which may be open (`..` or empty).
+*/
+pub fn parse_datetime(datetime: &str) -> Result<DatetimeFilter, FeaturesError> {
+    let invalid = || FeaturesError::BadRequest(format!("Invalid datetime: {}", datetime));
+    let open_or_value = |v: &str| {
+        if v.is_empty() || v == ".." {
+            None
+        } else {
+            Some(v.to_string())
+        }
+    };
+
+    match datetime.split('/').collect::<Vec<&str>>().as_slice() {
+        [instant] if !instant.is_empty() && *instant != ".." => {
+            Ok(DatetimeFilter::Instant(instant.to_string()))
+        }
+        [start, end] => match (open_or_value(start), open_or_value(end)) {
+            (None, None) => Err(invalid()),
+            (start, end) => Ok(DatetimeFilter::Interval(start, end)),
+        },
+        _ => Err(invalid()),
+    }
+}
+
+fn make_where_clause(
+    table: &Table,
+    geom_col: &str,
+    bbox: Option<[f64; 4]>,
+    datetime: Option<&DatetimeFilter>,
+) -> (String, Vec<BindValue>) {
+    let mut conditions = Vec::<String>::new();
+    let mut binds = Vec::<BindValue>::new();
+
+    if let (Some(bbox), Some(srid)) = (bbox, table.srid) {
+        let first = binds.len() + 1;
+        conditions.push(format!(
+            "t.{} && ST_Transform(ST_MakeEnvelope(${}, ${}, ${}, ${}, {}), {})",
+            geom_col,
+            first,
+            first + 1,
+            first + 2,
+            first + 3,
+            OUTGOING_SRID,
+            srid
+        ));
+        binds.extend(bbox.iter().map(|v| BindValue::Float(*v)));
+    }
+
+    //Collections without a temporal column ignore the datetime filter, as permitted by the standard
+    if let (Some(filter), Some(dt_col)) = (datetime, &table.datetime_column) {
+        match filter {
+            DatetimeFilter::Instant(instant) => {
+                binds.push(BindValue::Text(instant.clone()));
+                conditions.push(format!("t.{} = ${}::timestamptz", dt_col, binds.len()));
+            }
+            DatetimeFilter::Interval(start, end) => {
+                if let Some(start) = start {
+                    binds.push(BindValue::Text(start.clone()));
+                    conditions.push(format!("t.{} >= ${}::timestamptz", dt_col, binds.len()));
+                }
+                if let Some(end) = end {
+                    binds.push(BindValue::Text(end.clone()));
+                    conditions.push(format!("t.{} <= ${}::timestamptz", dt_col, binds.len()));
+                }
+            }
+        }
+    }
+
+    if conditions.is_empty() {
+        ("".to_string(), binds)
+    } else {
+        (format!("where {}", conditions.join(" and ")), binds)
+    }
+}
+
+fn make_feature_select(table: &Table, geom_col: &str, pk_col: &str) -> String {
+    format!(
+        "select
+            t.{}::text as id,
+            ST_AsGeoJSON(ST_Transform(t.{}, {}))::json as geometry,
+            to_jsonb(t) - '{}' as properties
+        from
+            {}.{} t",
+        pk_col, geom_col, OUTGOING_SRID, geom_col, table.schema_name, table.name
+    )
+}
+
+/**
+Builds a condition matching the row of a table aliased as `t` whose (first) primary key column equals a feature ID bound as text in parameter `param`. The parameter is cast to the column's type, rather than the column to text, so the primary key index can be used.
+*/
+pub(super) fn make_pk_condition(table: &Table, pk_col: &str, param: usize) -> String {
+    match table.primary_key_types.first() {
+        Some(pk_type) => format!("t.{} = ${}::{}", pk_col, param, pk_type),
+        None => format!("t.{}::text = ${}", pk_col, param),
+    }
+}
+
+fn get_geom_and_pk_columns(table: &Table) -> Result<(&String, &String), AppError> {
+    match (
+        table.geom_column.as_ref(),
+        table.primary_key_columns.first(),
+    ) {
+        (Some(geom_col), Some(pk_col)) => Ok((geom_col, pk_col)),
+        _ => Err(AppError(anyhow!(
+            "Table does not contain valid geometry and primary key data"
+        ))),
+    }
+}
+
+/**
+Retrieves a page of features from a table, along with the total number of features matching the filters.
+
+# Arguments
+
+* `pool` - A PostgreSQL connection pool.
+* `table` - A struct representing the database table.
+* `bbox` - An optional bounding box, in WGS84, that features must intersect.
+* `datetime` - An optional filter applied to the table's temporal column, if it has one.
+* `limit` - The maximum number of features to return.
+* `offset` - The number of matching features to skip, in primary key order.
+
+# Returns
+
+A `Result` containing the features and the number matched, or an `AppError` if there is an error.
+*/
+pub async fn get_feature_items(
+    pool: &PgPool,
+    table: &Table,
+    bbox: Option<[f64; 4]>,
+    datetime: Option<&DatetimeFilter>,
+    limit: usize,
+    offset: usize,
+) -> Result<(Vec<FeatureRow>, i64), AppError> {
+    let (geom_col, pk_col) = get_geom_and_pk_columns(table)?;
+    let (where_clause, binds) = make_where_clause(table, geom_col, bbox, datetime);
+
+    let items_query = format!(
+        "{}
+        {}
+        order by t.{}
+        limit {} offset {}",
+        make_feature_select(table, geom_col, pk_col),
+        where_clause,
+        pk_col,
+        limit,
+        offset
+    );
+    let count_query = format!(
+        "select count(*) from {}.{} t {}",
+        table.schema_name, table.name, where_clause
+    );
+
+    let mut items = query_as::<Postgres, FeatureRow>(&items_query);
+    let mut count = query_scalar::<Postgres, i64>(&count_query);
+    for bind in binds {
+        match bind {
+            BindValue::Float(v) => {
+                items = items.bind(v);
+                count = count.bind(v);
+            }
+            BindValue::Text(v) => {
+                items = items.bind(v.clone());
+                count = count.bind(v);
+            }
+        }
+    }
+
+    let features = items
+        .fetch_all(pool)
+        .await
+        .map_err(|e| AppError(anyhow!(e)))?;
+    let number_matched = count
+        .fetch_one(pool)
+        .await
+        .map_err(|e| AppError(anyhow!(e)))?;
+
+    Ok((features, number_matched))
+}
+
+/**
+Retrieves a single feature from a table by the value of its (first) primary key column.
+
+# Returns
+
+A `Result` containing the feature, `FeaturesError::NotFound` if no feature has that ID, or `FeaturesError::Internal` if there is an error.
+*/
+pub async fn get_feature_item(
+    pool: &PgPool,
+    table: &Table,
+    feature_id: &str,
+) -> Result<FeatureRow, FeaturesError> {
+    let (geom_col, pk_col) = get_geom_and_pk_columns(table)?;
+
+    let item_query = format!(
+        "{}
+        where {}",
+        make_feature_select(table, geom_col, pk_col),
+        make_pk_condition(table, pk_col, 1)
+    );
+
+    let not_found = || FeaturesError::NotFound(format!("Unknown feature: {}", feature_id));
+    match query_as::<Postgres, FeatureRow>(&item_query)
+        .bind(feature_id)
+        .fetch_optional(pool)
+        .await
+    {
+        Ok(Some(feature)) => Ok(feature),
+        Ok(None) => Err(not_found()),
+        //An ID that cannot be cast to the primary key's type matches no feature
+        Err(sqlx::Error::Database(e))
+            if e.code().as_deref() == Some(INVALID_TEXT_REPRESENTATION) =>
+        {
+            Err(not_found())
+        }
+        Err(e) => Err(FeaturesError::Internal(AppError(anyhow!(e)))),
+    }
+}
+
+/**
+Retrieves the estimated extent of a table from planner statistics, as a WGS84 bounding box. Returns `None` when the table has not been analyzed.
+*/
+pub async fn get_estimated_extent(pool: &PgPool, table: &Table) -> Option<[f64; 4]> {
+    let geom_col = table.geom_column.as_ref()?;
+    let srid = table.srid?;
+
+    let extent_query = format!(
+        "select
+            array[ST_XMin(e), ST_YMin(e), ST_XMax(e), ST_YMax(e)]
+        from
+            (select ST_Transform(ST_SetSRID(ST_EstimatedExtent($1, $2, $3)::geometry, {}), {}) as e) ext",
+        srid, OUTGOING_SRID
+    );
+
+    query_scalar::<Postgres, Vec<f64>>(&extent_query)
+        .bind(&table.schema_name)
+        .bind(&table.name)
+        .bind(geom_col)
+        .fetch_one(pool)
+        .await
+        .ok()
+        .and_then(|v| v.try_into().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roads() -> Table {
+        Table::new(
+            "roads".to_string(),
+            "public".to_string(),
+            vec!["id".to_string()],
+            "geom".to_string(),
+            "LINESTRING".to_string(),
+            4326,
+            None,
+            "deg".to_string(),
+            true,
+        )
+    }
+
+    #[test]
+    fn bbox_takes_four_or_six_values() {
+        assert_eq!(
+            parse_bbox("-1.5, 50,0.5,51").unwrap(),
+            [-1.5, 50.0, 0.5, 51.0]
+        );
+        assert_eq!(
+            parse_bbox("-1.5,50,0,0.5,51,100").unwrap(),
+            [-1.5, 50.0, 0.5, 51.0]
+        );
+
+        for invalid in ["", "1,2,3", "1,2,3,4,5", "a,2,3,4"] {
+            assert!(matches!(
+                parse_bbox(invalid),
+                Err(FeaturesError::BadRequest(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn datetime_takes_instants_and_half_open_intervals() {
+        assert!(matches!(
+            parse_datetime("2024-01-01T00:00:00Z"),
+            Ok(DatetimeFilter::Instant(instant)) if instant == "2024-01-01T00:00:00Z"
+        ));
+        assert!(matches!(
+            parse_datetime("2024-01-01T00:00:00Z/.."),
+            Ok(DatetimeFilter::Interval(Some(start), None)) if start == "2024-01-01T00:00:00Z"
+        ));
+        assert!(matches!(
+            parse_datetime("/2024-01-01T00:00:00Z"),
+            Ok(DatetimeFilter::Interval(None, Some(end))) if end == "2024-01-01T00:00:00Z"
+        ));
+
+        for invalid in ["", "..", "../..", "/", "a/b/c"] {
+            assert!(matches!(
+                parse_datetime(invalid),
+                Err(FeaturesError::BadRequest(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn collections_need_a_geometry_and_primary_key() {
+        let mut registry = TableRegistry::new("test".to_string());
+        let mut keyless = roads();
+        keyless.name = "keyless".to_string();
+        keyless.primary_key_columns.clear();
+        let schema = registry
+            .schemas
+            .entry("public".to_string())
+            .or_insert_with(|| crate::db::Schema::new("public".to_string()));
+        schema.tables.insert("roads".to_string(), roads());
+        schema.tables.insert("keyless".to_string(), keyless);
+
+        assert_eq!(
+            find_collection(&registry, "public.roads").unwrap().name,
+            "roads"
+        );
+        for unknown in ["public.keyless", "public.rivers", "roads"] {
+            assert!(matches!(
+                find_collection(&registry, unknown),
+                Err(FeaturesError::NotFound(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn pk_condition_casts_the_feature_id_to_the_key_type() {
+        let mut table = roads();
+        table.primary_key_types = vec!["integer".to_string()];
+        assert_eq!(make_pk_condition(&table, "id", 1), "t.id = $1::integer");
+        assert_eq!(make_pk_condition(&table, "id", 3), "t.id = $3::integer");
+
+        //Tables built outside the registry have no key types to cast to
+        assert_eq!(make_pk_condition(&roads(), "id", 1), "t.id::text = $1");
+    }
+}
@@ -1,41 +1,100 @@
-use crate::AppError;
-extern crate dotenv;
-use axum::{extract::Path, response::IntoResponse, Json};
+mod nominatim;
+mod pelias;
+mod photon;
+mod postgis;
+
+use crate::db::TableRegistry;
+use crate::{AppError, AppState};
+use anyhow::{anyhow, Context, Result};
+use axum::{
+    async_trait,
+    extract::{Path, Query, State},
+    response::IntoResponse,
+    Json,
+};
+use nominatim::{NominatimGeocoder, RapidApiGeocoder};
+use pelias::PeliasGeocoder;
+use photon::PhotonGeocoder;
+use postgis::PostgisGeocoder;
 use reqwest::{
-    header::{HeaderMap, HeaderName, HeaderValue, ACCESS_CONTROL_ALLOW_ORIGIN},
+    header::{HeaderMap, ACCESS_CONTROL_ALLOW_ORIGIN},
     Client,
 };
-use serde::{de, Deserialize, Deserializer, Serialize};
-use serde_json::Value;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sqlx::PgPool;
+use std::env::var;
+use std::sync::Arc;
 
-use anyhow::anyhow;
 use axum_macros::debug_handler;
-use lazy_static::lazy_static;
-use urlencoding::encode;
 
-lazy_static! {
-    static ref CLIENT: Client = Client::new();
+#[derive(Serialize, Debug, Clone)]
+pub struct GeocoderResult {
+    pub lat: f64,
+    pub lon: f64,
+    pub display_name: Option<String>,
 }
 
-//Error handling here is awful. Clean up after getting the shell working.
+impl IntoResponse for GeocoderResult {
+    fn into_response(self) -> axum::response::Response {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, "*".parse().unwrap());
+        (headers, Json(self)).into_response()
+    }
+}
 
-fn deserialize_to_f64<'de, D: Deserializer<'de>>(d: D) -> Result<f64, D::Error> {
-    Ok(match Value::deserialize(d)? {
-        Value::String(s) => s.parse().map_err(de::Error::custom)?,
-        Value::Number(num) => num.as_f64().ok_or(de::Error::custom(""))?,
-        _ => return Err(de::Error::custom("")),
-    })
+#[derive(Serialize, Debug, Clone)]
+pub struct ReverseGeocoderResult {
+    pub lat: f64,
+    pub lon: f64,
+    pub display_name: Option<String>,
+    pub address: AddressComponents,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct GeocoderResult {
-    #[serde(deserialize_with = "deserialize_to_f64")]
-    lat: f64,
-    #[serde(deserialize_with = "deserialize_to_f64")]
-    lon: f64,
+/**
+The parts of an address found by reverse geocoding. Any part may be missing. `city` holds the town, village or hamlet where the place is not in a city.
+*/
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct AddressComponents {
+    pub house_number: Option<String>,
+    pub road: Option<String>,
+    pub neighbourhood: Option<String>,
+    pub suburb: Option<String>,
+    pub city: Option<String>,
+    pub county: Option<String>,
+    pub state: Option<String>,
+    pub postcode: Option<String>,
+    pub country: Option<String>,
+    pub country_code: Option<String>,
 }
 
-impl IntoResponse for GeocoderResult {
+/**
+Reads the parts of an address keyed as Nominatim keys them, such as a Nominatim `address` object or the columns of an address table. Places outside cities are named under one of several keys, which may appear together.
+*/
+fn read_address(mut parts: Map<String, Value>) -> AddressComponents {
+    let mut take = |key: &str| match parts.remove(key) {
+        Some(Value::String(s)) => Some(s),
+        Some(Value::Number(n)) => Some(n.to_string()),
+        _ => None,
+    };
+    AddressComponents {
+        house_number: take("house_number"),
+        road: take("road"),
+        neighbourhood: take("neighbourhood"),
+        suburb: take("suburb"),
+        city: take("city")
+            .or_else(|| take("town"))
+            .or_else(|| take("village"))
+            .or_else(|| take("hamlet")),
+        county: take("county"),
+        state: take("state"),
+        postcode: take("postcode"),
+        country: take("country"),
+        country_code: take("country_code"),
+    }
+}
+
+impl IntoResponse for ReverseGeocoderResult {
     fn into_response(self) -> axum::response::Response {
         let mut headers = HeaderMap::new();
         headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, "*".parse().unwrap());
@@ -44,54 +103,460 @@ impl IntoResponse for GeocoderResult {
 }
 
 /**
-Makes an API call to a geocoding service to retrieve latitude and longitude coordinates based on a given query string.
+A location, in degrees, near which search results are favoured.
+*/
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct Focus {
+    pub lat: f64,
+    pub lon: f64,
+}
 
-# Arguments
+/**
+A geocoding service, searching for places by name and addresses by location.
+*/
+#[async_trait]
+pub trait GeocoderProvider: Send + Sync {
+    /**
+    Returns the best match for a free-text search, such as an address or place name, favouring places near the focus point where one is given and the provider supports it.
+    */
+    async fn search(&self, query: &str, focus: Option<Focus>) -> Result<GeocoderResult, AppError>;
 
-* `query_string` - The query string used to search for a location in the geocoding service.
-* `client` - A reference to a `reqwest::Client` object used to send the API request.
+    /**
+    Returns the address of the place nearest to a location, given in degrees.
+    */
+    async fn reverse(&self, lat: f64, lon: f64) -> Result<ReverseGeocoderResult, AppError>;
+}
 
-# Returns
+/**
+The geocoding service configured for the server, shared between requests.
+*/
+#[derive(Clone)]
+pub struct Geocoder(Arc<dyn GeocoderProvider>);
 
-A `Result` object that contains either a `GeocoderResult` struct representing the latitude and longitude coordinates of the queried location, or an `AppError` if there was an error during the API call or if the API key is missing.
+impl Geocoder {
+    pub fn new(provider: impl GeocoderProvider + 'static) -> Geocoder {
+        Geocoder(Arc::new(provider))
+    }
 
+    /**
+    Configures the geocoder from the environment.
 
+    `GEOCODER_PROVIDER` chooses the service: `rapidapi` (the default), `nominatim`, `pelias`, `photon` or `postgis`. `GEOCODER_URL` overrides the service's base URL, such as to use a self-hosted instance, and `GEOCODER_API_KEY` gives the key sent to services that need one. A RapidAPI geocoder without a key is configured, but fails each request.
+
+    The `postgis` provider searches a registered table of the database instead, named as `<schema>.<table>` by `GEOCODER_TABLE`, over the comma-separated text columns given by `GEOCODER_COLUMNS` (by default `name`), as described by `PostgisGeocoder`.
+
+    # Errors
+
+    - If the provider is not recognised, or the `postgis` provider's table or columns are not registered, returns an `anyhow::Error`.
+    */
+    pub fn from_env(pool: &PgPool, registry: &TableRegistry) -> Result<Geocoder> {
+        let provider = var("GEOCODER_PROVIDER").unwrap_or_else(|_| "rapidapi".to_string());
+        let base_url = var("GEOCODER_URL").ok().filter(|url| !url.is_empty());
+        let api_key = var("GEOCODER_API_KEY").ok().filter(|key| !key.is_empty());
+
+        match provider.to_lowercase().as_str() {
+            "rapidapi" => Ok(Geocoder::new(RapidApiGeocoder::new(base_url, api_key)?)),
+            "nominatim" => Ok(Geocoder::new(NominatimGeocoder::new(base_url))),
+            "pelias" => Ok(Geocoder::new(PeliasGeocoder::new(base_url, api_key))),
+            "photon" => Ok(Geocoder::new(PhotonGeocoder::new(base_url))),
+            "postgis" => {
+                let table = var("GEOCODER_TABLE")
+                    .context("GEOCODER_TABLE must be set for the postgis geocoder")?;
+                let columns = var("GEOCODER_COLUMNS").unwrap_or_else(|_| "name".to_string());
+                Ok(Geocoder::new(PostgisGeocoder::new(
+                    pool.clone(),
+                    registry,
+                    &table,
+                    &columns,
+                )?))
+            }
+            other => Err(anyhow!("Unknown GEOCODER_PROVIDER: {}", other)),
+        }
+    }
+
+    /**
+    Returns the best match for a free-text search, as `GeocoderProvider::search` does.
+
+    # Errors
+
+    - If the focus coordinates are out of range, or the provider fails, returns an `AppError`.
+    */
+    pub async fn search(
+        &self,
+        query: &str,
+        focus: Option<Focus>,
+    ) -> Result<GeocoderResult, AppError> {
+        if let Some(focus) = focus {
+            check_coordinates(focus.lat, focus.lon)?;
+        }
+        self.0.search(query, focus).await
+    }
+
+    /**
+    Returns the address of the place nearest to a location, as `GeocoderProvider::reverse` does.
+
+    # Errors
+
+    - If the coordinates are out of range, or the provider fails, returns an `AppError`.
+    */
+    pub async fn reverse(&self, lat: f64, lon: f64) -> Result<ReverseGeocoderResult, AppError> {
+        check_coordinates(lat, lon)?;
+        self.0.reverse(lat, lon).await
+    }
+}
+
+fn check_coordinates(lat: f64, lon: f64) -> Result<(), AppError> {
+    if !(-90.0..=90.0).contains(&lat) || !(-180.0..=180.0).contains(&lon) {
+        return Err(AppError(anyhow!("Coordinates out of range")));
+    }
+    Ok(())
+}
+
+/**
+Resolves a geocoding service's base URL, falling back to its public instance and dropping any trailing slash so endpoint paths can be appended.
 */
+fn base_url_or(base_url: Option<String>, default: &str) -> String {
+    base_url
+        .as_deref()
+        .unwrap_or(default)
+        .trim_end_matches('/')
+        .to_string()
+}
+
+/**
+Sends a GET request to a geocoding service, returning the JSON body of a successful response.
+
+# Arguments
+
+* `client` - The client used to send the request.
+* `url` - The URL of the endpoint.
+* `params` - The query parameters, which are URL-encoded.
+* `headers` - Any headers needed by the service, such as for authentication.
 
-async fn call_geocoder_api(
-    query_string: String,
+# Errors
+
+- If the request fails, the service responds with an error status or the body is not JSON, returns an `AppError`.
+*/
+async fn get_json(
     client: &Client,
-) -> Result<GeocoderResult, AppError> {
-    if let Ok(key) = dotenv::var("GEOCODER_API_KEY") {
-        let encoded_query_string = encode(&query_string);
-        let request_url = format!(
-            "https://forward-reverse-geocoding.p.rapidapi.com/v1/search?q={queryString}",
-            queryString = encoded_query_string
+    url: &str,
+    params: &[(&str, String)],
+    headers: HeaderMap,
+) -> Result<Value, AppError> {
+    let response = client
+        .get(url)
+        .query(params)
+        .headers(headers)
+        .send()
+        .await
+        .context("Failed to reach the geocoding service")?
+        .error_for_status()
+        .context("The geocoding service returned an error")?;
+    Ok(response.json::<Value>().await?)
+}
+
+/**
+Reads the location and properties of the first feature of a GeoJSON `FeatureCollection`, as returned by Pelias and Photon.
+
+# Errors
+
+- If the collection is empty or its first feature has no point geometry, returns an `AppError`.
+*/
+fn first_feature(collection: &Value) -> Result<(f64, f64, &Map<String, Value>), AppError> {
+    let feature = collection["features"]
+        .get(0)
+        .ok_or_else(|| AppError(anyhow!("Failed to obtain valid geocoding result")))?;
+    let coordinates = &feature["geometry"]["coordinates"];
+    match (
+        coordinates[0].as_f64(),
+        coordinates[1].as_f64(),
+        feature["properties"].as_object(),
+    ) {
+        (Some(lon), Some(lat), Some(properties)) => Ok((lat, lon, properties)),
+        _ => Err(AppError(anyhow!("Failed to obtain valid geocoding result"))),
+    }
+}
+
+/**
+Reads a string property of a geocoding result.
+*/
+fn string_property(properties: &Map<String, Value>, key: &str) -> Option<String> {
+    properties
+        .get(key)
+        .and_then(|value| value.as_str())
+        .map(|value| value.to_string())
+}
+
+/**
+Query parameters for forward geocoding. Where both `lat` and `lon` are given, places near them are favoured.
+*/
+#[derive(Deserialize)]
+pub struct SearchOptions {
+    lat: Option<f64>,
+    lon: Option<f64>,
+}
+
+#[debug_handler]
+pub async fn get_latlong(
+    State(state): State<AppState>,
+    Path(query_string): Path<String>,
+    Query(options): Query<SearchOptions>,
+) -> impl IntoResponse {
+    let focus = match (options.lat, options.lon) {
+        (Some(lat), Some(lon)) => Some(Focus { lat, lon }),
+        _ => None,
+    };
+    state.geocoder.search(&query_string, focus).await
+}
+
+#[debug_handler]
+/**
+Handles a GET request for the address of a location, such as a pin dropped on the map.
+
+# Arguments
+
+* `State(state)`: The state of the application, which contains the configured geocoder.
+* `Path((lat, lon))`: The latitude and longitude of the location, in degrees.
+
+# Returns
+
+Returns a result that either contains a `ReverseGeocoderResult` with the address as a single line and broken into its parts, or an `AppError` if no address could be found.
+*/
+pub async fn get_address(
+    State(state): State<AppState>,
+    Path((lat, lon)): Path<(f64, f64)>,
+) -> impl IntoResponse {
+    state.geocoder.reverse(lat, lon).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{extract::Query, http::StatusCode, routing::get, Router};
+    use serde_json::json;
+    use std::collections::HashMap;
+    use std::net::TcpListener;
+
+    type Params = Query<HashMap<String, String>>;
+
+    fn feature_collection(lon: f64, lat: f64, properties: Value) -> Json<Value> {
+        Json(json!({
+            "type": "FeatureCollection",
+            "features": [{
+                "type": "Feature",
+                "geometry": { "type": "Point", "coordinates": [lon, lat] },
+                "properties": properties,
+            }],
+        }))
+    }
+
+    /**
+    Starts a server imitating each provider's API under its own path, returning its base URL.
+    */
+    fn start_mock_server() -> String {
+        let nominatim_place = json!({
+            "lat": "51.5007", "lon": "-0.1246", "display_name": "Big Ben, London",
+            "address": {
+                "house_number": "1", "road": "Bridge Street", "town": "Westminster",
+                "village": "Not a city", "postcode": "SW1A 0AA", "country": "United Kingdom",
+                "country_code": "gb",
+            },
+        });
+        let search_place = nominatim_place.clone();
+        let rapidapi_place = nominatim_place.clone();
+
+        let app = Router::new()
+            .route(
+                "/nominatim/search",
+                get(move |Query(params): Params| async move {
+                    match params.get("q").map(|q| q.as_str()) {
+                        Some("Big Ben") => Json(json!([search_place])),
+                        _ => Json(json!([])),
+                    }
+                }),
+            )
+            .route(
+                "/nominatim/reverse",
+                get(move |Query(params): Params| async move {
+                    match params.get("lat").map(|lat| lat.as_str()) {
+                        Some("0") => Json(json!({ "error": "Unable to geocode" })),
+                        _ => Json(nominatim_place),
+                    }
+                }),
+            )
+            .route(
+                "/rapidapi/search",
+                get(move |headers: HeaderMap| async move {
+                    let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+                    match (header("x-rapidapi-key"), header("x-rapidapi-host")) {
+                        (Some("secret"), Some("127.0.0.1")) => Ok(Json(json!([rapidapi_place]))),
+                        _ => Err(StatusCode::UNAUTHORIZED),
+                    }
+                }),
+            )
+            .route(
+                "/pelias/v1/search",
+                get(|Query(params): Params| async move {
+                    match params.get("api_key").map(|key| key.as_str()) {
+                        Some("secret") => Ok(feature_collection(
+                            -0.1246,
+                            51.5007,
+                            json!({ "label": "Big Ben, London, England, United Kingdom" }),
+                        )),
+                        _ => Err(StatusCode::UNAUTHORIZED),
+                    }
+                }),
+            )
+            .route(
+                "/pelias/v1/reverse",
+                get(|| async {
+                    feature_collection(
+                        -0.1246,
+                        51.5007,
+                        json!({
+                            "label": "1 Bridge Street, London, England, United Kingdom",
+                            "housenumber": "1", "street": "Bridge Street", "locality": "London",
+                            "region": "England", "postalcode": "SW1A 0AA",
+                            "country": "United Kingdom", "country_code": "GB",
+                        }),
+                    )
+                }),
+            )
+            .route(
+                "/photon/api",
+                get(|Query(params): Params| async move {
+                    match (params.get("q").map(|q| q.as_str()), params.get("lat")) {
+                        (Some("Big Ben"), None) => feature_collection(
+                            -0.1246,
+                            51.5007,
+                            json!({ "name": "Big Ben", "city": "London", "country": "United Kingdom" }),
+                        ),
+                        (Some("Big Ben"), Some(_)) => feature_collection(
+                            -123.0,
+                            45.0,
+                            json!({ "name": "Big Ben", "city": "Portland", "country": "United States" }),
+                        ),
+                        _ => Json(json!({ "type": "FeatureCollection", "features": [] })),
+                    }
+                }),
+            )
+            .route(
+                "/photon/reverse",
+                get(|| async {
+                    feature_collection(
+                        -0.1246,
+                        51.5007,
+                        json!({
+                            "housenumber": "1", "street": "Bridge Street", "district": "Westminster",
+                            "city": "London", "postcode": "SW1A 0AA", "country": "United Kingdom",
+                            "countrycode": "GB",
+                        }),
+                    )
+                }),
+            );
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
         );
+        format!("http://{}", address)
+    }
 
-        let mut headers = HeaderMap::new();
-        headers.insert(
-            HeaderName::from_static("x-rapidapi-key"),
-            HeaderValue::from_str(key.as_str())?,
+    #[tokio::test]
+    async fn nominatim_searches_and_reads_addresses() {
+        let base_url = start_mock_server();
+        let geocoder = Geocoder::new(NominatimGeocoder::new(Some(format!(
+            "{}/nominatim/",
+            base_url
+        ))));
+
+        let result = geocoder.search("Big Ben", None).await.unwrap();
+        assert_eq!((result.lat, result.lon), (51.5007, -0.1246));
+        assert_eq!(result.display_name.as_deref(), Some("Big Ben, London"));
+        assert!(geocoder.search("Nowhere", None).await.is_err());
+
+        let result = geocoder.reverse(51.5007, -0.1246).await.unwrap();
+        assert_eq!(result.address.road.as_deref(), Some("Bridge Street"));
+        assert_eq!(result.address.city.as_deref(), Some("Westminster"));
+        assert_eq!(result.address.county, None);
+        assert!(geocoder.reverse(0.0, 0.0).await.is_err());
+        assert!(geocoder.reverse(91.0, 0.0).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rapidapi_sends_its_key() {
+        let base_url = format!("{}/rapidapi", start_mock_server());
+
+        let geocoder = Geocoder::new(
+            RapidApiGeocoder::new(Some(base_url.clone()), Some("secret".to_string())).unwrap(),
         );
-        headers.insert(
-            HeaderName::from_static("x-rapidapi-host"),
-            HeaderValue::from_static("forward-reverse-geocoding.p.rapidapi.com"),
+        assert_eq!(geocoder.search("Big Ben", None).await.unwrap().lat, 51.5007);
+
+        let geocoder = Geocoder::new(
+            RapidApiGeocoder::new(Some(base_url.clone()), Some("wrong".to_string())).unwrap(),
         );
+        assert!(geocoder.search("Big Ben", None).await.is_err());
 
-        let response = client.get(request_url).headers(headers).send().await?;
-        let result_vec = response.json::<Vec<GeocoderResult>>().await?;
-        result_vec
-            .into_iter()
-            .next()
-            .ok_or(AppError(anyhow!("Failed to obtain valid geocoding result")))
-    } else {
-        Err(AppError(anyhow!("Missing geocoder API key")))
+        let geocoder = Geocoder::new(RapidApiGeocoder::new(Some(base_url), None).unwrap());
+        assert!(geocoder.search("Big Ben", None).await.is_err());
     }
-}
 
-#[debug_handler]
-pub async fn get_latlong(Path(query_string): Path<String>) -> impl IntoResponse {
-    let result = call_geocoder_api(query_string, &CLIENT).await;
-    result
+    #[tokio::test]
+    async fn pelias_reads_feature_collections() {
+        let base_url = format!("{}/pelias", start_mock_server());
+        let geocoder = Geocoder::new(PeliasGeocoder::new(
+            Some(base_url),
+            Some("secret".to_string()),
+        ));
+
+        let result = geocoder.search("Big Ben", None).await.unwrap();
+        assert_eq!((result.lat, result.lon), (51.5007, -0.1246));
+        assert_eq!(
+            result.display_name.as_deref(),
+            Some("Big Ben, London, England, United Kingdom")
+        );
+
+        let address = geocoder.reverse(51.5, -0.12).await.unwrap().address;
+        assert_eq!(address.house_number.as_deref(), Some("1"));
+        assert_eq!(address.city.as_deref(), Some("London"));
+        assert_eq!(address.state.as_deref(), Some("England"));
+        assert_eq!(address.country_code.as_deref(), Some("gb"));
+    }
+
+    #[tokio::test]
+    async fn photon_reads_feature_collections() {
+        let base_url = format!("{}/photon", start_mock_server());
+        let geocoder = Geocoder::new(PhotonGeocoder::new(Some(base_url)));
+
+        let result = geocoder.search("Big Ben", None).await.unwrap();
+        assert_eq!((result.lat, result.lon), (51.5007, -0.1246));
+        assert_eq!(
+            result.display_name.as_deref(),
+            Some("Big Ben, London, United Kingdom")
+        );
+        assert!(geocoder.search("Nowhere", None).await.is_err());
+
+        let focus = Focus {
+            lat: 45.5,
+            lon: -122.7,
+        };
+        let result = geocoder.search("Big Ben", Some(focus)).await.unwrap();
+        assert_eq!((result.lat, result.lon), (45.0, -123.0));
+        let focus = Focus {
+            lat: 100.0,
+            lon: 0.0,
+        };
+        assert!(geocoder.search("Big Ben", Some(focus)).await.is_err());
+
+        let result = geocoder.reverse(51.5, -0.12).await.unwrap();
+        assert_eq!(
+            result.display_name.as_deref(),
+            Some("1 Bridge Street, London, SW1A 0AA, United Kingdom")
+        );
+        assert_eq!(result.address.suburb.as_deref(), Some("Westminster"));
+        assert_eq!(result.address.country_code.as_deref(), Some("gb"));
+    }
 }
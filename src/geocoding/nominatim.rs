@@ -0,0 +1,169 @@
+use super::{
+    base_url_or, get_json, read_address, Focus, GeocoderProvider, GeocoderResult,
+    ReverseGeocoderResult,
+};
+use crate::AppError;
+use anyhow::{anyhow, Context, Result};
+use axum::async_trait;
+use reqwest::{
+    header::{HeaderMap, HeaderName, HeaderValue, USER_AGENT},
+    Client, Url,
+};
+use serde::{de, Deserialize, Deserializer};
+use serde_json::{Map, Value};
+
+const DEFAULT_NOMINATIM_URL: &str = "https://nominatim.openstreetmap.org";
+const DEFAULT_RAPIDAPI_URL: &str = "https://forward-reverse-geocoding.p.rapidapi.com/v1";
+
+fn deserialize_to_f64<'de, D: Deserializer<'de>>(d: D) -> Result<f64, D::Error> {
+    Ok(match Value::deserialize(d)? {
+        Value::String(s) => s.parse().map_err(de::Error::custom)?,
+        Value::Number(num) => num.as_f64().ok_or(de::Error::custom(""))?,
+        _ => return Err(de::Error::custom("")),
+    })
+}
+
+/**
+A place as returned by Nominatim, whose coordinates are given as strings.
+*/
+#[derive(Deserialize, Debug)]
+struct NominatimPlace {
+    #[serde(deserialize_with = "deserialize_to_f64")]
+    lat: f64,
+    #[serde(deserialize_with = "deserialize_to_f64")]
+    lon: f64,
+    display_name: Option<String>,
+    address: Option<Map<String, Value>>,
+}
+
+/**
+A geocoder using the Nominatim API, by default at the OpenStreetMap Foundation's public instance.
+*/
+pub struct NominatimGeocoder {
+    client: Client,
+    base_url: String,
+    headers: HeaderMap,
+}
+
+impl NominatimGeocoder {
+    pub fn new(base_url: Option<String>) -> NominatimGeocoder {
+        //Nominatim's usage policy requires clients to identify themselves
+        let mut headers = HeaderMap::new();
+        headers.insert(USER_AGENT, HeaderValue::from_static("rusty-mvt"));
+        NominatimGeocoder {
+            client: Client::new(),
+            base_url: base_url_or(base_url, DEFAULT_NOMINATIM_URL),
+            headers,
+        }
+    }
+}
+
+#[async_trait]
+impl GeocoderProvider for NominatimGeocoder {
+    //Nominatim only biases searches towards a bounding box, so the focus point is not used
+    async fn search(&self, query: &str, _focus: Option<Focus>) -> Result<GeocoderResult, AppError> {
+        let response = get_json(
+            &self.client,
+            &format!("{}/search", self.base_url),
+            &[
+                ("q", query.to_string()),
+                ("format", "json".to_string()),
+                ("limit", "1".to_string()),
+            ],
+            self.headers.clone(),
+        )
+        .await?;
+        serde_json::from_value::<Vec<NominatimPlace>>(response)?
+            .into_iter()
+            .next()
+            .map(|place| GeocoderResult {
+                lat: place.lat,
+                lon: place.lon,
+                display_name: place.display_name,
+            })
+            .ok_or(AppError(anyhow!("Failed to obtain valid geocoding result")))
+    }
+
+    async fn reverse(&self, lat: f64, lon: f64) -> Result<ReverseGeocoderResult, AppError> {
+        let response = get_json(
+            &self.client,
+            &format!("{}/reverse", self.base_url),
+            &[
+                ("lat", lat.to_string()),
+                ("lon", lon.to_string()),
+                ("format", "json".to_string()),
+                ("addressdetails", "1".to_string()),
+            ],
+            self.headers.clone(),
+        )
+        .await?;
+        //Locations without an address, such as open water, are reported as an error object
+        if response.get("error").is_some() {
+            return Err(AppError(anyhow!("Failed to obtain valid geocoding result")));
+        }
+        let place = serde_json::from_value::<NominatimPlace>(response)?;
+        let address = place
+            .address
+            .ok_or(AppError(anyhow!("Failed to obtain valid geocoding result")))?;
+        Ok(ReverseGeocoderResult {
+            lat: place.lat,
+            lon: place.lon,
+            display_name: place.display_name,
+            address: read_address(address),
+        })
+    }
+}
+
+/**
+A geocoder using the Nominatim-based forward and reverse geocoding API on RapidAPI, which needs an API key.
+*/
+pub struct RapidApiGeocoder(Option<NominatimGeocoder>);
+
+impl RapidApiGeocoder {
+    /**
+    Configures the geocoder, deriving the RapidAPI host header from the base URL. Without a key, the geocoder fails each request.
+
+    # Errors
+
+    - If the base URL or the key is invalid, returns an `anyhow::Error`.
+    */
+    pub fn new(base_url: Option<String>, api_key: Option<String>) -> Result<RapidApiGeocoder> {
+        let Some(key) = api_key else {
+            return Ok(RapidApiGeocoder(None));
+        };
+        let mut geocoder =
+            NominatimGeocoder::new(Some(base_url_or(base_url, DEFAULT_RAPIDAPI_URL)));
+        let host = Url::parse(&geocoder.base_url)
+            .context("Failed to parse GEOCODER_URL")?
+            .host_str()
+            .ok_or_else(|| anyhow!("GEOCODER_URL has no host"))?
+            .to_string();
+
+        geocoder.headers.insert(
+            HeaderName::from_static("x-rapidapi-key"),
+            HeaderValue::from_str(&key).context("Invalid GEOCODER_API_KEY")?,
+        );
+        geocoder.headers.insert(
+            HeaderName::from_static("x-rapidapi-host"),
+            HeaderValue::from_str(&host).context("Invalid GEOCODER_URL host")?,
+        );
+        Ok(RapidApiGeocoder(Some(geocoder)))
+    }
+
+    fn geocoder(&self) -> Result<&NominatimGeocoder, AppError> {
+        self.0
+            .as_ref()
+            .ok_or(AppError(anyhow!("Missing geocoder API key")))
+    }
+}
+
+#[async_trait]
+impl GeocoderProvider for RapidApiGeocoder {
+    async fn search(&self, query: &str, focus: Option<Focus>) -> Result<GeocoderResult, AppError> {
+        self.geocoder()?.search(query, focus).await
+    }
+
+    async fn reverse(&self, lat: f64, lon: f64) -> Result<ReverseGeocoderResult, AppError> {
+        self.geocoder()?.reverse(lat, lon).await
+    }
+}
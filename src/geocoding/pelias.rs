@@ -0,0 +1,98 @@
+use super::{
+    base_url_or, first_feature, get_json, string_property, AddressComponents, Focus,
+    GeocoderProvider, GeocoderResult, ReverseGeocoderResult,
+};
+use crate::AppError;
+use axum::async_trait;
+use reqwest::{header::HeaderMap, Client};
+
+const DEFAULT_PELIAS_URL: &str = "https://api.geocode.earth";
+
+/**
+A geocoder using the Pelias API, either self-hosted or at a hosted service such as Geocode Earth, which needs an API key.
+*/
+pub struct PeliasGeocoder {
+    client: Client,
+    base_url: String,
+    api_key: Option<String>,
+}
+
+impl PeliasGeocoder {
+    pub fn new(base_url: Option<String>, api_key: Option<String>) -> PeliasGeocoder {
+        PeliasGeocoder {
+            client: Client::new(),
+            base_url: base_url_or(base_url, DEFAULT_PELIAS_URL),
+            api_key,
+        }
+    }
+
+    /**
+    Queries one of the API's endpoints for a single result, adding the API key where there is one.
+    */
+    async fn get_first(
+        &self,
+        endpoint: &str,
+        mut params: Vec<(&str, String)>,
+    ) -> Result<serde_json::Value, AppError> {
+        params.push(("size", "1".to_string()));
+        if let Some(key) = &self.api_key {
+            params.push(("api_key", key.clone()));
+        }
+        get_json(
+            &self.client,
+            &format!("{}/v1/{}", self.base_url, endpoint),
+            &params,
+            HeaderMap::new(),
+        )
+        .await
+    }
+}
+
+#[async_trait]
+impl GeocoderProvider for PeliasGeocoder {
+    async fn search(&self, query: &str, focus: Option<Focus>) -> Result<GeocoderResult, AppError> {
+        let mut params = vec![("text", query.to_string())];
+        if let Some(focus) = focus {
+            params.push(("focus.point.lat", focus.lat.to_string()));
+            params.push(("focus.point.lon", focus.lon.to_string()));
+        }
+        let response = self.get_first("search", params).await?;
+        let (lat, lon, properties) = first_feature(&response)?;
+        Ok(GeocoderResult {
+            lat,
+            lon,
+            display_name: string_property(properties, "label"),
+        })
+    }
+
+    async fn reverse(&self, lat: f64, lon: f64) -> Result<ReverseGeocoderResult, AppError> {
+        let response = self
+            .get_first(
+                "reverse",
+                vec![
+                    ("point.lat", lat.to_string()),
+                    ("point.lon", lon.to_string()),
+                ],
+            )
+            .await?;
+        let (lat, lon, properties) = first_feature(&response)?;
+        let property = |key: &str| string_property(properties, key);
+        Ok(ReverseGeocoderResult {
+            lat,
+            lon,
+            display_name: property("label"),
+            address: AddressComponents {
+                house_number: property("housenumber"),
+                road: property("street"),
+                neighbourhood: property("neighbourhood"),
+                suburb: property("borough"),
+                city: property("locality").or_else(|| property("localadmin")),
+                county: property("county"),
+                state: property("region"),
+                postcode: property("postalcode"),
+                country: property("country"),
+                country_code: property("country_code").map(|code| code.to_lowercase()),
+            },
+        })
+    }
+}
@@ -0,0 +1,114 @@
+use super::{
+    base_url_or, first_feature, get_json, string_property, AddressComponents, Focus,
+    GeocoderProvider, GeocoderResult, ReverseGeocoderResult,
+};
+use crate::AppError;
+use axum::async_trait;
+use reqwest::{header::HeaderMap, Client};
+use serde_json::{Map, Value};
+
+const DEFAULT_PHOTON_URL: &str = "https://photon.komoot.io";
+
+/**
+Joins the name and address of a Photon result into a single line, as Photon gives no label of its own.
+*/
+fn make_display_name(properties: &Map<String, Value>) -> Option<String> {
+    let street = match (
+        string_property(properties, "housenumber"),
+        string_property(properties, "street"),
+    ) {
+        (Some(number), Some(street)) => Some(format!("{} {}", number, street)),
+        (_, street) => street,
+    };
+    let parts = [
+        string_property(properties, "name"),
+        street,
+        string_property(properties, "city"),
+        string_property(properties, "state"),
+        string_property(properties, "postcode"),
+        string_property(properties, "country"),
+    ]
+    .into_iter()
+    .flatten()
+    .collect::<Vec<String>>();
+
+    if parts.is_empty() {
+        None
+    } else {
+        Some(parts.join(", "))
+    }
+}
+
+/**
+A geocoder using the Photon API, by default at Komoot's public instance.
+*/
+pub struct PhotonGeocoder {
+    client: Client,
+    base_url: String,
+}
+
+impl PhotonGeocoder {
+    pub fn new(base_url: Option<String>) -> PhotonGeocoder {
+        PhotonGeocoder {
+            client: Client::new(),
+            base_url: base_url_or(base_url, DEFAULT_PHOTON_URL),
+        }
+    }
+}
+
+#[async_trait]
+impl GeocoderProvider for PhotonGeocoder {
+    async fn search(&self, query: &str, focus: Option<Focus>) -> Result<GeocoderResult, AppError> {
+        let mut params = vec![("q", query.to_string()), ("limit", "1".to_string())];
+        if let Some(focus) = focus {
+            params.push(("lat", focus.lat.to_string()));
+            params.push(("lon", focus.lon.to_string()));
+        }
+        let response = get_json(
+            &self.client,
+            &format!("{}/api", self.base_url),
+            &params,
+            HeaderMap::new(),
+        )
+        .await?;
+        let (lat, lon, properties) = first_feature(&response)?;
+        Ok(GeocoderResult {
+            lat,
+            lon,
+            display_name: make_display_name(properties),
+        })
+    }
+
+    async fn reverse(&self, lat: f64, lon: f64) -> Result<ReverseGeocoderResult, AppError> {
+        let response = get_json(
+            &self.client,
+            &format!("{}/reverse", self.base_url),
+            &[
+                ("lat", lat.to_string()),
+                ("lon", lon.to_string()),
+                ("limit", "1".to_string()),
+            ],
+            HeaderMap::new(),
+        )
+        .await?;
+        let (lat, lon, properties) = first_feature(&response)?;
+        let property = |key: &str| string_property(properties, key);
+        Ok(ReverseGeocoderResult {
+            lat,
+            lon,
+            display_name: make_display_name(properties),
+            address: AddressComponents {
+                house_number: property("housenumber"),
+                road: property("street"),
+                neighbourhood: property("locality"),
+                suburb: property("district"),
+                city: property("city"),
+                county: property("county"),
+                state: property("state"),
+                postcode: property("postcode"),
+                country: property("country"),
+                country_code: property("countrycode").map(|code| code.to_lowercase()),
+            },
+        })
+    }
+}
@@ -1,30 +1,140 @@
+mod cache;
+mod cluster;
+mod extract;
+mod inspection;
 mod vector_tile;
 use anyhow::anyhow;
-use axum::extract::{Path, State};
-use vector_tile::{get_mvt, MVTBuffer, Tile};
+use axum::{
+    extract::{Path, Query, State},
+    response::{IntoResponse, Response},
+    Json,
+};
+pub use cache::TileCache;
+pub(crate) use cache::TileCacheKey;
+use cluster::get_clustered_mvt;
+pub use cluster::ClusterConfig;
+use extract::{get_fgb, get_geojson};
+use inspection::{inspect_mvt, TileInspection};
+use serde::Deserialize;
+use vector_tile::get_mvt;
+pub(crate) use vector_tile::{
+    check_tile_coordinates, make_envelope_statement, MVTBuffer, Tile, BUFFER, EXTENT,
+};
 
 use axum_macros::debug_handler;
 
-use crate::{AppError, AppState};
+use crate::{
+    db::{Table, TableRegistry},
+    AppError, AppState,
+};
+
+pub(crate) fn parse_y_ext(y_ext: &str) -> Result<(usize, String), AppError> {
+    match y_ext.split('.').collect::<Vec<&str>>().as_slice() {
+        [y_str, ext] => match y_str.parse::<usize>() {
+            Ok(y) => Ok((y, ext.to_string())),
+            Err(_) => Err(AppError(anyhow!("Failed to parse y_str as usize"))),
+        },
+        _ => Err(AppError(anyhow!("Invalid y_ext format: {}", y_ext))),
+    }
+}
+
+fn get_table_spec<'a>(
+    registry: &'a TableRegistry,
+    schemaid: &str,
+    tableid: &str,
+) -> Result<&'a Table, AppError> {
+    if let Some(schema) = registry.schemas.get(schemaid) {
+        if let Some(table) = schema.tables.get(tableid) {
+            Ok(table)
+        } else {
+            Err(AppError(anyhow!("Failed to locate specified table")))
+        }
+    } else {
+        Err(AppError(anyhow!("Failed to locate specified schema")))
+    }
+}
+
+/**
+Optional query parameters for vector tile requests.
+
+* `cluster` - Whether to cluster the points of a point layer. Tiles above the configured maximum zoom contain raw points.
+* `cluster_max_zoom` - Overrides the configured maximum zoom at which points are clustered.
+* `cluster_radius` - Overrides the configured clustering distance, in tile pixels.
+*/
+#[derive(Deserialize)]
+pub struct LayerOptions {
+    #[serde(default)]
+    cluster: bool,
+    cluster_max_zoom: Option<usize>,
+    cluster_radius: Option<f32>,
+}
+
+async fn fetch_layer_mvt(
+    tile: &Tile,
+    table: &Table,
+    options: &LayerOptions,
+    state: &AppState,
+) -> Result<MVTBuffer, AppError> {
+    let max_zoom = options
+        .cluster_max_zoom
+        .unwrap_or(state.cluster_config.max_zoom);
+
+    let cluster_radius = if options.cluster && tile.z <= max_zoom {
+        Some(
+            options
+                .cluster_radius
+                .unwrap_or(state.cluster_config.radius),
+        )
+    } else {
+        None
+    };
+
+    let cache_key = TileCacheKey {
+        schema: table.schema_name.clone(),
+        table: table.name.clone(),
+        z: tile.z,
+        x: tile.x,
+        y: tile.y,
+        variant: match cluster_radius {
+            Some(radius) => format!("mvt:cluster:{}", radius),
+            None => "mvt".to_string(),
+        },
+    };
+    if let Some(cached) = state.tile_cache.get(&cache_key) {
+        return Ok(MVTBuffer(cached.to_vec()));
+    }
+
+    let mvt = match cluster_radius {
+        Some(radius) => get_clustered_mvt(tile, table, radius, state.db_pool.clone()).await?,
+        None => get_mvt(tile, table, state.db_pool.clone()).await?,
+    };
+    state.tile_cache.insert(cache_key, mvt.as_bytes().to_vec());
+
+    Ok(mvt)
+}
 
 #[debug_handler]
 /**
-Handles a GET request to retrieve a specific layer of a vector tile.
+Handles a GET request to retrieve a specific layer of a vector tile, or the features within that tile as a GeoJSON or FlatGeobuf extract.
 
 # Arguments
 
 * `State(state)`: The state of the application, which contains the table registry and database pool.
-* `Path((schemaid, tableid, z, x, y_ext))`: The path parameters extracted from the request URL, including the schema ID, table ID, zoom level, tile coordinates, and file extension.
+* `Path((schemaid, tableid, z, x, y_ext))`: The path parameters extracted from the request URL, including the schema ID, table ID, zoom level, tile coordinates, and file extension. Supported extensions are "mvt", "geojson" and "fgb".
+* `Query(options)`: Optional `LayerOptions`. Clustering options apply to the "mvt" extension only.
 
 # Returns
 
-Returns a result that either contains the `MVTBuffer` containing the vector tile data or an `AppError` if there was an error during the process.
+Returns a result that either contains the response body in the requested format (an `MVTBuffer`, `GeoJSONBuffer` or `FGBBuffer`) or an `AppError` if there was an error during the process.
 
 # Example
 
-```rust
+```no_run
+# use axum::extract::{Path, Query, State};
+# use rusty_mvt::{layers::{get_layer, LayerOptions}, AppState};
+#
+# async fn example(state: AppState, options: LayerOptions) {
 // Assuming the following inputs:
-let state = AppState { ... };
 let schemaid = "schema1";
 let tableid = "table1";
 let z = 10;
@@ -32,40 +142,66 @@ let x = 123;
 let y_ext = "456.mvt";
 
 // Calling the function:
-let result = get_layer(State(state), Path((schemaid.to_string(), tableid.to_string(), z, x, y_ext.to_string()))).await;
+let result = get_layer(State(state), Path((schemaid.to_string(), tableid.to_string(), z, x, y_ext.to_string())), Query(options)).await;
 
 // Expected output:
-// If the specified schema and table exist, and the file extension is "mvt", the function will return the MVTBuffer containing the vector tile data. Otherwise, it will return an AppError.
+// If the specified schema and table exist, and the file extension is supported, the function will return the tile data in that format. Otherwise, it will return an AppError.
+# }
 ```
 */
 pub async fn get_layer(
     State(state): State<AppState>,
     Path((schemaid, tableid, z, x, y_ext)): Path<(String, String, usize, usize, String)>,
-) -> Result<MVTBuffer, AppError> {
-    let (y, ext) = match y_ext.split('.').collect::<Vec<&str>>().as_slice() {
-        [y_str, ext] => {
-            match y_str.parse::<usize>() {
-                Ok(y) => (y, ext.to_string()),
-                Err(_) => return Err(AppError(anyhow!("Failed to parse y_str as usize"))),
-            }
-        }
-        _ => return Err(AppError(anyhow!("Invalid y_ext format: {}", y_ext))),
-    };
+    Query(options): Query<LayerOptions>,
+) -> Result<Response, AppError> {
+    let (y, ext) = parse_y_ext(&y_ext)?;
+    let table_spec = get_table_spec(&state.table_registry, &schemaid, &tableid)?;
 
-    let table_spec = if let Some(schema) = state.table_registry.schemas.get(&schemaid) {
-        if let Some(table) = schema.tables.get(&tableid) {
-            table
-        } else {
-            return Err(AppError(anyhow!("Failed to locate specified table")));
-        }
-    } else {
-        return Err(AppError(anyhow!("Failed to locate specified schema")));
-    };
+    let this_tile = Tile::new(x, y, z);
+
+    match ext.as_str() {
+        "mvt" => fetch_layer_mvt(&this_tile, table_spec, &options, &state)
+            .await
+            .map(|mvt| mvt.into_response()),
+        "geojson" => get_geojson(&this_tile, table_spec, state.db_pool)
+            .await
+            .map(|geojson| geojson.into_response()),
+        "fgb" => get_fgb(&this_tile, table_spec, state.db_pool)
+            .await
+            .map(|fgb| fgb.into_response()),
+        _ => Err(AppError(anyhow!("Specified file extension not supported"))),
+    }
+}
+
+#[debug_handler]
+/**
+Handles a GET request to inspect a vector tile, returning the same tile that `get_layer` would serve decoded into GeoJSON.
+
+# Arguments
+
+* `State(state)`: The state of the application, which contains the table registry and database pool.
+* `Path((schemaid, tableid, z, x, y_ext))`: The same path parameters accepted by `get_layer`. Only the "mvt" extension is supported.
+* `Query(options)`: The same `LayerOptions` accepted by `get_layer`.
+
+# Returns
+
+Returns a result that either contains a `TileInspection` with the tile size and, for each layer, its feature count, byte statistics and a GeoJSON `FeatureCollection` of its features, or an `AppError` if there was an error during the process.
+*/
+pub async fn inspect_layer(
+    State(state): State<AppState>,
+    Path((schemaid, tableid, z, x, y_ext)): Path<(String, String, usize, usize, String)>,
+    Query(options): Query<LayerOptions>,
+) -> Result<Json<TileInspection>, AppError> {
+    let (y, ext) = parse_y_ext(&y_ext)?;
+    let table_spec = get_table_spec(&state.table_registry, &schemaid, &tableid)?;
 
     let this_tile = Tile::new(x, y, z);
 
     match ext.as_str() {
-        "mvt" => get_mvt(&this_tile, table_spec, state.db_pool).await,
+        "mvt" => {
+            let mvt = fetch_layer_mvt(&this_tile, table_spec, &options, &state).await?;
+            inspect_mvt(&this_tile, mvt.as_bytes()).map(Json)
+        }
         _ => Err(AppError(anyhow!("Specified file extension not supported"))),
     }
 }
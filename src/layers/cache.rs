@@ -0,0 +1,234 @@
+use super::vector_tile::{Tile, BUFFER, EXTENT, WORLD_MERC_MAX};
+use anyhow::{Context, Result};
+use std::collections::{HashMap, VecDeque};
+use std::env::var;
+use std::sync::{Arc, RwLock};
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+pub struct TileCacheKey {
+    pub schema: String,
+    pub table: String,
+    pub z: usize,
+    pub x: usize,
+    pub y: usize,
+    pub variant: String,
+}
+
+#[derive(Default)]
+struct TileCacheEntries {
+    tiles: HashMap<TileCacheKey, Arc<Vec<u8>>>,
+    order: VecDeque<TileCacheKey>,
+}
+
+/**
+An in-memory cache of encoded tiles, shared between requests. Once full, the oldest tiles are evicted first.
+
+A cache with a capacity of zero is disabled: lookups always miss and nothing is stored.
+*/
+#[derive(Clone)]
+pub struct TileCache {
+    max_entries: usize,
+    entries: Arc<RwLock<TileCacheEntries>>,
+}
+
+impl TileCache {
+    pub fn new(max_entries: usize) -> TileCache {
+        TileCache {
+            max_entries,
+            entries: Arc::new(RwLock::new(TileCacheEntries::default())),
+        }
+    }
+
+    /**
+    Creates a cache sized by the `TILE_CACHE_MAX_ENTRIES` environment variable. Caching is disabled when the variable is unset.
+
+    # Errors
+
+    - If the variable is set but cannot be parsed as a `usize`, returns an `anyhow::Error`.
+    */
+    pub fn from_env() -> Result<TileCache> {
+        let max_entries = match var("TILE_CACHE_MAX_ENTRIES") {
+            Ok(entries) => entries
+                .parse::<usize>()
+                .context("Failed to parse TILE_CACHE_MAX_ENTRIES as usize")?,
+            Err(_) => 0,
+        };
+
+        Ok(TileCache::new(max_entries))
+    }
+
+    pub fn get(&self, key: &TileCacheKey) -> Option<Arc<Vec<u8>>> {
+        if self.max_entries == 0 {
+            return None;
+        }
+        self.entries.read().ok()?.tiles.get(key).cloned()
+    }
+
+    pub fn insert(&self, key: TileCacheKey, tile: Vec<u8>) {
+        if self.max_entries == 0 {
+            return;
+        }
+        if let Ok(mut entries) = self.entries.write() {
+            if entries.tiles.insert(key.clone(), Arc::new(tile)).is_none() {
+                entries.order.push_back(key);
+            }
+            while entries.order.len() > self.max_entries {
+                if let Some(oldest) = entries.order.pop_front() {
+                    entries.tiles.remove(&oldest);
+                }
+            }
+        }
+    }
+
+    /**
+    Evicts every cached tile of a table whose envelope, including the rendering buffer, intersects the given bounds.
+
+    # Arguments
+
+    * `schema` - The schema name of the edited table.
+    * `table` - The name of the edited table.
+    * `bounds` - The affected area in Web Mercator, as `[min_x, min_y, max_x, max_y]`.
+
+    # Returns
+
+    The number of tiles evicted.
+    */
+    pub fn invalidate(&self, schema: &str, table: &str, bounds: [f64; 4]) -> usize {
+        if self.max_entries == 0 {
+            return 0;
+        }
+        if let Ok(mut entries) = self.entries.write() {
+            let stale = entries
+                .tiles
+                .keys()
+                .filter(|key| {
+                    key.schema == schema
+                        && key.table == table
+                        && bounds_intersect(
+                            get_tile_bounds(&Tile::new(key.x, key.y, key.z)),
+                            bounds,
+                        )
+                })
+                .cloned()
+                .collect::<Vec<TileCacheKey>>();
+
+            for key in stale.iter() {
+                entries.tiles.remove(key);
+            }
+            entries.order.retain(|key| !stale.contains(key));
+            stale.len()
+        } else {
+            0
+        }
+    }
+}
+
+/**
+Computes the Web Mercator bounds of a tile, expanded by the buffer used when rendering it.
+*/
+fn get_tile_bounds(t: &Tile) -> [f64; 4] {
+    let tile_span = (2.0 * WORLD_MERC_MAX) / 2_f64.powi(t.z as i32);
+    let margin = tile_span * (BUFFER / EXTENT) as f64;
+    let min_x = -WORLD_MERC_MAX + t.x as f64 * tile_span;
+    let max_y = WORLD_MERC_MAX - t.y as f64 * tile_span;
+
+    [
+        min_x - margin,
+        max_y - tile_span - margin,
+        min_x + tile_span + margin,
+        max_y + margin,
+    ]
+}
+
+fn bounds_intersect(a: [f64; 4], b: [f64; 4]) -> bool {
+    a[0] <= b[2] && b[0] <= a[2] && a[1] <= b[3] && b[1] <= a[3]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(table: &str, z: usize, x: usize, y: usize) -> TileCacheKey {
+        TileCacheKey {
+            schema: "public".to_string(),
+            table: table.to_string(),
+            z,
+            x,
+            y,
+            variant: String::new(),
+        }
+    }
+
+    #[test]
+    fn tile_bounds_include_the_buffer() {
+        let margin = WORLD_MERC_MAX * (BUFFER / EXTENT) as f64;
+        let [min_x, min_y, max_x, max_y] = get_tile_bounds(&Tile::new(0, 0, 1));
+        assert_eq!(min_x, -WORLD_MERC_MAX - margin);
+        assert_eq!(min_y, -margin);
+        assert_eq!(max_x, margin);
+        assert_eq!(max_y, WORLD_MERC_MAX + margin);
+    }
+
+    #[test]
+    fn invalidate_evicts_only_intersecting_tiles_of_the_table() {
+        let cache = TileCache::new(10);
+        for k in [
+            key("roads", 0, 0, 0),
+            key("roads", 1, 0, 0),
+            key("roads", 1, 1, 1),
+            key("roads", 2, 3, 3),
+            key("rivers", 1, 0, 0),
+        ] {
+            cache.insert(k, vec![1]);
+        }
+
+        //Well inside the north-western quadrant, clear of the buffers of its neighbours
+        let edited = [-10_000_000.0, 10_000_000.0, -9_900_000.0, 10_100_000.0];
+        assert_eq!(cache.invalidate("public", "roads", edited), 2);
+
+        assert!(cache.get(&key("roads", 0, 0, 0)).is_none());
+        assert!(cache.get(&key("roads", 1, 0, 0)).is_none());
+        assert!(cache.get(&key("roads", 1, 1, 1)).is_some());
+        assert!(cache.get(&key("roads", 2, 3, 3)).is_some());
+        assert!(cache.get(&key("rivers", 1, 0, 0)).is_some());
+        assert_eq!(cache.invalidate("other", "roads", edited), 0);
+    }
+
+    #[test]
+    fn invalidate_reaches_tiles_through_their_buffer() {
+        let cache = TileCache::new(10);
+        cache.insert(key("roads", 1, 1, 0), vec![1]);
+
+        //Just west of the tile's edge at x = 0, within its rendering buffer
+        assert_eq!(
+            cache.invalidate("public", "roads", [-1000.0, 1000.0, -1000.0, 1000.0]),
+            1
+        );
+    }
+
+    #[test]
+    fn evicted_tiles_leave_the_insertion_order() {
+        let cache = TileCache::new(2);
+        cache.insert(key("roads", 1, 0, 0), vec![1]);
+        cache.insert(key("roads", 1, 1, 1), vec![2]);
+        cache.invalidate(
+            "public",
+            "roads",
+            [10_000_000.0, -10_000_000.0, 10_000_000.0, -10_000_000.0],
+        );
+
+        //Only one tile remains, so one more fits without evicting it
+        cache.insert(key("roads", 2, 0, 0), vec![3]);
+        assert!(cache.get(&key("roads", 1, 0, 0)).is_some());
+        assert!(cache.get(&key("roads", 1, 1, 1)).is_none());
+        assert!(cache.get(&key("roads", 2, 0, 0)).is_some());
+    }
+
+    #[test]
+    fn disabled_cache_stores_nothing() {
+        let cache = TileCache::new(0);
+        cache.insert(key("roads", 0, 0, 0), vec![1]);
+        assert!(cache.get(&key("roads", 0, 0, 0)).is_none());
+        assert_eq!(cache.invalidate("public", "roads", [0.0, 0.0, 1.0, 1.0]), 0);
+    }
+}
@@ -0,0 +1,262 @@
+use super::vector_tile::{
+    check_tile_coordinates, get_layer_columns, make_envelope_statement, run_mvt_query, MVTBuffer,
+    Tile, BUFFER, EXTENT, INCOMING_SRID, WORLD_MERC_MAX,
+};
+use crate::{db::Table, AppError};
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use std::env::var;
+
+const DEFAULT_CLUSTER_MAX_ZOOM: usize = 14;
+const DEFAULT_CLUSTER_RADIUS: f32 = 40.0;
+//Names given to the clustered layer's own columns, which published columns of the same name would collide with
+const CLUSTER_COLUMNS: [&str; 3] = ["geom", "cluster_id", "point_count"];
+
+/**
+Server-wide defaults for point clustering, which individual requests may override.
+
+* `max_zoom` - The highest zoom level at which points are clustered. Tiles above this zoom contain raw points.
+* `radius` - The clustering distance, in tile pixels (out of an extent of 4096).
+*/
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClusterConfig {
+    pub max_zoom: usize,
+    pub radius: f32,
+}
+
+impl ClusterConfig {
+    /**
+    Reads clustering defaults from the `CLUSTER_MAX_ZOOM` and `CLUSTER_RADIUS` environment variables, falling back to built-in defaults when they are unset.
+
+    # Errors
+
+    - If either variable is set but cannot be parsed, returns an `anyhow::Error`.
+    */
+    pub fn from_env() -> Result<ClusterConfig> {
+        let max_zoom = match var("CLUSTER_MAX_ZOOM") {
+            Ok(zoom) => zoom
+                .parse::<usize>()
+                .context("Failed to parse CLUSTER_MAX_ZOOM as usize")?,
+            Err(_) => DEFAULT_CLUSTER_MAX_ZOOM,
+        };
+        let radius = match var("CLUSTER_RADIUS") {
+            Ok(radius) => radius
+                .parse::<f32>()
+                .context("Failed to parse CLUSTER_RADIUS as f32")?,
+            Err(_) => DEFAULT_CLUSTER_RADIUS,
+        };
+
+        Ok(ClusterConfig { max_zoom, radius })
+    }
+}
+
+fn is_point_table(tab: &Table) -> bool {
+    matches!(
+        tab.geom_type
+            .as_deref()
+            .map(|t| t.to_uppercase())
+            .as_deref(),
+        Some("POINT") | Some("MULTIPOINT")
+    )
+}
+
+/**
+Generates a SQL query string for a vector tile in which the points of a table are grouped with `ST_ClusterDBSCAN`.
+
+Each cluster is emitted as a single point at the centroid of its members, with a `cluster_id`, a `point_count`, and each of the table's published columns. A column keeps its value only where every member of the cluster shares it, compared as text so that columns without an equality operator, such as `json`, can be clustered, and is null otherwise; single-point clusters therefore carry their original attributes. Published columns named `geom`, `cluster_id` or `point_count` are left out, as they would collide with the layer's own.
+
+# Arguments
+
+* `t` - A reference to a `Tile` object representing the map tile coordinates.
+* `tab` - A reference to a `Table` object representing the database table information.
+* `radius` - The clustering distance, in tile pixels.
+
+# Returns
+
+A `Result` object containing the SQL query string if successful, or an `AppError` if there is an invalid tile coordinate, or the table has no point geometry column.
+*/
+pub fn make_cluster_query(t: &Tile, tab: &Table, radius: f32) -> Result<String, AppError> {
+    check_tile_coordinates(t)?;
+
+    if !is_point_table(tab) {
+        return Err(AppError(anyhow!(
+            "Clustering is only supported for point layers"
+        )));
+    }
+
+    if let Some(geom_col) = &tab.geom_column {
+        let envelope = make_envelope_statement(t, None);
+        let envelope_with_margin = make_envelope_statement(t, Some(BUFFER / EXTENT));
+
+        let tile_span = (2.0 * WORLD_MERC_MAX) / 2_f64.powi(t.z as i32);
+        let eps = tile_span * (radius as f64) / (EXTENT as f64);
+
+        let columns = get_layer_columns(tab)
+            .into_iter()
+            .filter(|c| !CLUSTER_COLUMNS.contains(&c.as_str()))
+            .collect::<Vec<String>>();
+        let aggregated_columns = columns
+            .iter()
+            .map(|c| {
+                format!(
+                    "case when count(distinct {c}::text) <= 1 then (array_agg({c}))[1] end as {c}",
+                    c = c
+                )
+            })
+            .collect::<Vec<String>>();
+
+        let column_list = if columns.is_empty() {
+            "".to_string()
+        } else {
+            format!(", {}", columns.join(", "))
+        };
+        let aggregated_column_list = if aggregated_columns.is_empty() {
+            "".to_string()
+        } else {
+            format!(", {}", aggregated_columns.join(", "))
+        };
+
+        Ok(format!(
+            "with points as (
+              select
+                  ST_Transform(t.{}, {}) as geom{}
+              from
+                  {}.{} t
+              where
+                  ST_Transform(t.{},
+                  {}) && {}),
+              clustered as (
+              select
+                  ST_ClusterDBSCAN(points.geom, eps := {}, minpoints := 1) over () as cluster_id,
+                  points.*
+              from
+                  points),
+              mvtgeom as (
+              select
+                  ST_AsMVTGeom(
+                          ST_Centroid(ST_Collect(clustered.geom)),
+                  {},
+                  {},
+                  {}
+                      ) as geom,
+                  cluster_id,
+                  count(*) as point_count{}
+              from
+                  clustered
+              group by
+                  cluster_id)
+                  select
+                  ST_AsMVT(mvtgeom.*)
+              from
+                  mvtgeom;",
+            geom_col,
+            INCOMING_SRID,
+            column_list,
+            tab.schema_name,
+            tab.name,
+            geom_col,
+            INCOMING_SRID,
+            envelope_with_margin,
+            eps,
+            envelope,
+            EXTENT,
+            BUFFER,
+            aggregated_column_list
+        ))
+    } else {
+        Err(AppError(anyhow!(
+            "No geometry column found in table. Unable to retrieve data."
+        )))
+    }
+}
+
+/**
+Retrieves a clustered point tile from a database table. See `make_cluster_query` for the layout of the resulting layer.
+
+# Arguments
+
+* `tile` - A reference to a `Tile` object representing the map tile coordinates.
+* `table` - A reference to a `Table` object representing the database table information.
+* `radius` - The clustering distance, in tile pixels.
+* `conn` - A connection pool to the database.
+
+# Returns
+
+A `Result` object containing the MVT data if successful, or an `AppError` if there is an error in the query generation or execution.
+*/
+pub async fn get_clustered_mvt(
+    tile: &Tile,
+    table: &Table,
+    radius: f32,
+    conn: Pool<Postgres>,
+) -> Result<MVTBuffer, AppError> {
+    match make_cluster_query(tile, table, radius) {
+        Ok(cluster_query) => run_mvt_query(&cluster_query, conn).await,
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn places(attrs: &[&str]) -> Table {
+        Table::new(
+            "places".to_string(),
+            "public".to_string(),
+            vec!["id".to_string()],
+            "geom".to_string(),
+            "POINT".to_string(),
+            4326,
+            Some(attrs.iter().map(|a| a.to_string()).collect()),
+            "deg".to_string(),
+            true,
+        )
+    }
+
+    #[test]
+    fn cluster_query_keeps_only_shared_values() {
+        let query =
+            make_cluster_query(&Tile::new(0, 0, 0), &places(&["name", "tags"]), 40.0).unwrap();
+
+        assert!(query.contains("ST_Transform(t.geom, 3857) as geom, id, name, tags"));
+        assert!(query.contains("from\n                  public.places t"));
+        for column in ["id", "name", "tags"] {
+            assert!(query.contains(&format!(
+                "case when count(distinct {c}::text) <= 1 then (array_agg({c}))[1] end as {c}",
+                c = column
+            )));
+        }
+        assert!(query.contains(&format!(
+            "eps := {}",
+            2.0 * WORLD_MERC_MAX * 40.0 / EXTENT as f64
+        )));
+    }
+
+    #[test]
+    fn cluster_query_leaves_out_colliding_columns() {
+        let query = make_cluster_query(
+            &Tile::new(0, 0, 0),
+            &places(&["cluster_id", "name", "point_count"]),
+            40.0,
+        )
+        .unwrap();
+
+        assert!(query.contains("as geom, id, name\n"));
+        assert_eq!(query.matches(" as cluster_id").count(), 1);
+        assert_eq!(query.matches(" as point_count").count(), 1);
+        assert!(!query.contains("distinct cluster_id"));
+        assert!(!query.contains("distinct point_count"));
+    }
+
+    #[test]
+    fn cluster_query_needs_a_point_layer_and_valid_tile() {
+        let mut lines = places(&[]);
+        lines.geom_type = Some("LINESTRING".to_string());
+        assert!(make_cluster_query(&Tile::new(0, 0, 0), &lines, 40.0).is_err());
+
+        assert!(make_cluster_query(&Tile::new(1, 0, 0), &places(&[]), 40.0).is_err());
+        assert!(make_cluster_query(&Tile::new(0, 0, 1), &places(&[]), 40.0).is_ok());
+    }
+}
@@ -0,0 +1,147 @@
+use super::vector_tile::{
+    check_tile_coordinates, get_layer_columns, make_envelope_statement, Tile, BUFFER, EXTENT,
+    INCOMING_SRID,
+};
+use crate::{db::Table, AppError};
+use anyhow::anyhow;
+use axum::{http::HeaderMap, response::IntoResponse};
+use reqwest::header::CONTENT_TYPE;
+use sqlx::{query, Pool, Postgres, Row};
+
+const OUTGOING_SRID: usize = 4326;
+
+pub struct GeoJSONBuffer(String);
+
+impl IntoResponse for GeoJSONBuffer {
+    fn into_response(self) -> axum::response::Response {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, "application/geo+json".parse().unwrap());
+        (headers, self.0).into_response()
+    }
+}
+
+pub struct FGBBuffer(Vec<u8>);
+
+impl IntoResponse for FGBBuffer {
+    fn into_response(self) -> axum::response::Response {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, "application/flatgeobuf".parse().unwrap());
+        (headers, self.0).into_response()
+    }
+}
+
+/**
+Generates the common table expression selecting the features of a table that fall within a tile, using the same envelope and columns as `make_tile_data_query`. Geometries are returned untrimmed, in WGS84.
+
+# Arguments
+
+* `t` - A reference to a `Tile` object representing the map tile coordinates.
+* `tab` - A reference to a `Table` object representing the database table information.
+
+# Returns
+
+A `Result` object containing the SQL string for a CTE named `features`, or an `AppError` if there is an invalid tile coordinate or no geometry column found in the table.
+*/
+fn make_tile_features_cte(t: &Tile, tab: &Table) -> Result<String, AppError> {
+    check_tile_coordinates(t)?;
+
+    if let Some(geom_col) = &tab.geom_column {
+        let envelope_with_margin = make_envelope_statement(t, Some(BUFFER / EXTENT));
+
+        Ok(format!(
+            "with features as (
+              select
+                  ST_Transform(t.{}, {}) as geom,
+                  {}
+              from
+                  {}.{} t
+              where
+                  ST_Transform(t.{},
+                  {}) && {})",
+            geom_col,
+            OUTGOING_SRID,
+            get_layer_columns(tab).join(", "),
+            tab.schema_name,
+            tab.name,
+            geom_col,
+            INCOMING_SRID,
+            envelope_with_margin
+        ))
+    } else {
+        Err(AppError(anyhow!(
+            "No geometry column found in table. Unable to retrieve data."
+        )))
+    }
+}
+
+/**
+Retrieves the features of a table within a tile as a GeoJSON `FeatureCollection`.
+
+# Arguments
+
+* `tile` - A reference to a `Tile` object representing the map tile coordinates.
+* `table` - A reference to a `Table` object representing the database table information.
+* `conn` - A connection pool to the database.
+
+# Returns
+
+A `Result` object containing the GeoJSON document if successful, or an `AppError` if there is an error in the query generation or execution.
+*/
+pub async fn get_geojson(
+    tile: &Tile,
+    table: &Table,
+    conn: Pool<Postgres>,
+) -> Result<GeoJSONBuffer, AppError> {
+    let geojson_query = format!(
+        "{}
+        select
+            json_build_object(
+                'type', 'FeatureCollection',
+                'features', coalesce(json_agg(ST_AsGeoJSON(features.*)::json), '[]'::json)
+            )::text
+        from
+            features;",
+        make_tile_features_cte(tile, table)?
+    );
+
+    match query(&geojson_query).fetch_one(&conn).await {
+        Ok(row) => Ok(GeoJSONBuffer(row.get(0))),
+        Err(e) => Err(AppError(anyhow!(e))),
+    }
+}
+
+/**
+Retrieves the features of a table within a tile as a spatially indexed FlatGeobuf file.
+
+# Arguments
+
+* `tile` - A reference to a `Tile` object representing the map tile coordinates.
+* `table` - A reference to a `Table` object representing the database table information.
+* `conn` - A connection pool to the database.
+
+# Returns
+
+A `Result` object containing the FlatGeobuf data if successful, or an `AppError` if there is an error in the query generation or execution.
+*/
+pub async fn get_fgb(
+    tile: &Tile,
+    table: &Table,
+    conn: Pool<Postgres>,
+) -> Result<FGBBuffer, AppError> {
+    let fgb_query = format!(
+        "{}
+        select
+            ST_AsFlatGeobuf(features.*, true, 'geom')
+        from
+            features;",
+        make_tile_features_cte(tile, table)?
+    );
+
+    match query(&fgb_query).fetch_one(&conn).await {
+        Ok(row) => {
+            let fgb_bytes: Option<Vec<u8>> = row.get(0);
+            Ok(FGBBuffer(fgb_bytes.unwrap_or_default()))
+        }
+        Err(e) => Err(AppError(anyhow!(e))),
+    }
+}
@@ -0,0 +1,522 @@
+use super::vector_tile::{Tile, WORLD_MERC_MAX};
+use crate::{web_mercator_to_lon_lat, AppError};
+use anyhow::anyhow;
+use geojson::{Feature, FeatureCollection, Geometry, JsonObject, JsonValue, Value};
+use prost::{encoding::encoded_len_varint, Message};
+use serde::Serialize;
+
+//Message definitions follow version 2.1 of the Mapbox Vector Tile specification:
+//https://github.com/mapbox/vector-tile-spec/blob/master/2.1/vector_tile.proto
+#[derive(Clone, PartialEq, Message)]
+pub struct MVTTile {
+    #[prost(message, repeated, tag = "3")]
+    pub layers: Vec<MVTLayer>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct MVTValue {
+    #[prost(string, optional, tag = "1")]
+    pub string_value: Option<String>,
+    #[prost(float, optional, tag = "2")]
+    pub float_value: Option<f32>,
+    #[prost(double, optional, tag = "3")]
+    pub double_value: Option<f64>,
+    #[prost(int64, optional, tag = "4")]
+    pub int_value: Option<i64>,
+    #[prost(uint64, optional, tag = "5")]
+    pub uint_value: Option<u64>,
+    #[prost(sint64, optional, tag = "6")]
+    pub sint_value: Option<i64>,
+    #[prost(bool, optional, tag = "7")]
+    pub bool_value: Option<bool>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct MVTFeature {
+    #[prost(uint64, optional, tag = "1")]
+    pub id: Option<u64>,
+    #[prost(uint32, repeated, packed = "true", tag = "2")]
+    pub tags: Vec<u32>,
+    #[prost(enumeration = "MVTGeomType", optional, tag = "3")]
+    pub r#type: Option<i32>,
+    #[prost(uint32, repeated, packed = "true", tag = "4")]
+    pub geometry: Vec<u32>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct MVTLayer {
+    #[prost(uint32, required, tag = "15")]
+    pub version: u32,
+    #[prost(string, required, tag = "1")]
+    pub name: String,
+    #[prost(message, repeated, tag = "2")]
+    pub features: Vec<MVTFeature>,
+    #[prost(string, repeated, tag = "3")]
+    pub keys: Vec<String>,
+    #[prost(message, repeated, tag = "4")]
+    pub values: Vec<MVTValue>,
+    #[prost(uint32, optional, tag = "5")]
+    pub extent: Option<u32>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum MVTGeomType {
+    Unknown = 0,
+    Point = 1,
+    Linestring = 2,
+    Polygon = 3,
+}
+
+const DEFAULT_EXTENT: u32 = 4096;
+const MOVE_TO: u32 = 1;
+const LINE_TO: u32 = 2;
+const CLOSE_PATH: u32 = 7;
+
+#[derive(Serialize)]
+pub struct TileCoordinates {
+    pub z: usize,
+    pub x: usize,
+    pub y: usize,
+}
+
+#[derive(Serialize)]
+pub struct LayerByteStats {
+    pub total: usize,
+    pub features: usize,
+    pub geometry: usize,
+    pub keys: usize,
+    pub values: usize,
+}
+
+#[derive(Serialize)]
+pub struct LayerInspection {
+    pub name: String,
+    pub version: u32,
+    pub extent: u32,
+    pub feature_count: usize,
+    pub key_count: usize,
+    pub value_count: usize,
+    pub bytes: LayerByteStats,
+    pub features: FeatureCollection,
+}
+
+#[derive(Serialize)]
+pub struct TileInspection {
+    pub tile: TileCoordinates,
+    pub size_bytes: usize,
+    pub layer_count: usize,
+    pub layers: Vec<LayerInspection>,
+}
+
+/**
+Decodes an encoded vector tile into a per-layer summary, with each layer's features converted into a GeoJSON `FeatureCollection` in WGS84 coordinates.
+
+# Arguments
+
+* `t` - A reference to the `Tile` the buffer was generated for, used to georeference tile coordinates.
+* `buffer` - The raw MVT bytes, as returned by `get_mvt`.
+
+# Returns
+
+A `Result` containing the `TileInspection` if the buffer could be decoded, or an `AppError` if it is not a valid vector tile.
+*/
+pub fn inspect_mvt(t: &Tile, buffer: &[u8]) -> Result<TileInspection, AppError> {
+    let decoded = MVTTile::decode(buffer)
+        .map_err(|e| AppError(anyhow!("Failed to decode vector tile: {}", e)))?;
+
+    let layers = decoded
+        .layers
+        .iter()
+        .map(|layer| inspect_layer(t, layer))
+        .collect::<Vec<LayerInspection>>();
+
+    Ok(TileInspection {
+        tile: TileCoordinates {
+            z: t.z,
+            x: t.x,
+            y: t.y,
+        },
+        size_bytes: buffer.len(),
+        layer_count: layers.len(),
+        layers,
+    })
+}
+
+fn inspect_layer(t: &Tile, layer: &MVTLayer) -> LayerInspection {
+    let extent = layer.extent.unwrap_or(DEFAULT_EXTENT);
+
+    let features = layer
+        .features
+        .iter()
+        .map(|feature| decode_feature(t, extent, layer, feature))
+        .collect::<Vec<Feature>>();
+
+    let bytes = LayerByteStats {
+        total: layer.encoded_len(),
+        features: layer.features.iter().map(|f| f.encoded_len()).sum(),
+        geometry: layer
+            .features
+            .iter()
+            .flat_map(|f| f.geometry.iter())
+            .map(|c| encoded_len_varint(*c as u64))
+            .sum(),
+        keys: layer.keys.iter().map(|k| k.len()).sum(),
+        values: layer.values.iter().map(|v| v.encoded_len()).sum(),
+    };
+
+    LayerInspection {
+        name: layer.name.clone(),
+        version: layer.version,
+        extent,
+        feature_count: layer.features.len(),
+        key_count: layer.keys.len(),
+        value_count: layer.values.len(),
+        bytes,
+        features: FeatureCollection {
+            bbox: None,
+            features,
+            foreign_members: None,
+        },
+    }
+}
+
+fn decode_feature(t: &Tile, extent: u32, layer: &MVTLayer, feature: &MVTFeature) -> Feature {
+    let mut properties = JsonObject::new();
+    for pair in feature.tags.chunks(2) {
+        if let [key_idx, value_idx] = pair {
+            if let (Some(key), Some(value)) = (
+                layer.keys.get(*key_idx as usize),
+                layer.values.get(*value_idx as usize),
+            ) {
+                properties.insert(key.clone(), mvt_value_to_json(value));
+            }
+        }
+    }
+
+    let paths = decode_geometry_commands(&feature.geometry)
+        .into_iter()
+        .map(|path| {
+            path.into_iter()
+                .map(|(px, py)| tile_point_to_lon_lat(t, extent, px, py))
+                .collect::<Vec<Vec<f64>>>()
+        })
+        .collect::<Vec<Vec<Vec<f64>>>>();
+
+    let geometry = match MVTGeomType::try_from(feature.r#type.unwrap_or_default()) {
+        Ok(MVTGeomType::Point) => {
+            let mut points = paths.into_iter().flatten().collect::<Vec<Vec<f64>>>();
+            if points.len() == 1 {
+                points.pop().map(Value::Point)
+            } else {
+                Some(Value::MultiPoint(points))
+            }
+        }
+        Ok(MVTGeomType::Linestring) => {
+            let mut lines = paths;
+            if lines.len() == 1 {
+                lines.pop().map(Value::LineString)
+            } else {
+                Some(Value::MultiLineString(lines))
+            }
+        }
+        Ok(MVTGeomType::Polygon) => {
+            let mut polygons = group_rings_into_polygons(&feature.geometry, paths);
+            if polygons.len() == 1 {
+                polygons.pop().map(Value::Polygon)
+            } else {
+                Some(Value::MultiPolygon(polygons))
+            }
+        }
+        _ => None,
+    };
+
+    Feature {
+        bbox: None,
+        geometry: geometry.map(Geometry::new),
+        id: feature
+            .id
+            .map(|id| geojson::feature::Id::Number(serde_json::Number::from(id))),
+        properties: Some(properties),
+        foreign_members: None,
+    }
+}
+
+fn mvt_value_to_json(v: &MVTValue) -> JsonValue {
+    if let Some(s) = &v.string_value {
+        JsonValue::from(s.clone())
+    } else if let Some(f) = v.float_value {
+        JsonValue::from(f)
+    } else if let Some(d) = v.double_value {
+        JsonValue::from(d)
+    } else if let Some(i) = v.int_value {
+        JsonValue::from(i)
+    } else if let Some(u) = v.uint_value {
+        JsonValue::from(u)
+    } else if let Some(s) = v.sint_value {
+        JsonValue::from(s)
+    } else if let Some(b) = v.bool_value {
+        JsonValue::from(b)
+    } else {
+        JsonValue::Null
+    }
+}
+
+/**
+Walks an MVT geometry command stream, returning each path (a `MoveTo` followed by any `LineTo`s) in tile coordinates. Closed rings repeat their first vertex, as GeoJSON expects.
+*/
+fn decode_geometry_commands(commands: &[u32]) -> Vec<Vec<(i64, i64)>> {
+    let mut paths: Vec<Vec<(i64, i64)>> = Vec::new();
+    let mut cursor = (0_i64, 0_i64);
+    let mut i = 0;
+
+    while i < commands.len() {
+        let command_id = commands[i] & 0x7;
+        let count = (commands[i] >> 3) as usize;
+        i += 1;
+
+        match command_id {
+            MOVE_TO | LINE_TO => {
+                for _ in 0..count {
+                    if i + 1 >= commands.len() {
+                        return paths;
+                    }
+                    cursor.0 += zigzag_decode(commands[i]);
+                    cursor.1 += zigzag_decode(commands[i + 1]);
+                    i += 2;
+
+                    if command_id == MOVE_TO {
+                        paths.push(vec![cursor]);
+                    } else if let Some(path) = paths.last_mut() {
+                        path.push(cursor);
+                    }
+                }
+            }
+            CLOSE_PATH => {
+                if let Some(path) = paths.last_mut() {
+                    if let Some(first) = path.first().copied() {
+                        path.push(first);
+                    }
+                }
+            }
+            _ => return paths,
+        }
+    }
+
+    paths
+}
+
+/**
+Groups decoded rings into polygons. Per the specification, an exterior ring has a positive area under the surveyor's formula in tile coordinates and is followed by its interior rings.
+*/
+fn group_rings_into_polygons(
+    commands: &[u32],
+    rings: Vec<Vec<Vec<f64>>>,
+) -> Vec<Vec<Vec<Vec<f64>>>> {
+    let tile_rings = decode_geometry_commands(commands);
+    let mut polygons: Vec<Vec<Vec<Vec<f64>>>> = Vec::new();
+
+    for (tile_ring, ring) in tile_rings.iter().zip(rings) {
+        let area: i64 = tile_ring
+            .iter()
+            .zip(tile_ring.iter().skip(1))
+            .map(|(a, b)| a.0 * b.1 - b.0 * a.1)
+            .sum();
+
+        match polygons.last_mut() {
+            Some(polygon) if area < 0 => polygon.push(ring),
+            _ => polygons.push(vec![ring]),
+        }
+    }
+
+    polygons
+}
+
+fn zigzag_decode(n: u32) -> i64 {
+    ((n >> 1) as i64) ^ -((n & 1) as i64)
+}
+
+fn tile_point_to_lon_lat(t: &Tile, extent: u32, px: i64, py: i64) -> Vec<f64> {
+    let tile_span = (2.0 * WORLD_MERC_MAX) / 2_f64.powi(t.z as i32);
+    let merc_x = -WORLD_MERC_MAX + (t.x as f64 + px as f64 / extent as f64) * tile_span;
+    let merc_y = WORLD_MERC_MAX - (t.y as f64 + py as f64 / extent as f64) * tile_span;
+    let (lon, lat) = web_mercator_to_lon_lat(merc_x, merc_y);
+    vec![lon, lat]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //Geometries from the examples in section 4.3.5 of the specification
+    const POINT: [u32; 3] = [9, 50, 34];
+    const LINESTRING: [u32; 8] = [9, 4, 4, 18, 0, 16, 16, 0];
+    const MULTIPOLYGON: [u32; 33] = [
+        9, 0, 0, 26, 20, 0, 0, 20, 19, 0, 15, 9, 22, 2, 26, 18, 0, 0, 18, 17, 0, 15, 9, 4, 13, 26,
+        0, 8, 8, 0, 0, 7, 15,
+    ];
+
+    fn feature(id: u64, geom_type: MVTGeomType, tags: Vec<u32>, geometry: &[u32]) -> MVTFeature {
+        MVTFeature {
+            id: Some(id),
+            tags,
+            r#type: Some(geom_type as i32),
+            geometry: geometry.to_vec(),
+        }
+    }
+
+    fn known_tile() -> Vec<u8> {
+        MVTTile {
+            layers: vec![MVTLayer {
+                version: 2,
+                name: "roads".to_string(),
+                features: vec![
+                    feature(1, MVTGeomType::Point, vec![0, 0], &POINT),
+                    //The last pair points past the keys, and the trailing tag has no value
+                    feature(
+                        2,
+                        MVTGeomType::Linestring,
+                        vec![0, 1, 1, 2, 5, 0, 1],
+                        &LINESTRING,
+                    ),
+                    feature(3, MVTGeomType::Polygon, vec![], &MULTIPOLYGON),
+                ],
+                keys: vec!["name".to_string(), "lanes".to_string()],
+                values: vec![
+                    MVTValue {
+                        string_value: Some("Main Street".to_string()),
+                        ..Default::default()
+                    },
+                    MVTValue {
+                        string_value: Some("High Street".to_string()),
+                        ..Default::default()
+                    },
+                    MVTValue {
+                        uint_value: Some(2),
+                        ..Default::default()
+                    },
+                ],
+                extent: Some(4096),
+            }],
+        }
+        .encode_to_vec()
+    }
+
+    #[test]
+    fn zigzag_decoding_alternates_signs() {
+        let decoded = [0, 1, 2, 3, 4, u32::MAX]
+            .into_iter()
+            .map(zigzag_decode)
+            .collect::<Vec<i64>>();
+        assert_eq!(decoded, vec![0, -1, 1, -2, 2, -2147483648]);
+    }
+
+    #[test]
+    fn commands_move_draw_and_close_paths() {
+        assert_eq!(decode_geometry_commands(&POINT), vec![vec![(25, 17)]]);
+        assert_eq!(
+            decode_geometry_commands(&LINESTRING),
+            vec![vec![(2, 2), (2, 10), (10, 10)]]
+        );
+        //A single MoveTo with a count of 2 starts two paths, each relative to the last
+        assert_eq!(
+            decode_geometry_commands(&[17, 10, 14, 3, 9]),
+            vec![vec![(5, 7)], vec![(3, 2)]]
+        );
+        assert_eq!(
+            decode_geometry_commands(&[9, 6, 12, 18, 10, 12, 24, 44, 15]),
+            vec![vec![(3, 6), (8, 12), (20, 34), (3, 6)]]
+        );
+        //A truncated parameter list or an unknown command ends the stream
+        assert_eq!(
+            decode_geometry_commands(&[9, 4, 4, 18, 0]),
+            vec![vec![(2, 2)]]
+        );
+        assert_eq!(
+            decode_geometry_commands(&[9, 4, 4, 4, 18, 0]),
+            vec![vec![(2, 2)]]
+        );
+    }
+
+    #[test]
+    fn rings_are_grouped_by_winding_order() {
+        let rings = decode_geometry_commands(&MULTIPOLYGON)
+            .into_iter()
+            .map(|ring| {
+                ring.into_iter()
+                    .map(|(x, y)| vec![x as f64, y as f64])
+                    .collect::<Vec<Vec<f64>>>()
+            })
+            .collect::<Vec<Vec<Vec<f64>>>>();
+
+        let polygons = group_rings_into_polygons(&MULTIPOLYGON, rings);
+        assert_eq!(polygons.len(), 2);
+        assert_eq!(polygons[0].len(), 1);
+        assert_eq!(polygons[1].len(), 2);
+        assert_eq!(polygons[0][0][1], vec![10.0, 0.0]);
+        assert_eq!(polygons[1][1][0], vec![13.0, 13.0]);
+        assert_eq!(polygons[1][1].len(), 5);
+    }
+
+    #[test]
+    fn known_tile_decodes_to_geojson() {
+        let buffer = known_tile();
+        let inspection = inspect_mvt(&Tile::new(0, 0, 0), &buffer).unwrap();
+        assert_eq!(inspection.size_bytes, buffer.len());
+        assert_eq!(inspection.layer_count, 1);
+
+        let layer = &inspection.layers[0];
+        assert_eq!(layer.name, "roads");
+        assert_eq!((layer.version, layer.extent), (2, 4096));
+        assert_eq!(
+            (layer.feature_count, layer.key_count, layer.value_count),
+            (3, 2, 3)
+        );
+        //The tile holds just the layer, after its field tag and a two-byte length
+        assert_eq!(layer.bytes.total, buffer.len() - 3);
+        assert_eq!(layer.bytes.keys, "name".len() + "lanes".len());
+
+        let features = &layer.features.features;
+        let properties = |i: usize| JsonValue::from(features[i].properties.clone().unwrap());
+        assert_eq!(properties(0), serde_json::json!({"name": "Main Street"}));
+        assert_eq!(
+            properties(1),
+            serde_json::json!({"name": "High Street", "lanes": 2})
+        );
+        assert_eq!(properties(2), serde_json::json!({}));
+
+        match features[0].geometry.as_ref().map(|g| &g.value) {
+            Some(Value::Point(point)) => {
+                assert_eq!(
+                    point,
+                    &tile_point_to_lon_lat(&Tile::new(0, 0, 0), 4096, 25, 17)
+                )
+            }
+            other => panic!("Expected a point, got {:?}", other),
+        }
+        assert!(matches!(
+            features[1].geometry.as_ref().map(|g| &g.value),
+            Some(Value::LineString(line)) if line.len() == 3
+        ));
+        assert!(matches!(
+            features[2].geometry.as_ref().map(|g| &g.value),
+            Some(Value::MultiPolygon(polygons)) if polygons.len() == 2 && polygons[1].len() == 2
+        ));
+    }
+
+    #[test]
+    fn tile_points_are_georeferenced() {
+        let centre = tile_point_to_lon_lat(&Tile::new(0, 0, 0), 4096, 2048, 2048);
+        assert!(centre[0].abs() < 1e-9 && centre[1].abs() < 1e-9);
+
+        //The top left corner of the second tile across at zoom 1 is on the prime meridian
+        let corner = tile_point_to_lon_lat(&Tile::new(1, 0, 1), 4096, 0, 0);
+        assert!(corner[0].abs() < 1e-9);
+        assert!((corner[1] - 85.0511287798).abs() < 1e-6);
+    }
+
+    #[test]
+    fn invalid_buffers_fail_to_decode() {
+        assert!(inspect_mvt(&Tile::new(0, 0, 0), &[0x1a, 0x05, 0x0a]).is_err());
+    }
+}
@@ -17,7 +17,13 @@ impl Tile {
     }
 }
 
-pub struct MVTBuffer(Vec<u8>);
+pub struct MVTBuffer(pub(crate) Vec<u8>);
+
+impl MVTBuffer {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
 
 impl IntoResponse for MVTBuffer {
     fn into_response(self) -> axum::response::Response {
@@ -30,11 +36,11 @@ impl IntoResponse for MVTBuffer {
     }
 }
 
-const WORLD_MERC_MAX: f64 = 20037508.3427892;
-const WORLD_MERC_MIN: f64 = WORLD_MERC_MAX * -1_f64;
-const INCOMING_SRID: usize = 3857;
-const EXTENT: f32 = 4096.0;
-const BUFFER: f32 = 64.0;
+pub(super) const WORLD_MERC_MAX: f64 = 20037508.3427892;
+const WORLD_MERC_MIN: f64 = -WORLD_MERC_MAX;
+pub(super) const INCOMING_SRID: usize = 3857;
+pub(crate) const EXTENT: f32 = 4096.0;
+pub(crate) const BUFFER: f32 = 64.0;
 
 /**
 Creates an envelope statement for a given `Tile` object and an optional margin value.
@@ -46,22 +52,22 @@ Creates an envelope statement for a given `Tile` object and an optional margin v
 
 # Example
 
-```
+```ignore
 let tile = Tile::new(1, 2, 3);
 let margin = Some(0.5);
 let envelope_statement = make_envelope_statement(&tile, margin);
 println!("{}", envelope_statement);
 ```
 Expected output:
-"ST_TileEnvelope(3, 1, 2, -20037508.3427892, -20037508.3427892, 20037508.3427892, 20037508.3427892, 3857, 0.5)"
+"ST_TileEnvelope(3, 1, 2, ST_MakeEnvelope(-20037508.3427892, -20037508.3427892, 20037508.3427892, 20037508.3427892, 3857), 0.5)"
 
 # Returns
 
 A formatted string representing the envelope statement for the given `Tile` object and margin value.
 */
-fn make_envelope_statement(t: &Tile, m: Option<f32>) -> String {
+pub(crate) fn make_envelope_statement(t: &Tile, m: Option<f32>) -> String {
     let margin_text = if let Some(margin) = m {
-        format!(", {}", margin.to_string())
+        format!(", {}", margin)
     } else {
         "".to_string()
     };
@@ -79,6 +85,26 @@ fn make_envelope_statement(t: &Tile, m: Option<f32>) -> String {
         margin_text
     )
 }
+/**
+Returns an error if the tile's x or y coordinate falls outside the range valid for its zoom level.
+*/
+pub(crate) fn check_tile_coordinates(t: &Tile) -> Result<(), AppError> {
+    let tile_size = 2_usize.pow(t.z as u32);
+    if (t.x >= tile_size) | (t.y >= tile_size) {
+        return Err(AppError(anyhow!("Invalid tile coordinates")));
+    };
+    Ok(())
+}
+
+/**
+Lists the non-geometry columns published for a table: its primary key columns, followed by any attribute columns.
+*/
+pub(super) fn get_layer_columns(tab: &Table) -> Vec<String> {
+    let mut columns = tab.primary_key_columns.clone();
+    columns.extend(tab.attr_columns.clone().unwrap_or_default());
+    columns
+}
+
 /**
 Generates a SQL query string for retrieving map tile data from a database table based on a given `Tile` object and table information.
 
@@ -89,34 +115,29 @@ Generates a SQL query string for retrieving map tile data from a database table
 
 # Returns
 
-A `Result` object containing the SQL query string if successful, or a `TileDataQueryError` if there is an invalid tile coordinate or no geometry column found in the table.
+A `Result` object containing the SQL query string if successful, or an `AppError` if there is an invalid tile coordinate or no geometry column found in the table.
 
 # Example
 
-```
-use crate::{Tile, Table, TileDataQueryError};
+```ignore
+use crate::{db::Table, AppError};
 
 let tile = Tile::new(1, 2, 3);
 let table = Table { ... };
 let query = make_tile_data_query(&tile, &table)?;
 println!("{}", query);
-# Ok::<(), TileDataQueryError>(())
+# Ok::<(), AppError>(())
 ```
 */
 pub fn make_tile_data_query(t: &Tile, tab: &Table) -> Result<String, AppError> {
-    let tile_size = 2_usize.pow(t.z as u32);
-    if (t.x >= tile_size) | (t.y >= tile_size) {
-        return Err(AppError(anyhow!("Invalid tile coordinates")));
-    };
+    check_tile_coordinates(t)?;
 
     if let Some(geom_col) = &tab.geom_column {
         let envelope = make_envelope_statement(t, None);
 
         let envelope_with_margin = make_envelope_statement(t, Some(BUFFER / EXTENT));
 
-        let mut id_columns = tab.primary_key_columns.clone();
-        let attr_columns = tab.attr_columns.clone().unwrap_or_default();
-        id_columns.extend(attr_columns);
+        let id_columns = get_layer_columns(tab);
 
         Ok(format!(
             "with mvtgeom as (
@@ -151,9 +172,9 @@ pub fn make_tile_data_query(t: &Tile, tab: &Table) -> Result<String, AppError> {
             envelope_with_margin
         ))
     } else {
-        return Err(AppError(anyhow!(
+        Err(AppError(anyhow!(
             "No geometry column found in table. Unable to retrieve data."
-        )));
+        )))
     }
 }
 /**
@@ -161,7 +182,7 @@ Retrieves map tile data from a database table based on the given `Tile` object a
 
 # Example
 
-```rust
+```ignore
 let tile = Tile::new(1, 2, 3);
 let table = Table { ... };
 let conn = Pool::new(...).await?;
@@ -184,17 +205,27 @@ pub async fn get_mvt(
     conn: Pool<Postgres>,
 ) -> Result<MVTBuffer, AppError> {
     match make_tile_data_query(tile, table) {
-        Ok(mvt_query) => match query(&mvt_query).fetch_all(&conn).await {
-            Ok(mvt_result) => {
-                let mvt_bytes: Vec<u8> = mvt_result[0].get(0);
-                Ok(MVTBuffer(mvt_bytes))
-            }
-            Err(e) => Err(AppError(anyhow!(e))),
-        },
+        Ok(mvt_query) => run_mvt_query(&mvt_query, conn).await,
         Err(e) => Err(e),
     }
 }
 
+/**
+Executes a query whose single result column is an `ST_AsMVT` aggregate, wrapping the returned bytes in an `MVTBuffer`.
+*/
+pub(super) async fn run_mvt_query(
+    mvt_query: &str,
+    conn: Pool<Postgres>,
+) -> Result<MVTBuffer, AppError> {
+    match query(mvt_query).fetch_all(&conn).await {
+        Ok(mvt_result) => {
+            let mvt_bytes: Vec<u8> = mvt_result[0].get(0);
+            Ok(MVTBuffer(mvt_bytes))
+        }
+        Err(e) => Err(AppError(anyhow!(e))),
+    }
+}
+
 // #[cfg(test)]
 
 // mod tests {
@@ -267,3 +298,55 @@ pub async fn get_mvt(
 //         );
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roads() -> Table {
+        Table::new(
+            "roads".to_string(),
+            "public".to_string(),
+            vec!["id".to_string()],
+            "geom".to_string(),
+            "LINESTRING".to_string(),
+            3857,
+            Some(vec!["name".to_string()]),
+            "m".to_string(),
+            false,
+        )
+    }
+
+    #[test]
+    fn envelope_statement_appends_margin() {
+        let tile = Tile::new(1, 2, 3);
+        assert_eq!(
+            make_envelope_statement(&tile, Some(0.5)),
+            "ST_TileEnvelope(3, 1, 2, ST_MakeEnvelope(-20037508.3427892, -20037508.3427892, 20037508.3427892, 20037508.3427892, 3857), 0.5)"
+        );
+        assert_eq!(
+            make_envelope_statement(&tile, None),
+            "ST_TileEnvelope(3, 1, 2, ST_MakeEnvelope(-20037508.3427892, -20037508.3427892, 20037508.3427892, 20037508.3427892, 3857))"
+        );
+    }
+
+    #[test]
+    fn tile_data_query_selects_layer_columns() {
+        let query = make_tile_data_query(&Tile::new(1, 2, 3), &roads()).unwrap();
+        assert!(query.contains("public.roads t"));
+        assert!(query.contains("id, name"));
+        assert!(query.contains(&make_envelope_statement(
+            &Tile::new(1, 2, 3),
+            Some(BUFFER / EXTENT)
+        )));
+    }
+
+    #[test]
+    fn tile_data_query_rejects_invalid_tiles_and_tables() {
+        assert!(make_tile_data_query(&Tile::new(8, 0, 3), &roads()).is_err());
+
+        let mut table = roads();
+        table.geom_column = None;
+        assert!(make_tile_data_query(&Tile::new(1, 2, 3), &table).is_err());
+    }
+}
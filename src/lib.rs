@@ -1,14 +1,19 @@
 include!(concat!(env!("OUT_DIR"), "/codegen.rs"));
 pub mod db;
+pub mod features;
 pub mod geocoding;
 pub mod layers;
 pub mod routing;
 
 use crate::db::TableRegistry;
+use crate::geocoding::Geocoder;
+use crate::layers::{ClusterConfig, TileCache};
+use crate::routing::{ElevationRaster, NetworkAnalyses, PreloadedNetworks, RoutingProfiles};
 use axum::response::{IntoResponse, Response};
 use reqwest::StatusCode;
 use sqlx::{Pool, Postgres};
 
+#[derive(Debug)]
 pub struct AppError(anyhow::Error);
 
 impl IntoResponse for AppError {
@@ -34,10 +39,59 @@ where
 pub struct AppState {
     pub db_pool: Pool<Postgres>,
     pub table_registry: TableRegistry,
+    pub cluster_config: ClusterConfig,
+    pub tile_cache: TileCache,
+    pub edit_token: Option<String>,
+    pub routing_profiles: RoutingProfiles,
+    pub elevation_raster: Option<ElevationRaster>,
+    pub routing_networks: PreloadedNetworks,
+    pub network_analyses: NetworkAnalyses,
+    pub geocoder: Geocoder,
 }
 
 pub fn get_srid_unit(srid: i32) -> Option<&'static str> {
     UNIT_BY_SRID.get(&srid).copied()
 }
 
-pub static MILES_TO_MINUTES_FACTOR: f64 = 0.05;
+/**
+Checks that a name from configuration or the command line is a lowercase SQL identifier, which can be written into a query unquoted.
+*/
+pub(crate) fn validate_identifier(name: &str) -> anyhow::Result<&str> {
+    let mut chars = name.chars();
+    let valid = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_lowercase() || c == '_')
+        && chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+    if valid {
+        Ok(name)
+    } else {
+        Err(anyhow::anyhow!(
+            "Invalid name {}: use lowercase letters, digits and underscores",
+            name
+        ))
+    }
+}
+
+static EARTH_RADIUS_METRES: f64 = 6378137.0;
+
+/**
+Converts a Web Mercator (EPSG:3857) coordinate into a WGS84 (EPSG:4326) longitude/latitude pair.
+*/
+pub fn web_mercator_to_lon_lat(x: f64, y: f64) -> (f64, f64) {
+    let lon = (x / EARTH_RADIUS_METRES).to_degrees();
+    let lat =
+        (2.0 * (y / EARTH_RADIUS_METRES).exp().atan() - std::f64::consts::FRAC_PI_2).to_degrees();
+    (lon, lat)
+}
+
+/**
+Converts a WGS84 (EPSG:4326) longitude/latitude pair into a Web Mercator (EPSG:3857) coordinate.
+*/
+pub fn lon_lat_to_web_mercator(lon: f64, lat: f64) -> (f64, f64) {
+    let x = lon.to_radians() * EARTH_RADIUS_METRES;
+    let y = (std::f64::consts::FRAC_PI_4 + lat.to_radians() / 2.0)
+        .tan()
+        .ln()
+        * EARTH_RADIUS_METRES;
+    (x, y)
+}
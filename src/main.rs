@@ -2,7 +2,7 @@ extern crate dotenv;
 extern crate dotenv_codegen;
 extern crate rusty_mvt;
 
-use std::time::Duration;
+use std::{env::var, time::Duration};
 
 use anyhow::{anyhow, Error};
 use axum::{
@@ -14,12 +14,23 @@ use axum::{
 
 use dotenv::dotenv;
 use hyper::{http::Request, Body};
-use reqwest::{header::CONTENT_TYPE, Method};
+use reqwest::{
+    header::{HeaderName, ACCEPT, AUTHORIZATION, CONTENT_TYPE},
+    Method,
+};
 use rusty_mvt::{
     db::{get_db_connector, load_table_registry, TableRegistry},
-    geocoding::get_latlong,
-    layers::get_layer,
-    routing::get_circuit,
+    features::{
+        create_item, delete_item, get_collection, get_collections, get_conformance, get_item,
+        get_items, get_landing_page, replace_item, update_item,
+    },
+    geocoding::{get_address, get_latlong, Geocoder},
+    layers::{get_layer, inspect_layer, ClusterConfig, TileCache},
+    routing::{
+        build_topology, get_circuit, get_diagnostics, get_diagnostics_tile, get_isochrone,
+        get_match, get_route, ElevationRaster, NetworkAnalyses, PreloadedNetworks, RoutingProfiles,
+        TopologyOptions,
+    },
     AppState,
 };
 
@@ -84,15 +95,80 @@ async fn main() -> Result<(), Error> {
         }
     }
 
+    //Maintenance commands run against the database and exit, rather than starting the server
+    let args = std::env::args().skip(1).collect::<Vec<String>>();
+    if args
+        .first()
+        .is_some_and(|command| command == "build-topology")
+    {
+        let options = TopologyOptions::from_args(&args[1..]).map_err(|e| {
+            anyhow!(
+                "{}\nUsage: rusty-mvt build-topology <schema>.<table> [--tolerance <metres>] [--output <table>] [--overwrite]",
+                e
+            )
+        })?;
+        let summary = build_topology(&db_pool, &table_registry, &options)
+            .await
+            .map_err(|e| anyhow!("Failed to build topology: {}", e))?;
+        println!("{}", serde_json::to_string_pretty(&summary)?);
+        return Ok(());
+    }
+
+    let cluster_config = ClusterConfig::from_env()
+        .map_err(|e| anyhow!("Failed to load clustering configuration: {}", e))?;
+
+    let tile_cache =
+        TileCache::from_env().map_err(|e| anyhow!("Failed to configure tile cache: {}", e))?;
+
+    let edit_token = var("EDIT_API_TOKEN").ok().filter(|token| !token.is_empty());
+
+    let routing_profiles = RoutingProfiles::from_env()
+        .map_err(|e| anyhow!("Failed to load routing profiles: {}", e))?;
+
+    let elevation_raster = ElevationRaster::from_env()
+        .map_err(|e| anyhow!("Failed to configure elevation raster: {}", e))?;
+
+    let routing_networks = PreloadedNetworks::from_env()
+        .map_err(|e| anyhow!("Failed to configure routing network preloading: {}", e))?;
+
+    let geocoder = Geocoder::from_env(&db_pool, &table_registry)
+        .map_err(|e| anyhow!("Failed to configure geocoder: {}", e))?;
+
     let state = AppState {
         db_pool,
         table_registry,
+        cluster_config,
+        tile_cache,
+        edit_token,
+        routing_profiles,
+        elevation_raster,
+        routing_networks,
+        network_analyses: NetworkAnalyses::default(),
+        geocoder,
     };
 
+    state
+        .routing_networks
+        .load_all(&state)
+        .await
+        .map_err(|e| anyhow!("Failed to preload routing networks: {}", e))?;
+    state.routing_networks.spawn_refresh(state.clone());
+
     let cors = CorsLayer::new()
-        .allow_methods([Method::GET, Method::POST])
+        .allow_methods([
+            Method::GET,
+            Method::POST,
+            Method::PUT,
+            Method::PATCH,
+            Method::DELETE,
+        ])
         .allow_origin(Any)
-        .allow_headers([CONTENT_TYPE]);
+        .allow_headers([
+            CONTENT_TYPE,
+            AUTHORIZATION,
+            ACCEPT,
+            HeaderName::from_static("content-crs"),
+        ]);
 
     let timeout = ServiceBuilder::new()
         // `timeout` will produce an error if the handler takes
@@ -102,8 +178,38 @@ async fn main() -> Result<(), Error> {
 
     let app = Router::new()
         .route("/geocode/:queryString", get(get_latlong))
+        .route("/reverse-geocode/:lat/:lon", get(get_address))
         .route("/layers/:schemaid/:tableid/:z/:x/:y_ext", get(get_layer))
+        .route(
+            "/layers/:schemaid/:tableid/:z/:x/:y_ext/inspect",
+            get(inspect_layer),
+        )
         .route("/circuit/:schemaid/:tableid/", post(get_circuit))
+        .route("/circuit/:schemaid/:tableid/:format", post(get_circuit))
+        .route("/route/:schemaid/:tableid/", post(get_route))
+        .route("/route/:schemaid/:tableid/:format", post(get_route))
+        .route("/isochrone/:schemaid/:tableid/", post(get_isochrone))
+        .route("/match/:schemaid/:tableid/", post(get_match))
+        .route("/diagnostics/:schemaid/:tableid", get(get_diagnostics))
+        .route(
+            "/diagnostics/:schemaid/:tableid/:z/:x/:y_ext",
+            get(get_diagnostics_tile),
+        )
+        .route("/ogc", get(get_landing_page))
+        .route("/ogc/conformance", get(get_conformance))
+        .route("/ogc/collections", get(get_collections))
+        .route("/ogc/collections/:collectionid", get(get_collection))
+        .route(
+            "/ogc/collections/:collectionid/items",
+            get(get_items).post(create_item),
+        )
+        .route(
+            "/ogc/collections/:collectionid/items/:featureid",
+            get(get_item)
+                .put(replace_item)
+                .patch(update_item)
+                .delete(delete_item),
+        )
         .layer(timeout)
         .layer(trace_layer)
         .layer(cors)
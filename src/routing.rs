@@ -1,94 +1,244 @@
+mod alternatives;
+mod constraints;
+mod diagnostics;
+mod elevation;
+mod export;
+mod graph;
+mod matching;
+mod network;
+mod profiles;
+mod snapping;
+mod topology;
+mod turns;
+mod units;
 mod utils;
-use std::collections::VecDeque;
-
-use crate::{AppError, AppState, MILES_TO_MINUTES_FACTOR};
+use crate::db::{Table, TableRegistry};
+use crate::layers::{parse_y_ext, MVTBuffer, Tile};
+use crate::{AppError, AppState};
+use alternatives::{count_edge_uses, penalize_used_edges, rank_alternatives};
 use anyhow::anyhow;
 use axum::{
     extract::{Path, Query, State},
+    http::{header::CONTENT_TYPE, HeaderMap},
+    response::{IntoResponse, Response},
     Json,
 };
 use axum_macros::debug_handler;
+use constraints::RoutingConstraints;
+pub use diagnostics::NetworkAnalyses;
+pub use elevation::ElevationRaster;
+use elevation::ElevationSource;
+use export::{render_routes, RouteFormat};
 use geo_types::Point;
-use geojson::de::{deserialize_geometry, deserialize_single_feature};
-use serde::Deserialize;
+use geojson::de::{
+    deserialize_feature_collection_str_to_vec, deserialize_geometry, deserialize_single_feature,
+};
+use graph::{reachable_portions, GraphPath, RoutingGraph};
+use matching::{
+    get_match_candidates, match_track, read_track, segment_path, thin_track, MatchModel,
+    MAX_MATCH_POINTS,
+};
+pub use network::PreloadedNetworks;
+use network::{ground_distance, PreloadedNetwork};
+use profiles::RoutingProfile;
+pub use profiles::RoutingProfiles;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use snapping::{snap_to_network, split_edges, EdgeSplit, SnapMode, SnappedPoint, VIRTUAL_NODE_ID};
 use speedicycle::make_route_from_edges_json;
-use unit_conversions::length::miles::{to_feet, to_metres};
+pub use topology::{build_topology, TopologyOptions, TopologySummary};
 use utils::{
-    get_edge_to_vertex_pair_mapper, get_path_geometries, get_proximal_features,
-    process_routing_result_as_edge_list,
+    get_features_along_line, get_features_along_waypoints, get_ordered_path_geometry,
+    get_proximal_features, get_reachable_geometries, retain_bidirectional_edges, summarize_path,
 };
 
-use self::utils::try_convert_to_edge_json;
+use self::utils::{try_convert_to_edge_json, TopoEdgeRepresentation};
+use std::collections::{HashMap, HashSet};
+use std::iter::once;
 use tokio::task::spawn;
+use turns::{get_turn_rules, TurnRules};
+use units::{
+    metres_to_table_distance, to_table_distance, ResultUnits, RoutingUnit, DEFAULT_SPEED_KMH,
+};
+
+/**
+Path parameters for routing endpoints that return routes. The optional `format` segment (`geojson`, `gpx` or `kml`) selects the output format.
+*/
+#[derive(Deserialize)]
+pub struct RoutingPath {
+    schemaid: String,
+    tableid: String,
+    format: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitMode {
+    #[default]
+    Loop,
+    OutAndBack,
+    PointToPoint,
+}
 
 #[derive(Deserialize)]
 pub struct RoutingOptions {
     dist: f64,
-    units: String,
+    units: RoutingUnit,
+    speed: Option<f64>,
+    #[serde(default)]
+    mode: CircuitMode,
+    alternatives: Option<usize>,
+    #[serde(default)]
+    snap: SnapMode,
+    profile: Option<String>,
+}
+
+const MAX_CIRCUIT_ALTERNATIVES: usize = 5;
+//Searches run beyond the number of alternatives requested, to leave room for choosing among them
+const ALTERNATIVE_EXTRA_ROUNDS: usize = 2;
+
+//Corridor widths, relative to the straight line through the waypoints, searched in turn for a route
+const ROUTE_SEARCH_BUFFER_FACTORS: [f64; 2] = [0.5, 2.0];
+
+#[derive(Deserialize)]
+pub struct RouteOptions {
+    #[serde(default)]
+    snap: SnapMode,
+    profile: Option<String>,
+    units: Option<RoutingUnit>,
+    speed: Option<f64>,
+}
+
+const MAX_ISOCHRONE_BANDS: usize = 10;
+const DEFAULT_HULL_CONCAVITY: f64 = 0.3;
+
+#[derive(Deserialize)]
+pub struct IsochroneOptions {
+    bands: String,
+    units: RoutingUnit,
+    speed: Option<f64>,
+    #[serde(default)]
+    snap: SnapMode,
+    concavity: Option<f64>,
+    profile: Option<String>,
+}
+
+//Defaults for map matching, in metres: the standard deviation of GPS error, the radius searched for
+//candidate edges around each point and the scale of differences between route and straight-line
+//distances between consecutive points
+const DEFAULT_MATCH_ACCURACY: f64 = 10.0;
+const DEFAULT_MATCH_RADIUS: f64 = 50.0;
+const DEFAULT_MATCH_TRANSITION_SCALE: f64 = 20.0;
+
+#[derive(Deserialize)]
+pub struct MatchOptions {
+    accuracy: Option<f64>,
+    radius: Option<f64>,
+    profile: Option<String>,
+    units: Option<RoutingUnit>,
+    speed: Option<f64>,
+}
+
+#[derive(Deserialize)]
+pub struct DiagnosticsOptions {
+    format: Option<String>,
+    x: Option<f64>,
+    y: Option<f64>,
+    node_id: Option<u32>,
 }
 
 #[derive(Deserialize)]
 pub struct StartingGeom {
     #[serde(deserialize_with = "deserialize_geometry")]
     geometry: Point<f64>,
-    node_id: u32,
+    #[serde(default)]
+    node_id: Option<u32>,
+    #[serde(default)]
+    constraints: RoutingConstraints,
+}
+
+fn find_table<'a>(
+    registry: &'a TableRegistry,
+    schemaid: &str,
+    tableid: &str,
+) -> Result<&'a Table, AppError> {
+    match registry.schemas.get(schemaid) {
+        Some(schema) => schema
+            .tables
+            .get(tableid)
+            .ok_or_else(|| AppError(anyhow!("Failed to locate specified table"))),
+        None => Err(AppError(anyhow!("Failed to locate specified schema"))),
+    }
 }
 
 #[debug_handler]
+/**
+Handles a POST request for routes of a given length, in one of three modes:
+
+* `loop` (the default) - Circuits returning to the starting point.
+* `out_and_back` - Routes out to a turning point and back along the same edges.
+* `point_to_point` - Routes from the starting point to a destination, stretched to the requested length by passing through a via point.
+
+The request body is a single GeoJSON feature for the starting point, or, in `point_to_point` mode, a `FeatureCollection` of the starting point followed by the destination. Each point is used as-is when the feature carries a `node_id` property. Otherwise it is snapped to the network, either to the nearest vertex or, with `snap=edge`, to the nearest point along an edge.
+
+The target `dist` is given in `units` of `km`, `m`, `ft`, `mi` or, as a time, `min` or `h`. Times are converted to distances at the request's `speed` in kilometres per hour, or else the profile's speed, or else 3 mph. Results are reported in the requested unit, with the other quantity in miles or minutes.
+
+Edges can be avoided through the starting feature's `constraints` property, as described by `RoutingConstraints`.
+
+With `alternatives=N`, up to N distinct routes are returned, found by repeating the search with previously used edges penalised. They are ranked by their deviation from the target, their overlap with better-ranked routes and the share of their length spent retracing edges, and each carries its scores in an `alternative` property.
+
+When the table's network has been preloaded and neither a profile nor constraints are requested, snapping, subgraph selection and path geometries all use the in-memory network rather than the database.
+
+# Returns
+
+Returns a result that either contains a GeoJSON `FeatureCollection` with a single, oriented line for each route found, along with its distance, duration, edge IDs and deviation from the requested target, plus the snapped start (and end) locations and snap distances, or an `AppError` if there was an error during the process. GPX or KML is returned instead when requested through the path or the `Accept` header.
+*/
 pub async fn get_circuit(
     State(state): State<AppState>,
-    Path((schemaid, tableid)): Path<(String, String)>,
+    Path(RoutingPath {
+        schemaid,
+        tableid,
+        format,
+    }): Path<RoutingPath>,
     options: Query<RoutingOptions>,
+    headers: HeaderMap,
     Json(raw_starting_geom): Json<String>, //Json(starting_geom): Json<StartingGeom>,
-) -> Result<Json<VecDeque<String>>, AppError> {
+) -> Result<Response, AppError> {
     let options = options.0;
-    if options.units != String::from("mins") && options.units != String::from("miles") {
-        return Err(AppError(anyhow!("Missing or invalid distance units")));
-    } else if options.dist <= 0.0 {
-        return Err(AppError(anyhow!("Missing or invalid distance units")));
+    let format = match RouteFormat::from_request(format.as_deref(), &headers) {
+        Ok(format) => format,
+        Err(rejection) => return Ok(rejection.into_response()),
+    };
+    if options.dist <= 0.0 {
+        return Err(AppError(anyhow!("Missing or invalid distance")));
     }
 
-    println!("{}", &raw_starting_geom);
-    let starting_geom: StartingGeom;
-
-    match deserialize_single_feature::<StartingGeom>(raw_starting_geom.as_bytes()) {
-        Ok(geom) => {
-            starting_geom = geom;
-        }
-        Err(e) => {
-            return Err(AppError(anyhow!(e)));
+    tracing::debug!("Circuit requested from {}", raw_starting_geom);
+    let (starting_geom, ending_geom) = match options.mode {
+        CircuitMode::PointToPoint => {
+            let mut points =
+                deserialize_feature_collection_str_to_vec::<StartingGeom>(&raw_starting_geom)
+                    .map_err(|e| AppError(anyhow!(e)))?;
+            if points.len() != 2 {
+                return Err(AppError(anyhow!(
+                    "Point-to-point routes require a start and a destination"
+                )));
+            }
+            let ending_geom = points.pop();
+            (points.remove(0), ending_geom)
         }
+        _ => match deserialize_single_feature::<StartingGeom>(raw_starting_geom.as_bytes()) {
+            Ok(geom) => (geom, None),
+            Err(e) => {
+                return Err(AppError(anyhow!(e)));
+            }
+        },
     };
 
-    let table_spec;
-    if let Some(schema) = state.table_registry.schemas.get(&schemaid) {
-        if let Some(table) = schema.tables.get(&tableid) {
-            table_spec = table;
-        } else {
-            return Err(AppError(anyhow!("Failed to locate specified table")));
-        }
-    } else {
-        return Err(AppError(anyhow!("Failed to locate specified schema")));
-    }
-
-    let mut desired_distance: f64;
-    println!("{:?}", &table_spec);
-
-    match &table_spec.dist_unit {
-        Some(unit) => {
-            desired_distance = {
-                if *unit == String::from("m") {
-                    to_metres(options.dist)
-                } else if *unit == String::from("us-ft") {
-                    to_feet(options.dist)
-                } else if *unit == String::from("deg") {
-                    to_metres(options.dist)
-                } else {
-                    options.dist
-                }
-            }
-        }
+    let table_spec = find_table(&state.table_registry, &schemaid, &tableid)?;
 
+    let dist_unit = match &table_spec.dist_unit {
+        Some(unit) => unit,
         None => {
             return Err(AppError(anyhow!(
                 "Specified table does not contain valid distance data"
@@ -96,62 +246,834 @@ pub async fn get_circuit(
         }
     };
 
-    if options.units == String::from("mins") {
-        desired_distance *= MILES_TO_MINUTES_FACTOR;
+    let profile = state
+        .routing_profiles
+        .get(table_spec, options.profile.as_deref())?;
+    let units = ResultUnits::new(
+        Some(options.units),
+        options
+            .speed
+            .or(profile.speed())
+            .unwrap_or(DEFAULT_SPEED_KMH),
+    )?;
+    let desired_distance =
+        to_table_distance(options.dist, options.units, units.speed_kmh, dist_unit)?;
+
+    //Preloaded networks are weighted by the default profile without constraints, so other
+    //requests use the database
+    let network = match (&options.profile, starting_geom.constraints.is_empty()) {
+        (None, true) => state.routing_networks.get(table_spec),
+        _ => None,
     };
 
-    let proximal_features =
-        get_proximal_features(&state.db_pool, table_spec, &starting_geom, desired_distance).await;
-
-    match proximal_features {
-        Ok(rows) => {
-            let edge_to_vertex_mapper = get_edge_to_vertex_pair_mapper(&rows);
-
-            match try_convert_to_edge_json(&rows) {
-                Ok(json_str) => {
-                    let routing_task = spawn(async move {
-                        make_route_from_edges_json::<u32, f64, u32>(
-                            json_str,
-                            starting_geom.node_id,
-                            desired_distance,
-                        )
-                    })
-                    .await?;
-                    match routing_task.map_err(|e| AppError(anyhow!(e))) {
-                        Ok(results) => {
-                            let mut valid_paths = VecDeque::new();
-                            let upper_edges = process_routing_result_as_edge_list(
-                                &results.upper,
-                                &edge_to_vertex_mapper,
-                            );
-                            let lower_edges = process_routing_result_as_edge_list(
-                                &results.lower,
-                                &edge_to_vertex_mapper,
-                            );
-                            if let Ok(path) =
-                                get_path_geometries(upper_edges, table_spec, &state.db_pool).await
-                            {
-                                valid_paths.push_back(path);
-                            }
-                            if let Ok(path) =
-                                get_path_geometries(lower_edges, table_spec, &state.db_pool).await
-                            {
-                                valid_paths.push_back(path);
-                            }
-                            return Ok(Json(valid_paths));
-                        }
-                        Err(e) => {
-                            return Err(e);
-                        }
-                    }
+    let start = snap_point(
+        &state,
+        table_spec,
+        network.as_deref(),
+        &starting_geom,
+        options.snap,
+        VIRTUAL_NODE_ID,
+    )
+    .await?;
+    let start_node = start.routing_node_id();
+    let end = match &ending_geom {
+        Some(ending_geom) => Some(
+            snap_point(
+                &state,
+                table_spec,
+                network.as_deref(),
+                ending_geom,
+                options.snap,
+                VIRTUAL_NODE_ID - 1,
+            )
+            .await?,
+        ),
+        None => None,
+    };
+
+    let elevation = ElevationSource::for_table(table_spec, state.elevation_raster.as_ref());
+    let mut rows = match (&network, &end, &ending_geom) {
+        //A destination further away than the target is still kept within reach
+        (Some(network), Some(end), _) => network.edges_between(&start, end, desired_distance),
+        (Some(network), None, _) => network.edges_around(&start, desired_distance),
+        //Every via point worth considering lies within half the target of the midpoint, and the
+        //corridor between the two points keeps a direct route when they are further apart
+        (None, _, Some(ending_geom)) => {
+            let midpoint = StartingGeom {
+                geometry: Point::new(
+                    (starting_geom.geometry.x() + ending_geom.geometry.x()) / 2.0,
+                    (starting_geom.geometry.y() + ending_geom.geometry.y()) / 2.0,
+                ),
+                node_id: None,
+                constraints: RoutingConstraints::default(),
+            };
+            let mut rows = get_proximal_features(
+                &state.db_pool,
+                table_spec,
+                &midpoint,
+                desired_distance / 2.0,
+                &profile,
+                &elevation,
+                &starting_geom.constraints,
+            )
+            .await?;
+            let corridor = get_features_along_waypoints(
+                &state.db_pool,
+                table_spec,
+                &[starting_geom.geometry, ending_geom.geometry],
+                ROUTE_SEARCH_BUFFER_FACTORS[0],
+                &profile,
+                &elevation,
+                &starting_geom.constraints,
+            )
+            .await?;
+            let seen = rows.iter().map(|e| e.edge_id).collect::<HashSet<u32>>();
+            rows.extend(corridor.into_iter().filter(|e| !seen.contains(&e.edge_id)));
+            rows
+        }
+        (None, _, None) => {
+            get_proximal_features(
+                &state.db_pool,
+                table_spec,
+                &starting_geom,
+                desired_distance,
+                &profile,
+                &elevation,
+                &starting_geom.constraints,
+            )
+            .await?
+        }
+    };
+    let splits = once(&start)
+        .chain(end.as_ref())
+        .filter_map(|point| point.split.as_ref())
+        .collect::<Vec<&EdgeSplit>>();
+    split_edges(&mut rows, &splits);
+    //Loops are found over an undirected graph, and out-and-backs retrace their steps
+    if options.mode != CircuitMode::PointToPoint {
+        retain_bidirectional_edges(&mut rows);
+    }
+    let turns = match &network {
+        Some(network) => network.turn_rules().clone(),
+        None => get_subgraph_turn_rules(&state, table_spec, &profile, &rows).await?,
+    };
+    let graph = RoutingGraph::new(&rows, &turns);
+    let end_node = end.as_ref().map(|end| end.routing_node_id());
+
+    let paths =
+        match options.alternatives {
+            None => find_paths(
+                options.mode,
+                &rows,
+                &turns,
+                start_node,
+                end_node,
+                desired_distance,
+            )
+            .await?
+            .into_iter()
+            .map(|(name, path)| (name, path, None))
+            .collect::<Vec<_>>(),
+            Some(count) => {
+                if count == 0 || count > MAX_CIRCUIT_ALTERNATIVES {
+                    return Err(AppError(anyhow!(
+                        "alternatives must be between 1 and {}",
+                        MAX_CIRCUIT_ALTERNATIVES
+                    )));
                 }
-                Err(e) => {
-                    return Err(e);
+
+                let mut uses = HashMap::new();
+                let mut candidates = Vec::new();
+                for round in 0..count + ALTERNATIVE_EXTRA_ROUNDS {
+                    let mut penalized = rows.clone();
+                    penalize_used_edges(&mut penalized, &uses);
+                    let found = match find_paths(
+                        options.mode,
+                        &penalized,
+                        &turns,
+                        start_node,
+                        end_node,
+                        desired_distance,
+                    )
+                    .await
+                    {
+                        Ok(found) => found,
+                        Err(e) if round == 0 => return Err(e),
+                        Err(_) => break,
+                    };
+                    count_edge_uses(&found, &mut uses);
+                    //Candidates are re-costed over the unpenalised graph
+                    candidates.extend(found.into_iter().filter_map(|(name, path)| {
+                        Some((name, graph.path_from_nodes(&path.nodes)?))
+                    }));
                 }
+
+                rank_alternatives(candidates, count, desired_distance)?
+                    .into_iter()
+                    .map(|(name, path, score)| (name, path, Some(score)))
+                    .collect()
+            }
+        };
+    if paths.is_empty() {
+        return Err(AppError(anyhow!(
+            "Failed to find a route of the requested length"
+        )));
+    }
+
+    let mut features = Vec::with_capacity(paths.len());
+    for (name, path, score) in paths {
+        let geometry = match &network {
+            Some(network) => network.path_geometry(&path.edges)?,
+            None => {
+                get_ordered_path_geometry(&path.edges, table_spec, &elevation, &state.db_pool)
+                    .await?
             }
+        };
+        let summary = summarize_path(&path, &geometry, dist_unit, &units)?;
+
+        let achieved = if options.units.is_time() {
+            summary.duration
+        } else {
+            summary.distance
+        };
+        let deviation = achieved - options.dist;
+
+        let mut properties = json!(summary);
+        properties["path"] = json!(name);
+        properties["mode"] = json!(options.mode);
+        properties["target"] = json!(options.dist);
+        properties["target_units"] = json!(options.units);
+        properties["deviation"] = json!(deviation);
+        properties["deviation_ratio"] = json!(deviation / options.dist);
+        if let Some(score) = score {
+            properties["alternative"] = json!(score);
         }
-        Err(e) => {
-            return Err(e);
+
+        features.push(json!({
+            "type": "Feature",
+            "geometry": geometry.geoms,
+            "properties": properties,
+        }));
+    }
+
+    let mut collection = json!({
+        "type": "FeatureCollection",
+        "features": features,
+        "units": { "distance": units.distance, "duration": units.duration, "speed": units.speed_kmh },
+        "start": start,
+    });
+    if let Some(end) = end {
+        collection["end"] = json!(end);
+    }
+
+    Ok(render_routes(collection, format))
+}
+
+/**
+Finds routes over a subgraph in the given mode, as named paths. Loops are found by speedicycle, which treats every edge as two-way and knows nothing of turns, so loops making a forbidden turn are discarded and turn costs are only added afterwards.
+*/
+async fn find_paths(
+    mode: CircuitMode,
+    rows: &[TopoEdgeRepresentation],
+    turns: &TurnRules,
+    start_node: u32,
+    end_node: Option<u32>,
+    desired_distance: f64,
+) -> Result<Vec<(&'static str, GraphPath)>, AppError> {
+    let graph = RoutingGraph::new(rows, turns);
+
+    Ok(match (mode, end_node) {
+        (CircuitMode::Loop, _) => {
+            let json_str = try_convert_to_edge_json(rows)?;
+            let routing_task = spawn(async move {
+                make_route_from_edges_json::<u32, f64, u32>(json_str, start_node, desired_distance)
+            })
+            .await?;
+            let results = routing_task.map_err(|e| AppError(anyhow!(e)))?;
+
+            [("upper", &results.upper), ("lower", &results.lower)]
+                .into_iter()
+                .filter_map(|(name, nodes)| Some((name, graph.path_from_nodes(nodes)?)))
+                .collect()
+        }
+        (CircuitMode::OutAndBack, _) => graph.out_and_back_paths(start_node, desired_distance),
+        (CircuitMode::PointToPoint, Some(end_node)) => {
+            graph.stretched_paths(start_node, end_node, desired_distance)
+        }
+        (CircuitMode::PointToPoint, None) => Vec::new(),
+    })
+}
+
+/**
+Looks up the turn rules applying to a routing subgraph.
+*/
+async fn get_subgraph_turn_rules(
+    state: &AppState,
+    table: &Table,
+    profile: &RoutingProfile,
+    rows: &[TopoEdgeRepresentation],
+) -> Result<TurnRules, AppError> {
+    let edge_ids = rows.iter().map(|e| e.edge_id).collect::<Vec<u32>>();
+    get_turn_rules(
+        &state.db_pool,
+        &state.table_registry,
+        table,
+        profile,
+        Some(&edge_ids),
+    )
+    .await
+}
+
+/**
+Resolves a requested point to a location on a table's network, using the preloaded network when there is one and the database otherwise.
+*/
+async fn snap_point(
+    state: &AppState,
+    table: &Table,
+    network: Option<&PreloadedNetwork>,
+    point: &StartingGeom,
+    mode: SnapMode,
+    virtual_node_id: u32,
+) -> Result<SnappedPoint, AppError> {
+    match (point.node_id, network) {
+        (Some(node_id), _) => Ok(SnappedPoint::from_node(node_id, &point.geometry, table)),
+        (None, Some(network)) => network.snap(&point.geometry, mode, virtual_node_id),
+        (None, None) => {
+            snap_to_network(
+                &state.db_pool,
+                table,
+                &point.geometry,
+                mode,
+                virtual_node_id,
+            )
+            .await
         }
     }
 }
+
+#[debug_handler]
+/**
+Handles a POST request for the shortest route through an ordered series of waypoints.
+
+# Arguments
+
+* `State(state)`: The state of the application, which contains the table registry and database pool.
+* `Path(path)`: The schema and table holding the routing network, and optionally the output format.
+* `Query(options)`: Whether waypoints without a `node_id` are snapped to vertices or edges, the routing `profile` to use, the `units` in which to report distances or durations, and the `speed` in kilometres per hour at which durations are measured, overriding the profile's.
+* `headers`: The request headers, whose `Accept` header may request GPX or KML output.
+* `Json(raw_waypoints)`: A GeoJSON `FeatureCollection` of at least two waypoints, in Web Mercator, in the order they are to be visited. Constraints given on the first waypoint apply to the whole route.
+
+# Returns
+
+Returns a result that either contains a GeoJSON `FeatureCollection` with one line per leg, ordered in the direction of travel, along with the total distance (in miles unless a distance unit is requested), the duration (in minutes unless a time unit is requested) and the snapped waypoints, or an `AppError` if there was an error during the process. GPX or KML is returned instead when requested.
+*/
+pub async fn get_route(
+    State(state): State<AppState>,
+    Path(RoutingPath {
+        schemaid,
+        tableid,
+        format,
+    }): Path<RoutingPath>,
+    Query(options): Query<RouteOptions>,
+    headers: HeaderMap,
+    Json(raw_waypoints): Json<String>,
+) -> Result<Response, AppError> {
+    let format = match RouteFormat::from_request(format.as_deref(), &headers) {
+        Ok(format) => format,
+        Err(rejection) => return Ok(rejection.into_response()),
+    };
+    let waypoints = deserialize_feature_collection_str_to_vec::<StartingGeom>(&raw_waypoints)
+        .map_err(|e| AppError(anyhow!(e)))?;
+    if waypoints.len() < 2 {
+        return Err(AppError(anyhow!("At least two waypoints are required")));
+    }
+
+    let table_spec = find_table(&state.table_registry, &schemaid, &tableid)?;
+    let dist_unit = table_spec.dist_unit.as_ref().ok_or_else(|| {
+        AppError(anyhow!(
+            "Specified table does not contain valid distance data"
+        ))
+    })?;
+
+    let mut snapped = Vec::with_capacity(waypoints.len());
+    for (i, waypoint) in waypoints.iter().enumerate() {
+        snapped.push(match waypoint.node_id {
+            Some(node_id) => SnappedPoint::from_node(node_id, &waypoint.geometry, table_spec),
+            None => {
+                snap_to_network(
+                    &state.db_pool,
+                    table_spec,
+                    &waypoint.geometry,
+                    options.snap,
+                    VIRTUAL_NODE_ID - i as u32,
+                )
+                .await?
+            }
+        });
+    }
+    let splits = snapped
+        .iter()
+        .filter_map(|s| s.split.as_ref())
+        .collect::<Vec<_>>();
+    let points = waypoints.iter().map(|w| w.geometry).collect::<Vec<_>>();
+
+    let profile = state
+        .routing_profiles
+        .get(table_spec, options.profile.as_deref())?;
+    let units = ResultUnits::new(
+        options.units,
+        options
+            .speed
+            .or(profile.speed())
+            .unwrap_or(DEFAULT_SPEED_KMH),
+    )?;
+    let elevation = ElevationSource::for_table(table_spec, state.elevation_raster.as_ref());
+
+    let mut legs = None;
+    for buffer_factor in ROUTE_SEARCH_BUFFER_FACTORS {
+        let mut rows = get_features_along_waypoints(
+            &state.db_pool,
+            table_spec,
+            &points,
+            buffer_factor,
+            &profile,
+            &elevation,
+            &waypoints[0].constraints,
+        )
+        .await?;
+        split_edges(&mut rows, &splits);
+        let turns = get_subgraph_turn_rules(&state, table_spec, &profile, &rows).await?;
+        let graph = RoutingGraph::new(&rows, &turns);
+
+        legs = snapped
+            .windows(2)
+            .map(|pair| graph.shortest_path(pair[0].routing_node_id(), pair[1].routing_node_id()))
+            .collect::<Option<Vec<GraphPath>>>();
+        if legs.is_some() {
+            break;
+        }
+    }
+    let legs =
+        legs.ok_or_else(|| AppError(anyhow!("No route found between the supplied waypoints")))?;
+
+    let mut features = Vec::with_capacity(legs.len());
+    let mut total_distance = 0.0;
+    let mut total_duration = 0.0;
+    let mut total_ascent = None;
+    let mut total_descent = None;
+    for (i, leg) in legs.iter().enumerate() {
+        let geometry =
+            get_ordered_path_geometry(&leg.edges, table_spec, &elevation, &state.db_pool).await?;
+        let summary = summarize_path(leg, &geometry, dist_unit, &units)?;
+        total_distance += summary.distance;
+        total_duration += summary.duration;
+        if let (Some(ascent), Some(descent)) = (summary.ascent, summary.descent) {
+            total_ascent = Some(total_ascent.unwrap_or(0.0) + ascent);
+            total_descent = Some(total_descent.unwrap_or(0.0) + descent);
+        }
+
+        let mut properties = json!(summary);
+        properties["leg"] = json!(i);
+
+        features.push(json!({
+            "type": "Feature",
+            "geometry": geometry.geoms,
+            "properties": properties,
+        }));
+    }
+
+    let collection = json!({
+        "type": "FeatureCollection",
+        "features": features,
+        "distance": total_distance,
+        "duration": total_duration,
+        "ascent": total_ascent,
+        "descent": total_descent,
+        "units": { "distance": units.distance, "duration": units.duration, "speed": units.speed_kmh },
+        "waypoints": snapped,
+    });
+
+    Ok(render_routes(collection, format))
+}
+
+#[debug_handler]
+/**
+Handles a POST request for the parts of a network reachable from a starting point within one or more time or distance bands.
+
+# Arguments
+
+* `State(state)`: The state of the application, which contains the table registry and database pool.
+* `Path((schemaid, tableid))`: The schema and table holding the routing network.
+* `Query(options)`: A comma separated list of `bands` measured in `units` (`km`, `m`, `ft`, `mi`, `min` or `h`), how the start is snapped, the routing `profile` to use, the `concavity` of the hulls, between 0 and 1, and the `speed` in kilometres per hour at which times are travelled, overriding the profile's.
+* `Json(raw_starting_geom)`: A GeoJSON `Feature` for the starting point, in Web Mercator, optionally carrying routing `constraints`.
+
+# Returns
+
+Returns a result that either contains a GeoJSON `FeatureCollection` with, for each band, a concave hull polygon and the reachable edges, partial edges included, or an `AppError` if there was an error during the process.
+*/
+pub async fn get_isochrone(
+    State(state): State<AppState>,
+    Path((schemaid, tableid)): Path<(String, String)>,
+    Query(options): Query<IsochroneOptions>,
+    Json(raw_starting_geom): Json<String>,
+) -> Result<Json<Value>, AppError> {
+    let mut bands = options
+        .bands
+        .split(',')
+        .map(|b| b.trim().parse::<f64>())
+        .collect::<Result<Vec<f64>, _>>()
+        .map_err(|_| AppError(anyhow!("Invalid bands: {}", options.bands)))?;
+    if bands.is_empty() || bands.len() > MAX_ISOCHRONE_BANDS || bands.iter().any(|b| *b <= 0.0) {
+        return Err(AppError(anyhow!(
+            "Between 1 and {} positive bands are required",
+            MAX_ISOCHRONE_BANDS
+        )));
+    }
+    bands.sort_by(|a, b| a.total_cmp(b));
+    bands.dedup();
+
+    let concavity = options.concavity.unwrap_or(DEFAULT_HULL_CONCAVITY);
+    if !(0.0..=1.0).contains(&concavity) {
+        return Err(AppError(anyhow!("concavity must be between 0 and 1")));
+    }
+
+    let starting_geom = deserialize_single_feature::<StartingGeom>(raw_starting_geom.as_bytes())
+        .map_err(|e| AppError(anyhow!(e)))?;
+    let table_spec = find_table(&state.table_registry, &schemaid, &tableid)?;
+    let dist_unit = table_spec.dist_unit.as_ref().ok_or_else(|| {
+        AppError(anyhow!(
+            "Specified table does not contain valid distance data"
+        ))
+    })?;
+
+    let profile = state
+        .routing_profiles
+        .get(table_spec, options.profile.as_deref())?;
+    let units = ResultUnits::new(
+        Some(options.units),
+        options
+            .speed
+            .or(profile.speed())
+            .unwrap_or(DEFAULT_SPEED_KMH),
+    )?;
+    let limits = bands
+        .iter()
+        .map(|b| to_table_distance(*b, options.units, units.speed_kmh, dist_unit))
+        .collect::<Result<Vec<f64>, AppError>>()?;
+    let max_limit = limits.iter().copied().fold(0.0, f64::max);
+
+    let start = match starting_geom.node_id {
+        Some(node_id) => SnappedPoint::from_node(node_id, &starting_geom.geometry, table_spec),
+        None => {
+            snap_to_network(
+                &state.db_pool,
+                table_spec,
+                &starting_geom.geometry,
+                options.snap,
+                VIRTUAL_NODE_ID,
+            )
+            .await?
+        }
+    };
+
+    let elevation = ElevationSource::for_table(table_spec, state.elevation_raster.as_ref());
+    let mut rows = get_proximal_features(
+        &state.db_pool,
+        table_spec,
+        &starting_geom,
+        max_limit,
+        &profile,
+        &elevation,
+        &starting_geom.constraints,
+    )
+    .await?;
+    if let Some(split) = &start.split {
+        split_edges(&mut rows, &[split]);
+    }
+    let turns = get_subgraph_turn_rules(&state, table_spec, &profile, &rows).await?;
+    let graph = RoutingGraph::new(&rows, &turns);
+    let costs = graph.costs_within(start.routing_node_id(), max_limit);
+
+    let mut features = Vec::with_capacity(bands.len() * 2);
+    for (band, limit) in bands.iter().zip(limits.iter()) {
+        let portions = reachable_portions(&rows, &costs, *limit);
+        let geometries =
+            get_reachable_geometries(&portions, concavity, table_spec, &state.db_pool).await?;
+
+        features.push(json!({
+            "type": "Feature",
+            "geometry": geometries.hull,
+            "properties": { "band": band, "units": options.units, "kind": "hull" },
+        }));
+        features.push(json!({
+            "type": "Feature",
+            "geometry": geometries.edges,
+            "properties": { "band": band, "units": options.units, "kind": "edges" },
+        }));
+    }
+
+    Ok(Json(json!({
+        "type": "FeatureCollection",
+        "features": features,
+        "speed": units.speed_kmh,
+        "start": start,
+    })))
+}
+
+#[debug_handler]
+/**
+Handles a POST request to match a recorded track, such as a GPS trace from a run, onto the routing network.
+
+Each track point is matched to one of the edges within `radius` of it by a hidden Markov model, solved with the Viterbi algorithm as described by `match_track`. Consecutive matched locations are joined along the shortest route between them, over the edges the routing `profile` allows in each direction, measured by length. Points closer together than twice the GPS `accuracy` are thinned out first. Where the track leaves the network and no route joins consecutive points, matching starts afresh, giving several matched segments.
+
+# Arguments
+
+* `State(state)`: The state of the application, which contains the table registry and database pool.
+* `Path((schemaid, tableid))`: The schema and table holding the routing network.
+* `Query(options)`: The GPS `accuracy` and search `radius`, in metres (defaulting to 10 and 50), the routing `profile` whose one-way rules and constraints apply, the `units` in which to report distances or durations, and the `speed` in kilometres per hour at which durations are measured.
+* `headers`: The request headers, whose `Content-Type` marks GPX uploads.
+* `body`: The track, as a GPX document or as a GeoJSON `LineString` in Web Mercator, as described by `read_track`.
+
+# Returns
+
+Returns a result that either contains a GeoJSON `FeatureCollection` with one line per matched segment, along with its distance, duration, the IDs of the edges it follows in order and the range of track points it covers, plus the location each track point was matched to, or an `AppError` if there was an error during the process.
+*/
+pub async fn get_match(
+    State(state): State<AppState>,
+    Path((schemaid, tableid)): Path<(String, String)>,
+    Query(options): Query<MatchOptions>,
+    headers: HeaderMap,
+    body: String,
+) -> Result<Json<Value>, AppError> {
+    let accuracy = options.accuracy.unwrap_or(DEFAULT_MATCH_ACCURACY);
+    let radius = options.radius.unwrap_or(DEFAULT_MATCH_RADIUS);
+    if accuracy <= 0.0 || radius <= 0.0 {
+        return Err(AppError(anyhow!("accuracy and radius must be positive")));
+    }
+
+    let content_type = headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok());
+    let track = read_track(&body, content_type)?;
+    let (track_indices, points): (Vec<usize>, Vec<Point<f64>>) =
+        thin_track(&track, 2.0 * accuracy).into_iter().unzip();
+    if points.len() > MAX_MATCH_POINTS {
+        return Err(AppError(anyhow!(
+            "Tracks may have at most {} points once thinned",
+            MAX_MATCH_POINTS
+        )));
+    }
+
+    let table_spec = find_table(&state.table_registry, &schemaid, &tableid)?;
+    let dist_unit = table_spec.dist_unit.as_ref().ok_or_else(|| {
+        AppError(anyhow!(
+            "Specified table does not contain valid distance data"
+        ))
+    })?;
+    let model = MatchModel {
+        accuracy: metres_to_table_distance(accuracy, dist_unit)?,
+        transition_scale: metres_to_table_distance(DEFAULT_MATCH_TRANSITION_SCALE, dist_unit)?,
+        radius: metres_to_table_distance(radius, dist_unit)?,
+    };
+
+    let profile = state
+        .routing_profiles
+        .get(table_spec, options.profile.as_deref())?;
+    let units = ResultUnits::new(
+        options.units,
+        options
+            .speed
+            .or(profile.speed())
+            .unwrap_or(DEFAULT_SPEED_KMH),
+    )?;
+    let elevation = ElevationSource::for_table(table_spec, state.elevation_raster.as_ref());
+
+    let candidates =
+        get_match_candidates(&state.db_pool, table_spec, &points, model.radius).await?;
+    if candidates.iter().all(|c| c.is_empty()) {
+        return Err(AppError(anyhow!("No routable edges found near the track")));
+    }
+
+    //Routes between matched locations are measured by length, over the directions the profile allows
+    let mut rows = get_features_along_line(
+        &state.db_pool,
+        table_spec,
+        &points,
+        2.0 * model.radius,
+        &profile,
+        &elevation,
+        &RoutingConstraints::default(),
+    )
+    .await?;
+    for row in rows.iter_mut() {
+        if row.weight >= 0.0 {
+            row.weight = row.length;
+        }
+        if row.reverse_weight >= 0.0 {
+            row.reverse_weight = row.length;
+        }
+    }
+    let splits = candidates
+        .iter()
+        .flatten()
+        .filter_map(|c| c.split.as_ref())
+        .collect::<Vec<_>>();
+    split_edges(&mut rows, &splits);
+    let graph = RoutingGraph::new(&rows, &TurnRules::default());
+
+    let straight_distances = points
+        .windows(2)
+        .map(|pair| {
+            metres_to_table_distance(
+                ground_distance([pair[0].x(), pair[0].y()], [pair[1].x(), pair[1].y()]),
+                dist_unit,
+            )
+        })
+        .collect::<Result<Vec<f64>, AppError>>()?;
+    let segments = match_track(&graph, &candidates, &straight_distances, &model);
+    if segments.is_empty() {
+        return Err(AppError(anyhow!(
+            "Failed to match the track to the network"
+        )));
+    }
+
+    let mut features = Vec::with_capacity(segments.len());
+    let mut matches = Vec::new();
+    for (i, segment) in segments.iter().enumerate() {
+        let Some(path) = segment_path(&graph, &candidates, segment) else {
+            continue;
+        };
+        for (point, candidate) in segment {
+            let mut matched = json!(candidates[*point][*candidate]);
+            matched["point"] = json!(track_indices[*point]);
+            matched["segment"] = json!(i);
+            matches.push(matched);
+        }
+        if path.edges.is_empty() {
+            continue;
+        }
+
+        let geometry =
+            get_ordered_path_geometry(&path.edges, table_spec, &elevation, &state.db_pool).await?;
+        let summary = summarize_path(&path, &geometry, dist_unit, &units)?;
+
+        let mut properties = json!(summary);
+        properties["segment"] = json!(i);
+        properties["from_point"] = json!(segment.first().map(|(p, _)| track_indices[*p]));
+        properties["to_point"] = json!(segment.last().map(|(p, _)| track_indices[*p]));
+
+        features.push(json!({
+            "type": "Feature",
+            "geometry": geometry.geoms,
+            "properties": properties,
+        }));
+    }
+
+    Ok(Json(json!({
+        "type": "FeatureCollection",
+        "features": features,
+        "units": { "distance": units.distance, "duration": units.duration, "speed": units.speed_kmh },
+        "track_points": track.len(),
+        "matches": matches,
+    })))
+}
+
+#[debug_handler]
+/**
+Handles a GET request for a report on the connectivity of a routing network, to find where it needs fixing before routes across it can be trusted.
+
+The report gives the network's connected components, the number of vertices with each number of edge ends, and lists the dead ends, self-loops, duplicated edges and zero-length edges found, as described by `NetworkDiagnostics`. Where a point is given, the report also says which component it is snapped to, and whether that is the largest.
+
+# Arguments
+
+* `State(state)`: The state of the application, which contains the table registry and database pool.
+* `Path((schemaid, tableid))`: The schema and table holding the routing network.
+* `Query(options)`: The `format`, either `json` (the default) for the report alone or `geojson` for the problem spots as a `FeatureCollection`, with the report as its `diagnostics` member. Optionally, a point to check, either as `x` and `y` in Web Mercator or as a `node_id`.
+
+# Returns
+
+Returns a result that either contains the report, or the GeoJSON overlay of problem spots described by `NetworkAnalysis::overlay_geojson`, or an `AppError` if there was an error during the process.
+*/
+pub async fn get_diagnostics(
+    State(state): State<AppState>,
+    Path((schemaid, tableid)): Path<(String, String)>,
+    Query(options): Query<DiagnosticsOptions>,
+) -> Result<Json<Value>, AppError> {
+    let table_spec = find_table(&state.table_registry, &schemaid, &tableid)?;
+
+    let snapped = match (options.node_id, options.x, options.y) {
+        (Some(_), _, _) | (None, None, None) => None,
+        (None, Some(x), Some(y)) => Some(
+            snap_to_network(
+                &state.db_pool,
+                table_spec,
+                &Point::new(x, y),
+                SnapMode::Vertex,
+                VIRTUAL_NODE_ID,
+            )
+            .await?,
+        ),
+        _ => {
+            return Err(AppError(anyhow!(
+                "Both x and y are needed to check a point"
+            )))
+        }
+    };
+
+    let analysis = state
+        .network_analyses
+        .get_or_analyze(&state.db_pool, table_spec)
+        .await?;
+    let mut report = json!(analysis.report(table_spec));
+    if let Some(node_id) = options.node_id.or(snapped.as_ref().and_then(|s| s.node_id)) {
+        let component = analysis.component_of(node_id as i32);
+        report["point"] = json!({
+            "node_id": node_id,
+            "snapped": snapped,
+            "component": component,
+            "in_largest_component": component == Some(1),
+        });
+    }
+
+    match options.format.as_deref() {
+        None | Some("json") => Ok(Json(report)),
+        Some("geojson") => {
+            let mut overlay = analysis.overlay_geojson(&state.db_pool, table_spec).await?;
+            overlay["diagnostics"] = report;
+            Ok(Json(overlay))
+        }
+        _ => Err(AppError(anyhow!("Specified format not supported"))),
+    }
+}
+
+#[debug_handler]
+/**
+Handles a GET request for a vector tile of the problem spots in a routing network, to overlay on a map of it. The tile has a single `problems` layer, as described by `NetworkAnalysis::overlay_mvt`.
+
+# Arguments
+
+* `State(state)`: The state of the application, which contains the table registry and database pool.
+* `Path((schemaid, tableid, z, x, y_ext))`: The schema and table holding the routing network, and the tile coordinates. Only the "mvt" extension is supported.
+
+# Returns
+
+Returns a result that either contains an `MVTBuffer`, or an `AppError` if there was an error during the process.
+*/
+pub async fn get_diagnostics_tile(
+    State(state): State<AppState>,
+    Path((schemaid, tableid, z, x, y_ext)): Path<(String, String, usize, usize, String)>,
+) -> Result<MVTBuffer, AppError> {
+    let (y, ext) = parse_y_ext(&y_ext)?;
+    if ext != "mvt" {
+        return Err(AppError(anyhow!("Specified file extension not supported")));
+    }
+    let table_spec = find_table(&state.table_registry, &schemaid, &tableid)?;
+
+    let analysis = state
+        .network_analyses
+        .get_or_analyze(&state.db_pool, table_spec)
+        .await?;
+    analysis
+        .overlay_mvt(&state.db_pool, table_spec, &Tile::new(x, y, z))
+        .await
+}
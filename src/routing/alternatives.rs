@@ -0,0 +1,250 @@
+use super::graph::GraphPath;
+use super::utils::TopoEdgeRepresentation;
+use crate::AppError;
+use anyhow::anyhow;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+
+//Factor by which an edge's weights grow each time a previous candidate used it
+const USED_EDGE_PENALTY: f64 = 1.5;
+//Weights of overlap and repeated edges against relative deviation from the target when scoring
+const OVERLAP_WEIGHT: f64 = 0.5;
+const REPEAT_WEIGHT: f64 = 0.5;
+
+/**
+How well an alternative route fits a request, where lower scores are better.
+
+* `rank` - The position of the route among the alternatives returned, from 1.
+* `score` - The sum of the deviation and the weighted overlap and repeated share.
+* `deviation` - The route's relative deviation from the target.
+* `overlap` - The largest share of edges the route has in common with any better-ranked route.
+* `repeated_share` - The share of the route's length spent on edges it has already traversed.
+*/
+#[derive(Serialize, Debug, Clone)]
+pub struct AlternativeScore {
+    pub rank: usize,
+    pub score: f64,
+    pub deviation: f64,
+    pub overlap: f64,
+    pub repeated_share: f64,
+}
+
+/**
+Counts how many times each edge has been used by a set of candidate paths.
+*/
+pub fn count_edge_uses(paths: &[(&str, GraphPath)], uses: &mut HashMap<u32, u32>) {
+    for (_, path) in paths {
+        let edge_ids = path
+            .edges
+            .iter()
+            .map(|e| e.edge_id)
+            .collect::<HashSet<u32>>();
+        for edge_id in edge_ids {
+            *uses.entry(edge_id).or_default() += 1;
+        }
+    }
+}
+
+/**
+Raises the weights of edges used by earlier candidates, compounding with each use, to steer the next search onto different edges. Impassable directions are left impassable.
+*/
+pub fn penalize_used_edges(rows: &mut [TopoEdgeRepresentation], uses: &HashMap<u32, u32>) {
+    for row in rows.iter_mut() {
+        if let Some(count) = uses.get(&row.edge_id) {
+            let factor = USED_EDGE_PENALTY.powi(*count as i32);
+            if row.weight >= 0.0 {
+                row.weight *= factor;
+            }
+            if row.reverse_weight >= 0.0 {
+                row.reverse_weight *= factor;
+            }
+        }
+    }
+}
+
+fn jaccard(a: &HashSet<u32>, b: &HashSet<u32>) -> f64 {
+    let union = a.union(b).count();
+    if union == 0 {
+        return 1.0;
+    }
+    a.intersection(b).count() as f64 / union as f64
+}
+
+fn repeated_share(path: &GraphPath) -> f64 {
+    let mut seen = HashSet::new();
+    let (mut total, mut repeated) = (0.0, 0.0);
+    for step in path.edges.iter() {
+        total += step.length;
+        if !seen.insert(step.edge_id) {
+            repeated += step.length;
+        }
+    }
+    if total > 0.0 {
+        repeated / total
+    } else {
+        0.0
+    }
+}
+
+/**
+Picks up to `count` distinct alternatives from a set of candidate paths, one at a time, each time taking the candidate with the lowest score against those already picked. Candidates following exactly the same edges as one already picked are dropped.
+
+# Arguments
+
+* `candidates` - The candidate paths, with their names.
+* `count` - The number of alternatives wanted.
+* `target` - The target, in the table's distance units. Time targets are converted at the travel speed, so every target is measured against the paths' lengths rather than their costs, which include penalties that only steer the search.
+
+# Returns
+
+The alternatives picked, best first, with their scores.
+
+# Errors
+
+- If the target is not a positive number, as deviations are relative to it, returns an `AppError`.
+*/
+pub fn rank_alternatives(
+    candidates: Vec<(&'static str, GraphPath)>,
+    count: usize,
+    target: f64,
+) -> Result<Vec<(&'static str, GraphPath, AlternativeScore)>, AppError> {
+    if !(target > 0.0 && target.is_finite()) {
+        return Err(AppError(anyhow!(
+            "Alternatives require a positive target, not {}",
+            target
+        )));
+    }
+
+    let mut remaining = candidates
+        .into_iter()
+        .map(|(name, path)| {
+            let achieved = path.edges.iter().map(|e| e.length).sum::<f64>();
+            let edge_ids = path
+                .edges
+                .iter()
+                .map(|e| e.edge_id)
+                .collect::<HashSet<u32>>();
+            let deviation = (achieved - target).abs() / target;
+            let repeated = repeated_share(&path);
+            (name, path, edge_ids, deviation, repeated)
+        })
+        .collect::<Vec<_>>();
+
+    let mut picked: Vec<(&'static str, GraphPath, AlternativeScore)> = Vec::with_capacity(count);
+    let mut picked_edges: Vec<HashSet<u32>> = Vec::with_capacity(count);
+    while picked.len() < count {
+        let scored = remaining
+            .iter()
+            .enumerate()
+            .map(|(i, (_, _, edge_ids, deviation, repeated))| {
+                let overlap = picked_edges
+                    .iter()
+                    .map(|other| jaccard(edge_ids, other))
+                    .fold(0.0, f64::max);
+                let score = deviation + OVERLAP_WEIGHT * overlap + REPEAT_WEIGHT * repeated;
+                (i, overlap, score)
+            })
+            .filter(|(_, overlap, _)| *overlap < 1.0)
+            .min_by(|a, b| a.2.total_cmp(&b.2));
+        let Some((i, overlap, score)) = scored else {
+            break;
+        };
+
+        let (name, path, edge_ids, deviation, repeated) = remaining.swap_remove(i);
+        picked_edges.push(edge_ids);
+        picked.push((
+            name,
+            path,
+            AlternativeScore {
+                rank: picked.len() + 1,
+                score,
+                deviation,
+                overlap,
+                repeated_share: repeated,
+            },
+        ));
+    }
+
+    Ok(picked)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::routing::graph::TraversedEdge;
+
+    fn path(edges: &[(u32, f64)]) -> GraphPath {
+        GraphPath {
+            nodes: (0..=edges.len() as u32).collect(),
+            edges: edges
+                .iter()
+                .map(|(edge_id, length)| TraversedEdge {
+                    edge_id: *edge_id,
+                    from_fraction: 0.0,
+                    to_fraction: 1.0,
+                    weight: *length,
+                    length: *length,
+                })
+                .collect(),
+            cost: edges.iter().map(|(_, length)| length).sum(),
+        }
+    }
+
+    #[test]
+    fn used_edges_grow_heavier_with_each_use() {
+        let found = vec![
+            ("upper", path(&[(1, 10.0), (2, 10.0), (1, 10.0)])),
+            ("lower", path(&[(1, 10.0), (3, 10.0)])),
+        ];
+        let mut uses = HashMap::new();
+        count_edge_uses(&found, &mut uses);
+        //An edge retraced within one path counts once for it
+        assert_eq!(uses, HashMap::from([(1, 2), (2, 1), (3, 1)]));
+
+        let mut rows = vec![
+            TopoEdgeRepresentation::new(1, 1, 2, 10.0, 20.0, 10.0),
+            TopoEdgeRepresentation::new(2, 2, 3, 10.0, -1.0, 10.0),
+            TopoEdgeRepresentation::new(4, 3, 4, 10.0, 10.0, 10.0),
+        ];
+        penalize_used_edges(&mut rows, &uses);
+        assert_eq!((rows[0].weight, rows[0].reverse_weight), (22.5, 45.0));
+        assert_eq!((rows[1].weight, rows[1].reverse_weight), (15.0, -1.0));
+        assert_eq!((rows[2].weight, rows[2].reverse_weight), (10.0, 10.0));
+        assert_eq!(rows[0].length, 10.0);
+    }
+
+    #[test]
+    fn alternatives_trade_deviation_against_overlap() {
+        let candidates = vec![
+            ("exact", path(&[(1, 50.0), (2, 50.0)])),
+            ("same", path(&[(2, 60.0), (1, 60.0)])),
+            ("shared", path(&[(1, 50.0), (3, 55.0)])),
+            ("separate", path(&[(4, 60.0), (5, 60.0)])),
+            ("retraced", path(&[(6, 50.0), (6, 50.0)])),
+        ];
+        let ranked = rank_alternatives(candidates, 4, 100.0).unwrap();
+        let names = ranked.iter().map(|(name, _, _)| *name).collect::<Vec<_>>();
+        //A route over the same edges as a better one is never picked
+        assert_eq!(names, vec!["exact", "separate", "shared", "retraced"]);
+
+        let scores = ranked.iter().map(|(_, _, s)| s).collect::<Vec<_>>();
+        assert_eq!(
+            scores.iter().map(|s| s.rank).collect::<Vec<_>>(),
+            vec![1, 2, 3, 4]
+        );
+        assert_eq!((scores[0].score, scores[0].overlap), (0.0, 0.0));
+        assert!((scores[1].deviation - 0.2).abs() < 1e-9);
+        assert!((scores[2].overlap - 1.0 / 3.0).abs() < 1e-9);
+        assert!((scores[2].score - (0.05 + 0.5 / 3.0)).abs() < 1e-9);
+        assert_eq!(scores[3].repeated_share, 0.5);
+        assert!((scores[3].score - 0.25).abs() < 1e-9);
+    }
+
+    #[test]
+    fn alternatives_need_a_positive_target() {
+        for target in [0.0, -100.0, f64::NAN, f64::INFINITY] {
+            assert!(rank_alternatives(vec![("exact", path(&[(1, 50.0)]))], 1, target).is_err());
+        }
+        assert!(rank_alternatives(Vec::new(), 2, 100.0).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,179 @@
+use crate::db::Table;
+use crate::AppError;
+use anyhow::anyhow;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+
+/**
+Parts of the network a routing request wants to stay off, read from the `constraints` property of the request's starting feature.
+
+* `avoid` - Polygons or multipolygons, in Web Mercator, whose edges are avoided.
+* `exclude_edges` - IDs of edges to avoid.
+* `exclude` - Values to avoid, keyed by column name. An edge is avoided if any listed column holds one of the listed values; `null` matches missing values.
+* `penalty` - When given, constrained edges are not blocked but have their costs multiplied by this factor, so they are used only when there is no reasonable alternative.
+*/
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct RoutingConstraints {
+    #[serde(default)]
+    avoid: Vec<geojson::Geometry>,
+    #[serde(default)]
+    exclude_edges: Vec<u32>,
+    #[serde(default)]
+    exclude: HashMap<String, Vec<Value>>,
+    penalty: Option<f64>,
+}
+
+/**
+Checks that a column name supplied in a request is a plain SQL identifier, so that it can be safely quoted into a query.
+*/
+fn validate_identifier(name: &str) -> Result<&str, AppError> {
+    let mut chars = name.chars();
+    let valid = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if valid {
+        Ok(name)
+    } else {
+        Err(AppError(anyhow!("Invalid column name: {}", name)))
+    }
+}
+
+fn quote_literal(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "''"))
+}
+
+impl RoutingConstraints {
+    pub fn is_empty(&self) -> bool {
+        self.avoid.is_empty() && self.exclude_edges.is_empty() && self.exclude.is_empty()
+    }
+
+    /**
+    Builds an SQL predicate that holds for the constrained edges of a table aliased as `t`. Returns `None` when nothing is constrained.
+
+    # Errors
+
+    - If an avoided geometry is not a polygon, a column name is not a valid identifier, or an excluded value is not a string, number, boolean or `null`, returns an `AppError`.
+    */
+    fn make_predicate(&self, table: &Table) -> Result<Option<String>, AppError> {
+        let (geom_col, srid, pk_col) = match (
+            table.geom_column.as_ref(),
+            table.srid.as_ref(),
+            table.primary_key_columns.first(),
+        ) {
+            (Some(geom_col), Some(srid), Some(pk_col)) => (geom_col, srid, pk_col),
+            _ => {
+                return Err(AppError(anyhow!(
+                    "Table does not contain valid geometry data"
+                )))
+            }
+        };
+        let mut conditions = Vec::new();
+
+        for geometry in self.avoid.iter() {
+            if !matches!(
+                geometry.value,
+                geojson::Value::Polygon(_) | geojson::Value::MultiPolygon(_)
+            ) {
+                return Err(AppError(anyhow!("Avoided areas must be polygons")));
+            }
+            //Rebuilt from the coordinates alone, dropping any foreign members
+            let geojson = geojson::Geometry::new(geometry.value.clone()).to_string();
+            conditions.push(format!(
+                "ST_Intersects(t.{}, ST_Transform(ST_SetSRID(ST_GeomFromGeoJSON({}), 3857), {}))",
+                geom_col,
+                quote_literal(&geojson),
+                srid
+            ));
+        }
+
+        if !self.exclude_edges.is_empty() {
+            conditions.push(format!(
+                "t.{} in ({})",
+                pk_col,
+                self.exclude_edges
+                    .iter()
+                    .map(|id| id.to_string())
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            ));
+        }
+
+        for (column, values) in self.exclude.iter() {
+            let column = validate_identifier(column)?;
+            let mut literals = Vec::new();
+            for value in values {
+                match value {
+                    Value::Null => conditions.push(format!("t.\"{}\" is null", column)),
+                    Value::String(text) => literals.push(quote_literal(text)),
+                    Value::Number(_) | Value::Bool(_) => {
+                        literals.push(quote_literal(&value.to_string()))
+                    }
+                    _ => {
+                        return Err(AppError(anyhow!(
+                            "Invalid excluded value for {}: {}",
+                            column,
+                            value
+                        )))
+                    }
+                }
+            }
+            if !literals.is_empty() {
+                conditions.push(format!(
+                    "t.\"{}\"::text in ({})",
+                    column,
+                    literals.join(", ")
+                ));
+            }
+        }
+
+        if conditions.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(format!("({})", conditions.join(" or "))))
+        }
+    }
+
+    /**
+    Applies the constraints to the SQL expressions for an edge's forward and reverse costs, blocking constrained edges with a cost of -1 or, when a penalty is given, multiplying their costs by it. Penalties only steer the search: the distances, durations and deviations reported for a route follow its edges' lengths.
+
+    # Errors
+
+    - If the constraints are invalid, or the penalty is not positive, returns an `AppError`.
+    */
+    pub fn apply_to_costs(
+        &self,
+        table: &Table,
+        forward: String,
+        reverse: String,
+    ) -> Result<(String, String), AppError> {
+        let Some(predicate) = self.make_predicate(table)? else {
+            return Ok((forward, reverse));
+        };
+
+        Ok(match self.penalty {
+            Some(penalty) if penalty <= 0.0 => {
+                return Err(AppError(anyhow!("Constraint penalty must be positive")))
+            }
+            Some(penalty) => (
+                format!(
+                    "case when {p} and {c} >= 0 then {c} * {f} else {c} end",
+                    p = predicate,
+                    c = forward,
+                    f = penalty
+                ),
+                format!(
+                    "case when {p} and {c} >= 0 then {c} * {f} else {c} end",
+                    p = predicate,
+                    c = reverse,
+                    f = penalty
+                ),
+            ),
+            None => (
+                format!("case when {} then -1 else {} end", predicate, forward),
+                format!("case when {} then -1 else {} end", predicate, reverse),
+            ),
+        })
+    }
+}
@@ -0,0 +1,569 @@
+use super::topology::{
+    label_components, summarize_components, ComponentSummary, NetworkEdge, MAX_LISTED_COMPONENTS,
+};
+use super::units::measured_unit;
+use crate::db::Table;
+use crate::layers::{
+    check_tile_coordinates, make_envelope_statement, MVTBuffer, Tile, BUFFER, EXTENT,
+};
+use crate::AppError;
+use anyhow::anyhow;
+use serde::Serialize;
+use serde_json::Value;
+use sqlx::{query, query_as, PgPool, Postgres, Row};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, RwLock};
+
+//Number of problem edges, vertices or groups listed in the report for each kind of problem
+const MAX_LISTED_PROBLEMS: usize = 100;
+
+/**
+A kind of problem found in a network, with the number found and up to 100 of them.
+*/
+#[derive(Serialize, Debug, Clone)]
+pub struct ProblemList<T> {
+    pub count: usize,
+    pub items: Vec<T>,
+}
+
+impl<T: Clone> ProblemList<T> {
+    fn new(items: &[T]) -> ProblemList<T> {
+        ProblemList {
+            count: items.len(),
+            items: items.iter().take(MAX_LISTED_PROBLEMS).cloned().collect(),
+        }
+    }
+}
+
+/**
+A report on the connectivity of a routing network.
+
+* `edges`, `vertices` - The numbers of edges with both nodes set, and of the vertices they join.
+* `unnoded_edges` - The number of edges missing a `start_node` or `end_node`, which cannot be routed over.
+* `components` - The number of connected components.
+* `largest_component_share` - The share of the network's length in the largest component.
+* `disconnected` - The components other than the largest, largest first, up to 20 of them.
+* `degree_distribution` - The number of vertices joined to each number of edge ends.
+* `dead_ends` - The vertices joined to a single edge.
+* `self_loops` - The edges starting and ending at the same vertex.
+* `duplicate_edges` - Groups of edges joining the same vertices along the same geometry.
+* `zero_length_edges` - The edges with no length.
+* `units` - The distance units of the lengths reported: metres for tables in geographic coordinates, and the table's distance unit otherwise.
+*/
+#[derive(Serialize, Debug, Clone)]
+pub struct NetworkDiagnostics {
+    pub edges: usize,
+    pub vertices: usize,
+    pub unnoded_edges: i64,
+    pub components: usize,
+    pub largest_component_share: f64,
+    pub disconnected: Vec<ComponentSummary>,
+    pub degree_distribution: BTreeMap<usize, usize>,
+    pub dead_ends: ProblemList<i32>,
+    pub self_loops: ProblemList<i32>,
+    pub duplicate_edges: ProblemList<Vec<i32>>,
+    pub zero_length_edges: ProblemList<i32>,
+    pub units: Option<String>,
+}
+
+/**
+The connectivity of a routing network, as read from its edge table.
+*/
+pub struct NetworkAnalysis {
+    edges: Vec<NetworkEdge>,
+    components: HashMap<i32, usize>,
+    degrees: HashMap<i32, usize>,
+    duplicates: Vec<Vec<i32>>,
+    unnoded_edges: i64,
+}
+
+fn get_edge_columns(table: &Table) -> Result<(&String, &String), AppError> {
+    match (
+        table.geom_column.as_ref(),
+        table.primary_key_columns.first(),
+    ) {
+        (Some(geom_col), Some(pk_col)) => Ok((geom_col, pk_col)),
+        _ => Err(AppError(anyhow!(
+            "Table does not contain valid geometry data"
+        ))),
+    }
+}
+
+/**
+Builds a query selecting the problem spots of a network, bound to the edge and vertex IDs of each kind of problem in the order given by `NetworkAnalysis::overlay_binds`. Each row has a `problem` (`island`, `dead_end`, `self_loop`, `duplicate` or `zero_length`), the `edge_id` and, for dead ends, the `node_id` involved, the `component` of island edges and a `geom` in the table's SRID. Edges are drawn as lines and vertices as points.
+
+If a tile is given, only edges within it, including the rendering buffer, are selected.
+*/
+fn make_problems_cte(table: &Table, tile: Option<&Tile>) -> Result<String, AppError> {
+    let (geom_col, pk_col) = get_edge_columns(table)?;
+    let srid = table
+        .srid
+        .as_ref()
+        .ok_or_else(|| AppError(anyhow!("Table does not contain valid geometry data")))?;
+    let tile_filter = match tile {
+        Some(tile) => format!(
+            "and t.{} && ST_Transform({}, {})",
+            geom_col,
+            make_envelope_statement(tile, Some(BUFFER / EXTENT)),
+            srid
+        ),
+        None => "".to_string(),
+    };
+
+    Ok(format!(
+        "with edges as (
+            select t.{pk}::int4 as id, t.start_node::int4 as start_node, t.end_node::int4 as end_node,
+                ST_GeometryN(ST_LineMerge(ST_Multi(t.{geom})), 1) as geom
+            from {schema}.{table} t
+            where t.start_node is not null and t.end_node is not null {filter}
+        ),
+        problems as (
+            select 'island' as problem, e.id as edge_id, null::int4 as node_id, i.component, e.geom
+            from edges e
+            join unnest($1::int4[], $2::int4[]) as i(id, component) on i.id = e.id
+            union all
+            select 'dead_end', e.id, d.node_id, null,
+                case when e.start_node = d.node_id then ST_StartPoint(e.geom) else ST_EndPoint(e.geom) end
+            from edges e
+            join unnest($3::int4[]) as d(node_id) on d.node_id in (e.start_node, e.end_node)
+            union all
+            select 'self_loop', e.id, e.start_node, null, e.geom
+            from edges e
+            where e.id = any($4::int4[])
+            union all
+            select 'duplicate', e.id, null, null, e.geom
+            from edges e
+            where e.id = any($5::int4[])
+            union all
+            select 'zero_length', e.id, null, null, ST_StartPoint(e.geom)
+            from edges e
+            where e.id = any($6::int4[])
+        )",
+        pk = pk_col,
+        geom = geom_col,
+        schema = table.schema_name,
+        table = table.name,
+        filter = tile_filter
+    ))
+}
+
+impl NetworkAnalysis {
+    /**
+    Analyzes the connectivity of a network's noded edges, given the groups of edges found to duplicate each other and the number of edges left unnoded.
+    */
+    fn new(
+        edges: Vec<NetworkEdge>,
+        duplicates: Vec<Vec<i32>>,
+        unnoded_edges: i64,
+    ) -> NetworkAnalysis {
+        let components = label_components(&edges);
+        let mut degrees: HashMap<i32, usize> = HashMap::new();
+        for edge in edges.iter() {
+            *degrees.entry(edge.start_node).or_default() += 1;
+            *degrees.entry(edge.end_node).or_default() += 1;
+        }
+
+        NetworkAnalysis {
+            edges,
+            components,
+            degrees,
+            duplicates,
+            unnoded_edges,
+        }
+    }
+
+    /**
+    Returns the connected component of a vertex, numbered from 1 for the largest by length.
+    */
+    pub fn component_of(&self, node_id: i32) -> Option<usize> {
+        self.components.get(&node_id).copied()
+    }
+
+    fn dead_ends(&self) -> Vec<i32> {
+        let mut dead_ends = self
+            .degrees
+            .iter()
+            .filter(|(_, degree)| **degree == 1)
+            .map(|(vertex, _)| *vertex)
+            .collect::<Vec<i32>>();
+        dead_ends.sort_unstable();
+        dead_ends
+    }
+
+    fn self_loops(&self) -> Vec<i32> {
+        self.edges
+            .iter()
+            .filter(|e| e.start_node == e.end_node)
+            .map(|e| e.id)
+            .collect()
+    }
+
+    fn zero_length_edges(&self) -> Vec<i32> {
+        self.edges
+            .iter()
+            .filter(|e| e.length <= 0.0)
+            .map(|e| e.id)
+            .collect()
+    }
+
+    /**
+    Returns the edges outside the largest component, with the component of each.
+    */
+    fn island_edges(&self) -> (Vec<i32>, Vec<i32>) {
+        self.edges
+            .iter()
+            .filter_map(|e| match self.components[&e.start_node] {
+                1 => None,
+                component => Some((e.id, component as i32)),
+            })
+            .unzip()
+    }
+
+    /**
+    Summarizes the analysis as a report, listing a limited number of each kind of problem.
+    */
+    pub fn report(&self, table: &Table) -> NetworkDiagnostics {
+        let summaries = summarize_components(&self.edges, &self.components);
+        let total_length = summaries.iter().map(|s| s.length).sum::<f64>();
+        let mut degree_distribution = BTreeMap::new();
+        for degree in self.degrees.values() {
+            *degree_distribution.entry(*degree).or_default() += 1;
+        }
+
+        NetworkDiagnostics {
+            edges: self.edges.len(),
+            vertices: self.components.len(),
+            unnoded_edges: self.unnoded_edges,
+            components: summaries.len(),
+            largest_component_share: match summaries.first() {
+                Some(largest) if total_length > 0.0 => largest.length / total_length,
+                _ => 0.0,
+            },
+            disconnected: summaries
+                .into_iter()
+                .skip(1)
+                .take(MAX_LISTED_COMPONENTS)
+                .collect(),
+            degree_distribution,
+            dead_ends: ProblemList::new(&self.dead_ends()),
+            self_loops: ProblemList::new(&self.self_loops()),
+            duplicate_edges: ProblemList::new(&self.duplicates),
+            zero_length_edges: ProblemList::new(&self.zero_length_edges()),
+            units: measured_unit(table),
+        }
+    }
+
+    /**
+    Returns every problem edge and vertex, in the order bound by `make_problems_cte`.
+    */
+    fn overlay_binds(&self) -> [Vec<i32>; 6] {
+        let (island_ids, island_components) = self.island_edges();
+        [
+            island_ids,
+            island_components,
+            self.dead_ends(),
+            self.self_loops(),
+            self.duplicates.iter().flatten().copied().collect(),
+            self.zero_length_edges(),
+        ]
+    }
+
+    /**
+    Renders every problem spot of the network as a GeoJSON `FeatureCollection` in Web Mercator, as described by `make_problems_cte`.
+    */
+    pub async fn overlay_geojson(&self, pool: &PgPool, table: &Table) -> Result<Value, AppError> {
+        let overlay_query = format!(
+            "{}
+            select
+                json_build_object(
+                    'type', 'FeatureCollection',
+                    'features', coalesce(json_agg(ST_AsGeoJSON(p.*)::json), '[]'::json)
+                )::text
+            from (
+                select problem, edge_id, node_id, component, ST_Transform(geom, 3857) as geom
+                from problems
+            ) p",
+            make_problems_cte(table, None)?
+        );
+
+        let mut overlay = query(&overlay_query);
+        for bind in self.overlay_binds() {
+            overlay = overlay.bind(bind);
+        }
+        let row = overlay
+            .fetch_one(pool)
+            .await
+            .map_err(|e| AppError(anyhow!(e)))?;
+        serde_json::from_str(row.get(0)).map_err(|e| AppError(anyhow!(e)))
+    }
+
+    /**
+    Renders the problem spots of the network within a tile as a vector tile with a single `problems` layer, as described by `make_problems_cte`.
+    */
+    pub async fn overlay_mvt(
+        &self,
+        pool: &PgPool,
+        table: &Table,
+        tile: &Tile,
+    ) -> Result<MVTBuffer, AppError> {
+        check_tile_coordinates(tile)?;
+        let overlay_query = format!(
+            "{}
+            select ST_AsMVT(m.*, 'problems')
+            from (
+                select problem, edge_id, node_id, component,
+                    ST_AsMVTGeom(ST_Transform(geom, 3857), {}, {}, {}) as geom
+                from problems
+            ) m
+            where m.geom is not null",
+            make_problems_cte(table, Some(tile))?,
+            make_envelope_statement(tile, None),
+            EXTENT,
+            BUFFER
+        );
+
+        let mut overlay = query(&overlay_query);
+        for bind in self.overlay_binds() {
+            overlay = overlay.bind(bind);
+        }
+        let row = overlay
+            .fetch_one(pool)
+            .await
+            .map_err(|e| AppError(anyhow!(e)))?;
+        Ok(MVTBuffer(row.get(0)))
+    }
+}
+
+/**
+Analyzes the connectivity of a routing network: its connected components, the number of edge ends at each vertex, and the edges that repeat another between the same vertices along the same geometry.
+
+# Arguments
+
+* `pool` - A PostgreSQL connection pool.
+* `table` - The edge table, with `start_node` and `end_node` columns.
+
+# Returns
+
+A `Result` containing the `NetworkAnalysis`, or an `AppError` if the table has no geometry or primary key, or a query fails.
+*/
+pub async fn analyze_network(pool: &PgPool, table: &Table) -> Result<NetworkAnalysis, AppError> {
+    let (geom_col, pk_col) = get_edge_columns(table)?;
+    let use_geog = if table.use_geog { "::geography" } else { "" };
+
+    let edges = query_as::<Postgres, NetworkEdge>(&format!(
+        "select t.{pk}::int4 as id, t.start_node::int4 as start_node, t.end_node::int4 as end_node,
+            coalesce(ST_Length(t.{geom}{geog}), 0)::float8 as length
+        from {schema}.{table} t
+        where t.start_node is not null and t.end_node is not null",
+        pk = pk_col,
+        geom = geom_col,
+        geog = use_geog,
+        schema = table.schema_name,
+        table = table.name
+    ))
+    .fetch_all(pool)
+    .await
+    .map_err(|e| AppError(anyhow!("Failed to read the network's edges: {}", e)))?;
+
+    let unnoded_edges = query_as::<Postgres, (i64,)>(&format!(
+        "select count(*) from {}.{} t where t.start_node is null or t.end_node is null",
+        table.schema_name, table.name
+    ))
+    .fetch_one(pool)
+    .await
+    .map_err(|e| AppError(anyhow!(e)))?
+    .0;
+
+    //Lines are normalized so that an edge repeated in the opposite direction is also found
+    let duplicates = query_as::<Postgres, (Vec<i32>,)>(&format!(
+        "select array_agg(t.{pk}::int4 order by t.{pk}) as edges
+        from {schema}.{table} t
+        where t.start_node is not null and t.end_node is not null
+        group by least(t.start_node, t.end_node), greatest(t.start_node, t.end_node),
+            ST_AsBinary(ST_Normalize(t.{geom}))
+        having count(*) > 1
+        order by min(t.{pk})",
+        pk = pk_col,
+        geom = geom_col,
+        schema = table.schema_name,
+        table = table.name
+    ))
+    .fetch_all(pool)
+    .await
+    .map_err(|e| AppError(anyhow!("Failed to find duplicate edges: {}", e)))?
+    .into_iter()
+    .map(|(group,)| group)
+    .collect::<Vec<Vec<i32>>>();
+
+    Ok(NetworkAnalysis::new(edges, duplicates, unnoded_edges))
+}
+
+/**
+The analyses of routing networks, computed once per table and shared between requests until the table is edited or its preloaded network is reloaded.
+*/
+#[derive(Clone, Default)]
+pub struct NetworkAnalyses {
+    analyses: Arc<RwLock<HashMap<String, Arc<NetworkAnalysis>>>>,
+}
+
+impl NetworkAnalyses {
+    /**
+    Returns the cached analysis of a table, analyzing the network first if it has not been, or has changed since.
+
+    # Errors
+
+    - If the network cannot be analyzed, returns an `AppError`, as `analyze_network` does.
+    */
+    pub async fn get_or_analyze(
+        &self,
+        pool: &PgPool,
+        table: &Table,
+    ) -> Result<Arc<NetworkAnalysis>, AppError> {
+        let key = format!("{}.{}", table.schema_name, table.name);
+        if let Some(analysis) = self
+            .analyses
+            .read()
+            .ok()
+            .and_then(|analyses| analyses.get(&key).cloned())
+        {
+            return Ok(analysis);
+        }
+
+        let analysis = Arc::new(analyze_network(pool, table).await?);
+        if let Ok(mut analyses) = self.analyses.write() {
+            analyses.insert(key, analysis.clone());
+        }
+        Ok(analysis)
+    }
+
+    /**
+    Discards the cached analysis of a table, so that the next request analyzes it again.
+    */
+    pub fn invalidate(&self, schema: &str, table: &str) {
+        if let Ok(mut analyses) = self.analyses.write() {
+            analyses.remove(&format!("{}.{}", schema, table));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn edge(id: i32, start_node: i32, end_node: i32, length: f64) -> NetworkEdge {
+        NetworkEdge {
+            id,
+            start_node,
+            end_node,
+            length,
+        }
+    }
+
+    fn roads() -> Table {
+        Table::new(
+            "roads".to_string(),
+            "public".to_string(),
+            vec!["id".to_string()],
+            "geom".to_string(),
+            "LINESTRING".to_string(),
+            4326,
+            None,
+            "deg".to_string(),
+            true,
+        )
+    }
+
+    //A triangle 1-2-3 with a spur to 4 and on to 5 by a zero-length edge, a loop at 2 and a
+    //duplicate of 1-2, and an island 10-11 cut off from it all
+    fn analysis() -> NetworkAnalysis {
+        NetworkAnalysis::new(
+            vec![
+                edge(1, 1, 2, 10.0),
+                edge(2, 2, 3, 10.0),
+                edge(3, 3, 1, 10.0),
+                edge(4, 3, 4, 10.0),
+                edge(5, 2, 2, 5.0),
+                edge(6, 2, 1, 10.0),
+                edge(7, 4, 5, 0.0),
+                edge(8, 10, 11, 3.0),
+            ],
+            vec![vec![1, 6]],
+            2,
+        )
+    }
+
+    #[test]
+    fn problems_are_found_by_kind() {
+        let analysis = analysis();
+        assert_eq!(analysis.dead_ends(), vec![5, 10, 11]);
+        assert_eq!(analysis.self_loops(), vec![5]);
+        assert_eq!(analysis.zero_length_edges(), vec![7]);
+        assert_eq!(analysis.island_edges(), (vec![8], vec![2]));
+        assert_eq!(analysis.component_of(4), Some(1));
+        assert_eq!(analysis.component_of(11), Some(2));
+        assert_eq!(analysis.component_of(99), None);
+    }
+
+    #[test]
+    fn report_summarizes_the_network() {
+        let report = analysis().report(&roads());
+        assert_eq!(report.edges, 8);
+        assert_eq!(report.vertices, 7);
+        assert_eq!(report.unnoded_edges, 2);
+        assert_eq!(report.components, 2);
+        assert!((report.largest_component_share - 55.0 / 58.0).abs() < 1e-9);
+        assert_eq!(report.disconnected.len(), 1);
+        assert_eq!(report.disconnected[0].component, 2);
+        assert_eq!(report.disconnected[0].length, 3.0);
+        assert_eq!(
+            report.degree_distribution,
+            BTreeMap::from([(1, 3), (2, 1), (3, 2), (5, 1)])
+        );
+        assert_eq!(report.dead_ends.items, vec![5, 10, 11]);
+        assert_eq!(report.self_loops.items, vec![5]);
+        assert_eq!(report.duplicate_edges.items, vec![vec![1, 6]]);
+        assert_eq!(report.zero_length_edges.items, vec![7]);
+        //Lengths of geographic tables are measured as geography
+        assert_eq!(report.units.as_deref(), Some("m"));
+    }
+
+    #[test]
+    fn problem_lists_are_truncated_but_counted() {
+        let problems = ProblemList::new(&(0..150).collect::<Vec<i32>>());
+        assert_eq!(problems.count, 150);
+        assert_eq!(problems.items.len(), MAX_LISTED_PROBLEMS);
+        assert_eq!(problems.items[0], 0);
+    }
+
+    #[test]
+    fn overlay_binds_match_the_problems_query() {
+        let [island_ids, island_components, dead_ends, self_loops, duplicates, zero_length] =
+            analysis().overlay_binds();
+        assert_eq!(island_ids, vec![8]);
+        assert_eq!(island_components, vec![2]);
+        assert_eq!(dead_ends, vec![5, 10, 11]);
+        assert_eq!(self_loops, vec![5]);
+        assert_eq!(duplicates, vec![1, 6]);
+        assert_eq!(zero_length, vec![7]);
+
+        //Each kind of problem reads the parameters bound for it above
+        let cte = make_problems_cte(&roads(), None).unwrap();
+        let problems = cte.split("problems as (").nth(1).unwrap();
+        let expected = [
+            ("'island'", &["$1::int4[]", "$2::int4[]"][..]),
+            ("'dead_end'", &["$3::int4[]"]),
+            ("'self_loop'", &["$4::int4[]"]),
+            ("'duplicate'", &["$5::int4[]"]),
+            ("'zero_length'", &["$6::int4[]"]),
+        ];
+        let selects = problems.split("union all").collect::<Vec<&str>>();
+        assert_eq!(selects.len(), expected.len());
+        for (select, (problem, params)) in selects.iter().zip(expected) {
+            assert!(select.contains(problem), "{}", select);
+            for param in params {
+                assert!(select.contains(param), "{} in {}", param, select);
+            }
+            assert_eq!(select.matches('$').count(), params.len(), "{}", select);
+        }
+    }
+}
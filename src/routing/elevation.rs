@@ -0,0 +1,124 @@
+use crate::db::Table;
+use anyhow::{anyhow, Result};
+use std::env::var;
+
+/**
+A raster of elevations, such as a DEM, used to add elevations to tables without Z coordinates. Elevations are read from the first band.
+*/
+#[derive(Clone, Debug)]
+pub struct ElevationRaster {
+    schema: String,
+    table: String,
+    column: String,
+}
+
+impl ElevationRaster {
+    /**
+    Reads the raster named by the `ELEVATION_RASTER` environment variable, in the form `schema.table` or `schema.table.column`. The column defaults to `rast`. Returns `None` when the variable is unset.
+
+    # Errors
+
+    - If the variable is set but is not in one of the expected forms, returns an `anyhow::Error`.
+    */
+    pub fn from_env() -> Result<Option<ElevationRaster>> {
+        let raster = match var("ELEVATION_RASTER") {
+            Ok(raster) if !raster.is_empty() => raster,
+            _ => return Ok(None),
+        };
+
+        match raster.split('.').collect::<Vec<&str>>().as_slice() {
+            [schema, table] => Ok(Some(ElevationRaster {
+                schema: schema.to_string(),
+                table: table.to_string(),
+                column: "rast".to_string(),
+            })),
+            [schema, table, column] => Ok(Some(ElevationRaster {
+                schema: schema.to_string(),
+                table: table.to_string(),
+                column: column.to_string(),
+            })),
+            _ => Err(anyhow!("Invalid ELEVATION_RASTER: {}", raster)),
+        }
+    }
+}
+
+/**
+Where the elevations of a table's edges come from: their own Z coordinates, a raster, or nowhere.
+*/
+#[derive(Clone, Debug)]
+pub enum ElevationSource {
+    None,
+    Geometry,
+    Raster(ElevationRaster),
+}
+
+impl ElevationSource {
+    /**
+    Chooses the elevation source for a table, preferring Z coordinates over the configured raster.
+    */
+    pub fn for_table(table: &Table, raster: Option<&ElevationRaster>) -> ElevationSource {
+        let is_measured = table
+            .geom_type
+            .as_ref()
+            .is_some_and(|geom_type| geom_type.ends_with('M'));
+        let has_z = match table.geom_coord_dimension {
+            Some(4) => true,
+            Some(3) => !is_measured,
+            _ => false,
+        };
+
+        match (has_z, raster) {
+            (true, _) => ElevationSource::Geometry,
+            (false, Some(raster)) => ElevationSource::Raster(raster.clone()),
+            (false, None) => ElevationSource::None,
+        }
+    }
+
+    pub fn is_available(&self) -> bool {
+        !matches!(self, ElevationSource::None)
+    }
+
+    /**
+    Builds an SQL expression for the elevation at a point, given as an SQL expression in the table's SRID. Returns `None` when no elevations are available.
+    */
+    pub fn make_point_elevation(&self, point: &str) -> Option<String> {
+        match self {
+            ElevationSource::None => None,
+            ElevationSource::Geometry => Some(format!("ST_Z({})", point)),
+            ElevationSource::Raster(raster) => Some(format!(
+                "(select ST_Value(r.{col}, ST_Transform({pt}, ST_SRID(r.{col})))
+                from {schema}.{table} r
+                where ST_Intersects(r.{col}, ST_Transform({pt}, ST_SRID(r.{col})))
+                limit 1)",
+                col = raster.column,
+                pt = point,
+                schema = raster.schema,
+                table = raster.table
+            )),
+        }
+    }
+
+    /**
+    Builds a lateral join, aliased `elev`, giving the total `ascent` and `descent` along each edge of a table aliased as `t`, in its digitised direction. Both are zero when no elevations are available.
+    */
+    pub fn make_edge_gain_join(&self, geom_col: &str) -> String {
+        match self.make_point_elevation("dp.geom") {
+            Some(elevation) => format!(
+                "left join lateral (
+                    select
+                        coalesce(sum(greatest(z.d, 0)), 0)::float8 as ascent,
+                        coalesce(sum(greatest(-z.d, 0)), 0)::float8 as descent
+                    from (
+                        select {elev} - lag({elev}) over (order by dp.path) as d
+                        from ST_DumpPoints(ST_GeometryN(ST_LineMerge(ST_Multi(t.{geom})), 1)) dp
+                    ) z
+                ) elev on true",
+                elev = elevation,
+                geom = geom_col
+            ),
+            None => {
+                "cross join (select 0::float8 as ascent, 0::float8 as descent) elev".to_string()
+            }
+        }
+    }
+}
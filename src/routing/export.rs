@@ -0,0 +1,376 @@
+use crate::web_mercator_to_lon_lat;
+use axum::{
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use reqwest::header::{ACCEPT, CONTENT_TYPE};
+use serde_json::Value;
+
+const GPX_MEDIA_TYPE: &str = "application/gpx+xml";
+const KML_MEDIA_TYPE: &str = "application/vnd.google-earth.kml+xml";
+
+pub struct GPXBuffer(String);
+
+impl IntoResponse for GPXBuffer {
+    fn into_response(self) -> axum::response::Response {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, GPX_MEDIA_TYPE.parse().unwrap());
+        (headers, self.0).into_response()
+    }
+}
+
+pub struct KMLBuffer(String);
+
+impl IntoResponse for KMLBuffer {
+    fn into_response(self) -> axum::response::Response {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, KML_MEDIA_TYPE.parse().unwrap());
+        (headers, self.0).into_response()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RouteFormat {
+    GeoJson,
+    Gpx,
+    Kml,
+}
+
+impl RouteFormat {
+    /**
+    Chooses the format of a routing response. A format given in the request path (`geojson`, `gpx` or `kml`) takes precedence over the `Accept` header, and GeoJSON is used when neither asks for anything else.
+
+    # Errors
+
+    - If the path gives any other format, returns a `400 Bad Request` rejection.
+    */
+    pub fn from_request(
+        format: Option<&str>,
+        headers: &HeaderMap,
+    ) -> Result<RouteFormat, (StatusCode, String)> {
+        if let Some(format) = format {
+            return match format.trim_start_matches('.') {
+                "geojson" | "json" => Ok(RouteFormat::GeoJson),
+                "gpx" => Ok(RouteFormat::Gpx),
+                "kml" => Ok(RouteFormat::Kml),
+                _ => Err((
+                    StatusCode::BAD_REQUEST,
+                    format!("Unsupported route format: {}", format),
+                )),
+            };
+        }
+
+        let accept = headers
+            .get(ACCEPT)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default();
+        if accept.contains(GPX_MEDIA_TYPE) {
+            Ok(RouteFormat::Gpx)
+        } else if accept.contains(KML_MEDIA_TYPE) {
+            Ok(RouteFormat::Kml)
+        } else {
+            Ok(RouteFormat::GeoJson)
+        }
+    }
+}
+
+struct ExportedLine {
+    name: String,
+    coordinates: Vec<(f64, f64, Option<f64>)>,
+}
+
+struct ExportedPoint {
+    name: String,
+    coordinate: (f64, f64, Option<f64>),
+}
+
+/**
+Converts a Web Mercator GeoJSON position into a WGS84 longitude, latitude and, if present, elevation.
+*/
+fn to_lon_lat(position: &Value) -> Option<(f64, f64, Option<f64>)> {
+    let position = position.as_array()?;
+    let (lon, lat) =
+        web_mercator_to_lon_lat(position.first()?.as_f64()?, position.get(1)?.as_f64()?);
+    Some((lon, lat, position.get(2).and_then(|z| z.as_f64())))
+}
+
+fn get_lines(collection: &Value) -> Vec<ExportedLine> {
+    collection["features"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter(|feature| feature["geometry"]["type"] == "LineString")
+        .enumerate()
+        .map(|(i, feature)| {
+            let properties = &feature["properties"];
+            let title = match properties["mode"].as_str() {
+                Some("out_and_back") => "Out and back",
+                Some("point_to_point") => "Route",
+                _ => "Circuit",
+            };
+            let name = match (properties["path"].as_str(), properties["leg"].as_u64()) {
+                (Some(path), _) => match properties["alternative"]["rank"].as_u64() {
+                    Some(rank) => format!("{} {} ({})", title, rank, path),
+                    None => format!("{} ({})", title, path),
+                },
+                (None, Some(leg)) => format!("Leg {}", leg + 1),
+                (None, None) => format!("Route {}", i + 1),
+            };
+            ExportedLine {
+                name,
+                coordinates: feature["geometry"]["coordinates"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .filter_map(to_lon_lat)
+                    .collect(),
+            }
+        })
+        .collect()
+}
+
+fn get_points(collection: &Value) -> Vec<ExportedPoint> {
+    let mut points = Vec::new();
+    if let Some(coordinate) = to_lon_lat(&collection["start"]["geometry"]["coordinates"]) {
+        points.push(ExportedPoint {
+            name: "Start".to_string(),
+            coordinate,
+        });
+    }
+    for (i, waypoint) in collection["waypoints"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .enumerate()
+    {
+        if let Some(coordinate) = to_lon_lat(&waypoint["geometry"]["coordinates"]) {
+            points.push(ExportedPoint {
+                name: format!("Waypoint {}", i + 1),
+                coordinate,
+            });
+        }
+    }
+    if let Some(coordinate) = to_lon_lat(&collection["end"]["geometry"]["coordinates"]) {
+        points.push(ExportedPoint {
+            name: "End".to_string(),
+            coordinate,
+        });
+    }
+    points
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/**
+Builds a GPX 1.1 document with a track per route and a waypoint for each start, waypoint or end.
+*/
+fn make_gpx(collection: &Value) -> String {
+    let mut gpx = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<gpx version=\"1.1\" creator=\"RustyMVT\" xmlns=\"http://www.topografix.com/GPX/1/1\">\n",
+    );
+
+    for point in get_points(collection) {
+        let (lon, lat, ele) = point.coordinate;
+        gpx.push_str(&format!("  <wpt lat=\"{}\" lon=\"{}\">", lat, lon));
+        if let Some(ele) = ele {
+            gpx.push_str(&format!("<ele>{}</ele>", ele));
+        }
+        gpx.push_str(&format!("<name>{}</name></wpt>\n", escape_xml(&point.name)));
+    }
+
+    for line in get_lines(collection) {
+        gpx.push_str(&format!(
+            "  <trk>\n    <name>{}</name>\n    <trkseg>\n",
+            escape_xml(&line.name)
+        ));
+        for (lon, lat, ele) in line.coordinates {
+            match ele {
+                Some(ele) => gpx.push_str(&format!(
+                    "      <trkpt lat=\"{}\" lon=\"{}\"><ele>{}</ele></trkpt>\n",
+                    lat, lon, ele
+                )),
+                None => gpx.push_str(&format!("      <trkpt lat=\"{}\" lon=\"{}\"/>\n", lat, lon)),
+            }
+        }
+        gpx.push_str("    </trkseg>\n  </trk>\n");
+    }
+
+    gpx.push_str("</gpx>\n");
+    gpx
+}
+
+fn format_kml_coordinate((lon, lat, ele): (f64, f64, Option<f64>)) -> String {
+    match ele {
+        Some(ele) => format!("{},{},{}", lon, lat, ele),
+        None => format!("{},{}", lon, lat),
+    }
+}
+
+/**
+Builds a KML document with a line placemark per route and a point placemark for each start, waypoint or end.
+*/
+fn make_kml(collection: &Value) -> String {
+    let mut kml = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<kml xmlns=\"http://www.opengis.net/kml/2.2\">\n<Document>\n",
+    );
+
+    for line in get_lines(collection) {
+        kml.push_str(&format!(
+            "  <Placemark>\n    <name>{}</name>\n    <LineString><coordinates>{}</coordinates></LineString>\n  </Placemark>\n",
+            escape_xml(&line.name),
+            line.coordinates
+                .into_iter()
+                .map(format_kml_coordinate)
+                .collect::<Vec<String>>()
+                .join(" ")
+        ));
+    }
+
+    for point in get_points(collection) {
+        kml.push_str(&format!(
+            "  <Placemark>\n    <name>{}</name>\n    <Point><coordinates>{}</coordinates></Point>\n  </Placemark>\n",
+            escape_xml(&point.name),
+            format_kml_coordinate(point.coordinate)
+        ));
+    }
+
+    kml.push_str("</Document>\n</kml>\n");
+    kml
+}
+
+/**
+Renders a routing response, built as a GeoJSON `FeatureCollection` in Web Mercator, in the requested format. GPX and KML output is converted to WGS84.
+*/
+pub fn render_routes(collection: Value, format: RouteFormat) -> Response {
+    match format {
+        RouteFormat::GeoJson => Json(collection).into_response(),
+        RouteFormat::Gpx => GPXBuffer(make_gpx(&collection)).into_response(),
+        RouteFormat::Kml => KMLBuffer(make_kml(&collection)).into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    //Web Mercator coordinates of roughly 1°E, 2°N, so that longitude and latitude differ
+    const X: f64 = 111_319.490_793_273_57;
+    const Y: f64 = 222_684.208_505_545_7;
+
+    fn accepting(media_type: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT, media_type.parse().unwrap());
+        headers
+    }
+
+    fn collection() -> Value {
+        json!({
+            "type": "FeatureCollection",
+            "features": [{
+                "type": "Feature",
+                "geometry": { "type": "LineString", "coordinates": [[0.0, 0.0, 10.0], [X, Y, 12.5]] },
+                "properties": { "mode": "circuit", "path": "Mill Lane & <Church Street>" }
+            }],
+            "start": { "type": "Feature", "geometry": { "type": "Point", "coordinates": [X, Y] } },
+        })
+    }
+
+    #[test]
+    fn format_in_path_takes_precedence_over_accept() {
+        let gpx = accepting(GPX_MEDIA_TYPE);
+        assert_eq!(
+            RouteFormat::from_request(Some(".kml"), &gpx).unwrap(),
+            RouteFormat::Kml
+        );
+        assert_eq!(
+            RouteFormat::from_request(Some("json"), &gpx).unwrap(),
+            RouteFormat::GeoJson
+        );
+        assert_eq!(
+            RouteFormat::from_request(None, &gpx).unwrap(),
+            RouteFormat::Gpx
+        );
+        assert_eq!(
+            RouteFormat::from_request(None, &accepting(&format!("{}, */*", KML_MEDIA_TYPE)))
+                .unwrap(),
+            RouteFormat::Kml
+        );
+        assert_eq!(
+            RouteFormat::from_request(None, &HeaderMap::new()).unwrap(),
+            RouteFormat::GeoJson
+        );
+
+        let (status, _) = RouteFormat::from_request(Some("shp"), &gpx).unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn gpx_gives_latitude_before_longitude() {
+        let (lon, lat) = web_mercator_to_lon_lat(X, Y);
+        assert!((lon - 1.0).abs() < 1e-9 && (lat - 2.0).abs() < 1e-6);
+
+        let gpx = make_gpx(&collection());
+        let document = roxmltree::Document::parse(&gpx).unwrap();
+        let trkpts = document
+            .descendants()
+            .filter(|n| n.has_tag_name("trkpt"))
+            .collect::<Vec<roxmltree::Node>>();
+        assert_eq!(trkpts.len(), 2);
+        assert_eq!(trkpts[1].attribute("lat"), Some(lat.to_string().as_str()));
+        assert_eq!(trkpts[1].attribute("lon"), Some(lon.to_string().as_str()));
+        assert_eq!(
+            trkpts
+                .iter()
+                .map(|n| n.children().find(|c| c.has_tag_name("ele")).unwrap().text())
+                .collect::<Vec<Option<&str>>>(),
+            vec![Some("10"), Some("12.5")]
+        );
+
+        //The start has no elevation, so is written without one
+        let wpt = document
+            .descendants()
+            .find(|n| n.has_tag_name("wpt"))
+            .unwrap();
+        assert_eq!(wpt.attribute("lat"), Some(lat.to_string().as_str()));
+        assert!(wpt.children().all(|c| !c.has_tag_name("ele")));
+    }
+
+    #[test]
+    fn kml_gives_longitude_before_latitude() {
+        let (lon, lat) = web_mercator_to_lon_lat(X, Y);
+        let kml = make_kml(&collection());
+        assert!(kml.contains(&format!(
+            "<LineString><coordinates>0,0,10 {},{},12.5</coordinates></LineString>",
+            lon, lat
+        )));
+        assert!(kml.contains(&format!(
+            "<Point><coordinates>{},{}</coordinates></Point>",
+            lon, lat
+        )));
+        roxmltree::Document::parse(&kml).unwrap();
+    }
+
+    #[test]
+    fn names_are_escaped() {
+        assert_eq!(
+            escape_xml(r#"Mill Lane & <Church "Street">"#),
+            "Mill Lane &amp; &lt;Church &quot;Street&quot;&gt;"
+        );
+
+        let gpx = make_gpx(&collection());
+        assert!(gpx.contains("<name>Circuit (Mill Lane &amp; &lt;Church Street&gt;)</name>"));
+        let document = roxmltree::Document::parse(&gpx).unwrap();
+        assert!(document
+            .descendants()
+            .any(|n| n.text() == Some("Circuit (Mill Lane & <Church Street>)")));
+        assert!(make_kml(&collection())
+            .contains("<name>Circuit (Mill Lane &amp; &lt;Church Street&gt;)</name>"));
+    }
+}
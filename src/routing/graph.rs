@@ -0,0 +1,651 @@
+use super::turns::TurnRules;
+use super::utils::TopoEdgeRepresentation;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+
+/**
+One step of a path through the graph: the edge taken, and the portion of its geometry covered, as fractions along the edge from `from_fraction` to `to_fraction`. A step with `from_fraction > to_fraction` runs against the edge's digitised direction.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraversedEdge {
+    pub edge_id: u32,
+    pub from_fraction: f64,
+    pub to_fraction: f64,
+    pub weight: f64,
+    pub length: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct GraphPath {
+    pub nodes: Vec<u32>,
+    pub edges: Vec<TraversedEdge>,
+    pub cost: f64,
+}
+
+/**
+A directed step between two nodes, with the headings on leaving `from` and on reaching `to`, where known.
+*/
+#[derive(Debug, Clone)]
+struct Adjacency {
+    from: u32,
+    to: u32,
+    departure: Option<f64>,
+    arrival: Option<f64>,
+    step: TraversedEdge,
+}
+
+//A search state: a node, and the step used to reach it, if any
+type Label = (u32, Option<usize>);
+
+#[derive(Debug, PartialEq)]
+struct QueueEntry {
+    cost: f64,
+    label: Label,
+}
+
+impl Eq for QueueEntry {}
+
+impl Ord for QueueEntry {
+    //Reversed so that `BinaryHeap` pops the cheapest entry first
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .cost
+            .total_cmp(&self.cost)
+            .then_with(|| self.label.cmp(&other.label))
+    }
+}
+
+impl PartialOrd for QueueEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+fn reverse_heading(heading: Option<f64>) -> Option<f64> {
+    heading.map(|h| (h + 180.0).rem_euclid(360.0))
+}
+
+/**
+An adjacency list over a routing subgraph. Edges are traversable in each direction with a non-negative weight, at the edge's forward and reverse weights respectively.
+
+Searches run over edges rather than nodes, so that turn restrictions and turn costs can be honoured: a node may be reached more cheaply along one edge, yet only be left onto the next edge from another.
+*/
+pub struct RoutingGraph {
+    steps: Vec<Adjacency>,
+    adjacency: HashMap<u32, Vec<usize>>,
+    turns: TurnRules,
+}
+
+impl RoutingGraph {
+    pub fn new(edges: &[TopoEdgeRepresentation], turns: &TurnRules) -> RoutingGraph {
+        let mut steps = Vec::with_capacity(edges.len() * 2);
+        for e in edges {
+            let (start_fraction, end_fraction) = e.span.unwrap_or((0.0, 1.0));
+            if e.weight >= 0.0 {
+                steps.push(Adjacency {
+                    from: e.start_node,
+                    to: e.end_node,
+                    departure: e.start_bearing,
+                    arrival: e.end_bearing,
+                    step: TraversedEdge {
+                        edge_id: e.edge_id,
+                        from_fraction: start_fraction,
+                        to_fraction: end_fraction,
+                        weight: e.weight,
+                        length: e.length,
+                    },
+                });
+            }
+            if e.reverse_weight >= 0.0 {
+                steps.push(Adjacency {
+                    from: e.end_node,
+                    to: e.start_node,
+                    departure: reverse_heading(e.end_bearing),
+                    arrival: reverse_heading(e.start_bearing),
+                    step: TraversedEdge {
+                        edge_id: e.edge_id,
+                        from_fraction: end_fraction,
+                        to_fraction: start_fraction,
+                        weight: e.reverse_weight,
+                        length: e.length,
+                    },
+                });
+            }
+        }
+
+        let mut adjacency: HashMap<u32, Vec<usize>> = HashMap::with_capacity(edges.len());
+        for (i, adj) in steps.iter().enumerate() {
+            adjacency.entry(adj.from).or_default().push(i);
+        }
+
+        RoutingGraph {
+            steps,
+            adjacency,
+            turns: turns.clone(),
+        }
+    }
+
+    /**
+    Costs the move from one step onto the next, including any turn between them. Returns `None` if the turn is forbidden.
+    */
+    fn transition_cost(&self, previous: Option<usize>, next: usize) -> Option<f64> {
+        let next_adj = &self.steps[next];
+        let turn = match previous {
+            Some(previous) => {
+                let previous_adj = &self.steps[previous];
+                self.turns.turn_cost(
+                    previous_adj.step.edge_id,
+                    previous_adj.arrival,
+                    next_adj.from,
+                    next_adj.step.edge_id,
+                    next_adj.departure,
+                )?
+            }
+            None => 0.0,
+        };
+        Some(next_adj.step.weight + turn)
+    }
+
+    /**
+    Runs Dijkstra's algorithm over the graph's steps from `source`, stopping once `target` is settled or, if given, once every state within `max_cost` has been settled.
+
+    Returns the cheapest cost of reaching each node, the step used to reach each state, and the state in which `target` was settled, if it was.
+    */
+    fn dijkstra(
+        &self,
+        source: u32,
+        target: Option<u32>,
+        max_cost: Option<f64>,
+    ) -> (HashMap<u32, f64>, HashMap<Label, Label>, Option<Label>) {
+        let mut node_costs: HashMap<u32, f64> = HashMap::new();
+        let mut costs: HashMap<Label, f64> = HashMap::new();
+        let mut previous: HashMap<Label, Label> = HashMap::new();
+        let mut queue = BinaryHeap::new();
+
+        let start = (source, None);
+        costs.insert(start, 0.0);
+        queue.push(QueueEntry {
+            cost: 0.0,
+            label: start,
+        });
+
+        while let Some(QueueEntry { cost, label }) = queue.pop() {
+            if costs.get(&label).is_some_and(|best| cost > *best) {
+                continue;
+            }
+            let (node, arrived_by) = label;
+            if node_costs.get(&node).is_none_or(|best| cost < *best) {
+                node_costs.insert(node, cost);
+            }
+            if Some(node) == target {
+                return (node_costs, previous, Some(label));
+            }
+
+            for next in self.adjacency.get(&node).into_iter().flatten() {
+                let Some(step_cost) = self.transition_cost(arrived_by, *next) else {
+                    continue;
+                };
+                let next_cost = cost + step_cost;
+                if max_cost.is_some_and(|max| next_cost > max) {
+                    continue;
+                }
+                let next_label = (self.steps[*next].to, Some(*next));
+                if costs.get(&next_label).is_none_or(|best| next_cost < *best) {
+                    costs.insert(next_label, next_cost);
+                    previous.insert(next_label, label);
+                    queue.push(QueueEntry {
+                        cost: next_cost,
+                        label: next_label,
+                    });
+                }
+            }
+        }
+
+        (node_costs, previous, None)
+    }
+
+    /**
+    Finds the cost of reaching every node no more than `max_cost` from `source`.
+    */
+    pub fn costs_within(&self, source: u32, max_cost: f64) -> HashMap<u32, f64> {
+        self.dijkstra(source, None, Some(max_cost)).0
+    }
+
+    /**
+    Builds a path from a sequence of steps, costing the turns between them.
+    */
+    fn path_from_steps(&self, source: u32, steps: &[usize]) -> Option<GraphPath> {
+        let mut nodes = vec![source];
+        let mut cost = 0.0;
+        let mut previous = None;
+        for step in steps {
+            cost += self.transition_cost(previous, *step)?;
+            nodes.push(self.steps[*step].to);
+            previous = Some(*step);
+        }
+
+        Some(GraphPath {
+            nodes,
+            edges: steps.iter().map(|i| self.steps[*i].step.clone()).collect(),
+            cost,
+        })
+    }
+
+    /**
+    Rebuilds a path from the sequence of nodes it visits, such as one produced by circuit search, taking the cheapest edge between each consecutive pair.
+
+    # Returns
+
+    The path, or `None` if any pair of consecutive nodes is not joined by a traversable edge, or the path makes a forbidden turn.
+    */
+    pub fn path_from_nodes(&self, nodes: &[u32]) -> Option<GraphPath> {
+        let mut steps = Vec::with_capacity(nodes.len().saturating_sub(1));
+        for pair in nodes.windows(2) {
+            let step = self
+                .adjacency
+                .get(&pair[0])?
+                .iter()
+                .filter(|i| self.steps[**i].to == pair[1])
+                .min_by(|a, b| {
+                    self.steps[**a]
+                        .step
+                        .weight
+                        .total_cmp(&self.steps[**b].step.weight)
+                })?;
+            steps.push(*step);
+        }
+
+        self.path_from_steps(*nodes.first()?, &steps)
+    }
+
+    /**
+    Finds the cheapest path between two nodes.
+
+    # Returns
+
+    The path, or `None` if `target` cannot be reached from `source`.
+    */
+    pub fn shortest_path(&self, source: u32, target: u32) -> Option<GraphPath> {
+        let (_, previous, settled) = self.dijkstra(source, Some(target), None);
+
+        let mut steps = Vec::new();
+        let mut current = settled?;
+        while let (_, Some(step)) = current {
+            steps.push(step);
+            current = *previous.get(&current)?;
+        }
+        steps.reverse();
+
+        self.path_from_steps(source, &steps)
+    }
+
+    /**
+    Appends a path starting where another ends, costing the turn where they meet.
+
+    # Returns
+
+    The joined path, or `None` if the turn where they meet is forbidden.
+    */
+    fn join(&self, first: GraphPath, second: GraphPath) -> Option<GraphPath> {
+        let steps = self.find_steps(&first).chain(self.find_steps(&second));
+        let steps = steps.collect::<Option<Vec<usize>>>()?;
+        self.path_from_steps(*first.nodes.first()?, &steps)
+    }
+
+    /**
+    Recovers the steps of a path found over this graph.
+    */
+    fn find_steps<'a>(&'a self, path: &'a GraphPath) -> impl Iterator<Item = Option<usize>> + 'a {
+        path.nodes
+            .windows(2)
+            .zip(path.edges.iter())
+            .map(|(pair, edge)| {
+                self.adjacency.get(&pair[0])?.iter().copied().find(|i| {
+                    let adj = &self.steps[*i];
+                    adj.to == pair[1] && adj.step == *edge
+                })
+            })
+    }
+
+    /**
+    Finds out-and-back paths whose cost is close to `target_cost`: the shortest path to a turning point roughly half that cost away, then the same edges back. The `upper` path turns at the nearest node at or beyond half the target and the `lower` path at the furthest node short of it.
+
+    Every edge must be traversable in both directions.
+    */
+    pub fn out_and_back_paths(
+        &self,
+        source: u32,
+        target_cost: f64,
+    ) -> Vec<(&'static str, GraphPath)> {
+        let costs = self.costs_within(source, target_cost);
+        let mut paths = Vec::with_capacity(2);
+        for (name, turn) in bracket_target(&costs, target_cost / 2.0, &[source]) {
+            let Some(out) = self.shortest_path(source, turn) else {
+                continue;
+            };
+            let back_nodes = out.nodes.iter().rev().copied().collect::<Vec<u32>>();
+            if let Some(path) = self
+                .path_from_nodes(&back_nodes)
+                .and_then(|back| self.join(out, back))
+            {
+                paths.push((name, path));
+            }
+        }
+        paths
+    }
+
+    /**
+    Finds paths from `source` to `destination` whose cost is close to `target_cost`, each passing through a via node chosen so that the shortest paths to and from it add up to roughly the target. The `upper` path is the cheapest at or above the target and the `lower` path the most expensive below it. If the shortest path between the two is already at least the target, it is returned on its own as the `upper` path.
+    */
+    pub fn stretched_paths(
+        &self,
+        source: u32,
+        destination: u32,
+        target_cost: f64,
+    ) -> Vec<(&'static str, GraphPath)> {
+        let Some(direct) = self.shortest_path(source, destination) else {
+            return Vec::new();
+        };
+        if direct.cost >= target_cost {
+            return vec![("upper", direct)];
+        }
+
+        //Costs from the destination stand in for costs to it, which match when weights are symmetric
+        let from_source = self.costs_within(source, target_cost);
+        let from_destination = self.costs_within(destination, target_cost);
+        let via_costs = from_source
+            .iter()
+            .filter_map(|(node, cost)| Some((*node, cost + from_destination.get(node)?)))
+            .collect::<HashMap<u32, f64>>();
+
+        let mut paths = Vec::with_capacity(2);
+        for (name, via) in bracket_target(&via_costs, target_cost, &[source, destination]) {
+            let path = self
+                .shortest_path(source, via)
+                .zip(self.shortest_path(via, destination))
+                .and_then(|(to_via, from_via)| self.join(to_via, from_via));
+            if let Some(path) = path {
+                paths.push((name, path));
+            }
+        }
+        if paths.is_empty() {
+            paths.push(("lower", direct));
+        }
+        paths
+    }
+}
+
+/**
+Picks the nodes whose costs most closely bracket a target: the cheapest at or above it, named `upper`, and the most expensive below it, named `lower`. Excluded nodes are never picked.
+*/
+fn bracket_target(
+    costs: &HashMap<u32, f64>,
+    target: f64,
+    excluded: &[u32],
+) -> Vec<(&'static str, u32)> {
+    let candidates = costs
+        .iter()
+        .filter(|(node, _)| !excluded.contains(node))
+        .map(|(node, cost)| (*node, *cost));
+    let upper = candidates
+        .clone()
+        .filter(|(_, cost)| *cost >= target)
+        .min_by(|a, b| a.1.total_cmp(&b.1).then_with(|| a.0.cmp(&b.0)));
+    let lower = candidates
+        .filter(|(_, cost)| *cost < target)
+        .max_by(|a, b| a.1.total_cmp(&b.1).then_with(|| b.0.cmp(&a.0)));
+
+    [("upper", upper), ("lower", lower)]
+        .into_iter()
+        .filter_map(|(name, node)| node.map(|(node, _)| (name, node)))
+        .collect()
+}
+
+/**
+Determines the edges, or parts of edges, that lie within `limit` of a search origin, given the cost of reaching each node from it.
+
+An edge is covered in full if the portions reachable from its two ends, each travelling at that direction's weight, add up to the whole edge. Directions that cannot be travelled contribute nothing. Otherwise, the portion reachable from each end is returned as a separate step running away from that end.
+*/
+pub fn reachable_portions(
+    edges: &[TopoEdgeRepresentation],
+    costs: &HashMap<u32, f64>,
+    limit: f64,
+) -> Vec<TraversedEdge> {
+    let mut portions = Vec::new();
+    for e in edges {
+        let (start_fraction, end_fraction) = e.span.unwrap_or((0.0, 1.0));
+        let reachable_from = |node: u32, weight: f64| match costs.get(&node) {
+            Some(cost) if *cost > limit || weight < 0.0 => 0.0,
+            Some(_) if weight <= 0.0 => 1.0,
+            Some(cost) => ((limit - cost) / weight).min(1.0),
+            None => 0.0,
+        };
+        let from_start = reachable_from(e.start_node, e.weight);
+        let from_end = reachable_from(e.end_node, e.reverse_weight);
+
+        if from_start + from_end >= 1.0 {
+            portions.push(TraversedEdge {
+                edge_id: e.edge_id,
+                from_fraction: start_fraction,
+                to_fraction: end_fraction,
+                weight: e.weight,
+                length: e.length,
+            });
+            continue;
+        }
+
+        let span = end_fraction - start_fraction;
+        if from_start > 0.0 {
+            portions.push(TraversedEdge {
+                edge_id: e.edge_id,
+                from_fraction: start_fraction,
+                to_fraction: start_fraction + span * from_start,
+                weight: e.weight * from_start,
+                length: e.length * from_start,
+            });
+        }
+        if from_end > 0.0 {
+            portions.push(TraversedEdge {
+                edge_id: e.edge_id,
+                from_fraction: end_fraction,
+                to_fraction: end_fraction - span * from_end,
+                weight: e.reverse_weight * from_end,
+                length: e.length * from_end,
+            });
+        }
+    }
+
+    portions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //1-2 both ways, 2-3 one way, 1-3 both ways by a long and a shorter edge, 3-4 closed in both
+    //directions and 4-5 cut off beyond it
+    fn network() -> RoutingGraph {
+        let edges = vec![
+            TopoEdgeRepresentation::new(1, 1, 2, 1.0, 1.0, 100.0),
+            TopoEdgeRepresentation::new(2, 2, 3, 1.0, -1.0, 100.0),
+            TopoEdgeRepresentation::new(3, 1, 3, 5.0, 5.0, 500.0),
+            TopoEdgeRepresentation::new(6, 3, 1, 3.0, 3.0, 300.0),
+            TopoEdgeRepresentation::new(4, 3, 4, -1.0, -1.0, 100.0),
+            TopoEdgeRepresentation::new(5, 4, 5, 1.0, 1.0, 100.0),
+        ];
+        RoutingGraph::new(&edges, &TurnRules::default())
+    }
+
+    fn edge_ids(path: &GraphPath) -> Vec<u32> {
+        path.edges.iter().map(|e| e.edge_id).collect()
+    }
+
+    #[test]
+    fn shortest_path_follows_one_way_edges() {
+        let graph = network();
+
+        let forward = graph.shortest_path(1, 3).unwrap();
+        assert_eq!(forward.nodes, vec![1, 2, 3]);
+        assert_eq!(edge_ids(&forward), vec![1, 2]);
+        assert_eq!(forward.cost, 2.0);
+
+        //Edge 2 cannot be taken back, so the return uses the shorter of the two direct edges
+        let back = graph.shortest_path(3, 1).unwrap();
+        assert_eq!(back.nodes, vec![3, 1]);
+        assert_eq!(edge_ids(&back), vec![6]);
+        assert_eq!(back.cost, 3.0);
+        assert_eq!(
+            (back.edges[0].from_fraction, back.edges[0].to_fraction),
+            (0.0, 1.0)
+        );
+
+        let against = graph.shortest_path(2, 1).unwrap();
+        assert_eq!(
+            (against.edges[0].from_fraction, against.edges[0].to_fraction),
+            (1.0, 0.0)
+        );
+    }
+
+    #[test]
+    fn impassable_edges_leave_targets_unreachable() {
+        let graph = network();
+        assert!(graph.shortest_path(1, 4).is_none());
+        assert!(graph.shortest_path(1, 5).is_none());
+        assert!(graph.shortest_path(1, 99).is_none());
+
+        let costs = graph.costs_within(1, f64::INFINITY);
+        assert_eq!(costs.len(), 3);
+        assert_eq!((costs[&1], costs[&2], costs[&3]), (0.0, 1.0, 2.0));
+        assert_eq!(graph.costs_within(5, f64::INFINITY).len(), 2);
+    }
+
+    #[test]
+    fn dijkstra_stops_at_target_or_cost_limit() {
+        let graph = network();
+
+        let (costs, _, settled) = graph.dijkstra(1, None, Some(1.5));
+        assert_eq!(costs, HashMap::from([(1, 0.0), (2, 1.0)]));
+        assert!(settled.is_none());
+
+        let (_, previous, settled) = graph.dijkstra(1, Some(2), None);
+        let (node, step) = settled.unwrap();
+        assert_eq!(node, 2);
+        assert_eq!(graph.steps[step.unwrap()].step.edge_id, 1);
+        assert_eq!(previous[&settled.unwrap()], (1, None));
+    }
+
+    #[test]
+    fn path_from_nodes_takes_cheapest_traversable_edge() {
+        let graph = network();
+
+        let direct = graph.path_from_nodes(&[1, 3]).unwrap();
+        assert_eq!(edge_ids(&direct), vec![6]);
+        assert_eq!(direct.cost, 3.0);
+
+        let around = graph.path_from_nodes(&[1, 2, 3, 1]).unwrap();
+        assert_eq!(edge_ids(&around), vec![1, 2, 6]);
+        assert_eq!(around.cost, 5.0);
+
+        assert!(graph.path_from_nodes(&[3, 2]).is_none());
+        assert!(graph.path_from_nodes(&[3, 4]).is_none());
+        assert!(graph.path_from_nodes(&[]).is_none());
+    }
+
+    #[test]
+    fn waypoint_legs_chain_into_one_path() {
+        let graph = network();
+        let legs = [2, 3, 1, 2]
+            .windows(2)
+            .map(|pair| graph.shortest_path(pair[0], pair[1]))
+            .collect::<Option<Vec<GraphPath>>>()
+            .unwrap();
+        let route = legs
+            .into_iter()
+            .reduce(|route, leg| graph.join(route, leg).unwrap())
+            .unwrap();
+        assert_eq!(route.nodes, vec![2, 3, 1, 2]);
+        assert_eq!(edge_ids(&route), vec![2, 6, 1]);
+        assert_eq!(route.cost, 5.0);
+
+        //A single unreachable waypoint fails the whole route
+        assert!([1, 3, 5]
+            .windows(2)
+            .map(|pair| graph.shortest_path(pair[0], pair[1]))
+            .collect::<Option<Vec<GraphPath>>>()
+            .is_none());
+    }
+
+    #[test]
+    fn edges_beyond_the_limit_are_partly_reachable() {
+        let edges = vec![
+            TopoEdgeRepresentation::new(1, 1, 2, 10.0, 10.0, 100.0),
+            TopoEdgeRepresentation::new(2, 2, 3, 10.0, 10.0, 100.0),
+            //Split at a point 40% along, and reached only from its start
+            TopoEdgeRepresentation {
+                span: Some((0.4, 1.0)),
+                ..TopoEdgeRepresentation::new(3, 3, 4, 20.0, 20.0, 200.0)
+            },
+        ];
+        let costs = HashMap::from([(1, 0.0), (2, 10.0), (3, 20.0)]);
+
+        let portions = reachable_portions(&edges, &costs, 15.0);
+        assert_eq!(portions.len(), 2);
+        assert_eq!(
+            portions[0],
+            TraversedEdge {
+                edge_id: 1,
+                from_fraction: 0.0,
+                to_fraction: 1.0,
+                weight: 10.0,
+                length: 100.0,
+            }
+        );
+        assert_eq!(portions[1].edge_id, 2);
+        assert_eq!(
+            (portions[1].from_fraction, portions[1].to_fraction),
+            (0.0, 0.5)
+        );
+        assert_eq!((portions[1].weight, portions[1].length), (5.0, 50.0));
+
+        let portions = reachable_portions(&edges, &costs, 25.0);
+        let split = portions.iter().find(|p| p.edge_id == 3).unwrap();
+        assert_eq!((split.from_fraction, split.to_fraction), (0.4, 0.55));
+        assert_eq!((split.weight, split.length), (5.0, 50.0));
+    }
+
+    #[test]
+    fn edges_reached_from_both_ends_are_covered_in_full() {
+        //Each end reaches 60% of the way, so the two portions meet
+        let edges = vec![TopoEdgeRepresentation::new(1, 1, 2, 10.0, 10.0, 100.0)];
+        let costs = HashMap::from([(1, 4.0), (2, 4.0)]);
+        let portions = reachable_portions(&edges, &costs, 10.0);
+        assert_eq!(portions.len(), 1);
+        assert_eq!(
+            (portions[0].from_fraction, portions[0].to_fraction),
+            (0.0, 1.0)
+        );
+
+        //Each end reaches only 30%, leaving a gap between two portions running inwards
+        let costs = HashMap::from([(1, 7.0), (2, 7.0)]);
+        let portions = reachable_portions(&edges, &costs, 10.0);
+        assert_eq!(portions.len(), 2);
+        assert_eq!(
+            (portions[1].from_fraction, portions[1].to_fraction),
+            (1.0, 0.7)
+        );
+    }
+
+    #[test]
+    fn unreachable_edges_are_left_out() {
+        let edges = vec![
+            //Neither end was reached
+            TopoEdgeRepresentation::new(1, 5, 6, 10.0, 10.0, 100.0),
+            //Its only reached end is already beyond the limit
+            TopoEdgeRepresentation::new(2, 1, 7, 10.0, 10.0, 100.0),
+            //Its reached end can only be entered, not left, along it
+            TopoEdgeRepresentation::new(3, 8, 2, 10.0, -1.0, 100.0),
+        ];
+        let costs = HashMap::from([(1, 20.0), (2, 0.0)]);
+        assert!(reachable_portions(&edges, &costs, 15.0).is_empty());
+    }
+}
@@ -0,0 +1,605 @@
+use super::graph::{GraphPath, RoutingGraph};
+use super::network::ground_distance;
+use super::snapping::{EdgeSplit, SPLIT_FRACTION_TOLERANCE, VIRTUAL_NODE_ID};
+use crate::db::Table;
+use crate::{lon_lat_to_web_mercator, AppError};
+use anyhow::anyhow;
+use geo_types::Point;
+use geojson::GeoJson;
+use serde::Serialize;
+use serde_json::Value;
+use sqlx::{query_as, PgPool, Postgres};
+
+//Number of nearby edges considered as matches for each track point
+const MATCH_CANDIDATE_EDGES: i64 = 5;
+//Longest track accepted, in points remaining after thinning
+pub const MAX_MATCH_POINTS: usize = 2000;
+//Routes between consecutive candidates longer than this multiple of the straight line between the
+//track points, plus twice the search radius, are not considered
+const MAX_ROUTE_FACTOR: f64 = 4.0;
+
+/**
+Reads the points of a recorded track, in Web Mercator, from a request body. GPX is read when the content type names GPX or XML, or the body looks like XML; its track points, or failing those its route points, are taken in order. Otherwise the body is read as GeoJSON: a `LineString` or `MultiLineString` geometry, or features or a collection of features holding them. As with the other routing endpoints, the GeoJSON may be sent as a JSON string. GeoJSON coordinates are in Web Mercator.
+
+# Errors
+
+- If the body cannot be parsed, or holds fewer than two points, returns an `AppError`.
+*/
+pub fn read_track(body: &str, content_type: Option<&str>) -> Result<Vec<Point<f64>>, AppError> {
+    let is_gpx = content_type.is_some_and(|t| t.contains("gpx") || t.contains("xml"))
+        || body.trim_start().starts_with('<');
+    let points = if is_gpx {
+        read_gpx_track(body)?
+    } else {
+        read_geojson_track(body)?
+    };
+
+    if points.len() < 2 {
+        return Err(AppError(anyhow!("A track requires at least two points")));
+    }
+    Ok(points)
+}
+
+fn read_gpx_track(body: &str) -> Result<Vec<Point<f64>>, AppError> {
+    let document = roxmltree::Document::parse(body)
+        .map_err(|e| AppError(anyhow!("Failed to parse GPX: {}", e)))?;
+    let points_tagged = |tag: &str| {
+        document
+            .descendants()
+            .filter(|n| n.is_element() && n.tag_name().name() == tag)
+            .map(|n| {
+                let coordinate = |name: &str| {
+                    n.attribute(name)
+                        .and_then(|v| v.trim().parse::<f64>().ok())
+                        .ok_or_else(|| AppError(anyhow!("Invalid GPX {} attribute", name)))
+                };
+                let (x, y) = lon_lat_to_web_mercator(coordinate("lon")?, coordinate("lat")?);
+                Ok(Point::new(x, y))
+            })
+            .collect::<Result<Vec<Point<f64>>, AppError>>()
+    };
+
+    let points = points_tagged("trkpt")?;
+    if points.is_empty() {
+        points_tagged("rtept")
+    } else {
+        Ok(points)
+    }
+}
+
+fn read_geojson_track(body: &str) -> Result<Vec<Point<f64>>, AppError> {
+    let value = serde_json::from_str::<Value>(body)
+        .map_err(|e| AppError(anyhow!("Failed to parse GeoJSON: {}", e)))?;
+    let value = match value {
+        Value::String(text) => serde_json::from_str::<Value>(&text)
+            .map_err(|e| AppError(anyhow!("Failed to parse GeoJSON: {}", e)))?,
+        value => value,
+    };
+    let geojson = GeoJson::from_json_value(value).map_err(|e| AppError(anyhow!(e)))?;
+
+    let geometries = match geojson {
+        GeoJson::Geometry(geometry) => vec![geometry],
+        GeoJson::Feature(feature) => feature.geometry.into_iter().collect(),
+        GeoJson::FeatureCollection(collection) => collection
+            .features
+            .into_iter()
+            .filter_map(|f| f.geometry)
+            .collect(),
+    };
+
+    let mut points = Vec::new();
+    for geometry in geometries {
+        let lines = match geometry.value {
+            geojson::Value::LineString(line) => vec![line],
+            geojson::Value::MultiLineString(lines) => lines,
+            _ => {
+                return Err(AppError(anyhow!(
+                    "Tracks must be LineString or MultiLineString geometries"
+                )))
+            }
+        };
+        for position in lines.into_iter().flatten() {
+            match position[..] {
+                [x, y, ..] => points.push(Point::new(x, y)),
+                _ => return Err(AppError(anyhow!("Invalid track coordinate"))),
+            }
+        }
+    }
+    Ok(points)
+}
+
+/**
+Drops track points closer than `min_distance` metres to the last point kept, as successive fixes that close together add noise rather than information. The first point is always kept.
+
+# Returns
+
+The points kept, with their positions in the original track.
+*/
+pub fn thin_track(points: &[Point<f64>], min_distance: f64) -> Vec<(usize, Point<f64>)> {
+    let mut kept: Vec<(usize, Point<f64>)> = Vec::with_capacity(points.len());
+    for (i, point) in points.iter().enumerate() {
+        let far_enough = kept.last().is_none_or(|(_, last)| {
+            ground_distance([last.x(), last.y()], [point.x(), point.y()]) >= min_distance
+        });
+        if far_enough {
+            kept.push((i, *point));
+        }
+    }
+    kept
+}
+
+#[derive(sqlx::FromRow, Debug)]
+struct CandidateRow {
+    point_index: i64,
+    edge_id: i32,
+    start_node: i32,
+    end_node: i32,
+    fraction: f64,
+    distance: f64,
+    x: f64,
+    y: f64,
+}
+
+/**
+A place on the network a track point may have been recorded at.
+
+* `edge_id` - The edge the point would lie on.
+* `fraction` - How far along the edge the point would lie.
+* `distance` - The distance from the recorded point, in the table's distance units.
+* `geometry` - The location on the edge, in Web Mercator.
+* `node_id` - The graph node standing for the location: an end of the edge, or a virtual node when the location splits the edge.
+* `split` - The split the location makes in its edge, if any, to be applied to the routing subgraph with `split_edges`.
+*/
+#[derive(Serialize, Debug, Clone)]
+pub struct MatchCandidate {
+    pub edge_id: u32,
+    pub fraction: f64,
+    pub distance: f64,
+    pub geometry: geojson::Geometry,
+    #[serde(skip)]
+    pub node_id: u32,
+    #[serde(skip)]
+    pub split: Option<EdgeSplit>,
+}
+
+/**
+Finds the edges near each track point that it may have been recorded on, nearest first.
+
+# Arguments
+
+* `pool` - A PostgreSQL connection pool.
+* `table` - A struct representing the database table.
+* `points` - The track points, in Web Mercator.
+* `radius` - The search radius around each point, in the table's distance units.
+
+# Returns
+
+A `Result` containing the candidates for each point, in the order of `points`, or an `AppError` if the query fails. Points with no edges within the radius have no candidates.
+*/
+pub async fn get_match_candidates(
+    pool: &PgPool,
+    table: &Table,
+    points: &[Point<f64>],
+    radius: f64,
+) -> Result<Vec<Vec<MatchCandidate>>, AppError> {
+    let (geom_col, srid, pk_col) = match (
+        table.geom_column.as_ref(),
+        table.srid.as_ref(),
+        table.primary_key_columns.first(),
+    ) {
+        (Some(geom_col), Some(srid), Some(pk_col)) => (geom_col, srid, pk_col),
+        _ => {
+            return Err(AppError(anyhow!(
+                "Table does not contain valid geometry data"
+            )))
+        }
+    };
+    let use_geog = if table.use_geog { "::geography" } else { "" };
+
+    let candidates_query = format!(
+        "select
+            p.idx - 1 as point_index,
+            c.edge_id,
+            c.start_node,
+            c.end_node,
+            c.fraction,
+            c.distance,
+            ST_X(ST_Transform(c.geom, 3857)) as x,
+            ST_Y(ST_Transform(c.geom, 3857)) as y
+        from
+            unnest($1::float8[], $2::float8[]) with ordinality as p(x, y, idx)
+            cross join lateral (
+                select ST_Transform(ST_SetSRID(ST_MakePoint(p.x, p.y), 3857), {srid}) as g
+            ) pt
+            cross join lateral (
+                select
+                    t.{pk}::int4 as edge_id,
+                    t.start_node::int4 as start_node,
+                    t.end_node::int4 as end_node,
+                    ST_LineLocatePoint(e.geom, pt.g) as fraction,
+                    ST_Distance(t.{geom}{g}, pt.g{g}) as distance,
+                    ST_ClosestPoint(e.geom, pt.g) as geom
+                from {schema}.{table} t
+                    cross join lateral (
+                        select ST_GeometryN(ST_LineMerge(ST_Multi(t.{geom})), 1) as geom
+                    ) e
+                where ST_DWithin(t.{geom}{g}, pt.g{g}, $3)
+                order by t.{geom} <-> pt.g
+                limit $4
+            ) c
+        order by p.idx, c.distance",
+        srid = srid,
+        pk = pk_col,
+        geom = geom_col,
+        schema = table.schema_name,
+        table = table.name,
+        g = use_geog
+    );
+
+    let rows = query_as::<Postgres, CandidateRow>(&candidates_query)
+        .bind(points.iter().map(|p| p.x()).collect::<Vec<f64>>())
+        .bind(points.iter().map(|p| p.y()).collect::<Vec<f64>>())
+        .bind(radius)
+        .bind(MATCH_CANDIDATE_EDGES)
+        .fetch_all(pool)
+        .await
+        .map_err(|e| AppError(anyhow!(e)))?;
+
+    let mut candidates: Vec<Vec<MatchCandidate>> = vec![Vec::new(); points.len()];
+    let mut next_virtual_node = VIRTUAL_NODE_ID;
+    for row in rows {
+        let (Ok(point_index), Ok(edge_id), Ok(start_node), Ok(end_node)) = (
+            usize::try_from(row.point_index),
+            u32::try_from(row.edge_id),
+            u32::try_from(row.start_node),
+            u32::try_from(row.end_node),
+        ) else {
+            continue;
+        };
+        //As when snapping, locations very close to an end of the edge are taken to be at it
+        let (node_id, split) = if row.fraction <= SPLIT_FRACTION_TOLERANCE {
+            (start_node, None)
+        } else if row.fraction >= 1.0 - SPLIT_FRACTION_TOLERANCE {
+            (end_node, None)
+        } else {
+            let node_id = next_virtual_node;
+            next_virtual_node -= 1;
+            let split = EdgeSplit {
+                edge_id,
+                node_id,
+                fraction: row.fraction,
+            };
+            (node_id, Some(split))
+        };
+
+        if let Some(point_candidates) = candidates.get_mut(point_index) {
+            point_candidates.push(MatchCandidate {
+                edge_id,
+                fraction: row.fraction,
+                distance: row.distance,
+                geometry: geojson::Geometry::new(geojson::Value::from(&Point::new(row.x, row.y))),
+                node_id,
+                split,
+            });
+        }
+    }
+
+    Ok(candidates)
+}
+
+/**
+Parameters of the hidden Markov model used to match a track, all in the table's distance units.
+
+* `accuracy` - The standard deviation of GPS error, which scales how quickly a candidate's likelihood falls with its distance from the recorded point.
+* `transition_scale` - The scale of the differences between the distance travelled over the network between two candidates and the straight-line distance between their track points, which scales how quickly a transition's likelihood falls as its route becomes more roundabout.
+* `radius` - The search radius used for candidates.
+*/
+pub struct MatchModel {
+    pub accuracy: f64,
+    pub transition_scale: f64,
+    pub radius: f64,
+}
+
+//A point of the segment being matched: its position and, for each of its candidates, the
+//log-likelihood of the best sequence ending there and that sequence's previous candidate
+type Layer = (usize, Vec<(f64, Option<usize>)>);
+
+/**
+Matches a track onto a routing graph with the Viterbi algorithm, after Newson and Krumm: each point is emitted by one of its candidates, with a likelihood falling off normally with its distance from the point, and moving between the candidates of consecutive points is likelier the closer its route's length is to the straight-line distance between the points.
+
+Where no route joins any candidate of a point to any candidate of the next, or a point has no candidates, the track is broken there and matching starts afresh, so a track wandering off the network yields several matched segments.
+
+# Arguments
+
+* `graph` - The routing graph, with every candidate's split applied.
+* `candidates` - The candidates for each track point.
+* `straight_distances` - The straight-line distance from each track point to the next, in the table's distance units.
+* `model` - The model parameters.
+
+# Returns
+
+The matched segments, each a sequence of pairs of a track point's position in `candidates` and the position of its chosen candidate. Segments of a single point are dropped.
+*/
+pub fn match_track(
+    graph: &RoutingGraph,
+    candidates: &[Vec<MatchCandidate>],
+    straight_distances: &[f64],
+    model: &MatchModel,
+) -> Vec<Vec<(usize, usize)>> {
+    let mut segments = Vec::new();
+    let mut layers: Vec<Layer> = Vec::new();
+
+    for (i, point_candidates) in candidates.iter().enumerate() {
+        if point_candidates.is_empty() {
+            close_segment(&mut layers, &mut segments);
+            continue;
+        }
+        let emissions = point_candidates
+            .iter()
+            .map(|c| -0.5 * (c.distance / model.accuracy).powi(2))
+            .collect::<Vec<f64>>();
+
+        let mut layer = vec![(f64::NEG_INFINITY, None); point_candidates.len()];
+        if let Some((previous_i, previous_layer)) = layers.last() {
+            let straight = straight_distances[*previous_i..i].iter().sum::<f64>();
+            let max_route = straight * MAX_ROUTE_FACTOR + 2.0 * model.radius;
+            for (a, previous) in candidates[*previous_i].iter().enumerate() {
+                let (score, _) = previous_layer[a];
+                if score == f64::NEG_INFINITY {
+                    continue;
+                }
+                let costs = graph.costs_within(previous.node_id, max_route);
+                for (b, candidate) in point_candidates.iter().enumerate() {
+                    if let Some(route) = costs.get(&candidate.node_id) {
+                        let transition = -(route - straight).abs() / model.transition_scale;
+                        let total = score + transition + emissions[b];
+                        if total > layer[b].0 {
+                            layer[b] = (total, Some(a));
+                        }
+                    }
+                }
+            }
+        }
+
+        if layer.iter().all(|(score, _)| *score == f64::NEG_INFINITY) {
+            close_segment(&mut layers, &mut segments);
+            layer = emissions.into_iter().map(|e| (e, None)).collect();
+        }
+        layers.push((i, layer));
+    }
+    close_segment(&mut layers, &mut segments);
+
+    segments
+}
+
+/**
+Traces the likeliest sequence of candidates back through a segment's layers, and starts a new segment.
+*/
+fn close_segment(layers: &mut Vec<Layer>, segments: &mut Vec<Vec<(usize, usize)>>) {
+    let finished = std::mem::take(layers);
+    if finished.len() < 2 {
+        return;
+    }
+
+    let Some(mut chosen) = finished.last().and_then(|(_, layer)| {
+        layer
+            .iter()
+            .enumerate()
+            .max_by(|a, b| a.1 .0.total_cmp(&b.1 .0))
+            .map(|(b, _)| b)
+    }) else {
+        return;
+    };
+
+    let mut segment = Vec::with_capacity(finished.len());
+    for (i, layer) in finished.iter().rev() {
+        segment.push((*i, chosen));
+        match layer[chosen].1 {
+            Some(previous) => chosen = previous,
+            None => break,
+        }
+    }
+    segment.reverse();
+    segments.push(segment);
+}
+
+/**
+Joins the matched candidates of a segment into a single path, following the shortest route between each consecutive pair.
+
+# Returns
+
+The path, or `None` if consecutive candidates are not connected.
+*/
+pub fn segment_path(
+    graph: &RoutingGraph,
+    candidates: &[Vec<MatchCandidate>],
+    segment: &[(usize, usize)],
+) -> Option<GraphPath> {
+    let first = segment.first()?;
+    let mut path = GraphPath {
+        nodes: vec![candidates[first.0][first.1].node_id],
+        edges: Vec::new(),
+        cost: 0.0,
+    };
+    for pair in segment.windows(2) {
+        let from = &candidates[pair[0].0][pair[0].1];
+        let to = &candidates[pair[1].0][pair[1].1];
+        let leg = graph.shortest_path(from.node_id, to.node_id)?;
+        path.nodes.extend(leg.nodes.into_iter().skip(1));
+        path.edges.extend(leg.edges);
+        path.cost += leg.cost;
+    }
+    Some(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::routing::turns::TurnRules;
+    use crate::routing::utils::TopoEdgeRepresentation;
+
+    //A three by three grid of nodes 100 m apart, numbered 1 to 9 row by row from the top left
+    fn grid() -> RoutingGraph {
+        let node = |row: u32, col: u32| row * 3 + col + 1;
+        let mut edges = Vec::new();
+        for row in 0..3 {
+            for col in 0..3 {
+                if col < 2 {
+                    edges.push((node(row, col), node(row, col + 1)));
+                }
+                if row < 2 {
+                    edges.push((node(row, col), node(row + 1, col)));
+                }
+            }
+        }
+        let edges = edges
+            .into_iter()
+            .enumerate()
+            .map(|(i, (a, b))| TopoEdgeRepresentation::new(i as u32 + 1, a, b, 100.0, 100.0, 100.0))
+            .collect::<Vec<TopoEdgeRepresentation>>();
+        RoutingGraph::new(&edges, &TurnRules::default())
+    }
+
+    fn at_node(node_id: u32, distance: f64) -> MatchCandidate {
+        MatchCandidate {
+            edge_id: 0,
+            fraction: 0.0,
+            distance,
+            geometry: geojson::Geometry::new(geojson::Value::Point(vec![0.0, 0.0])),
+            node_id,
+            split: None,
+        }
+    }
+
+    const MODEL: MatchModel = MatchModel {
+        accuracy: 20.0,
+        transition_scale: 10.0,
+        radius: 50.0,
+    };
+
+    #[test]
+    fn noisy_track_follows_the_network() {
+        let graph = grid();
+        //Driving along the top row and down the right-hand side, the third fix drifts nearer
+        //node 6 than the corner it was recorded at, but only the corner keeps the route direct
+        let candidates = vec![
+            vec![at_node(1, 5.0), at_node(4, 95.0)],
+            vec![at_node(2, 10.0)],
+            vec![at_node(6, 25.0), at_node(3, 30.0)],
+            vec![at_node(6, 8.0), at_node(5, 92.0)],
+            vec![at_node(9, 12.0)],
+        ];
+        let segments = match_track(&graph, &candidates, &[100.0; 4], &MODEL);
+        assert_eq!(segments, vec![vec![(0, 0), (1, 0), (2, 1), (3, 0), (4, 0)]]);
+
+        let path = segment_path(&graph, &candidates, &segments[0]).unwrap();
+        assert_eq!(path.nodes, vec![1, 2, 3, 6, 9]);
+        assert_eq!(path.cost, 400.0);
+    }
+
+    #[test]
+    fn track_breaks_where_the_network_is_left() {
+        let graph = grid();
+        let candidates = vec![
+            vec![at_node(1, 5.0)],
+            vec![at_node(2, 5.0)],
+            Vec::new(),
+            vec![at_node(8, 5.0)],
+            vec![at_node(9, 5.0)],
+            //Far beyond any route the straight-line distance allows
+            vec![at_node(7, 5.0)],
+        ];
+        let segments = match_track(
+            &graph,
+            &candidates,
+            &[100.0, 100.0, 100.0, 100.0, 10.0],
+            &MODEL,
+        );
+        assert_eq!(segments, vec![vec![(0, 0), (1, 0)], vec![(3, 0), (4, 0)]]);
+    }
+
+    #[test]
+    fn close_fixes_are_thinned() {
+        let points = [0.0, 3.0, 10.0, 12.0, 30.0]
+            .into_iter()
+            .map(|x| Point::new(x, 0.0))
+            .collect::<Vec<Point<f64>>>();
+        let kept = thin_track(&points, 5.0)
+            .into_iter()
+            .map(|(i, _)| i)
+            .collect::<Vec<usize>>();
+        assert_eq!(kept, vec![0, 2, 4]);
+        assert_eq!(thin_track(&points, 0.0).len(), 5);
+    }
+
+    #[test]
+    fn gpx_tracks_are_read_in_order() {
+        let gpx = r#"<?xml version="1.0"?>
+            <gpx version="1.1" xmlns="http://www.topografix.com/GPX/1/1">
+              <trk><trkseg>
+                <trkpt lat="0" lon="0"><ele>12</ele></trkpt>
+                <trkpt lat="0.001" lon="0.002"/>
+              </trkseg></trk>
+            </gpx>"#;
+        let points = read_track(gpx, None).unwrap();
+        assert_eq!(points.len(), 2);
+        let (x, y) = lon_lat_to_web_mercator(0.002, 0.001);
+        assert!(points[0].x().abs() < 1e-6 && points[0].y().abs() < 1e-6);
+        assert_eq!(points[1], Point::new(x, y));
+
+        //Route points stand in for a missing track
+        let route = r#"<gpx><rte><rtept lat="1" lon="1"/><rtept lat="2" lon="2"/></rte></gpx>"#;
+        assert_eq!(
+            read_track(route, Some("application/gpx+xml"))
+                .unwrap()
+                .len(),
+            2
+        );
+    }
+
+    #[test]
+    fn geojson_tracks_are_read_in_order() {
+        let line = r#"{"type": "LineString", "coordinates": [[0, 0], [10, 5, 3]]}"#;
+        assert_eq!(
+            read_track(line, None).unwrap(),
+            vec![Point::new(0.0, 0.0), Point::new(10.0, 5.0)]
+        );
+
+        let collection = serde_json::json!({
+            "type": "FeatureCollection",
+            "features": [
+                {"type": "Feature", "properties": {}, "geometry": {"type": "MultiLineString", "coordinates": [[[0, 0], [1, 1]], [[2, 2]]]}},
+                {"type": "Feature", "properties": {}, "geometry": null},
+                {"type": "Feature", "properties": {}, "geometry": {"type": "LineString", "coordinates": [[3, 3]]}}
+            ]
+        })
+        .to_string();
+        assert_eq!(
+            read_track(&collection, Some("application/json"))
+                .unwrap()
+                .len(),
+            4
+        );
+
+        //As with the other routing endpoints, the GeoJSON may arrive as a JSON string
+        let quoted = serde_json::to_string(line).unwrap();
+        assert_eq!(read_track(&quoted, None).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn malformed_tracks_are_rejected() {
+        for (body, content_type) in [
+            ("<gpx><trk><trkpt lat=\"0\" lon=\"0\"></trk></gpx>", None),
+            ("<gpx><trk><trkpt lat=\"north\" lon=\"0\"/><trkpt lat=\"1\" lon=\"1\"/></trk></gpx>", None),
+            ("<gpx><trk><trkpt lat=\"0\" lon=\"0\"/></trk></gpx>", None),
+            ("{\"type\": \"LineString\", \"coordinates\": [[0, 0]]}", None),
+            ("{\"type\": \"Point\", \"coordinates\": [0, 0]}", None),
+            ("{\"type\": \"LineString\", \"coordinates\": [[0, 0], [1]]}", None),
+            ("{\"type\": \"LineString\"", Some("application/geo+json")),
+            ("[[0, 0], [1, 1]]", None),
+            ("0,0 1,1", Some("application/gpx+xml")),
+        ] {
+            assert!(read_track(body, content_type).is_err(), "{}", body);
+        }
+    }
+}
@@ -0,0 +1,1075 @@
+use super::constraints::RoutingConstraints;
+use super::elevation::ElevationSource;
+use super::find_table;
+use super::graph::{RoutingGraph, TraversedEdge};
+use super::snapping::{
+    EdgeSplit, SnapMode, SnapTarget, SnappedPoint, SNAP_CANDIDATE_EDGES, SPLIT_FRACTION_TOLERANCE,
+};
+use super::turns::{get_turn_rules, TurnRules};
+use super::units::metres_to_table_distance;
+use super::utils::{make_weighted_edges_query, PathGeometry, TopoEdgeRepresentation};
+use crate::db::Table;
+use crate::{web_mercator_to_lon_lat, AppError, AppState};
+use anyhow::{anyhow, Context, Result};
+use geo_types::Point;
+use serde_json::{json, Value};
+use sqlx::postgres::PgListener;
+use sqlx::{query_as, PgPool, Postgres};
+use std::collections::{HashMap, HashSet};
+use std::env::var;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::task::spawn;
+use tokio::time::{interval, sleep};
+
+//Width of the cells of the spatial index over edge segments, in Web Mercator metres
+const INDEX_CELL_SIZE: f64 = 250.0;
+//Points further than this, in Web Mercator metres, outside the indexed extent of a network are not snapped to it
+const MAX_SNAP_DISTANCE: f64 = 10000.0;
+const MEAN_EARTH_RADIUS_METRES: f64 = 6371008.8;
+const LISTENER_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(sqlx::FromRow)]
+struct PreloadedEdgeRow {
+    #[sqlx(flatten)]
+    edge: TopoEdgeRepresentation,
+    xs: Vec<f64>,
+    ys: Vec<f64>,
+    zs: Option<Vec<Option<f64>>>,
+}
+
+/**
+The geometry of an edge held in memory, in Web Mercator, along with the fraction of the edge's length reached at each vertex and, where available, each vertex's elevation.
+*/
+struct EdgeGeometry {
+    coords: Vec<[f64; 2]>,
+    fractions: Vec<f64>,
+    elevations: Option<Vec<Option<f64>>>,
+}
+
+/**
+A point along an edge: its fraction along the edge, its Web Mercator coordinates and, where available, its elevation.
+*/
+struct EdgePoint {
+    fraction: f64,
+    coord: [f64; 2],
+    elevation: Option<f64>,
+}
+
+impl EdgeGeometry {
+    fn new(xs: Vec<f64>, ys: Vec<f64>, elevations: Option<Vec<Option<f64>>>) -> EdgeGeometry {
+        let coords = xs
+            .into_iter()
+            .zip(ys)
+            .map(|(x, y)| [x, y])
+            .collect::<Vec<[f64; 2]>>();
+
+        let mut cumulative = Vec::with_capacity(coords.len());
+        let mut total = 0.0;
+        for (i, coord) in coords.iter().enumerate() {
+            if i > 0 {
+                total += planar_distance(coords[i - 1], *coord);
+            }
+            cumulative.push(total);
+        }
+        let fractions = cumulative
+            .into_iter()
+            .map(|d| if total > 0.0 { d / total } else { 0.0 })
+            .collect();
+
+        EdgeGeometry {
+            coords,
+            fractions,
+            elevations,
+        }
+    }
+
+    fn elevation(&self, i: usize) -> Option<f64> {
+        self.elevations.as_ref()?.get(i).copied().flatten()
+    }
+
+    /**
+    Interpolates the point at a fraction along the edge.
+    */
+    fn point_at(&self, fraction: f64) -> EdgePoint {
+        let last = self.coords.len().saturating_sub(1);
+        let i = self
+            .fractions
+            .windows(2)
+            .position(|pair| fraction <= pair[1])
+            .unwrap_or(last.saturating_sub(1));
+        let j = (i + 1).min(last);
+        let span = self.fractions[j] - self.fractions[i];
+        let t = if span > 0.0 {
+            ((fraction - self.fractions[i]) / span).clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+
+        EdgePoint {
+            fraction,
+            coord: [
+                self.coords[i][0] + t * (self.coords[j][0] - self.coords[i][0]),
+                self.coords[i][1] + t * (self.coords[j][1] - self.coords[i][1]),
+            ],
+            elevation: match (self.elevation(i), self.elevation(j)) {
+                (Some(a), Some(b)) => Some(a + t * (b - a)),
+                (a, b) => a.or(b),
+            },
+        }
+    }
+
+    /**
+    Returns the points of the portion of the edge between two fractions, in the direction of travel, as `ST_LineSubstring` would.
+    */
+    fn portion(&self, from_fraction: f64, to_fraction: f64) -> Vec<EdgePoint> {
+        let (low, high) = if from_fraction <= to_fraction {
+            (from_fraction, to_fraction)
+        } else {
+            (to_fraction, from_fraction)
+        };
+
+        let mut points = vec![self.point_at(low)];
+        for (i, fraction) in self.fractions.iter().enumerate() {
+            if *fraction > low && *fraction < high {
+                points.push(EdgePoint {
+                    fraction: *fraction,
+                    coord: self.coords[i],
+                    elevation: self.elevation(i),
+                });
+            }
+        }
+        points.push(self.point_at(high));
+
+        if from_fraction > to_fraction {
+            points.reverse();
+        }
+        points
+    }
+
+    /**
+    Finds the point on the edge closest to `point`, returning its distance, in Web Mercator metres, its fraction along the edge and the point itself.
+    */
+    fn closest_point(&self, point: [f64; 2]) -> (f64, f64, [f64; 2]) {
+        let mut best = (f64::INFINITY, 0.0, self.coords[0]);
+        for (i, pair) in self.coords.windows(2).enumerate() {
+            let (dx, dy) = (pair[1][0] - pair[0][0], pair[1][1] - pair[0][1]);
+            let length_sq = dx * dx + dy * dy;
+            let t = if length_sq > 0.0 {
+                (((point[0] - pair[0][0]) * dx + (point[1] - pair[0][1]) * dy) / length_sq)
+                    .clamp(0.0, 1.0)
+            } else {
+                0.0
+            };
+            let closest = [pair[0][0] + t * dx, pair[0][1] + t * dy];
+            let distance = planar_distance(closest, point);
+            if distance < best.0 {
+                let fraction = self.fractions[i] + t * (self.fractions[i + 1] - self.fractions[i]);
+                best = (distance, fraction, closest);
+            }
+        }
+        best
+    }
+}
+
+fn planar_distance(a: [f64; 2], b: [f64; 2]) -> f64 {
+    ((b[0] - a[0]).powi(2) + (b[1] - a[1]).powi(2)).sqrt()
+}
+
+/**
+Measures the great circle distance, in metres, between two Web Mercator coordinates.
+*/
+pub(super) fn ground_distance(a: [f64; 2], b: [f64; 2]) -> f64 {
+    let (lon_a, lat_a) = web_mercator_to_lon_lat(a[0], a[1]);
+    let (lon_b, lat_b) = web_mercator_to_lon_lat(b[0], b[1]);
+    let (lat_a, lat_b) = (lat_a.to_radians(), lat_b.to_radians());
+    let h = ((lat_b - lat_a) / 2.0).sin().powi(2)
+        + lat_a.cos() * lat_b.cos() * ((lon_b - lon_a).to_radians() / 2.0).sin().powi(2);
+    2.0 * MEAN_EARTH_RADIUS_METRES * h.sqrt().asin()
+}
+
+fn cell_of(coord: [f64; 2]) -> (i64, i64) {
+    (
+        (coord[0] / INDEX_CELL_SIZE).floor() as i64,
+        (coord[1] / INDEX_CELL_SIZE).floor() as i64,
+    )
+}
+
+/**
+Lists the cells on the perimeter of the square `ring` cells out from `centre`.
+*/
+fn ring_cells(centre: (i64, i64), ring: i64) -> Vec<(i64, i64)> {
+    if ring == 0 {
+        return vec![centre];
+    }
+
+    let mut cells = Vec::with_capacity(8 * ring as usize);
+    for x in (centre.0 - ring)..=(centre.0 + ring) {
+        cells.push((x, centre.1 - ring));
+        cells.push((x, centre.1 + ring));
+    }
+    for y in (centre.1 - ring + 1)..(centre.1 + ring) {
+        cells.push((centre.0 - ring, y));
+        cells.push((centre.0 + ring, y));
+    }
+    cells
+}
+
+/**
+A table's routing network held in memory, weighted by the table's default routing profile, with a grid index over its edges for snapping.
+*/
+pub struct PreloadedNetwork {
+    edges: Vec<TopoEdgeRepresentation>,
+    geometries: Vec<EdgeGeometry>,
+    edge_index: HashMap<u32, usize>,
+    incident_edges: HashMap<u32, Vec<usize>>,
+    grid: HashMap<(i64, i64), Vec<usize>>,
+    grid_bounds: ((i64, i64), (i64, i64)),
+    graph: RoutingGraph,
+    turns: TurnRules,
+    dist_unit: String,
+    elevation_in_geometry: bool,
+}
+
+impl PreloadedNetwork {
+    /**
+    Loads a table's routing network, weighted by its default routing profile.
+
+    # Arguments
+
+    * `pool` - A PostgreSQL connection pool.
+    * `table` - A struct representing the database table.
+    * `state` - The state of the application, which holds the routing profiles and elevation raster.
+
+    # Returns
+
+    A `Result` containing the network, or an `AppError` if the table is not routable or the query fails.
+    */
+    pub async fn load(
+        pool: &PgPool,
+        table: &Table,
+        state: &AppState,
+    ) -> Result<PreloadedNetwork, AppError> {
+        let (geom_col, dist_unit) = match (table.geom_column.as_ref(), table.dist_unit.as_ref()) {
+            (Some(geom_col), Some(dist_unit)) => (geom_col, dist_unit),
+            _ => {
+                return Err(AppError(anyhow!(
+                    "Table does not contain valid geometry and distance data"
+                )))
+            }
+        };
+        let profile = state.routing_profiles.get(table, None)?;
+        let elevation = ElevationSource::for_table(table, state.elevation_raster.as_ref());
+
+        let elevations_select = match elevation.make_point_elevation("dp.geom") {
+            Some(point_elevation) => format!(
+                "array_agg(({})::float8 order by dp.path) as zs",
+                point_elevation
+            ),
+            None => "null::float8[] as zs".to_string(),
+        };
+        let vertices_join = format!(
+            "cross join lateral (
+                select
+                    array_agg(ST_X(dp.merc) order by dp.path) as xs,
+                    array_agg(ST_Y(dp.merc) order by dp.path) as ys,
+                    {}
+                from (
+                    select d.path, d.geom, ST_Transform(d.geom, 3857) as merc
+                    from ST_DumpPoints(ST_GeometryN(ST_LineMerge(ST_Multi(t.{})), 1)) d
+                ) dp
+            ) v",
+            elevations_select, geom_col
+        );
+        let network_query = format!(
+            "{}
+            where v.xs is not null",
+            make_weighted_edges_query(
+                table,
+                &profile,
+                &elevation,
+                &RoutingConstraints::default(),
+                ", v.xs, v.ys, v.zs",
+                &vertices_join
+            )?
+        );
+
+        let rows = query_as::<Postgres, PreloadedEdgeRow>(&network_query)
+            .fetch_all(pool)
+            .await
+            .map_err(|e| AppError(anyhow!(e)))?;
+
+        let mut edges = Vec::with_capacity(rows.len());
+        let mut geometries = Vec::with_capacity(rows.len());
+        for row in rows {
+            edges.push(row.edge);
+            geometries.push(EdgeGeometry::new(row.xs, row.ys, row.zs));
+        }
+        let turns = get_turn_rules(pool, &state.table_registry, table, &profile, None).await?;
+
+        Ok(PreloadedNetwork::new(
+            edges,
+            geometries,
+            turns,
+            dist_unit.clone(),
+            matches!(elevation, ElevationSource::Geometry),
+        ))
+    }
+
+    fn new(
+        edges: Vec<TopoEdgeRepresentation>,
+        geometries: Vec<EdgeGeometry>,
+        turns: TurnRules,
+        dist_unit: String,
+        elevation_in_geometry: bool,
+    ) -> PreloadedNetwork {
+        let mut edge_index = HashMap::with_capacity(edges.len());
+        let mut incident_edges: HashMap<u32, Vec<usize>> = HashMap::new();
+        for (i, e) in edges.iter().enumerate() {
+            edge_index.insert(e.edge_id, i);
+            incident_edges.entry(e.start_node).or_default().push(i);
+            incident_edges.entry(e.end_node).or_default().push(i);
+        }
+
+        //Each segment is indexed in every cell its bounding box covers
+        let mut grid: HashMap<(i64, i64), Vec<usize>> = HashMap::new();
+        let mut grid_bounds = ((i64::MAX, i64::MAX), (i64::MIN, i64::MIN));
+        for (i, geometry) in geometries.iter().enumerate() {
+            let mut cells = HashSet::new();
+            for pair in geometry.coords.windows(2) {
+                let (a, b) = (cell_of(pair[0]), cell_of(pair[1]));
+                for x in a.0.min(b.0)..=a.0.max(b.0) {
+                    for y in a.1.min(b.1)..=a.1.max(b.1) {
+                        cells.insert((x, y));
+                    }
+                }
+            }
+            for cell in cells {
+                grid_bounds = (
+                    (grid_bounds.0 .0.min(cell.0), grid_bounds.0 .1.min(cell.1)),
+                    (grid_bounds.1 .0.max(cell.0), grid_bounds.1 .1.max(cell.1)),
+                );
+                grid.entry(cell).or_default().push(i);
+            }
+        }
+
+        let graph = RoutingGraph::new(&edges, &turns);
+
+        PreloadedNetwork {
+            edges,
+            geometries,
+            edge_index,
+            incident_edges,
+            grid,
+            grid_bounds,
+            graph,
+            turns,
+            dist_unit,
+            elevation_in_geometry,
+        }
+    }
+
+    pub fn turn_rules(&self) -> &TurnRules {
+        &self.turns
+    }
+
+    /**
+    Finds up to `count` edges closest to a point, nearest first, with their distance in Web Mercator metres, the fraction along them of their closest point, and that point. Points more than `MAX_SNAP_DISTANCE` outside the indexed extent find none.
+    */
+    fn nearest_edges(&self, point: [f64; 2], count: usize) -> Vec<(usize, f64, f64, [f64; 2])> {
+        let centre = cell_of(point);
+        let ((min_x, min_y), (max_x, max_y)) = self.grid_bounds;
+        let margin = (MAX_SNAP_DISTANCE / INDEX_CELL_SIZE).ceil() as i64;
+        if count == 0
+            || self.grid.is_empty()
+            || centre.0 < min_x - margin
+            || centre.0 > max_x + margin
+            || centre.1 < min_y - margin
+            || centre.1 > max_y + margin
+        {
+            return Vec::new();
+        }
+        let max_ring = [
+            centre.0 - min_x,
+            max_x - centre.0,
+            centre.1 - min_y,
+            max_y - centre.1,
+        ]
+        .into_iter()
+        .max()
+        .unwrap_or(0)
+        .max(0);
+
+        let mut seen = HashSet::new();
+        let mut candidates: Vec<(usize, f64, f64, [f64; 2])> = Vec::new();
+        for ring in 0..=max_ring {
+            //Once more cells would have been searched than there are edges, checking every edge is cheaper
+            if ((2 * ring + 1) as usize).pow(2) > self.geometries.len() {
+                candidates = self
+                    .geometries
+                    .iter()
+                    .enumerate()
+                    .map(|(i, geometry)| {
+                        let (distance, fraction, closest) = geometry.closest_point(point);
+                        (i, distance, fraction, closest)
+                    })
+                    .collect();
+                candidates.sort_by(|a, b| a.1.total_cmp(&b.1));
+                break;
+            }
+
+            for cell in ring_cells(centre, ring) {
+                for i in self.grid.get(&cell).into_iter().flatten() {
+                    if seen.insert(*i) {
+                        let (distance, fraction, closest) =
+                            self.geometries[*i].closest_point(point);
+                        candidates.push((*i, distance, fraction, closest));
+                    }
+                }
+            }
+
+            //Every edge within `ring` cells of the point has now been seen
+            candidates.sort_by(|a, b| a.1.total_cmp(&b.1));
+            let searched = ring as f64 * INDEX_CELL_SIZE;
+            if candidates.len() >= count && candidates[count - 1].1 <= searched {
+                break;
+            }
+        }
+
+        candidates.truncate(count);
+        candidates
+    }
+
+    /**
+    Snaps a point to the network, as `snap_to_network` does against the database.
+
+    # Arguments
+
+    * `point` - The requested start, in Web Mercator.
+    * `mode` - Whether to snap to vertices only, or to split points along edges.
+    * `virtual_node_id` - The node ID given to the split point, if the point is split along an edge.
+    */
+    pub fn snap(
+        &self,
+        point: &Point<f64>,
+        mode: SnapMode,
+        virtual_node_id: u32,
+    ) -> Result<SnappedPoint, AppError> {
+        let coord = [point.x(), point.y()];
+        let candidates = self.nearest_edges(coord, SNAP_CANDIDATE_EDGES);
+        let Some(&(nearest, _, fraction, closest)) = candidates.first() else {
+            return Err(AppError(anyhow!(
+                "No routable edges found in specified table"
+            )));
+        };
+
+        if mode == SnapMode::Edge
+            && fraction > SPLIT_FRACTION_TOLERANCE
+            && fraction < 1.0 - SPLIT_FRACTION_TOLERANCE
+        {
+            let edge_id = self.edges[nearest].edge_id;
+            return Ok(SnappedPoint {
+                node_id: None,
+                edge_id: Some(edge_id),
+                geometry: geojson::Geometry::new(geojson::Value::from(&Point::new(
+                    closest[0], closest[1],
+                ))),
+                distance: metres_to_table_distance(
+                    ground_distance(coord, closest),
+                    &self.dist_unit,
+                )?,
+                units: Some(self.dist_unit.clone()),
+                snapped_to: SnapTarget::Edge,
+                split: Some(EdgeSplit {
+                    edge_id,
+                    node_id: virtual_node_id,
+                    fraction,
+                }),
+            });
+        }
+
+        let (node_id, vertex) = candidates
+            .iter()
+            .flat_map(|(i, ..)| {
+                let coords = &self.geometries[*i].coords;
+                [
+                    (self.edges[*i].start_node, coords[0]),
+                    (self.edges[*i].end_node, coords[coords.len() - 1]),
+                ]
+            })
+            .min_by(|a, b| planar_distance(a.1, coord).total_cmp(&planar_distance(b.1, coord)))
+            .ok_or_else(|| AppError(anyhow!("No routable edges found in specified table")))?;
+
+        Ok(SnappedPoint {
+            node_id: Some(node_id),
+            edge_id: None,
+            geometry: geojson::Geometry::new(geojson::Value::from(&Point::new(
+                vertex[0], vertex[1],
+            ))),
+            distance: metres_to_table_distance(ground_distance(coord, vertex), &self.dist_unit)?,
+            units: Some(self.dist_unit.clone()),
+            snapped_to: SnapTarget::Vertex,
+            split: None,
+        })
+    }
+
+    /**
+    Finds the nodes a routing search from a point starts at, each with the most it may cost to reach that node from the point. A point split along an edge starts at either end of that edge.
+    */
+    fn seeds(&self, point: &SnappedPoint) -> Vec<(u32, f64)> {
+        match (&point.split, point.node_id) {
+            (Some(split), _) => match self.edge_index.get(&split.edge_id) {
+                Some(i) => vec![
+                    (self.edges[*i].start_node, self.edges[*i].weight.max(0.0)),
+                    (
+                        self.edges[*i].end_node,
+                        self.edges[*i].reverse_weight.max(0.0),
+                    ),
+                ],
+                None => Vec::new(),
+            },
+            (None, Some(node_id)) => vec![(node_id, 0.0)],
+            (None, None) => Vec::new(),
+        }
+    }
+
+    /**
+    Collects every edge touching any of the given nodes, in the order they were loaded.
+    */
+    fn edges_touching<'a>(
+        &self,
+        nodes: impl Iterator<Item = &'a u32>,
+    ) -> Vec<TopoEdgeRepresentation> {
+        let mut indices = HashSet::new();
+        for node in nodes {
+            indices.extend(self.incident_edges.get(node).into_iter().flatten());
+        }
+
+        let mut indices = indices.into_iter().collect::<Vec<usize>>();
+        indices.sort_unstable();
+        indices.into_iter().map(|i| self.edges[i].clone()).collect()
+    }
+
+    /**
+    Collects the edges touching any node reachable from a start point within `max_cost`, for use as a routing subgraph. A start split along an edge is reached through either end of that edge.
+    */
+    pub fn edges_around(&self, start: &SnappedPoint, max_cost: f64) -> Vec<TopoEdgeRepresentation> {
+        let mut nodes = HashSet::new();
+        for (seed, allowance) in self.seeds(start) {
+            nodes.extend(
+                self.graph
+                    .costs_within(seed, max_cost + allowance)
+                    .into_keys(),
+            );
+        }
+        self.edges_touching(nodes.iter())
+    }
+
+    /**
+    Collects the edges touching any node reachable from a start point within `max_cost`, or within the cost of the cheapest path to an end point where that is greater, for use as a routing subgraph between the two. Every path from the start to the end costing no more than either is kept, including the cheapest one.
+    */
+    pub fn edges_between(
+        &self,
+        start: &SnappedPoint,
+        end: &SnappedPoint,
+        max_cost: f64,
+    ) -> Vec<TopoEdgeRepresentation> {
+        let reached = self
+            .seeds(start)
+            .into_iter()
+            .map(|(seed, allowance)| (self.graph.costs_within(seed, f64::INFINITY), allowance))
+            .collect::<Vec<(HashMap<u32, f64>, f64)>>();
+        let direct = self
+            .seeds(end)
+            .iter()
+            .flat_map(|(node, _)| reached.iter().filter_map(|(costs, _)| costs.get(node)))
+            .fold(f64::INFINITY, |a, b| a.min(*b));
+        let limit = if direct.is_finite() {
+            max_cost.max(direct)
+        } else {
+            max_cost
+        };
+
+        let mut nodes = HashSet::new();
+        for (costs, allowance) in reached.iter() {
+            nodes.extend(
+                costs
+                    .iter()
+                    .filter(|(_, cost)| **cost <= limit + allowance)
+                    .map(|(node, _)| *node),
+            );
+        }
+        self.edges_touching(nodes.iter())
+    }
+
+    /**
+    Builds the geometry of a path as a single line in Web Mercator, as `get_ordered_path_geometry` does against the database, along with its elevation profile where elevations were loaded.
+    */
+    pub fn path_geometry(&self, path: &[TraversedEdge]) -> Result<PathGeometry, AppError> {
+        let mut points: Vec<([f64; 2], Option<f64>, f64)> = Vec::new();
+        let mut distance = 0.0;
+        for step in path {
+            let i = *self
+                .edge_index
+                .get(&step.edge_id)
+                .ok_or_else(|| AppError(anyhow!("Unknown edge id: {}", step.edge_id)))?;
+            let portion = self.geometries[i].portion(step.from_fraction, step.to_fraction);
+
+            let mut previous_fraction = portion.first().map(|p| p.fraction).unwrap_or_default();
+            for point in portion {
+                distance += (point.fraction - previous_fraction).abs() * self.edges[i].length;
+                previous_fraction = point.fraction;
+                //Repeated nodes where consecutive steps meet are collapsed, as in `ST_MakeLine`
+                if points.last().is_some_and(|last| last.0 == point.coord) {
+                    continue;
+                }
+                points.push((point.coord, point.elevation, distance));
+            }
+        }
+
+        let coordinates = points
+            .iter()
+            .map(
+                |(coord, elevation, _)| match (self.elevation_in_geometry, elevation) {
+                    (true, Some(z)) => json!([coord[0], coord[1], z]),
+                    _ => json!([coord[0], coord[1]]),
+                },
+            )
+            .collect::<Vec<Value>>();
+        let has_elevations = self.geometries.iter().any(|g| g.elevations.is_some());
+
+        Ok(PathGeometry {
+            geoms: json!({ "type": "LineString", "coordinates": coordinates }),
+            profile: has_elevations.then(|| {
+                json!(points
+                    .iter()
+                    .map(|(_, elevation, distance)| json!([distance, elevation]))
+                    .collect::<Vec<Value>>())
+            }),
+        })
+    }
+}
+
+/**
+Routing networks preloaded into memory, keyed by collection identifier (`schema.table`), and the configuration for keeping them current.
+
+Preloaded networks are weighted by each table's default routing profile. Requests using any other profile are routed against the database as usual.
+*/
+#[derive(Clone, Default)]
+pub struct PreloadedNetworks {
+    tables: Vec<String>,
+    refresh_interval: Option<Duration>,
+    notify_channel: Option<String>,
+    networks: Arc<RwLock<HashMap<String, Arc<PreloadedNetwork>>>>,
+}
+
+impl PreloadedNetworks {
+    /**
+    Configures preloading from the environment:
+
+    * `ROUTING_PRELOAD_TABLES` - A comma-separated list of tables to preload, as `schema.table`. Nothing is preloaded when unset.
+    * `ROUTING_REFRESH_SECS` - How often, in seconds, to reload every preloaded table.
+    * `ROUTING_REFRESH_CHANNEL` - A channel to `LISTEN` on. A notification whose payload is `schema.table` reloads that table, and one with an empty payload reloads them all.
+
+    # Errors
+
+    - If a table is not in the form `schema.table`, or the refresh interval cannot be parsed as a `u64`, returns an `anyhow::Error`.
+    */
+    pub fn from_env() -> Result<PreloadedNetworks> {
+        let tables = match var("ROUTING_PRELOAD_TABLES") {
+            Ok(tables) => tables
+                .split(',')
+                .map(str::trim)
+                .filter(|table| !table.is_empty())
+                .map(
+                    |table| match table.split('.').collect::<Vec<&str>>().as_slice() {
+                        [_, _] => Ok(table.to_string()),
+                        _ => Err(anyhow!(
+                            "Invalid table in ROUTING_PRELOAD_TABLES: {}",
+                            table
+                        )),
+                    },
+                )
+                .collect::<Result<Vec<String>>>()?,
+            Err(_) => Vec::new(),
+        };
+
+        let refresh_interval = match var("ROUTING_REFRESH_SECS") {
+            Ok(secs) => Some(
+                secs.parse::<u64>()
+                    .context("Failed to parse ROUTING_REFRESH_SECS as u64")?,
+            )
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs),
+            Err(_) => None,
+        };
+
+        let notify_channel = var("ROUTING_REFRESH_CHANNEL")
+            .ok()
+            .filter(|channel| !channel.is_empty());
+
+        Ok(PreloadedNetworks {
+            tables,
+            refresh_interval,
+            notify_channel,
+            networks: Arc::new(RwLock::new(HashMap::new())),
+        })
+    }
+
+    /**
+    Returns the preloaded network of a table, if it has been loaded.
+    */
+    pub fn get(&self, table: &Table) -> Option<Arc<PreloadedNetwork>> {
+        self.networks
+            .read()
+            .ok()?
+            .get(&format!("{}.{}", table.schema_name, table.name))
+            .cloned()
+    }
+
+    /**
+    Loads, or reloads, the network of a configured table, replacing the previous copy once loading succeeds.
+    */
+    async fn reload(&self, key: &str, state: &AppState) -> Result<(), AppError> {
+        let Some((schemaid, tableid)) = key.split_once('.') else {
+            return Err(AppError(anyhow!("Invalid table: {}", key)));
+        };
+        let table = find_table(&state.table_registry, schemaid, tableid)?;
+        let network = PreloadedNetwork::load(&state.db_pool, table, state).await?;
+
+        tracing::info!("Loaded {} routing edges for {}", network.edges.len(), key);
+        if let Ok(mut networks) = self.networks.write() {
+            networks.insert(key.to_string(), Arc::new(network));
+        }
+        //A reload follows a change to the table, which may also change its analysis
+        state.network_analyses.invalidate(schemaid, tableid);
+        Ok(())
+    }
+
+    /**
+    Loads the network of every configured table.
+
+    # Errors
+
+    - If any table is missing from the registry or cannot be loaded, returns an `anyhow::Error`.
+    */
+    pub async fn load_all(&self, state: &AppState) -> Result<()> {
+        for key in self.tables.iter() {
+            self.reload(key, state)
+                .await
+                .map_err(|e| anyhow!("Failed to preload {}: {}", key, e.0))?;
+        }
+        Ok(())
+    }
+
+    async fn reload_matching(&self, payload: &str, state: &AppState) {
+        for key in self
+            .tables
+            .iter()
+            .filter(|key| payload.is_empty() || payload == key.as_str())
+        {
+            if let Err(e) = self.reload(key, state).await {
+                tracing::warn!("Failed to refresh routing network {}: {}", key, e.0);
+            }
+        }
+    }
+
+    /**
+    Starts the background tasks that keep the preloaded networks current, as configured.
+    */
+    pub fn spawn_refresh(&self, state: AppState) {
+        if self.tables.is_empty() {
+            return;
+        }
+
+        if let Some(period) = self.refresh_interval {
+            let networks = self.clone();
+            let state = state.clone();
+            spawn(async move {
+                let mut ticker = interval(period);
+                //The first tick completes immediately, and the networks have just been loaded
+                ticker.tick().await;
+                loop {
+                    ticker.tick().await;
+                    networks.reload_matching("", &state).await;
+                }
+            });
+        }
+
+        if let Some(channel) = self.notify_channel.clone() {
+            let networks = self.clone();
+            spawn(async move {
+                let mut listener = match PgListener::connect_with(&state.db_pool).await {
+                    Ok(listener) => listener,
+                    Err(e) => {
+                        tracing::warn!("Failed to connect routing refresh listener: {}", e);
+                        return;
+                    }
+                };
+                if let Err(e) = listener.listen(&channel).await {
+                    tracing::warn!("Failed to listen on {}: {}", channel, e);
+                    return;
+                }
+                loop {
+                    match listener.recv().await {
+                        Ok(notification) => {
+                            networks
+                                .reload_matching(notification.payload(), &state)
+                                .await
+                        }
+                        Err(e) => {
+                            tracing::warn!("Routing refresh listener error: {}", e);
+                            sleep(LISTENER_RETRY_DELAY).await;
+                        }
+                    }
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::routing::snapping::VIRTUAL_NODE_ID;
+
+    fn line(coords: &[[f64; 2]], zs: Option<Vec<Option<f64>>>) -> EdgeGeometry {
+        EdgeGeometry::new(
+            coords.iter().map(|c| c[0]).collect(),
+            coords.iter().map(|c| c[1]).collect(),
+            zs,
+        )
+    }
+
+    //A 1km square 1-2-3-4 with 2-3 one way, and a spur 3-5-6 off its north-east corner
+    fn square() -> PreloadedNetwork {
+        let edges = vec![
+            TopoEdgeRepresentation::new(1, 1, 2, 1000.0, 1000.0, 1000.0),
+            TopoEdgeRepresentation::new(2, 2, 3, 1000.0, -1.0, 1000.0),
+            TopoEdgeRepresentation::new(3, 3, 4, 1000.0, 1000.0, 1000.0),
+            TopoEdgeRepresentation::new(4, 4, 1, 1000.0, 1000.0, 1000.0),
+            TopoEdgeRepresentation::new(5, 3, 5, 1000.0, 1000.0, 1000.0),
+            TopoEdgeRepresentation::new(6, 5, 6, 1000.0, 1000.0, 1000.0),
+        ];
+        let geometries = vec![
+            line(&[[0.0, 0.0], [1000.0, 0.0]], None),
+            line(&[[1000.0, 0.0], [1000.0, 1000.0]], None),
+            line(&[[1000.0, 1000.0], [0.0, 1000.0]], None),
+            line(&[[0.0, 1000.0], [0.0, 0.0]], None),
+            line(&[[1000.0, 1000.0], [2000.0, 1000.0]], None),
+            line(&[[2000.0, 1000.0], [3000.0, 1000.0]], None),
+        ];
+        PreloadedNetwork::new(
+            edges,
+            geometries,
+            TurnRules::default(),
+            "m".to_string(),
+            false,
+        )
+    }
+
+    fn snap_at(network: &PreloadedNetwork, x: f64, y: f64, mode: SnapMode) -> SnappedPoint {
+        network
+            .snap(&Point::new(x, y), mode, VIRTUAL_NODE_ID)
+            .unwrap()
+    }
+
+    fn edge_ids(edges: &[TopoEdgeRepresentation]) -> Vec<u32> {
+        edges.iter().map(|e| e.edge_id).collect()
+    }
+
+    #[test]
+    fn nearest_edges_are_ordered_by_distance() {
+        let network = square();
+
+        let nearest = network.nearest_edges([500.0, 100.0], 2);
+        assert_eq!(nearest.len(), 2);
+        assert_eq!(nearest[0].0, 0);
+        assert!((nearest[0].1 - 100.0).abs() < 1e-9);
+        assert!((nearest[0].2 - 0.5).abs() < 1e-9);
+        assert_eq!(nearest[0].3, [500.0, 0.0]);
+        assert!((nearest[1].1 - 500.0).abs() < 1e-9);
+
+        //Fewer edges than asked for are all returned
+        assert_eq!(network.nearest_edges([500.0, 100.0], 20).len(), 6);
+    }
+
+    #[test]
+    fn nearest_edges_search_the_grid_as_a_linear_scan_would() {
+        //Many short parallel edges, so the ring search stops well before falling back to a scan
+        let edges = (0..100)
+            .map(|k| TopoEdgeRepresentation::new(k, 2 * k, 2 * k + 1, 500.0, 500.0, 500.0))
+            .collect::<Vec<TopoEdgeRepresentation>>();
+        let geometries = (0..100)
+            .map(|k| {
+                line(
+                    &[[0.0, k as f64 * 1000.0], [500.0, k as f64 * 1000.0]],
+                    None,
+                )
+            })
+            .collect::<Vec<EdgeGeometry>>();
+        let network = PreloadedNetwork::new(
+            edges,
+            geometries,
+            TurnRules::default(),
+            "m".to_string(),
+            false,
+        );
+
+        let nearest = network.nearest_edges([250.0, 50100.0], 2);
+        assert_eq!(
+            nearest.iter().map(|c| c.0).collect::<Vec<usize>>(),
+            vec![50, 51]
+        );
+        assert!((nearest[0].1 - 100.0).abs() < 1e-9);
+        assert!((nearest[1].1 - 900.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn nearest_edges_ignore_points_far_outside_the_network() {
+        let network = square();
+
+        let nearby = network.nearest_edges([5000.0, 500.0], 1);
+        assert_eq!(nearby.len(), 1);
+        assert_eq!(nearby[0].0, 5);
+
+        assert!(network.nearest_edges([1.0e6, 1.0e6], 1).is_empty());
+        assert!(network
+            .snap(&Point::new(1.0e6, 1.0e6), SnapMode::Vertex, VIRTUAL_NODE_ID)
+            .is_err());
+    }
+
+    #[test]
+    fn snap_splits_edges_or_moves_to_the_nearest_vertex() {
+        let network = square();
+
+        let split = snap_at(&network, 500.0, 100.0, SnapMode::Edge);
+        assert_eq!(split.snapped_to, SnapTarget::Edge);
+        assert_eq!(split.edge_id, Some(1));
+        let split_edge = split.split.unwrap();
+        assert_eq!(split_edge.node_id, VIRTUAL_NODE_ID);
+        assert!((split_edge.fraction - 0.5).abs() < 1e-9);
+
+        let vertex = snap_at(&network, 900.0, 100.0, SnapMode::Vertex);
+        assert_eq!(vertex.snapped_to, SnapTarget::Vertex);
+        assert_eq!(vertex.node_id, Some(2));
+        assert!(vertex.split.is_none());
+
+        //Points near an end of an edge snap to that end even when splitting
+        let near_end = snap_at(&network, 999.9, 0.5, SnapMode::Edge);
+        assert_eq!(near_end.snapped_to, SnapTarget::Vertex);
+        assert_eq!(near_end.node_id, Some(2));
+    }
+
+    #[test]
+    fn edges_between_keep_every_path_up_to_the_cheapest() {
+        let network = square();
+        let start = snap_at(&network, 0.0, 0.0, SnapMode::Vertex);
+        let end = snap_at(&network, 1000.0, 1000.0, SnapMode::Vertex);
+
+        //Reaching node 3 costs 2000 either way round the square, which also reaches the spur's first edge
+        assert_eq!(
+            edge_ids(&network.edges_between(&start, &end, 0.0)),
+            vec![1, 2, 3, 4, 5]
+        );
+        assert_eq!(
+            edge_ids(&network.edges_between(&start, &end, 3000.0)),
+            vec![1, 2, 3, 4, 5, 6]
+        );
+        assert_eq!(
+            edge_ids(&network.edges_around(&start, 1000.0)),
+            vec![1, 2, 3, 4]
+        );
+    }
+
+    #[test]
+    fn path_geometry_follows_the_direction_of_travel() {
+        let network = square();
+        let path = vec![
+            TraversedEdge {
+                edge_id: 1,
+                from_fraction: 0.5,
+                to_fraction: 1.0,
+                weight: 500.0,
+                length: 500.0,
+            },
+            TraversedEdge {
+                edge_id: 2,
+                from_fraction: 0.0,
+                to_fraction: 1.0,
+                weight: 1000.0,
+                length: 1000.0,
+            },
+            TraversedEdge {
+                edge_id: 3,
+                from_fraction: 0.0,
+                to_fraction: 0.25,
+                weight: 250.0,
+                length: 250.0,
+            },
+        ];
+
+        let geometry = network.path_geometry(&path).unwrap();
+        assert_eq!(
+            geometry.geoms,
+            json!({
+                "type": "LineString",
+                "coordinates": [[500.0, 0.0], [1000.0, 0.0], [1000.0, 1000.0], [750.0, 1000.0]]
+            })
+        );
+        assert!(geometry.profile.is_none());
+
+        let reversed = network
+            .path_geometry(&[TraversedEdge {
+                edge_id: 1,
+                from_fraction: 1.0,
+                to_fraction: 0.5,
+                weight: 500.0,
+                length: 500.0,
+            }])
+            .unwrap();
+        assert_eq!(
+            reversed.geoms["coordinates"],
+            json!([[1000.0, 0.0], [500.0, 0.0]])
+        );
+
+        assert!(network
+            .path_geometry(&[TraversedEdge {
+                edge_id: 99,
+                from_fraction: 0.0,
+                to_fraction: 1.0,
+                weight: 0.0,
+                length: 0.0,
+            }])
+            .is_err());
+    }
+
+    #[test]
+    fn path_geometry_interpolates_elevations() {
+        let network = PreloadedNetwork::new(
+            vec![TopoEdgeRepresentation::new(1, 1, 2, 1000.0, 1000.0, 1000.0)],
+            vec![line(
+                &[[0.0, 0.0], [1000.0, 0.0]],
+                Some(vec![Some(10.0), Some(20.0)]),
+            )],
+            TurnRules::default(),
+            "m".to_string(),
+            true,
+        );
+
+        let geometry = network
+            .path_geometry(&[TraversedEdge {
+                edge_id: 1,
+                from_fraction: 0.0,
+                to_fraction: 0.5,
+                weight: 500.0,
+                length: 500.0,
+            }])
+            .unwrap();
+        assert_eq!(
+            geometry.geoms["coordinates"],
+            json!([[0.0, 0.0, 10.0], [500.0, 0.0, 15.0]])
+        );
+        assert_eq!(geometry.profile, Some(json!([[0.0, 10.0], [500.0, 15.0]])));
+    }
+}
@@ -0,0 +1,251 @@
+use super::elevation::ElevationSource;
+use super::turns::TurnCosts;
+use crate::db::Table;
+use crate::AppError;
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::env::var;
+use std::fs::read_to_string;
+use std::sync::Arc;
+
+//Values of a direction column restricting travel to, or against, an edge's digitised direction
+const FORWARD_ONLY_VALUES: &str = "'yes', 'true', 't', '1', 'ft', 'forward'";
+const REVERSE_ONLY_VALUES: &str = "'-1', 'tf', 'reverse', 'backward'";
+
+/**
+A named way of weighting a table's edges for routing.
+
+* `cost` - An SQL expression over the table's columns giving the cost of traversing an edge, as an equivalent distance in the table's distance units. Defaults to the edge's length.
+* `forward_cost` - A column holding the cost of traversing an edge in its digitised direction, used in place of `cost`.
+* `reverse_cost` - A column holding the cost of traversing an edge against its digitised direction. Defaults to the forward cost.
+* `ascent_penalty` - An extra cost added per unit of climb, in the elevation's units. Positive values favour flat routes and negative values hilly ones. Requires elevation data.
+* `direction` - A column marking one-way edges, such as an OpenStreetMap `oneway` tag. Values of `yes`, `true`, `t`, `1`, `FT` or `forward` allow travel only in the digitised direction, and `-1`, `TF`, `reverse` or `backward` only against it, ignoring case. Any other value allows both.
+* `turn_costs` - Costs charged for left turns, right turns and U-turns, as described by `TurnCosts`.
+* `turn_restrictions` - Whether the table's turn restrictions apply. Defaults to `true`; pedestrian profiles may want to ignore them.
+* `speed` - The travel speed, in kilometres per hour, used to convert between times and the profile's equivalent distances. Requests may override it.
+
+As in pgRouting, a negative or null cost marks an edge as impassable in that direction.
+*/
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct RoutingProfile {
+    cost: Option<String>,
+    forward_cost: Option<String>,
+    reverse_cost: Option<String>,
+    ascent_penalty: Option<f64>,
+    direction: Option<String>,
+    turn_costs: Option<TurnCosts>,
+    turn_restrictions: Option<bool>,
+    speed: Option<f64>,
+}
+
+impl RoutingProfile {
+    pub fn uses_elevation(&self) -> bool {
+        self.ascent_penalty.is_some()
+    }
+
+    pub fn turn_costs(&self) -> Option<&TurnCosts> {
+        self.turn_costs.as_ref()
+    }
+
+    pub fn obeys_turn_restrictions(&self) -> bool {
+        self.turn_restrictions.unwrap_or(true)
+    }
+
+    pub fn speed(&self) -> Option<f64> {
+        self.speed
+    }
+
+    /**
+    Builds the SQL expressions for the forward and reverse costs of an edge, for use in a query aliasing the table as `t` and its elevation gains as `elev`. Directions in which an edge cannot be travelled are given a cost of -1.
+
+    # Errors
+
+    - If the profile penalises ascent but the table has no elevation data, returns an `AppError`.
+    */
+    pub fn make_cost_expressions(
+        &self,
+        geom_col: &str,
+        use_geog: &str,
+        elevation: &ElevationSource,
+    ) -> Result<(String, String), AppError> {
+        let forward = match (&self.forward_cost, &self.cost) {
+            (Some(column), _) => format!("t.{}", column),
+            (None, Some(expression)) => format!("({})", expression),
+            (None, None) => format!("trunc(ST_Length(t.{}{}))", geom_col, use_geog),
+        };
+        let reverse = match &self.reverse_cost {
+            Some(column) => format!("t.{}", column),
+            None => forward.clone(),
+        };
+
+        //Climbing in one direction is descending in the other
+        let (forward, reverse) = match self.ascent_penalty {
+            Some(_) if !elevation.is_available() => {
+                return Err(AppError(anyhow!(
+                    "Routing profile requires elevation data, which the table does not have"
+                )))
+            }
+            Some(penalty) => (
+                format!(
+                    "case when {} >= 0 then greatest({} + {} * elev.ascent, 0) end",
+                    forward, forward, penalty
+                ),
+                format!(
+                    "case when {} >= 0 then greatest({} + {} * elev.descent, 0) end",
+                    reverse, reverse, penalty
+                ),
+            ),
+            None => (forward, reverse),
+        };
+
+        Ok(match &self.direction {
+            Some(column) => (
+                format!(
+                    "case when lower(t.{}::text) in ({}) then -1 else coalesce({}, -1) end",
+                    column, REVERSE_ONLY_VALUES, forward
+                ),
+                format!(
+                    "case when lower(t.{}::text) in ({}) then -1 else coalesce({}, -1) end",
+                    column, FORWARD_ONLY_VALUES, reverse
+                ),
+            ),
+            None => (
+                format!("coalesce({}, -1)", forward),
+                format!("coalesce({}, -1)", reverse),
+            ),
+        })
+    }
+}
+
+/**
+The routing profiles configured for each table, keyed by collection identifier (`schema.table`) and then by profile name. A profile named `default` is used when a request does not name one.
+*/
+#[derive(Clone, Debug, Default)]
+pub struct RoutingProfiles {
+    profiles: Arc<HashMap<String, HashMap<String, RoutingProfile>>>,
+}
+
+impl RoutingProfiles {
+    /**
+    Loads routing profiles from the JSON file named by the `ROUTING_PROFILES_PATH` environment variable. When the variable is unset, every table is routed by edge length alone.
+
+    # Errors
+
+    - If the file cannot be read or parsed, returns an `anyhow::Error`.
+    */
+    pub fn from_env() -> Result<RoutingProfiles> {
+        let path = match var("ROUTING_PROFILES_PATH") {
+            Ok(path) => path,
+            Err(_) => return Ok(RoutingProfiles::default()),
+        };
+
+        let contents = read_to_string(&path)
+            .with_context(|| format!("Failed to read routing profiles from {}", path))?;
+        let profiles = serde_json::from_str(&contents)
+            .with_context(|| format!("Failed to parse routing profiles in {}", path))?;
+
+        Ok(RoutingProfiles {
+            profiles: Arc::new(profiles),
+        })
+    }
+
+    /**
+    Looks up the profile to route a table with.
+
+    # Arguments
+
+    * `table` - The table being routed over.
+    * `name` - The profile requested, if any.
+
+    # Returns
+
+    A `Result` containing the named profile, the table's `default` profile or, failing that, a profile weighting edges by length. Returns an `AppError` if a named profile is not configured for the table.
+    */
+    pub fn get(&self, table: &Table, name: Option<&str>) -> Result<RoutingProfile, AppError> {
+        let table_profiles = self
+            .profiles
+            .get(&format!("{}.{}", table.schema_name, table.name));
+
+        match name {
+            Some(name) => table_profiles
+                .and_then(|profiles| profiles.get(name))
+                .cloned()
+                .ok_or_else(|| AppError(anyhow!("Unknown routing profile: {}", name))),
+            None => Ok(table_profiles
+                .and_then(|profiles| profiles.get("default"))
+                .cloned()
+                .unwrap_or_default()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile(json: &str) -> RoutingProfile {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn costs_default_to_edge_length_both_ways() {
+        let (forward, reverse) = RoutingProfile::default()
+            .make_cost_expressions("geom", "::geography", &ElevationSource::None)
+            .unwrap();
+        assert_eq!(forward, "coalesce(trunc(ST_Length(t.geom::geography)), -1)");
+        assert_eq!(reverse, forward);
+    }
+
+    #[test]
+    fn cost_columns_take_precedence_over_expressions() {
+        let (forward, reverse) = profile(
+            r#"{"cost": "length * 2", "forward_cost": "cost_ft", "reverse_cost": "cost_tf"}"#,
+        )
+        .make_cost_expressions("geom", "", &ElevationSource::None)
+        .unwrap();
+        assert_eq!(forward, "coalesce(t.cost_ft, -1)");
+        assert_eq!(reverse, "coalesce(t.cost_tf, -1)");
+
+        let (forward, reverse) = profile(r#"{"cost": "length * 2"}"#)
+            .make_cost_expressions("geom", "", &ElevationSource::None)
+            .unwrap();
+        assert_eq!(forward, "coalesce((length * 2), -1)");
+        assert_eq!(reverse, forward);
+    }
+
+    #[test]
+    fn direction_column_closes_the_opposite_direction() {
+        let (forward, reverse) = profile(r#"{"cost": "length", "direction": "oneway"}"#)
+            .make_cost_expressions("geom", "", &ElevationSource::None)
+            .unwrap();
+        assert_eq!(
+            forward,
+            "case when lower(t.oneway::text) in ('-1', 'tf', 'reverse', 'backward') then -1 else coalesce((length), -1) end"
+        );
+        assert_eq!(
+            reverse,
+            "case when lower(t.oneway::text) in ('yes', 'true', 't', '1', 'ft', 'forward') then -1 else coalesce((length), -1) end"
+        );
+    }
+
+    #[test]
+    fn ascent_penalty_charges_climbs_in_each_direction() {
+        let climbing = profile(r#"{"cost": "length", "ascent_penalty": 10.0}"#);
+        let (forward, reverse) = climbing
+            .make_cost_expressions("geom", "", &ElevationSource::Geometry)
+            .unwrap();
+        assert_eq!(
+            forward,
+            "coalesce(case when (length) >= 0 then greatest((length) + 10 * elev.ascent, 0) end, -1)"
+        );
+        assert_eq!(
+            reverse,
+            "coalesce(case when (length) >= 0 then greatest((length) + 10 * elev.descent, 0) end, -1)"
+        );
+
+        assert!(climbing
+            .make_cost_expressions("geom", "", &ElevationSource::None)
+            .is_err());
+    }
+}
@@ -0,0 +1,458 @@
+use super::utils::TopoEdgeRepresentation;
+use crate::db::Table;
+use crate::AppError;
+use anyhow::anyhow;
+use geo_types::Point;
+use serde::{Deserialize, Serialize};
+use sqlx::{query_as, PgPool, Postgres};
+use std::collections::HashMap;
+use std::iter::once;
+
+/**
+Node ID assigned to the temporary vertex created when a point is snapped part-way along an edge. Requests snapping several points count down from here, one ID per point. These never collide with real vertices, as routable tables store node IDs as `i32`.
+*/
+pub const VIRTUAL_NODE_ID: u32 = u32::MAX;
+
+//Number of nearby edges whose end points are considered when snapping to a vertex
+pub(super) const SNAP_CANDIDATE_EDGES: usize = 10;
+//Split points closer than this fraction of the edge to either end snap to that end instead
+pub(super) const SPLIT_FRACTION_TOLERANCE: f64 = 0.001;
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SnapMode {
+    #[default]
+    Vertex,
+    Edge,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SnapTarget {
+    Node,
+    Vertex,
+    Edge,
+}
+
+#[derive(Debug, Clone)]
+pub struct EdgeSplit {
+    pub edge_id: u32,
+    pub node_id: u32,
+    pub fraction: f64,
+}
+
+/**
+The graph location a requested point was resolved to, as returned to the client.
+
+* `node_id` - The graph vertex used for routing, or `None` if the point was split part-way along an edge.
+* `edge_id` - The edge the point was snapped onto, if snapped to an edge.
+* `geometry` - The snapped location, in Web Mercator.
+* `distance` - The distance between the requested and snapped locations, in the table's distance units.
+* `snapped_to` - Whether the point was given as a node, or snapped to a vertex or an edge.
+*/
+#[derive(Serialize, Debug, Clone)]
+pub struct SnappedPoint {
+    pub node_id: Option<u32>,
+    pub edge_id: Option<u32>,
+    pub geometry: geojson::Geometry,
+    pub distance: f64,
+    pub units: Option<String>,
+    pub snapped_to: SnapTarget,
+    #[serde(skip)]
+    pub split: Option<EdgeSplit>,
+}
+
+impl SnappedPoint {
+    /**
+    Describes a point supplied by the client with an explicit node ID, which is used without snapping.
+    */
+    pub fn from_node(node_id: u32, point: &Point<f64>, table: &Table) -> SnappedPoint {
+        SnappedPoint {
+            node_id: Some(node_id),
+            edge_id: None,
+            geometry: geojson::Geometry::new(geojson::Value::from(point)),
+            distance: 0.0,
+            units: table.dist_unit.clone(),
+            snapped_to: SnapTarget::Node,
+            split: None,
+        }
+    }
+
+    /**
+    Returns the node ID routing should use for this point, which is a virtual node for points split along an edge.
+    */
+    pub fn routing_node_id(&self) -> u32 {
+        match (self.node_id, &self.split) {
+            (Some(node_id), _) => node_id,
+            (None, Some(split)) => split.node_id,
+            (None, None) => VIRTUAL_NODE_ID,
+        }
+    }
+}
+
+#[derive(sqlx::FromRow, Debug)]
+struct SnapCandidate {
+    edge_id: i32,
+    vertex_id: i32,
+    vertex_x: f64,
+    vertex_y: f64,
+    vertex_distance: f64,
+    fraction: f64,
+    split_x: f64,
+    split_y: f64,
+    split_distance: f64,
+}
+
+impl SnapCandidate {
+    /**
+    Resolves the nearest vertex and nearest point along an edge into the location used for routing, splitting the edge only where the point lies clear of its ends.
+    */
+    fn resolve(
+        self,
+        table: &Table,
+        mode: SnapMode,
+        virtual_node_id: u32,
+    ) -> Result<SnappedPoint, AppError> {
+        let splits_edge = mode == SnapMode::Edge
+            && self.fraction > SPLIT_FRACTION_TOLERANCE
+            && self.fraction < 1.0 - SPLIT_FRACTION_TOLERANCE;
+
+        if splits_edge {
+            let edge_id = u32::try_from(self.edge_id)
+                .map_err(|_| AppError(anyhow!("Invalid edge id: {}", self.edge_id)))?;
+            Ok(SnappedPoint {
+                node_id: None,
+                edge_id: Some(edge_id),
+                geometry: geojson::Geometry::new(geojson::Value::from(&Point::new(
+                    self.split_x,
+                    self.split_y,
+                ))),
+                distance: self.split_distance,
+                units: table.dist_unit.clone(),
+                snapped_to: SnapTarget::Edge,
+                split: Some(EdgeSplit {
+                    edge_id,
+                    node_id: virtual_node_id,
+                    fraction: self.fraction,
+                }),
+            })
+        } else {
+            let node_id = u32::try_from(self.vertex_id)
+                .map_err(|_| AppError(anyhow!("Invalid node id: {}", self.vertex_id)))?;
+            Ok(SnappedPoint {
+                node_id: Some(node_id),
+                edge_id: None,
+                geometry: geojson::Geometry::new(geojson::Value::from(&Point::new(
+                    self.vertex_x,
+                    self.vertex_y,
+                ))),
+                distance: self.vertex_distance,
+                units: table.dist_unit.clone(),
+                snapped_to: SnapTarget::Vertex,
+                split: None,
+            })
+        }
+    }
+}
+
+/**
+Snaps a point to the routing network held in a table.
+
+In `SnapMode::Vertex`, the point is snapped to the closest end point of the edges nearest to it. In `SnapMode::Edge`, it is snapped to the closest point on the nearest edge; unless that point lies at one of the edge's ends, the result carries an `EdgeSplit` that must be applied to the routing subgraph with `split_edges`.
+
+# Arguments
+
+* `pool` - A PostgreSQL connection pool.
+* `table` - A struct representing the database table.
+* `point` - The requested start, in Web Mercator.
+* `mode` - Whether to snap to vertices only, or to split points along edges.
+* `virtual_node_id` - The node ID given to the split point, if the point is split along an edge.
+
+# Returns
+
+A `Result` containing the `SnappedPoint`, or an `AppError` if the table has no usable edges or the query fails.
+*/
+pub async fn snap_to_network(
+    pool: &PgPool,
+    table: &Table,
+    point: &Point<f64>,
+    mode: SnapMode,
+    virtual_node_id: u32,
+) -> Result<SnappedPoint, AppError> {
+    let (geom_col, srid, pk_col) = match (
+        table.geom_column.as_ref(),
+        table.srid.as_ref(),
+        table.primary_key_columns.first(),
+    ) {
+        (Some(geom_col), Some(srid), Some(pk_col)) => (geom_col, srid, pk_col),
+        _ => {
+            return Err(AppError(anyhow!(
+                "Table does not contain valid geometry data"
+            )))
+        }
+    };
+    let use_geog = if table.use_geog { "::geography" } else { "" };
+
+    let snap_query = format!(
+        "with pt as (
+            select ST_Transform(ST_SetSRID(ST_MakePoint($1, $2), 3857), {srid}) as g
+        ),
+        nearest_edges as (
+            select t.{pk} as edge_id, t.start_node, t.end_node, ST_GeometryN(ST_LineMerge(ST_Multi(t.{geom})), 1) as geom
+            from {schema}.{table} t, pt
+            order by t.{geom} <-> pt.g
+            limit {candidates}
+        ),
+        vertices as (
+            select e.start_node as vertex_id, ST_StartPoint(e.geom) as geom from nearest_edges e
+            union all
+            select e.end_node as vertex_id, ST_EndPoint(e.geom) as geom from nearest_edges e
+        ),
+        nearest_vertex as (
+            select v.vertex_id, v.geom
+            from vertices v, pt
+            order by ST_Distance(v.geom, pt.g)
+            limit 1
+        ),
+        nearest_edge as (
+            select e.edge_id, e.geom
+            from nearest_edges e, pt
+            order by ST_Distance(e.geom, pt.g)
+            limit 1
+        )
+        select
+            ne.edge_id,
+            nv.vertex_id,
+            ST_X(ST_Transform(nv.geom, 3857)) as vertex_x,
+            ST_Y(ST_Transform(nv.geom, 3857)) as vertex_y,
+            ST_Distance(nv.geom{g}, pt.g{g}) as vertex_distance,
+            ST_LineLocatePoint(ne.geom, pt.g) as fraction,
+            ST_X(ST_Transform(ST_ClosestPoint(ne.geom, pt.g), 3857)) as split_x,
+            ST_Y(ST_Transform(ST_ClosestPoint(ne.geom, pt.g), 3857)) as split_y,
+            ST_Distance(ne.geom{g}, pt.g{g}) as split_distance
+        from nearest_vertex nv, nearest_edge ne, pt",
+        srid = srid,
+        pk = pk_col,
+        geom = geom_col,
+        schema = table.schema_name,
+        table = table.name,
+        candidates = SNAP_CANDIDATE_EDGES,
+        g = use_geog
+    );
+
+    let candidate = query_as::<Postgres, SnapCandidate>(&snap_query)
+        .bind(point.x())
+        .bind(point.y())
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| AppError(anyhow!(e)))?
+        .ok_or_else(|| AppError(anyhow!("No routable edges found in specified table")))?;
+
+    candidate.resolve(table, mode, virtual_node_id)
+}
+
+/**
+Replaces each edge that points were snapped onto with a chain of edges joined at the points' virtual nodes, weighted in proportion to the portion of the edge each covers. Every piece keeps the original edge ID, so routes through them map back onto the original edge.
+*/
+pub fn split_edges(edges: &mut Vec<TopoEdgeRepresentation>, splits: &[&EdgeSplit]) {
+    let mut splits_by_edge: HashMap<u32, Vec<&EdgeSplit>> = HashMap::new();
+    for split in splits {
+        splits_by_edge.entry(split.edge_id).or_default().push(split);
+    }
+
+    for (edge_id, mut edge_splits) in splits_by_edge {
+        let Some(idx) = edges
+            .iter()
+            .position(|e| e.edge_id == edge_id && e.span.is_none())
+        else {
+            continue;
+        };
+        let edge = edges.swap_remove(idx);
+        edge_splits.sort_by(|a, b| a.fraction.total_cmp(&b.fraction));
+
+        let mut from = (edge.start_node, 0.0);
+        for to in edge_splits
+            .iter()
+            .map(|s| (s.node_id, s.fraction))
+            .chain(once((edge.end_node, 1.0)))
+        {
+            //Headings are only known at the original edge's ends
+            edges.push(TopoEdgeRepresentation {
+                start_node: from.0,
+                end_node: to.0,
+                weight: edge.weight * (to.1 - from.1),
+                reverse_weight: edge.reverse_weight * (to.1 - from.1),
+                length: edge.length * (to.1 - from.1),
+                start_bearing: edge.start_bearing.filter(|_| from.1 == 0.0),
+                end_bearing: edge.end_bearing.filter(|_| to.1 == 1.0),
+                span: Some((from.1, to.1)),
+                ..edge.clone()
+            });
+            from = to;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::routing::graph::RoutingGraph;
+    use crate::routing::turns::TurnRules;
+
+    fn candidate(fraction: f64) -> SnapCandidate {
+        SnapCandidate {
+            edge_id: 7,
+            vertex_id: 1,
+            vertex_x: 0.0,
+            vertex_y: 0.0,
+            vertex_distance: 4.0,
+            fraction,
+            split_x: 50.0,
+            split_y: 0.0,
+            split_distance: 3.0,
+        }
+    }
+
+    fn roads() -> Table {
+        Table::new(
+            "roads".to_string(),
+            "public".to_string(),
+            vec!["id".to_string()],
+            "geom".to_string(),
+            "LINESTRING".to_string(),
+            3857,
+            None,
+            "m".to_string(),
+            false,
+        )
+    }
+
+    fn edge(reverse_weight: f64) -> TopoEdgeRepresentation {
+        TopoEdgeRepresentation {
+            start_bearing: Some(90.0),
+            end_bearing: Some(180.0),
+            ..TopoEdgeRepresentation::new(7, 1, 2, 10.0, reverse_weight, 100.0)
+        }
+    }
+
+    fn split(node_id: u32, fraction: f64) -> EdgeSplit {
+        EdgeSplit {
+            edge_id: 7,
+            node_id,
+            fraction,
+        }
+    }
+
+    fn sum(edges: &[TopoEdgeRepresentation], value: fn(&TopoEdgeRepresentation) -> f64) -> f64 {
+        edges.iter().map(value).sum()
+    }
+
+    #[test]
+    fn mid_edge_points_split_the_edge() {
+        let snapped = candidate(0.25)
+            .resolve(&roads(), SnapMode::Edge, VIRTUAL_NODE_ID)
+            .unwrap();
+        assert_eq!(snapped.snapped_to, SnapTarget::Edge);
+        assert_eq!((snapped.node_id, snapped.edge_id), (None, Some(7)));
+        assert_eq!(snapped.routing_node_id(), VIRTUAL_NODE_ID);
+        assert_eq!(snapped.distance, 3.0);
+
+        let mut edges = vec![edge(12.0)];
+        split_edges(&mut edges, &[snapped.split.as_ref().unwrap()]);
+        assert_eq!(edges.len(), 2);
+        assert_eq!(
+            (edges[0].start_node, edges[0].end_node, edges[0].span),
+            (1, VIRTUAL_NODE_ID, Some((0.0, 0.25)))
+        );
+        assert_eq!(
+            (edges[1].start_node, edges[1].end_node, edges[1].span),
+            (VIRTUAL_NODE_ID, 2, Some((0.25, 1.0)))
+        );
+        assert_eq!((edges[0].weight, edges[0].reverse_weight), (2.5, 3.0));
+        assert_eq!(sum(&edges, |e| e.weight), 10.0);
+        assert_eq!(sum(&edges, |e| e.reverse_weight), 12.0);
+        assert_eq!(sum(&edges, |e| e.length), 100.0);
+
+        //Headings are kept only where the pieces meet the original edge's ends
+        assert_eq!(
+            (edges[0].start_bearing, edges[0].end_bearing),
+            (Some(90.0), None)
+        );
+        assert_eq!(
+            (edges[1].start_bearing, edges[1].end_bearing),
+            (None, Some(180.0))
+        );
+    }
+
+    #[test]
+    fn several_points_split_one_edge_in_order() {
+        let mut edges = vec![
+            edge(12.0),
+            TopoEdgeRepresentation::new(8, 2, 3, 1.0, 1.0, 10.0),
+        ];
+        let (end, start) = (
+            split(VIRTUAL_NODE_ID - 1, 0.75),
+            split(VIRTUAL_NODE_ID, 0.5),
+        );
+        split_edges(&mut edges, &[&end, &start]);
+
+        let pieces = edges
+            .iter()
+            .filter(|e| e.edge_id == 7)
+            .cloned()
+            .collect::<Vec<TopoEdgeRepresentation>>();
+        let chain = pieces
+            .iter()
+            .map(|e| (e.start_node, e.end_node))
+            .collect::<Vec<(u32, u32)>>();
+        assert_eq!(
+            chain,
+            vec![
+                (1, VIRTUAL_NODE_ID),
+                (VIRTUAL_NODE_ID, VIRTUAL_NODE_ID - 1),
+                (VIRTUAL_NODE_ID - 1, 2)
+            ]
+        );
+        assert_eq!(sum(&pieces, |e| e.weight), 10.0);
+        assert_eq!(sum(&pieces, |e| e.reverse_weight), 12.0);
+        assert_eq!(sum(&pieces, |e| e.length), 100.0);
+        assert!(edges.iter().any(|e| e.edge_id == 8 && e.span.is_none()));
+    }
+
+    #[test]
+    fn points_near_an_end_snap_to_the_vertex() {
+        for (fraction, mode) in [
+            (0.0005, SnapMode::Edge),
+            (0.9995, SnapMode::Edge),
+            (0.5, SnapMode::Vertex),
+        ] {
+            let snapped = candidate(fraction)
+                .resolve(&roads(), mode, VIRTUAL_NODE_ID)
+                .unwrap();
+            assert_eq!(snapped.snapped_to, SnapTarget::Vertex);
+            assert_eq!((snapped.node_id, snapped.edge_id), (Some(1), None));
+            assert!(snapped.split.is_none());
+            assert_eq!(snapped.distance, 4.0);
+        }
+
+        //Without a split, the subgraph is left as it was
+        let mut edges = vec![edge(12.0)];
+        split_edges(&mut edges, &[]);
+        assert_eq!(edges.len(), 1);
+        assert!(edges[0].span.is_none());
+    }
+
+    #[test]
+    fn one_way_edges_stay_one_way_when_split() {
+        let mut edges = vec![edge(-1.0)];
+        split_edges(&mut edges, &[&split(VIRTUAL_NODE_ID, 0.4)]);
+        assert!(edges.iter().all(|e| e.reverse_weight < 0.0));
+        assert!((sum(&edges, |e| e.weight) - 10.0).abs() < 1e-9);
+
+        let graph = RoutingGraph::new(&edges, &TurnRules::default());
+        let onward = graph.shortest_path(VIRTUAL_NODE_ID, 2).unwrap();
+        assert!((onward.cost - 6.0).abs() < 1e-9);
+        assert_eq!(onward.edges[0].from_fraction, 0.4);
+        assert!(graph.shortest_path(VIRTUAL_NODE_ID, 1).is_none());
+    }
+}
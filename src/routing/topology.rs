@@ -0,0 +1,757 @@
+use super::find_table;
+use super::units::{measured_unit, metres_to_table_distance};
+use crate::db::{Table, TableRegistry};
+use crate::validate_identifier;
+use anyhow::{anyhow, Context, Result};
+use serde::Serialize;
+use sqlx::{query, query_as, PgPool, Postgres};
+use std::collections::HashMap;
+
+//Distance, in metres, within which line ends are joined to nearby lines unless told otherwise
+const DEFAULT_TOLERANCE_METRES: f64 = 1.0;
+//Approximate length of a degree at the equator, used to express tolerances for tables in degrees
+const METRES_PER_DEGREE: f64 = 111_320.0;
+//Suffixes of the tables written, after the source table's name
+const DEFAULT_OUTPUT_SUFFIX: &str = "_topology";
+const VERTICES_SUFFIX: &str = "_vertices";
+//Number of disconnected components listed in the summary
+pub(super) const MAX_LISTED_COMPONENTS: usize = 20;
+//Columns written by the builder, which source columns of the same name cannot be copied over
+const RESERVED_COLUMNS: [&str; 6] = [
+    "id",
+    "source_id",
+    "start_node",
+    "end_node",
+    "component",
+    "geom",
+];
+
+/**
+Options for building a routing topology, read from the arguments following the `build-topology` command:
+
+```text
+build-topology <schema>.<table> [--tolerance <metres>] [--output <table>] [--overwrite]
+```
+
+* `schema`, `table` - The registered line table to build from.
+* `tolerance` - The distance, in metres, within which line ends are joined to nearby lines and vertices are merged. Defaults to 1.
+* `output` - The name of the edge table to write, in the same schema. Defaults to the source table's name with a `_topology` suffix. Vertices are written to a table of the same name with a `_vertices` suffix.
+* `overwrite` - Whether to replace existing output tables.
+*/
+#[derive(Debug, Clone)]
+pub struct TopologyOptions {
+    schema: String,
+    table: String,
+    tolerance: f64,
+    output: Option<String>,
+    overwrite: bool,
+}
+
+impl TopologyOptions {
+    /**
+    Reads the options from command line arguments.
+
+    # Errors
+
+    - If the table is missing or not qualified by its schema, an option is unknown or lacks its value, or the tolerance is not a non-negative number, returns an `anyhow::Error`.
+    */
+    pub fn from_args(args: &[String]) -> Result<TopologyOptions> {
+        let mut source = None;
+        let mut tolerance = DEFAULT_TOLERANCE_METRES;
+        let mut output = None;
+        let mut overwrite = false;
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--tolerance" => {
+                    tolerance = args
+                        .next()
+                        .context("--tolerance requires a value")?
+                        .parse::<f64>()
+                        .context("--tolerance must be a number")?;
+                    if tolerance.is_nan() || tolerance < 0.0 {
+                        return Err(anyhow!("--tolerance must not be negative"));
+                    }
+                }
+                "--output" => {
+                    output = Some(
+                        validate_identifier(args.next().context("--output requires a value")?)?
+                            .to_string(),
+                    );
+                }
+                "--overwrite" => overwrite = true,
+                other if other.starts_with("--") => {
+                    return Err(anyhow!("Unknown option: {}", other))
+                }
+                other if source.is_none() => source = Some(other.to_string()),
+                other => return Err(anyhow!("Unexpected argument: {}", other)),
+            }
+        }
+
+        let source = source.context("A source table, as <schema>.<table>, is required")?;
+        let (schema, table) = source
+            .split_once('.')
+            .context("The source table must be given as <schema>.<table>")?;
+
+        Ok(TopologyOptions {
+            schema: schema.to_string(),
+            table: table.to_string(),
+            tolerance,
+            output,
+            overwrite,
+        })
+    }
+}
+
+/**
+A connected part of a built network.
+
+* `component` - The component's number, with 1 the largest by length.
+* `edges` - The number of edges in the component.
+* `vertices` - The number of vertices in the component.
+* `length` - The total length of the component's edges, in the units of the summary it appears in.
+*/
+#[derive(Serialize, Debug, Clone)]
+pub struct ComponentSummary {
+    pub component: usize,
+    pub edges: usize,
+    pub vertices: usize,
+    pub length: f64,
+}
+
+/**
+A report on a built topology.
+
+* `edges_table`, `vertices_table` - The tables written, qualified by schema.
+* `source_lines` - The number of lines read from the source table.
+* `edges`, `vertices` - The numbers of edges and vertices written.
+* `dead_ends` - The number of vertices joined to a single edge.
+* `components` - The number of connected components.
+* `largest_component_share` - The share of the network's length in the largest component.
+* `disconnected` - The components other than the largest, largest first, up to 20 of them.
+* `units` - The distance units of the lengths reported: metres for tables in geographic coordinates, and the table's distance unit otherwise.
+*/
+#[derive(Serialize, Debug, Clone)]
+pub struct TopologySummary {
+    pub edges_table: String,
+    pub vertices_table: String,
+    pub source_lines: i64,
+    pub edges: usize,
+    pub vertices: usize,
+    pub dead_ends: usize,
+    pub components: usize,
+    pub largest_component_share: f64,
+    pub disconnected: Vec<ComponentSummary>,
+    pub units: Option<String>,
+}
+
+/**
+An edge of a routable table, as needed to analyse the network's connectivity.
+*/
+#[derive(sqlx::FromRow)]
+pub(super) struct NetworkEdge {
+    pub(super) id: i32,
+    pub(super) start_node: i32,
+    pub(super) end_node: i32,
+    pub(super) length: f64,
+}
+
+/**
+Lists the columns of the source table copied onto each edge: every column apart from the geometry, the primary key, which is kept as `source_id`, and any whose names the builder uses itself.
+*/
+async fn get_copied_columns(
+    pool: &PgPool,
+    table: &Table,
+    geom_col: &str,
+    pk_col: &str,
+) -> Result<Vec<String>> {
+    let columns = query_as::<Postgres, (String,)>(
+        "select column_name::text
+        from information_schema.columns
+        where table_schema = $1 and table_name = $2
+        order by ordinal_position",
+    )
+    .bind(&table.schema_name)
+    .bind(&table.name)
+    .fetch_all(pool)
+    .await
+    .context("Failed to read the source table's columns")?;
+
+    Ok(columns
+        .into_iter()
+        .map(|(name,)| name)
+        .filter(|name| {
+            name != geom_col && name != pk_col && !RESERVED_COLUMNS.contains(&name.as_str())
+        })
+        .collect())
+}
+
+/**
+Numbers the connected components of a network, from 1 for the largest by length. Components of equal length are numbered in order of their lowest vertex ID.
+
+# Returns
+
+The component of each vertex.
+*/
+pub(super) fn label_components(edges: &[NetworkEdge]) -> HashMap<i32, usize> {
+    //Union-find over the vertices, with path halving
+    let mut parents: HashMap<i32, i32> = HashMap::new();
+    fn find(parents: &mut HashMap<i32, i32>, vertex: i32) -> i32 {
+        let mut vertex = vertex;
+        loop {
+            let parent = *parents.entry(vertex).or_insert(vertex);
+            if parent == vertex {
+                return vertex;
+            }
+            let grandparent = *parents.entry(parent).or_insert(parent);
+            parents.insert(vertex, grandparent);
+            vertex = grandparent;
+        }
+    }
+    for edge in edges {
+        let (a, b) = (
+            find(&mut parents, edge.start_node),
+            find(&mut parents, edge.end_node),
+        );
+        if a != b {
+            parents.insert(a, b);
+        }
+    }
+
+    //The length and lowest vertex of each component, keyed by its root
+    let mut totals: HashMap<i32, (f64, i32)> = HashMap::new();
+    for edge in edges {
+        let root = find(&mut parents, edge.start_node);
+        let total = totals.entry(root).or_insert((0.0, i32::MAX));
+        total.0 += edge.length;
+        total.1 = total.1.min(edge.start_node).min(edge.end_node);
+    }
+    let mut roots = totals.into_iter().collect::<Vec<(i32, (f64, i32))>>();
+    roots.sort_by(|a, b| b.1 .0.total_cmp(&a.1 .0).then(a.1 .1.cmp(&b.1 .1)));
+    let numbers = roots
+        .into_iter()
+        .enumerate()
+        .map(|(i, (root, _))| (root, i + 1))
+        .collect::<HashMap<i32, usize>>();
+
+    let vertices = parents.keys().copied().collect::<Vec<i32>>();
+    vertices
+        .into_iter()
+        .map(|vertex| {
+            let root = find(&mut parents, vertex);
+            (vertex, numbers[&root])
+        })
+        .collect()
+}
+
+/**
+Totals the edges, vertices and length of each connected component of a network.
+
+# Returns
+
+The components' summaries, ordered by component number.
+*/
+pub(super) fn summarize_components(
+    edges: &[NetworkEdge],
+    components: &HashMap<i32, usize>,
+) -> Vec<ComponentSummary> {
+    let mut summaries: HashMap<usize, ComponentSummary> = HashMap::new();
+    for edge in edges.iter() {
+        let component = components[&edge.start_node];
+        let summary = summaries.entry(component).or_insert(ComponentSummary {
+            component,
+            edges: 0,
+            vertices: 0,
+            length: 0.0,
+        });
+        summary.edges += 1;
+        summary.length += edge.length;
+    }
+    for component in components.values() {
+        if let Some(summary) = summaries.get_mut(component) {
+            summary.vertices += 1;
+        }
+    }
+    let mut summaries = summaries.into_values().collect::<Vec<ComponentSummary>>();
+    summaries.sort_by_key(|s| s.component);
+    summaries
+}
+
+/**
+Builds a routing topology from a registered line table, writing an edge table with `start_node` and `end_node` columns ready for the routing endpoints, and a table of its vertices.
+
+Lines are first joined to their neighbours: each line end within the tolerance of another line is moved onto it, and the other line gains a vertex there. The lines are then noded at every intersection, each resulting edge taking the attributes of the source line it came from and its ID as `source_id`. Edge ends within the tolerance of each other share a vertex. Finally, the network's connected components are numbered, largest first, in a `component` column on both tables. Everything is written in one transaction, so a failed build leaves no tables behind.
+
+Geometries are flattened to two dimensions. The tables are only registered for routing once the server is restarted.
+
+# Arguments
+
+* `pool` - A PostgreSQL connection pool.
+* `registry` - The table registry, in which the source table must appear.
+* `options` - The source and output tables and the tolerance.
+
+# Returns
+
+A `Result` containing a summary of the topology built, including its disconnected components.
+
+# Errors
+
+- If the source table is not registered, has no line geometry or no single-column primary key, the output tables exist and `overwrite` is not set, or a query fails, returns an `anyhow::Error`.
+*/
+pub async fn build_topology(
+    pool: &PgPool,
+    registry: &TableRegistry,
+    options: &TopologyOptions,
+) -> Result<TopologySummary> {
+    let table = find_table(registry, &options.schema, &options.table)
+        .map_err(|e| anyhow!("{}.{}: {}", options.schema, options.table, e.0))?;
+    let (geom_col, srid, dist_unit) = match (
+        table.geom_column.as_ref(),
+        table.srid.as_ref(),
+        table.dist_unit.as_ref(),
+    ) {
+        (Some(geom_col), Some(srid), Some(dist_unit)) => (geom_col, srid, dist_unit),
+        _ => return Err(anyhow!("Table does not contain valid geometry data")),
+    };
+    if !table
+        .geom_type
+        .as_deref()
+        .is_some_and(|t| t.to_uppercase().contains("LINESTRING"))
+    {
+        return Err(anyhow!("Topologies can only be built from line tables"));
+    }
+    let pk_col = match &table.primary_key_columns[..] {
+        [pk_col] => pk_col,
+        _ => return Err(anyhow!("Table must have a single-column primary key")),
+    };
+
+    let output = match &options.output {
+        Some(output) => output.clone(),
+        None => {
+            validate_identifier(&format!("{}{}", table.name, DEFAULT_OUTPUT_SUFFIX))?.to_string()
+        }
+    };
+    let vertices = format!("{}{}", output, VERTICES_SUFFIX);
+    if output == table.name || vertices == table.name {
+        return Err(anyhow!("The output tables cannot replace the source table"));
+    }
+    let schema = &table.schema_name;
+    let tolerance = match dist_unit.as_str() {
+        "deg" => options.tolerance / METRES_PER_DEGREE,
+        unit => metres_to_table_distance(options.tolerance, unit).map_err(|e| e.0)?,
+    };
+    let use_geog = if table.use_geog { "::geography" } else { "" };
+    let copied_columns = get_copied_columns(pool, table, geom_col, pk_col).await?;
+
+    let mut tx = pool
+        .begin()
+        .await
+        .context("Failed to start a transaction")?;
+
+    let existing = query_as::<Postgres, (i64,)>(
+        "select count(*) from information_schema.tables
+        where table_schema = $1 and table_name in ($2, $3)",
+    )
+    .bind(schema)
+    .bind(&output)
+    .bind(&vertices)
+    .fetch_one(&mut *tx)
+    .await
+    .context("Failed to check for existing output tables")?;
+    if existing.0 > 0 {
+        if !options.overwrite {
+            return Err(anyhow!(
+                "{}.{} or {}.{} already exists; pass --overwrite to replace them",
+                schema,
+                output,
+                schema,
+                vertices
+            ));
+        }
+        query(&format!(
+            "drop table if exists {s}.{o}, {s}.{v}",
+            s = schema,
+            o = output,
+            v = vertices
+        ))
+        .execute(&mut *tx)
+        .await
+        .context("Failed to drop existing output tables")?;
+    }
+
+    let mut statements = vec![
+        format!(
+            "create temp table topo_src on commit drop as
+            select row_number() over ()::int4 as seq, d.source_id, d.geom
+            from (
+                select t.{pk} as source_id, (ST_Dump(ST_LineMerge(ST_Multi(ST_Force2D(t.{geom}))))).geom as geom
+                from {schema}.{table} t
+                where t.{geom} is not null
+            ) d
+            where ST_GeometryType(d.geom) = 'ST_LineString'",
+            pk = pk_col,
+            geom = geom_col,
+            schema = schema,
+            table = table.name
+        ),
+        "create index on topo_src using gist (geom)".to_string(),
+    ];
+    if tolerance > 0.0 {
+        //Line ends near another line are moved onto it, and every line near a line end gains a
+        //vertex exactly there, so that noding joins them
+        for (index, end) in [
+            ("0", "ST_StartPoint"),
+            ("ST_NPoints(s.geom) - 1", "ST_EndPoint"),
+        ] {
+            statements.push(format!(
+                "update topo_src s
+                set geom = ST_SetPoint(s.geom, {index}, coalesce((
+                    select ST_ClosestPoint(o.geom, {end}(s.geom))
+                    from topo_src o
+                    where o.seq <> s.seq and ST_DWithin(o.geom, {end}(s.geom), {tol})
+                    order by o.geom <-> {end}(s.geom)
+                    limit 1
+                ), {end}(s.geom)))",
+                index = index,
+                end = end,
+                tol = tolerance
+            ));
+        }
+        statements.push(format!(
+            "update topo_src s
+            set geom = ST_Snap(s.geom, p.ends, {tol})
+            from (
+                select s.seq, ST_Collect(e.geom) as ends
+                from topo_src s
+                    join (
+                        select seq, ST_StartPoint(geom) as geom from topo_src
+                        union all
+                        select seq, ST_EndPoint(geom) as geom from topo_src
+                    ) e on e.seq <> s.seq and ST_DWithin(s.geom, e.geom, {tol})
+                group by s.seq
+            ) p
+            where p.seq = s.seq",
+            tol = tolerance
+        ));
+    }
+    statements.extend([
+        "create temp table topo_edges on commit drop as
+        select row_number() over ()::int4 as id, n.geom, null::int4 as source_seq
+        from (select (ST_Dump(ST_Node(ST_Collect(geom)))).geom as geom from topo_src) n
+        where ST_Length(n.geom) > 0"
+            .to_string(),
+        //An edge comes from the line covering it or, where snapping has shifted it slightly, the line
+        //it overlaps most. Only edges overlapping no line fall back to the line nearest their midpoint,
+        //which at a crossing may be the other line
+        "update topo_edges e
+        set source_seq = coalesce(
+            (
+                select s.seq from topo_src s
+                where s.geom && e.geom and ST_Length(ST_Intersection(s.geom, e.geom)) > 0
+                order by ST_Covers(s.geom, e.geom) desc, ST_Length(ST_Intersection(s.geom, e.geom)) desc, s.seq
+                limit 1
+            ),
+            (
+                select s.seq from topo_src s
+                order by s.geom <-> ST_LineInterpolatePoint(e.geom, 0.5)
+                limit 1
+            )
+        )"
+        .to_string(),
+        format!(
+            "create temp table topo_ends on commit drop as
+            select c.edge_id, c.side, c.geom, ST_ClusterDBSCAN(c.geom, eps := {tol}, minpoints := 1) over () + 1 as vertex_id
+            from (
+                select id as edge_id, 0 as side, ST_StartPoint(geom) as geom from topo_edges
+                union all
+                select id as edge_id, 1 as side, ST_EndPoint(geom) as geom from topo_edges
+            ) c",
+            tol = tolerance
+        ),
+        format!(
+            "create table {schema}.{output} as
+            select
+                e.id,
+                s.source_id,
+                v0.vertex_id::int4 as start_node,
+                v1.vertex_id::int4 as end_node,
+                null::int4 as component{columns},
+                e.geom
+            from topo_edges e
+                join topo_src s on s.seq = e.source_seq
+                join {schema}.{table} t on t.{pk} = s.source_id
+                join topo_ends v0 on v0.edge_id = e.id and v0.side = 0
+                join topo_ends v1 on v1.edge_id = e.id and v1.side = 1",
+            schema = schema,
+            output = output,
+            columns = copied_columns
+                .iter()
+                .map(|c| format!(", t.\"{}\"", c))
+                .collect::<String>(),
+            table = table.name,
+            pk = pk_col
+        ),
+        format!(
+            "alter table {schema}.{output}
+                add primary key (id),
+                alter column start_node set not null,
+                alter column end_node set not null,
+                alter column geom type geometry(LineString, {srid}) using ST_SetSRID(geom, {srid})",
+            schema = schema,
+            output = output,
+            srid = srid
+        ),
+        format!(
+            "create index on {}.{} using gist (geom)",
+            schema, output
+        ),
+        format!("create index on {}.{} (start_node)", schema, output),
+        format!("create index on {}.{} (end_node)", schema, output),
+        format!(
+            "create table {schema}.{vertices} as
+            select vertex_id::int4 as id, count(*)::int4 as degree, null::int4 as component, ST_Centroid(ST_Collect(geom)) as geom
+            from topo_ends
+            group by vertex_id",
+            schema = schema,
+            vertices = vertices
+        ),
+        format!(
+            "alter table {schema}.{vertices}
+                add primary key (id),
+                alter column geom type geometry(Point, {srid}) using ST_SetSRID(geom, {srid})",
+            schema = schema,
+            vertices = vertices,
+            srid = srid
+        ),
+        format!(
+            "create index on {}.{} using gist (geom)",
+            schema, vertices
+        ),
+    ]);
+
+    for statement in statements.iter() {
+        query(statement)
+            .execute(&mut *tx)
+            .await
+            .with_context(|| format!("Failed to build topology: {}", statement))?;
+    }
+
+    let source_lines = query_as::<Postgres, (i64,)>("select count(*) from topo_src")
+        .fetch_one(&mut *tx)
+        .await
+        .context("Failed to count source lines")?
+        .0;
+    let edges = query_as::<Postgres, NetworkEdge>(&format!(
+        "select id, start_node, end_node, ST_Length(geom{})::float8 as length from {}.{}",
+        use_geog, schema, output
+    ))
+    .fetch_all(&mut *tx)
+    .await
+    .context("Failed to read the built edges")?;
+
+    let components = label_components(&edges);
+    let (vertex_ids, vertex_components): (Vec<i32>, Vec<i32>) = components
+        .iter()
+        .map(|(vertex, component)| (*vertex, *component as i32))
+        .unzip();
+    query(&format!(
+        "update {}.{} v set component = c.component
+        from unnest($1::int4[], $2::int4[]) as c(id, component)
+        where v.id = c.id",
+        schema, vertices
+    ))
+    .bind(&vertex_ids)
+    .bind(&vertex_components)
+    .execute(&mut *tx)
+    .await
+    .context("Failed to record connected components")?;
+    query(&format!(
+        "update {s}.{o} e set component = v.component
+        from {s}.{v} v
+        where v.id = e.start_node",
+        s = schema,
+        o = output,
+        v = vertices
+    ))
+    .execute(&mut *tx)
+    .await
+    .context("Failed to record connected components")?;
+
+    tx.commit().await.context("Failed to commit the topology")?;
+
+    let summaries = summarize_components(&edges, &components);
+    let total_length = summaries.iter().map(|s| s.length).sum::<f64>();
+    let mut degrees: HashMap<i32, usize> = HashMap::new();
+    for edge in edges.iter() {
+        *degrees.entry(edge.start_node).or_default() += 1;
+        *degrees.entry(edge.end_node).or_default() += 1;
+    }
+
+    Ok(TopologySummary {
+        edges_table: format!("{}.{}", schema, output),
+        vertices_table: format!("{}.{}", schema, vertices),
+        source_lines,
+        edges: edges.len(),
+        vertices: components.len(),
+        dead_ends: degrees.values().filter(|d| **d == 1).count(),
+        components: summaries.len(),
+        largest_component_share: match summaries.first() {
+            Some(largest) if total_length > 0.0 => largest.length / total_length,
+            _ => 0.0,
+        },
+        disconnected: summaries
+            .into_iter()
+            .skip(1)
+            .take(MAX_LISTED_COMPONENTS)
+            .collect(),
+        units: measured_unit(table),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    fn edge(id: i32, start_node: i32, end_node: i32, length: f64) -> NetworkEdge {
+        NetworkEdge {
+            id,
+            start_node,
+            end_node,
+            length,
+        }
+    }
+
+    #[test]
+    fn options_default_to_a_one_metre_tolerance() {
+        let options = TopologyOptions::from_args(&args(&["public.roads"])).unwrap();
+        assert_eq!(options.schema, "public");
+        assert_eq!(options.table, "roads");
+        assert_eq!(options.tolerance, DEFAULT_TOLERANCE_METRES);
+        assert_eq!(options.output, None);
+        assert!(!options.overwrite);
+
+        let options = TopologyOptions::from_args(&args(&[
+            "--tolerance",
+            "2.5",
+            "public.roads",
+            "--output",
+            "road_edges",
+            "--overwrite",
+        ]))
+        .unwrap();
+        assert_eq!(options.tolerance, 2.5);
+        assert_eq!(options.output.as_deref(), Some("road_edges"));
+        assert!(options.overwrite);
+    }
+
+    #[test]
+    fn options_reject_invalid_arguments() {
+        for invalid in [
+            &[][..],
+            &["roads"],
+            &["public.roads", "public.rivers"],
+            &["public.roads", "--tolerance"],
+            &["public.roads", "--tolerance", "-1"],
+            &["public.roads", "--tolerance", "NaN"],
+            &["public.roads", "--tolerance", "far"],
+            &["public.roads", "--output", "Road Edges"],
+            &["public.roads", "--snap"],
+        ] {
+            assert!(
+                TopologyOptions::from_args(&args(invalid)).is_err(),
+                "{:?}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn identifiers_are_lowercase_words() {
+        for valid in ["roads", "_roads", "roads_2024"] {
+            assert_eq!(validate_identifier(valid).unwrap(), valid);
+        }
+        for invalid in [
+            "",
+            "Roads",
+            "2024_roads",
+            "roads-topology",
+            "roads; drop table x",
+        ] {
+            assert!(validate_identifier(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn components_are_numbered_largest_first() {
+        //A triangle 1-2-3, a longer line 4-5-6 and an isolated edge 7-8
+        let edges = vec![
+            edge(1, 1, 2, 10.0),
+            edge(2, 2, 3, 10.0),
+            edge(3, 3, 1, 10.0),
+            edge(4, 4, 5, 20.0),
+            edge(5, 5, 6, 25.0),
+            edge(6, 7, 8, 5.0),
+        ];
+        let components = label_components(&edges);
+        assert_eq!(components.len(), 8);
+        for (vertices, component) in [(&[4, 5, 6][..], 1), (&[1, 2, 3], 2), (&[7, 8], 3)] {
+            for vertex in vertices {
+                assert_eq!(components[vertex], component, "vertex {}", vertex);
+            }
+        }
+
+        let summaries = summarize_components(&edges, &components);
+        assert_eq!(
+            summaries
+                .iter()
+                .map(|s| (s.component, s.edges, s.vertices, s.length))
+                .collect::<Vec<(usize, usize, usize, f64)>>(),
+            vec![(1, 2, 3, 45.0), (2, 3, 3, 30.0), (3, 1, 2, 5.0)]
+        );
+    }
+
+    #[test]
+    fn components_of_equal_length_are_numbered_by_lowest_vertex() {
+        //Union-find roots the first component at vertex 9 and the second at vertex 2
+        let edges = vec![edge(1, 1, 9, 10.0), edge(2, 5, 2, 10.0), edge(3, 9, 1, 0.0)];
+        let components = label_components(&edges);
+        assert_eq!(components[&1], 1);
+        assert_eq!(components[&9], 1);
+        assert_eq!(components[&2], 2);
+        assert_eq!(components[&5], 2);
+
+        let summaries = summarize_components(&edges, &components);
+        assert_eq!(
+            summaries
+                .iter()
+                .map(|s| (s.component, s.edges, s.vertices))
+                .collect::<Vec<(usize, usize, usize)>>(),
+            vec![(1, 2, 2), (2, 1, 2)]
+        );
+    }
+
+    #[test]
+    fn lengths_of_geographic_tables_are_reported_in_metres() {
+        let mut table = Table::new(
+            "roads".to_string(),
+            "public".to_string(),
+            vec!["id".to_string()],
+            "geom".to_string(),
+            "LINESTRING".to_string(),
+            4326,
+            None,
+            "deg".to_string(),
+            true,
+        );
+        assert_eq!(measured_unit(&table).as_deref(), Some("m"));
+
+        table.dist_unit = Some("ft".to_string());
+        table.use_geog = false;
+        assert_eq!(measured_unit(&table).as_deref(), Some("ft"));
+    }
+}
@@ -0,0 +1,221 @@
+use super::profiles::RoutingProfile;
+use crate::db::{Table, TableRegistry};
+use crate::AppError;
+use anyhow::anyhow;
+use serde::Deserialize;
+use sqlx::{query_as, PgPool, Postgres};
+use std::collections::HashSet;
+
+//Suffix of the table holding a routing table's turn restrictions, in the same schema
+const TURN_RESTRICTIONS_SUFFIX: &str = "_turn_restrictions";
+//Turns sharper than this, in degrees, are treated as U-turns
+const U_TURN_ANGLE: f64 = 150.0;
+const DEFAULT_STRAIGHT_ANGLE: f64 = 30.0;
+
+/**
+Costs added to a route for each turn it makes, as equivalent distances in the table's distance units.
+
+* `left` - The cost of a left turn.
+* `right` - The cost of a right turn.
+* `u_turn` - The cost of turning back on oneself.
+* `straight_angle` - The largest change of heading, in degrees, still counted as going straight on, which costs nothing. Defaults to 30.
+*/
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct TurnCosts {
+    #[serde(default)]
+    left: f64,
+    #[serde(default)]
+    right: f64,
+    #[serde(default)]
+    u_turn: f64,
+    straight_angle: Option<f64>,
+}
+
+impl TurnCosts {
+    /**
+    Costs a turn between an arrival and a departure heading, in degrees clockwise from north.
+    */
+    fn cost(&self, arrival: f64, departure: f64) -> f64 {
+        //Normalised to (-180, 180], with positive angles turning clockwise, to the right
+        let angle = (departure - arrival + 540.0).rem_euclid(360.0) - 180.0;
+        if angle.abs() <= self.straight_angle.unwrap_or(DEFAULT_STRAIGHT_ANGLE) {
+            0.0
+        } else if angle.abs() >= U_TURN_ANGLE {
+            self.u_turn
+        } else if angle > 0.0 {
+            self.right
+        } else {
+            self.left
+        }
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct TurnRestriction {
+    from_edge: i32,
+    via_node: i32,
+    to_edge: i32,
+}
+
+/**
+The turns a routing graph forbids or charges for.
+
+* `restrictions` - Forbidden turns, as (from edge, via node, to edge).
+* `costs` - The costs of turning, if the routing profile charges for turns.
+*/
+#[derive(Debug, Clone, Default)]
+pub struct TurnRules {
+    restrictions: HashSet<(u32, u32, u32)>,
+    costs: Option<TurnCosts>,
+}
+
+impl TurnRules {
+    /**
+    Costs the turn from one edge onto another at a node, given the heading on arrival and on departure where known.
+
+    # Returns
+
+    The cost of the turn, or `None` if the turn is forbidden.
+    */
+    pub fn turn_cost(
+        &self,
+        from_edge: u32,
+        arrival: Option<f64>,
+        via_node: u32,
+        to_edge: u32,
+        departure: Option<f64>,
+    ) -> Option<f64> {
+        if self.restrictions.contains(&(from_edge, via_node, to_edge)) {
+            return None;
+        }
+
+        Some(match (&self.costs, arrival, departure) {
+            (Some(costs), Some(arrival), Some(departure)) => costs.cost(arrival, departure),
+            _ => 0.0,
+        })
+    }
+}
+
+/**
+Looks up the turn rules for routing over a table with a profile. Turn restrictions are read from the table named after the routing table with a `_turn_restrictions` suffix, in the same schema, which holds a `from_edge`, `via_node` and `to_edge` for each forbidden turn. Tables without one have no restrictions, and profiles may opt out of them.
+
+The restrictions table is discovered through the table registry, so it must have a primary key to be found.
+
+# Arguments
+
+* `pool` - A PostgreSQL connection pool.
+* `registry` - The table registry, used to discover the restrictions table.
+* `table` - The routing table.
+* `profile` - The routing profile, which may charge for turns.
+* `edge_ids` - The edges being routed over, or `None` to load every restriction.
+
+# Returns
+
+A `Result` containing the turn rules, or an `AppError` if the restrictions cannot be read.
+*/
+pub async fn get_turn_rules(
+    pool: &PgPool,
+    registry: &TableRegistry,
+    table: &Table,
+    profile: &RoutingProfile,
+    edge_ids: Option<&[u32]>,
+) -> Result<TurnRules, AppError> {
+    if !profile.obeys_turn_restrictions() {
+        return Ok(TurnRules {
+            restrictions: HashSet::new(),
+            costs: profile.turn_costs().cloned(),
+        });
+    }
+
+    let restrictions_name = format!("{}{}", table.name, TURN_RESTRICTIONS_SUFFIX);
+    let has_restrictions = registry
+        .schemas
+        .get(&table.schema_name)
+        .is_some_and(|schema| schema.tables.contains_key(&restrictions_name));
+    let restrictions_table = format!("{}.{}", table.schema_name, restrictions_name);
+
+    let restrictions = if has_restrictions {
+        let restrictions_query = format!(
+            "select r.from_edge::int4 as from_edge, r.via_node::int4 as via_node, r.to_edge::int4 as to_edge
+            from {} r
+            where $1::int[] is null or (r.from_edge = any($1) and r.to_edge = any($1))",
+            restrictions_table
+        );
+
+        query_as::<Postgres, TurnRestriction>(&restrictions_query)
+            .bind(edge_ids.map(|ids| ids.iter().map(|id| *id as i32).collect::<Vec<i32>>()))
+            .fetch_all(pool)
+            .await
+            .map_err(|e| AppError(anyhow!("Failed to read {}: {}", restrictions_table, e)))?
+            .into_iter()
+            .filter_map(|r| {
+                Some((
+                    u32::try_from(r.from_edge).ok()?,
+                    u32::try_from(r.via_node).ok()?,
+                    u32::try_from(r.to_edge).ok()?,
+                ))
+            })
+            .collect()
+    } else {
+        HashSet::new()
+    };
+
+    Ok(TurnRules {
+        restrictions,
+        costs: profile.turn_costs().cloned(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::routing::graph::RoutingGraph;
+    use crate::routing::utils::TopoEdgeRepresentation;
+
+    //Straight on from 1 through 2 to 3 along edges 1 and 2, or around by 4 along edges 3 and 4
+    fn edges() -> Vec<TopoEdgeRepresentation> {
+        vec![
+            TopoEdgeRepresentation::new(1, 1, 2, 1.0, 1.0, 1.0),
+            TopoEdgeRepresentation::new(2, 2, 3, 1.0, 1.0, 1.0),
+            TopoEdgeRepresentation::new(3, 2, 4, 1.0, 1.0, 1.0),
+            TopoEdgeRepresentation::new(4, 4, 3, 1.0, 1.0, 1.0),
+        ]
+    }
+
+    #[test]
+    fn forbidden_turns_are_routed_around() {
+        let open = RoutingGraph::new(&edges(), &TurnRules::default());
+        assert_eq!(open.shortest_path(1, 3).unwrap().nodes, vec![1, 2, 3]);
+
+        let turns = TurnRules {
+            restrictions: HashSet::from([(1, 2, 2)]),
+            costs: None,
+        };
+        assert_eq!(turns.turn_cost(1, None, 2, 2, None), None);
+        assert_eq!(turns.turn_cost(1, None, 2, 3, None), Some(0.0));
+        //Restrictions apply only in the direction given
+        assert_eq!(turns.turn_cost(2, None, 2, 1, None), Some(0.0));
+
+        let restricted = RoutingGraph::new(&edges(), &turns);
+        let path = restricted.shortest_path(1, 3).unwrap();
+        assert_eq!(path.nodes, vec![1, 2, 4, 3]);
+        assert_eq!(path.cost, 3.0);
+        assert!(restricted.path_from_nodes(&[1, 2, 3]).is_none());
+        //Starting at the via node, there is no turn to forbid
+        assert_eq!(restricted.shortest_path(2, 3).unwrap().nodes, vec![2, 3]);
+    }
+
+    #[test]
+    fn turns_are_costed_by_angle() {
+        let costs = TurnCosts {
+            left: 5.0,
+            right: 2.0,
+            u_turn: 20.0,
+            straight_angle: None,
+        };
+        assert_eq!(costs.cost(0.0, 20.0), 0.0);
+        assert_eq!(costs.cost(0.0, 90.0), 2.0);
+        assert_eq!(costs.cost(10.0, 280.0), 5.0);
+        assert_eq!(costs.cost(90.0, 265.0), 20.0);
+    }
+}
@@ -0,0 +1,329 @@
+use crate::db::Table;
+use crate::AppError;
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+
+/**
+Walking speed assumed when neither the request nor the routing profile gives one, in kilometres per hour. This is 3 miles per hour, or a 20-minute mile.
+*/
+pub const DEFAULT_SPEED_KMH: f64 = 4.828032;
+
+const METRES_PER_KILOMETRE: f64 = 1000.0;
+const METRES_PER_FOOT: f64 = 0.3048;
+const METRES_PER_US_SURVEY_FOOT: f64 = 1200.0 / 3937.0;
+const METRES_PER_MILE: f64 = 1609.344;
+//Gunter's link, as used by EPSG unit 9098
+const METRES_PER_LINK: f64 = 0.201168;
+//Indian yard, as used by EPSG unit 9084
+const METRES_PER_INDIAN_YARD: f64 = 0.914_398_530_744_440_8;
+
+/**
+A unit in which routing targets and results are expressed. Times are converted to and from distances at a travel speed.
+*/
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum RoutingUnit {
+    #[serde(rename = "km")]
+    Kilometres,
+    #[serde(rename = "m")]
+    Metres,
+    #[serde(rename = "ft")]
+    Feet,
+    #[serde(rename = "mi", alias = "miles")]
+    Miles,
+    #[serde(rename = "min", alias = "mins")]
+    Minutes,
+    #[serde(rename = "h", alias = "hours")]
+    Hours,
+}
+
+impl RoutingUnit {
+    pub fn is_time(&self) -> bool {
+        matches!(self, RoutingUnit::Minutes | RoutingUnit::Hours)
+    }
+
+    /**
+    Returns the number of metres covered by one of this unit, travelling at `speed_kmh` for units of time.
+    */
+    fn metres(&self, speed_kmh: f64) -> f64 {
+        match self {
+            RoutingUnit::Kilometres => METRES_PER_KILOMETRE,
+            RoutingUnit::Metres => 1.0,
+            RoutingUnit::Feet => METRES_PER_FOOT,
+            RoutingUnit::Miles => METRES_PER_MILE,
+            RoutingUnit::Minutes => speed_kmh * METRES_PER_KILOMETRE / 60.0,
+            RoutingUnit::Hours => speed_kmh * METRES_PER_KILOMETRE,
+        }
+    }
+}
+
+/**
+The units in which a routing response reports distances and durations, and the speed relating the two.
+*/
+#[derive(Debug, Clone, Copy)]
+pub struct ResultUnits {
+    pub distance: RoutingUnit,
+    pub duration: RoutingUnit,
+    pub speed_kmh: f64,
+}
+
+impl ResultUnits {
+    /**
+    Chooses the units for a response, reporting distances and durations in the request's unit where it gave one of that kind, and otherwise in miles and minutes.
+
+    # Errors
+
+    - If the speed is not positive, returns an `AppError`.
+    */
+    pub fn new(requested: Option<RoutingUnit>, speed_kmh: f64) -> Result<ResultUnits, AppError> {
+        if speed_kmh <= 0.0 || !speed_kmh.is_finite() {
+            return Err(AppError(anyhow!("Speed must be positive")));
+        }
+
+        Ok(match requested {
+            Some(unit) if unit.is_time() => ResultUnits {
+                distance: RoutingUnit::Miles,
+                duration: unit,
+                speed_kmh,
+            },
+            Some(unit) => ResultUnits {
+                distance: unit,
+                duration: RoutingUnit::Minutes,
+                speed_kmh,
+            },
+            None => ResultUnits {
+                distance: RoutingUnit::Miles,
+                duration: RoutingUnit::Minutes,
+                speed_kmh,
+            },
+        })
+    }
+}
+
+/**
+Returns the number of metres in one of a table's distance units. Tables in geographic coordinates are measured as geography, in metres.
+
+# Errors
+
+- If the unit is not one used by any supported SRID, returns an `AppError`.
+*/
+fn table_unit_metres(table_unit: &str) -> Result<f64, AppError> {
+    match table_unit {
+        "m" | "deg" => Ok(1.0),
+        "ft" => Ok(METRES_PER_FOOT),
+        "us-ft" => Ok(METRES_PER_US_SURVEY_FOOT),
+        "link" => Ok(METRES_PER_LINK),
+        "ind-yd" => Ok(METRES_PER_INDIAN_YARD),
+        _ => Err(AppError(anyhow!(
+            "Unsupported distance unit: {}",
+            table_unit
+        ))),
+    }
+}
+
+/**
+Names the unit a table's lengths are measured in: metres for tables in geographic coordinates, which are measured as geography, and the table's distance unit otherwise.
+*/
+pub fn measured_unit(table: &Table) -> Option<String> {
+    if table.use_geog {
+        Some("m".to_string())
+    } else {
+        table.dist_unit.clone()
+    }
+}
+
+/**
+Converts a distance in metres into a table's distance units.
+*/
+pub fn metres_to_table_distance(distance: f64, table_unit: &str) -> Result<f64, AppError> {
+    Ok(distance / table_unit_metres(table_unit)?)
+}
+
+/**
+Converts a distance, or a time at a given speed, into a table's distance units.
+
+# Arguments
+
+* `value` - The distance or time.
+* `unit` - The unit of `value`.
+* `speed_kmh` - The travel speed, used for units of time.
+* `table_unit` - The table's distance unit.
+*/
+pub fn to_table_distance(
+    value: f64,
+    unit: RoutingUnit,
+    speed_kmh: f64,
+    table_unit: &str,
+) -> Result<f64, AppError> {
+    metres_to_table_distance(value * unit.metres(speed_kmh), table_unit)
+}
+
+/**
+Converts a distance in a table's distance units into another unit, or into the time taken to cover it at a given speed.
+
+# Arguments
+
+* `distance` - The distance, in the table's distance units.
+* `unit` - The unit to convert into.
+* `speed_kmh` - The travel speed, used for units of time.
+* `table_unit` - The table's distance unit.
+*/
+pub fn from_table_distance(
+    distance: f64,
+    unit: RoutingUnit,
+    speed_kmh: f64,
+    table_unit: &str,
+) -> Result<f64, AppError> {
+    Ok(distance * table_unit_metres(table_unit)? / unit.metres(speed_kmh))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::get_srid_unit;
+    use std::collections::HashMap;
+
+    const TABLE_UNITS: [&str; 6] = ["m", "deg", "ft", "us-ft", "link", "ind-yd"];
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() <= expected.abs() * 1e-9,
+            "expected {}, got {}",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn every_srid_unit_is_supported() {
+        let srid_units: HashMap<i32, String> =
+            serde_json::from_str(include_str!("../../data/srid_units.json")).unwrap();
+        for (srid, unit) in srid_units {
+            assert_eq!(get_srid_unit(srid), Some(unit.as_str()));
+            assert!(
+                TABLE_UNITS.contains(&unit.as_str()),
+                "SRID {} uses untested unit {}",
+                srid,
+                unit
+            );
+            assert!(to_table_distance(1.0, RoutingUnit::Miles, DEFAULT_SPEED_KMH, &unit).is_ok());
+        }
+    }
+
+    #[test]
+    fn converts_a_mile_into_every_table_unit() {
+        let expected = [
+            ("m", 1609.344),
+            ("deg", 1609.344),
+            ("ft", 5280.0),
+            ("us-ft", 5279.98944),
+            ("link", 8000.0),
+            ("ind-yd", 1760.002828),
+        ];
+        for (table_unit, mile) in expected {
+            let converted =
+                to_table_distance(1.0, RoutingUnit::Miles, DEFAULT_SPEED_KMH, table_unit).unwrap();
+            assert!(
+                (converted - mile).abs() < 1e-4,
+                "1 mi in {}: expected {}, got {}",
+                table_unit,
+                mile,
+                converted
+            );
+        }
+    }
+
+    #[test]
+    fn round_trips_every_unit_through_every_table_unit() {
+        let units = [
+            RoutingUnit::Kilometres,
+            RoutingUnit::Metres,
+            RoutingUnit::Feet,
+            RoutingUnit::Miles,
+            RoutingUnit::Minutes,
+            RoutingUnit::Hours,
+        ];
+        for table_unit in TABLE_UNITS {
+            for unit in units {
+                let distance = to_table_distance(12.5, unit, 15.0, table_unit).unwrap();
+                assert_close(
+                    from_table_distance(distance, unit, 15.0, table_unit).unwrap(),
+                    12.5,
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn converts_metric_and_imperial_distances() {
+        assert_close(
+            to_table_distance(2.0, RoutingUnit::Kilometres, DEFAULT_SPEED_KMH, "m").unwrap(),
+            2000.0,
+        );
+        assert_close(
+            to_table_distance(100.0, RoutingUnit::Feet, DEFAULT_SPEED_KMH, "ft").unwrap(),
+            100.0,
+        );
+        assert_close(
+            to_table_distance(100.0, RoutingUnit::Metres, DEFAULT_SPEED_KMH, "ft").unwrap(),
+            100.0 / 0.3048,
+        );
+        assert_close(
+            from_table_distance(5280.0, RoutingUnit::Miles, DEFAULT_SPEED_KMH, "ft").unwrap(),
+            1.0,
+        );
+    }
+
+    #[test]
+    fn converts_times_at_the_given_speed() {
+        //The default speed keeps the old 20-minute mile
+        assert_close(
+            from_table_distance(1609.344, RoutingUnit::Minutes, DEFAULT_SPEED_KMH, "m").unwrap(),
+            20.0,
+        );
+        assert_close(
+            to_table_distance(30.0, RoutingUnit::Minutes, 20.0, "m").unwrap(),
+            10_000.0,
+        );
+        assert_close(
+            to_table_distance(1.5, RoutingUnit::Hours, 4.0, "deg").unwrap(),
+            6000.0,
+        );
+        assert_close(
+            to_table_distance(1.0, RoutingUnit::Hours, 1.609344, "ft").unwrap(),
+            5280.0,
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_table_units() {
+        assert!(to_table_distance(1.0, RoutingUnit::Miles, DEFAULT_SPEED_KMH, "furlong").is_err());
+        assert!(metres_to_table_distance(1.0, "").is_err());
+    }
+
+    #[test]
+    fn parses_unit_names_and_aliases() {
+        let parse = |name: &str| serde_json::from_value::<RoutingUnit>(name.into()).unwrap();
+        assert_eq!(parse("km"), RoutingUnit::Kilometres);
+        assert_eq!(parse("m"), RoutingUnit::Metres);
+        assert_eq!(parse("ft"), RoutingUnit::Feet);
+        assert_eq!(parse("mi"), RoutingUnit::Miles);
+        assert_eq!(parse("miles"), RoutingUnit::Miles);
+        assert_eq!(parse("min"), RoutingUnit::Minutes);
+        assert_eq!(parse("mins"), RoutingUnit::Minutes);
+        assert_eq!(parse("h"), RoutingUnit::Hours);
+        assert!(serde_json::from_value::<RoutingUnit>("yd".into()).is_err());
+    }
+
+    #[test]
+    fn reports_results_in_the_requested_kind_of_unit() {
+        let units = ResultUnits::new(Some(RoutingUnit::Kilometres), 12.0).unwrap();
+        assert_eq!(units.distance, RoutingUnit::Kilometres);
+        assert_eq!(units.duration, RoutingUnit::Minutes);
+
+        let units = ResultUnits::new(Some(RoutingUnit::Hours), 12.0).unwrap();
+        assert_eq!(units.distance, RoutingUnit::Miles);
+        assert_eq!(units.duration, RoutingUnit::Hours);
+
+        assert!(ResultUnits::new(None, 0.0).is_err());
+    }
+}
@@ -1,41 +1,75 @@
+use super::constraints::RoutingConstraints;
+use super::elevation::ElevationSource;
+use super::graph::{GraphPath, TraversedEdge};
+use super::profiles::RoutingProfile;
+use super::units::{from_table_distance, ResultUnits};
 use super::StartingGeom;
 use crate::db::Table;
 use crate::AppError;
 use anyhow::anyhow;
+use geo_types::Point;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use sqlx::{query_as, PgPool, Postgres};
-use std::collections::HashMap;
 
 #[derive(sqlx::FromRow, Debug, Clone, Deserialize, Serialize)]
 pub struct TopoEdgeRepresentation {
     #[sqlx(try_from = "i32")]
-    edge_id: u32,
+    pub(super) edge_id: u32,
     #[sqlx(try_from = "i32")]
-    start_node: u32,
+    pub(super) start_node: u32,
     #[sqlx(try_from = "i32")]
-    end_node: u32,
+    pub(super) end_node: u32,
     #[sqlx(default)]
-    weight: f64,
+    pub(super) weight: f64,
+    #[sqlx(default)]
+    #[serde(skip)]
+    pub(super) reverse_weight: f64,
+    #[sqlx(default)]
+    #[serde(skip)]
+    pub(super) length: f64,
+    //Headings, in degrees clockwise from north, leaving the start and reaching the end of the edge
+    #[sqlx(default)]
+    #[serde(skip)]
+    pub(super) start_bearing: Option<f64>,
+    #[sqlx(default)]
+    #[serde(skip)]
+    pub(super) end_bearing: Option<f64>,
+    //Portion of the underlying edge covered, when it has been split at a snapped point
+    #[sqlx(skip)]
+    #[serde(skip)]
+    pub(super) span: Option<(f64, f64)>,
+}
+
+#[derive(sqlx::FromRow, Deserialize, Serialize)]
+pub struct ReachableGeometries {
+    pub hull: Option<Value>,
+    pub edges: Option<Value>,
 }
 
 #[derive(sqlx::FromRow, Deserialize, Serialize)]
-pub struct GeoJsonResult {
+pub struct PathGeometry {
+    #[sqlx(default)]
+    pub geoms: Value,
+    //Pairs of distance along the path, in the table's distance units, and elevation
     #[sqlx(default)]
-    geoms: Value,
+    pub profile: Option<Value>,
 }
 
 /**
 Retrieves a list of topological edge representations from a PostgreSQL database based distance to a given starting geometry.
 
 # Example Usage
-```rust
+```ignore
 let pool = PgPool::new(...);
 let table = Table::new(...);
 let starting_geom = StartingGeom::new(...);
 let desired_distance = 100.0;
+let profile = RoutingProfile::default();
+let elevation = ElevationSource::None;
+let constraints = RoutingConstraints::default();
 
-let result = get_proximal_features(&pool, &table, &starting_geom, desired_distance).await;
+let result = get_proximal_features(&pool, &table, &starting_geom, desired_distance, &profile, &elevation, &constraints).await;
 match result {
     Ok(features) => {
         for feature in features {
@@ -53,6 +87,9 @@ match result {
 * `table` - A struct representing the database table.
 * `starting_geom` - A struct representing the starting geometry.
 * `desired_distance` - The desired distance for proximity search.
+* `profile` - The routing profile used to weight the edges.
+* `elevation` - The source of the table's elevations, used by profiles that penalise climbing.
+* `constraints` - Edges to block or penalise.
 
 # Returns
 A `Result` containing either a vector of `TopoEdgeRepresentation` structs if the query is successful, or an `AppError` if there is an error.
@@ -62,15 +99,61 @@ pub async fn get_proximal_features(
     table: &Table,
     starting_geom: &StartingGeom,
     desired_distance: f64,
+    profile: &RoutingProfile,
+    elevation: &ElevationSource,
+    constraints: &RoutingConstraints,
 ) -> Result<Vec<TopoEdgeRepresentation>, AppError> {
-    let geom_col = match table.geom_column.as_ref() {
-        Some(geom_col) => geom_col,
+    let srid = match table.srid.as_ref() {
+        Some(srid) => srid,
         None => {
             return Err(AppError(anyhow!(
                 "Table does not contain valid geometry data"
             )))
         }
     };
+
+    let starting_coords = (starting_geom.geometry.x(), starting_geom.geometry.y());
+    let search_geom = format!(
+        "ST_Transform(ST_SetSRID(ST_MakePoint({},{}), 3857), {})",
+        starting_coords.0, starting_coords.1, srid
+    );
+
+    get_features_within(
+        pool,
+        table,
+        &search_geom,
+        &desired_distance.to_string(),
+        profile,
+        elevation,
+        constraints,
+    )
+    .await
+}
+
+/**
+Retrieves the topological edges of a table lying within a corridor around the straight line joining a series of waypoints. The corridor's half-width is the line's length multiplied by `buffer_factor`, so that detours proportional to the trip can be found.
+
+# Arguments
+* `pool` - A PostgreSQL connection pool.
+* `table` - A struct representing the database table.
+* `waypoints` - The waypoints, in Web Mercator.
+* `buffer_factor` - The corridor half-width, as a multiple of the straight line length.
+* `profile` - The routing profile used to weight the edges.
+* `elevation` - The source of the table's elevations, used by profiles that penalise climbing.
+* `constraints` - Edges to block or penalise.
+
+# Returns
+A `Result` containing either a vector of `TopoEdgeRepresentation` structs if the query is successful, or an `AppError` if there is an error.
+*/
+pub async fn get_features_along_waypoints(
+    pool: &PgPool,
+    table: &Table,
+    waypoints: &[Point<f64>],
+    buffer_factor: f64,
+    profile: &RoutingProfile,
+    elevation: &ElevationSource,
+    constraints: &RoutingConstraints,
+) -> Result<Vec<TopoEdgeRepresentation>, AppError> {
     let srid = match table.srid.as_ref() {
         Some(srid) => srid,
         None => {
@@ -79,30 +162,133 @@ pub async fn get_proximal_features(
             )))
         }
     };
+    let use_geog = if table.use_geog { "::geography" } else { "" };
+
+    let search_geom = format!(
+        "ST_Transform(ST_SetSRID(ST_MakeLine(ARRAY[{}]), 3857), {})",
+        waypoints
+            .iter()
+            .map(|p| format!("ST_MakePoint({},{})", p.x(), p.y()))
+            .collect::<Vec<String>>()
+            .join(", "),
+        srid
+    );
+    let search_distance = format!("ST_Length({}{}) * {}", search_geom, use_geog, buffer_factor);
+
+    get_features_within(
+        pool,
+        table,
+        &search_geom,
+        &search_distance,
+        profile,
+        elevation,
+        constraints,
+    )
+    .await
+}
+
+/**
+Retrieves the topological edges of a table lying within a distance of a line through a series of points, such as a recorded track.
+
+# Arguments
+* `pool` - A PostgreSQL connection pool.
+* `table` - A struct representing the database table.
+* `points` - The points, in Web Mercator.
+* `search_distance` - The distance from the line to search, in the table's distance units.
+* `profile` - The routing profile used to weight the edges.
+* `elevation` - The source of the table's elevations, used by profiles that penalise climbing.
+* `constraints` - Edges to block or penalise.
+
+# Returns
+A `Result` containing either a vector of `TopoEdgeRepresentation` structs if the query is successful, or an `AppError` if there is an error.
+*/
+pub async fn get_features_along_line(
+    pool: &PgPool,
+    table: &Table,
+    points: &[Point<f64>],
+    search_distance: f64,
+    profile: &RoutingProfile,
+    elevation: &ElevationSource,
+    constraints: &RoutingConstraints,
+) -> Result<Vec<TopoEdgeRepresentation>, AppError> {
+    let srid = match table.srid.as_ref() {
+        Some(srid) => srid,
+        None => {
+            return Err(AppError(anyhow!(
+                "Table does not contain valid geometry data"
+            )))
+        }
+    };
+
+    let search_geom = format!(
+        "ST_Transform(ST_SetSRID(ST_MakeLine(ARRAY[{}]), 3857), {})",
+        points
+            .iter()
+            .map(|p| format!("ST_MakePoint({},{})", p.x(), p.y()))
+            .collect::<Vec<String>>()
+            .join(", "),
+        srid
+    );
+
+    get_features_within(
+        pool,
+        table,
+        &search_geom,
+        &search_distance.to_string(),
+        profile,
+        elevation,
+        constraints,
+    )
+    .await
+}
+
+/**
+Retrieves the topological edges of a table lying within a distance of a search geometry, weighted in each direction by a routing profile.
+
+# Arguments
+* `pool` - A PostgreSQL connection pool.
+* `table` - A struct representing the database table.
+* `search_geom` - An SQL expression for the search geometry, in the table's SRID.
+* `search_distance` - An SQL expression for the search distance, in the table's distance units.
+* `profile` - The routing profile used to weight the edges.
+* `elevation` - The source of the table's elevations, used by profiles that penalise climbing.
+* `constraints` - Edges to block or penalise.
+*/
+async fn get_features_within(
+    pool: &PgPool,
+    table: &Table,
+    search_geom: &str,
+    search_distance: &str,
+    profile: &RoutingProfile,
+    elevation: &ElevationSource,
+    constraints: &RoutingConstraints,
+) -> Result<Vec<TopoEdgeRepresentation>, AppError> {
+    let geom_col = match table.geom_column.as_ref() {
+        Some(geom_col) => geom_col,
+        None => {
+            return Err(AppError(anyhow!(
+                "Table does not contain valid geometry data"
+            )))
+        }
+    };
 
-    let starting_coords = (starting_geom.geometry.x(), starting_geom.geometry.y());
     let use_geog = if table.use_geog { "::geography" } else { "" };
 
     let geom_restrictor = format!(
         "WHERE ST_DWithin(t.{}{},
-        ST_Transform(ST_SetSRID(ST_MakePoint({},{}), 3857), {}){}, {})",
-        geom_col, use_geog, starting_coords.0, starting_coords.1, srid, use_geog, desired_distance
+        {}{}, {})",
+        geom_col, use_geog, search_geom, use_geog, search_distance
     );
 
-    let pk_string = format!("{}", table.primary_key_columns.join(", "));
-    let attrs_string = match &table.attr_columns {
-        Some(columns) => format!(", {}", columns.join(", ")),
-        None => ", start_node, end_node".to_string(),
-    };
     let proximal_features_query = format!(
-        "select {}{}, trunc(ST_Length(t.{}{})) as weight
-        from {}.{} t
+        "{}
         {}
         ",
-        pk_string, attrs_string, geom_col, use_geog, table.schema_name, table.name, geom_restrictor
+        make_weighted_edges_query(table, profile, elevation, constraints, "", "")?,
+        geom_restrictor
     );
 
-    println!("{}", &proximal_features_query);
+    tracing::debug!("{}", proximal_features_query);
 
     query_as::<Postgres, TopoEdgeRepresentation>(&proximal_features_query)
         .fetch_all(pool)
@@ -110,44 +296,94 @@ pub async fn get_proximal_features(
         .map_err(|e| AppError(anyhow!(e)))
 }
 
-/*
-Create a mapping between pairs of start and end nodes in a list of `TopoEdgeRepresentation` structs and their corresponding edge IDs.
+/**
+Builds a query selecting the topological edges of a table, aliased as `t`, weighted in each direction by a routing profile. The query has no `WHERE` clause, so that callers can restrict it.
 
 # Arguments
+* `table` - A struct representing the database table.
+* `profile` - The routing profile used to weight the edges.
+* `elevation` - The source of the table's elevations, used by profiles that penalise climbing.
+* `constraints` - Edges to block or penalise.
+* `extra_columns` - Further columns to select, each preceded by a comma.
+* `extra_joins` - Further joins to make against `t`.
+*/
+pub(super) fn make_weighted_edges_query(
+    table: &Table,
+    profile: &RoutingProfile,
+    elevation: &ElevationSource,
+    constraints: &RoutingConstraints,
+    extra_columns: &str,
+    extra_joins: &str,
+) -> Result<String, AppError> {
+    let geom_col = match table.geom_column.as_ref() {
+        Some(geom_col) => geom_col,
+        None => {
+            return Err(AppError(anyhow!(
+                "Table does not contain valid geometry data"
+            )))
+        }
+    };
 
-* `edges` - A slice of `TopoEdgeRepresentation` structs representing the edges.
-
-# Example
-
-```rust
-let edges = vec![
-    TopoEdgeRepresentation { edge_id: 1, start_node: 2, end_node: 3, weight: 0.0 },
-    TopoEdgeRepresentation { edge_id: 2, start_node: 3, end_node: 4, weight: 0.0 },
-    TopoEdgeRepresentation { edge_id: 3, start_node: 4, end_node: 5, weight: 0.0 },
-];
-
-let mapper = get_edge_to_vertex_pair_mapper(&edges);
+    let use_geog = if table.use_geog { "::geography" } else { "" };
 
-// The expected output is a HashMap with the following mappings:
-// (2, 3) -> 1
-// (3, 4) -> 2
-// (4, 5) -> 3
-```
-*/
-pub fn get_edge_to_vertex_pair_mapper(
-    edges: &[TopoEdgeRepresentation],
-) -> HashMap<(u32, u32), u32> {
-    let mut pair_to_edge_mapper = HashMap::with_capacity(edges.len() * 2);
-    for e in edges {
-        pair_to_edge_mapper
-            .entry((e.start_node, e.end_node))
-            .or_insert(e.edge_id);
-        pair_to_edge_mapper
-            .entry((e.end_node, e.start_node))
-            .or_insert(e.edge_id);
-    }
+    let pk_string = table.primary_key_columns.join(", ");
+    let attrs_string = match &table.attr_columns {
+        Some(columns) => format!(", {}", columns.join(", ")),
+        None => ", start_node, end_node".to_string(),
+    };
+    let (forward_cost, reverse_cost) =
+        profile.make_cost_expressions(geom_col, use_geog, elevation)?;
+    let (forward_cost, reverse_cost) =
+        constraints.apply_to_costs(table, forward_cost, reverse_cost)?;
+    //Elevation gains are only looked up when the profile's costs depend on them
+    let gain_join = if profile.uses_elevation() {
+        elevation.make_edge_gain_join(geom_col)
+    } else {
+        String::new()
+    };
+    //Likewise headings, which are only needed to cost turns
+    let bearings = if profile.turn_costs().is_some() {
+        ",
+            degrees(ST_Azimuth(ST_PointN(b.geom, 1), ST_PointN(b.geom, 2)))::float8 as start_bearing,
+            degrees(ST_Azimuth(ST_PointN(b.geom, ST_NPoints(b.geom) - 1), ST_PointN(b.geom, ST_NPoints(b.geom))))::float8 as end_bearing"
+            .to_string()
+    } else {
+        String::new()
+    };
+    let bearings_join = if bearings.is_empty() {
+        String::new()
+    } else {
+        format!(
+            "cross join lateral (
+                select ST_Transform(ST_GeometryN(ST_LineMerge(ST_Multi(t.{})), 1), 3857) as geom
+            ) b",
+            geom_col
+        )
+    };
 
-    pair_to_edge_mapper
+    Ok(format!(
+        "select {}{},
+            ({})::float8 as weight,
+            ({})::float8 as reverse_weight,
+            ST_Length(t.{}{})::float8 as length{}{}
+        from {}.{} t
+        {}
+        {}
+        {}",
+        pk_string,
+        attrs_string,
+        forward_cost,
+        reverse_cost,
+        geom_col,
+        use_geog,
+        bearings,
+        extra_columns,
+        table.schema_name,
+        table.name,
+        gain_join,
+        bearings_join,
+        extra_joins
+    ))
 }
 
 /**
@@ -155,16 +391,16 @@ Tries to convert a slice of `TopoEdgeRepresentation` structs into a JSON string.
 
 # Example
 
-```
+```ignore
 let edges = vec![
-    TopoEdgeRepresentation { edge_id: 1, start_node: 2, end_node: 3, weight: 0.0 },
-    TopoEdgeRepresentation { edge_id: 2, start_node: 3, end_node: 4, weight: 0.0 },
-    TopoEdgeRepresentation { edge_id: 3, start_node: 4, end_node: 5, weight: 0.0 },
+    TopoEdgeRepresentation { edge_id: 1, start_node: 2, end_node: 3, weight: 1.5, .. },
+    TopoEdgeRepresentation { edge_id: 2, start_node: 3, end_node: 4, weight: 0.0, .. },
 ];
 
 let result = try_convert_to_edge_json(&edges);
 
-// The expected output is a Result containing a JSON string representation of the edges.
+// Only the fields speedicycle reads are written:
+// Ok("[{\"edge_id\":1,\"start_node\":2,\"end_node\":3,\"weight\":1.5},{\"edge_id\":2,\"start_node\":3,\"end_node\":4,\"weight\":0.0}]")
 ```
 
 # Arguments
@@ -181,117 +417,338 @@ pub fn try_convert_to_edge_json(rows: &[TopoEdgeRepresentation]) -> Result<Strin
 }
 
 /**
-Processes a routing result represented as a list of node IDs and converts it into a list of edge IDs using a mapping between node pairs and edge IDs.
-
-# Example
-
-```
-use std::collections::HashMap;
-
-let results = vec![1, 2, 3, 4];
-let nodes_to_edge_mapper: HashMap<(u32, u32), u32> = [
-    ((1, 2), 10),
-    ((2, 3), 20),
-    ((3, 4), 30),
-].iter().cloned().collect();
+Builds a query producing the geometry of each step of a path, in the table's SRID, oriented in the direction of travel and trimmed to the portion traversed. The steps are bound as `$1` (edge IDs), `$2` (start fractions) and `$3` (end fractions), and returned with their position in `seq`.
+*/
+fn make_traversed_segments_query(table: &Table) -> Result<String, AppError> {
+    let (geom_col, pk_col) = match (
+        table.geom_column.as_ref(),
+        table.primary_key_columns.first(),
+    ) {
+        (Some(geom_col), Some(pk_col)) => (geom_col, pk_col),
+        _ => {
+            return Err(AppError(anyhow!(
+                "Table does not contain valid geometry and primary key data"
+            )))
+        }
+    };
 
-let edges = process_routing_result_as_edge_list(&results, &nodes_to_edge_mapper);
+    Ok(format!(
+        "select
+            case
+                when p.from_fraction <= p.to_fraction
+                    then ST_LineSubstring(g.geom, p.from_fraction, p.to_fraction)
+                else ST_Reverse(ST_LineSubstring(g.geom, p.to_fraction, p.from_fraction))
+            end as geom,
+            p.seq
+        from
+            unnest($1::int[], $2::float8[], $3::float8[])
+                with ordinality as p(edge_id, from_fraction, to_fraction, seq)
+            join lateral (
+                select ST_GeometryN(ST_LineMerge(ST_Multi(t.{})), 1) as geom
+                from {}.{} t
+                where t.{} = p.edge_id
+            ) g on true",
+        geom_col, table.schema_name, table.name, pk_col
+    ))
+}
 
-// The expected output is a list of edge IDs: [Some("10"), Some("20"), Some("30")]
-```
+/**
+Retrieves the geometry of a path as a single line in Web Mercator, with each edge oriented in the direction of travel and partial edges trimmed to the portion traversed. Where elevations are available, the path's elevation profile is retrieved alongside it.
 
 # Arguments
 
-* `results` - A list of node IDs representing the routing result.
-* `nodes_to_edge_mapper` - A mapping between pairs of start and end nodes and their corresponding edge IDs.
+* `path` - The edges traversed, in order.
+* `table` - A struct representing the database table.
+* `elevation` - The source of the table's elevations.
+* `pool` - A PostgreSQL connection pool.
 
 # Returns
 
-A list of edge IDs corresponding to the routing result.
+A `Result` containing either the GeoJSON geometry and elevation profile of the path if the query is successful, or an `AppError` if there is an error.
 */
-pub fn process_routing_result_as_edge_list(
-    results: &[u32],
-    nodes_to_edge_mapper: &HashMap<(u32, u32), u32>,
-) -> Vec<Option<String>> {
-    let edges: Vec<Option<String>> = results
-        .iter()
-        .zip(results.iter().skip(1))
-        .map(|(u, v)| {
-            nodes_to_edge_mapper
-                .get(&(*u, *v))
-                .and_then(|eid| Some(eid.to_string()))
-        })
-        .collect();
-
-    edges
+pub async fn get_ordered_path_geometry(
+    path: &[TraversedEdge],
+    table: &Table,
+    elevation: &ElevationSource,
+    pool: &PgPool,
+) -> Result<PathGeometry, AppError> {
+    let use_geog = if table.use_geog { "::geography" } else { "" };
+    let profile_select = match elevation.make_point_elevation("dp.geom") {
+        Some(point_elevation) => format!(
+            ", (select json_agg(json_build_array(p.dist, p.z) order by p.idx)
+                from (
+                    select s.idx, s.z, sum(s.d) over (order by s.idx) as dist
+                    from (
+                        select
+                            dp.path[1] as idx,
+                            {} as z,
+                            coalesce(ST_Distance(dp.geom{g}, (lag(dp.geom) over (order by dp.path)){g}), 0) as d
+                        from ST_DumpPoints(line.geom) dp
+                    ) s
+                ) p
+            ) as profile",
+            point_elevation,
+            g = use_geog
+        ),
+        None => String::new(),
+    };
+
+    let geojson_query = format!(
+        "with line as (
+            select ST_MakeLine(array(
+                select s.geom from ({}) s order by s.seq
+            )) as geom
+        )
+        select ST_AsGeoJSON(ST_Transform(line.geom, 3857))::json as geoms{}
+        from line",
+        make_traversed_segments_query(table)?,
+        profile_select
+    );
+
+    query_as::<Postgres, PathGeometry>(&geojson_query)
+        .bind(path.iter().map(|e| e.edge_id as i32).collect::<Vec<i32>>())
+        .bind(path.iter().map(|e| e.from_fraction).collect::<Vec<f64>>())
+        .bind(path.iter().map(|e| e.to_fraction).collect::<Vec<f64>>())
+        .fetch_one(pool)
+        .await
+        .map_err(|e| AppError(anyhow!(e)))
 }
 
 /**
-Retrieves the geometries associated with a given path of edge IDs from a PostgreSQL database.
+Retrieves the geometries of a reachable portion of the network, in Web Mercator: the reachable edges, and a concave hull around them.
 
 # Arguments
 
-* `path` - A list of edge IDs representing the path.
+* `portions` - The edges, or parts of edges, that are reachable.
+* `concavity` - The target concavity of the hull, between 0 (most concave) and 1 (convex).
 * `table` - A struct representing the database table.
 * `pool` - A PostgreSQL connection pool.
 
 # Returns
 
-A `Result` containing either a JSON string representation of the geometries if the query is successful, or an `AppError` if there is an error.
+A `Result` containing either the hull and edge geometries as GeoJSON, or an `AppError` if there is an error.
 */
-pub async fn get_path_geometries(
-    path: Vec<Option<String>>,
+pub async fn get_reachable_geometries(
+    portions: &[TraversedEdge],
+    concavity: f64,
     table: &Table,
     pool: &PgPool,
-) -> Result<String, AppError> {
-    if let Some(geom_col) = &table.geom_column {
-        let primary_key_column = match table.primary_key_columns.get(0) {
-            Some(column) => column,
-            None => {
-                return Err(AppError(anyhow!(
-                    "Table does not contain valid primary key columns"
-                )))
-            }
+) -> Result<ReachableGeometries, AppError> {
+    let geojson_query = format!(
+        "with segments as ({})
+        select
+            ST_AsGeoJSON(ST_Transform(ST_ConcaveHull(ST_Collect(s.geom), {}), 3857))::json as hull,
+            ST_AsGeoJSON(ST_Transform(ST_Collect(s.geom), 3857))::json as edges
+        from segments s",
+        make_traversed_segments_query(table)?,
+        concavity
+    );
+
+    query_as::<Postgres, ReachableGeometries>(&geojson_query)
+        .bind(
+            portions
+                .iter()
+                .map(|e| e.edge_id as i32)
+                .collect::<Vec<i32>>(),
+        )
+        .bind(
+            portions
+                .iter()
+                .map(|e| e.from_fraction)
+                .collect::<Vec<f64>>(),
+        )
+        .bind(portions.iter().map(|e| e.to_fraction).collect::<Vec<f64>>())
+        .fetch_one(pool)
+        .await
+        .map_err(|e| AppError(anyhow!(e)))
+}
+
+/**
+Drops the edges that cannot be travelled in both directions. Circuit search treats every edge as two-way, so one-way edges are excluded from it rather than risk routing against them.
+*/
+pub fn retain_bidirectional_edges(rows: &mut Vec<TopoEdgeRepresentation>) {
+    rows.retain(|e| e.weight >= 0.0 && e.reverse_weight >= 0.0);
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct PathSummary {
+    pub distance: f64,
+    pub duration: f64,
+    pub cost: f64,
+    pub edge_ids: Vec<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ascent: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub descent: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub elevation_profile: Option<Vec<[f64; 2]>>,
+}
+
+/**
+Summarises a path for a route response: its length and duration in the response's units and the IDs of the edges it follows, in order. When the path's geometry carries an elevation profile, the total ascent and descent are included along with the profile, its distances converted to the response's distance unit.
+
+The duration is the time taken to cover the path's length at the response's speed. The path's cost, which includes the profile's multipliers, constraint penalties and turn costs that steer the search, is reported separately as an equivalent distance.
+*/
+pub fn summarize_path(
+    path: &GraphPath,
+    geometry: &PathGeometry,
+    unit: &str,
+    units: &ResultUnits,
+) -> Result<PathSummary, AppError> {
+    let length = path.edges.iter().map(|e| e.length).sum();
+    let distance = from_table_distance(length, units.distance, units.speed_kmh, unit)?;
+    let duration = from_table_distance(length, units.duration, units.speed_kmh, unit)?;
+    let cost = from_table_distance(path.cost, units.distance, units.speed_kmh, unit)?;
+
+    let mut edge_ids = path.edges.iter().map(|e| e.edge_id).collect::<Vec<u32>>();
+    edge_ids.dedup();
+
+    let elevation_profile = match &geometry.profile {
+        Some(profile) => Some(
+            serde_json::from_value::<Vec<(f64, Option<f64>)>>(profile.clone())
+                .map_err(|e| AppError(anyhow!("Failed to read elevation profile: {}", e)))?
+                .into_iter()
+                .filter_map(|(dist, z)| z.map(|z| (dist, z)))
+                .map(|(dist, z)| {
+                    Ok([
+                        from_table_distance(dist, units.distance, units.speed_kmh, unit)?,
+                        z,
+                    ])
+                })
+                .collect::<Result<Vec<[f64; 2]>, AppError>>()?,
+        ),
+        None => None,
+    };
+    let (ascent, descent) = match &elevation_profile {
+        Some(profile) => {
+            let (ascent, descent) = profile.windows(2).fold((0.0, 0.0), |(up, down), pair| {
+                let change = pair[1][1] - pair[0][1];
+                (up + change.max(0.0), down + (-change).max(0.0))
+            });
+            (Some(ascent), Some(descent))
+        }
+        None => (None, None),
+    };
+
+    Ok(PathSummary {
+        distance,
+        duration,
+        cost,
+        edge_ids,
+        ascent,
+        descent,
+        elevation_profile,
+    })
+}
+
+#[cfg(test)]
+impl TopoEdgeRepresentation {
+    /**
+    Builds an unsplit edge without headings, for tests over hand-built graphs.
+    */
+    pub(super) fn new(
+        edge_id: u32,
+        start_node: u32,
+        end_node: u32,
+        weight: f64,
+        reverse_weight: f64,
+        length: f64,
+    ) -> TopoEdgeRepresentation {
+        TopoEdgeRepresentation {
+            edge_id,
+            start_node,
+            end_node,
+            weight,
+            reverse_weight,
+            length,
+            start_bearing: None,
+            end_bearing: None,
+            span: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::routing::alternatives::rank_alternatives;
+    use crate::routing::graph::RoutingGraph;
+    use crate::routing::turns::TurnRules;
+    use crate::routing::units::RoutingUnit;
+
+    fn traversed(edge_id: u32, weight: f64, length: f64) -> TraversedEdge {
+        TraversedEdge {
+            edge_id,
+            from_fraction: 0.0,
+            to_fraction: 1.0,
+            weight,
+            length,
+        }
+    }
+
+    #[test]
+    fn duration_follows_length_rather_than_cost() {
+        //A climbing profile and a turn cost make the path cost three times its length
+        let path = GraphPath {
+            nodes: vec![1, 2, 3],
+            edges: vec![traversed(10, 3000.0, 1000.0), traversed(11, 2500.0, 1000.0)],
+            cost: 6000.0,
+        };
+        let geometry = PathGeometry {
+            geoms: Value::Null,
+            profile: None,
         };
+        let units = ResultUnits::new(Some(RoutingUnit::Kilometres), 6.0).unwrap();
 
-        let geojson_query = format!(
-            "select st_asgeojson(c.*)::json as geoms
-                from
-                    (
-                    select
-                        ST_Collect(
-                    array(
-                        select
-                            ST_Transform(t.{},
-                            3857) as geom
-                        from
-                            {}.{} t
-                        where
-                            t.{} in ({})
-                        )
-                    )
-                    ) c;",
-            geom_col,
-            table.schema_name,
-            table.name,
-            primary_key_column,
-            format!(
-                "{}",
-                path.iter()
-                    .filter_map(|item| item.clone())
-                    .collect::<Vec<String>>()
-                    .join(", ")
-            )
-        );
+        let summary = summarize_path(&path, &geometry, "m", &units).unwrap();
+        assert!((summary.distance - 2.0).abs() < 1e-9);
+        assert!((summary.duration - 20.0).abs() < 1e-9);
+        assert!((summary.cost - 6.0).abs() < 1e-9);
+        assert_eq!(summary.edge_ids, vec![10, 11]);
+    }
 
-        println!("{}", &geojson_query);
+    #[test]
+    fn penalties_steer_the_search_but_not_reported_stats() {
+        //The direct edge 1-3 is avoided with a penalty of 4, so the search takes the longer way round
+        let rows = vec![
+            TopoEdgeRepresentation::new(1, 1, 3, 4.0 * 1000.0, 4.0 * 1000.0, 1000.0),
+            TopoEdgeRepresentation::new(2, 1, 2, 900.0, 900.0, 900.0),
+            TopoEdgeRepresentation::new(3, 2, 3, 900.0, 900.0, 900.0),
+        ];
+        let graph = RoutingGraph::new(&rows, &TurnRules::default());
+        let geometry = PathGeometry {
+            geoms: Value::Null,
+            profile: None,
+        };
+        let units = ResultUnits::new(Some(RoutingUnit::Minutes), 6.0).unwrap();
+
+        let detour = graph.shortest_path(1, 3).unwrap();
+        assert_eq!(detour.nodes, vec![1, 2, 3]);
+        let penalized = graph.path_from_nodes(&[1, 3]).unwrap();
+        assert_eq!(penalized.cost, 4000.0);
+
+        let summary = summarize_path(&penalized, &geometry, "m", &units).unwrap();
+        assert!((summary.duration - 10.0).abs() < 1e-9);
+        assert!((summary.cost - 4000.0 / 1609.344).abs() < 1e-9);
+
+        //A 10-minute target at 6 km/h is 1000 m, met exactly by the penalized edge
+        let ranked =
+            rank_alternatives(vec![("direct", penalized), ("detour", detour)], 2, 1000.0).unwrap();
+        assert_eq!(ranked[0].0, "direct");
+        assert_eq!(ranked[0].2.deviation, 0.0);
+        assert!((ranked[1].2.deviation - 0.8).abs() < 1e-9);
+    }
 
-        query_as::<Postgres, GeoJsonResult>(&geojson_query)
-            .fetch_one(pool)
-            .await
-            .map(|gjs| gjs.geoms.to_string())
-            .map_err(|e| AppError(anyhow!(e)))
-    } else {
-        Err(AppError(anyhow!("Failed to fetch geometries as GeoJSON")))
+    #[test]
+    fn edge_json_holds_only_what_speedicycle_reads() {
+        let edges = vec![
+            TopoEdgeRepresentation::new(1, 2, 3, 1.5, 2.0, 1.0),
+            TopoEdgeRepresentation::new(2, 3, 4, 0.0, 0.0, 0.0),
+        ];
+
+        assert_eq!(
+            try_convert_to_edge_json(&edges).unwrap(),
+            r#"[{"edge_id":1,"start_node":2,"end_node":3,"weight":1.5},{"edge_id":2,"start_node":3,"end_node":4,"weight":0.0}]"#
+        );
     }
 }